@@ -3,16 +3,40 @@
 //! This module defines the core AST types used by the parser and interpreter:
 //! - [`Expr`]: Expression nodes (literals, variables, operations, function calls)
 //! - [`Stmt`]: Statement nodes (assignments, control flow, function definitions)
+//!
+//! Both are a kind ([`ExprKind`], [`StmtKind`]) plus the [`Span`] where the
+//! node starts.
 //! - [`BinOp`]: Binary operators
+//! - [`Span`]: Source locations for error reporting
+
+#[cfg(feature = "json")]
+use serde::Serialize;
+
+/// Source location of a node (1-based line and column).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
 
 /// Type annotation (Toki Pona type categories).
 ///
 /// Used in function signatures for optional type checking. Corresponds to
 /// `Value::type_name()` strings. `Ijo` acts as `any` — skips type checking.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize))]
 pub enum Type {
     /// nanpa - Number
     Nanpa,
+    /// kipisi - Exact rational number
+    Kipisi,
     /// sitelen - String
     Sitelen,
     /// lon - Boolean (true). Also accepts ala as a falsy value.
@@ -27,6 +51,22 @@ pub enum Type {
     Ala,
     /// ijo - Any (skips type check)
     Ijo,
+    /// A `nasin_ilo` capability: a nasin holding the listed functions, or
+    /// a single function when the capability lists only one.
+    Capability(Box<Capability>),
+    /// A `nimi_nasin` alias or `nasin_ilo` name. Only exists between
+    /// parsing and alias resolution; the parser replaces it with the type
+    /// it names.
+    Named(String),
+}
+
+/// Capability declaration: nasin_ilo Nimi open ilo f (a, b) ... pini
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Capability {
+    pub name: String,
+    /// Required functions with their parameter counts.
+    pub functions: Vec<(String, usize)>,
 }
 
 impl Type {
@@ -34,6 +74,7 @@ impl Type {
     pub fn from_name(s: &str) -> Option<Self> {
         match s {
             "nanpa" => Some(Type::Nanpa),
+            "kipisi" => Some(Type::Kipisi),
             "sitelen" => Some(Type::Sitelen),
             "lon" => Some(Type::Lon),
             "kulupu" => Some(Type::Kulupu),
@@ -46,9 +87,10 @@ impl Type {
     }
 
     /// Human-readable name (matches Value::type_name()).
-    pub fn name(&self) -> &'static str {
+    pub fn name(&self) -> &str {
         match self {
             Type::Nanpa => "nanpa",
+            Type::Kipisi => "kipisi",
             Type::Sitelen => "sitelen",
             Type::Lon => "lon",
             Type::Kulupu => "kulupu",
@@ -56,6 +98,8 @@ impl Type {
             Type::Ilo => "ilo",
             Type::Ala => "ala",
             Type::Ijo => "ijo",
+            Type::Capability(capability) => &capability.name,
+            Type::Named(name) => name,
         }
     }
 }
@@ -68,20 +112,47 @@ impl std::fmt::Display for Type {
 
 /// Binary operators
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize))]
 pub enum BinOp {
     Add, // +
     Sub, // -
     Mul, // *
     Div, // /
+    Mod, // % (never negative for a positive divisor)
+    Pow, // **
     Gt,  // suli (>)
     Lt,  // lili (<)
     Ge,  // suli_sama (>=)
     Le,  // lili_sama (<=)
     Eq,  // sama (==)
+    And, // en (short-circuit)
+    Or,  // anu (short-circuit)
+}
+
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "**",
+            BinOp::Gt => "suli",
+            BinOp::Lt => "lili",
+            BinOp::Ge => "suli_sama",
+            BinOp::Le => "lili_sama",
+            BinOp::Eq => "sama",
+            BinOp::And => "en",
+            BinOp::Or => "anu",
+        };
+        write!(f, "{symbol}")
+    }
 }
 
 /// A part of a template string
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
 pub enum StringPart {
     /// Literal text
     Literal(String),
@@ -89,9 +160,65 @@ pub enum StringPart {
     Interpolation(Box<Expr>),
 }
 
-/// Expression AST node
+/// Expression AST node: what it is, and where it starts.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    // Constructors for generating code (see `printer::ToSource`). The nodes
+    // get the default span, line 0 column 0, as they have no source yet.
+
+    pub fn number(n: f64) -> Self {
+        Self::new(ExprKind::Number(n), Span::default())
+    }
+
+    /// A string literal without interpolation.
+    pub fn string(text: &str) -> Self {
+        Self::new(
+            ExprKind::TemplateString(vec![StringPart::Literal(text.to_string())]),
+            Span::default(),
+        )
+    }
+
+    pub fn var(name: &str) -> Self {
+        Self::new(ExprKind::Var(name.to_string()), Span::default())
+    }
+
+    pub fn binary(left: Expr, op: BinOp, right: Expr) -> Self {
+        Self::new(
+            ExprKind::Binary {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+                span: Span::default(),
+            },
+            Span::default(),
+        )
+    }
+
+    pub fn call(name: &str, args: impl IntoIterator<Item = Expr>) -> Self {
+        Self::new(
+            ExprKind::FuncCall {
+                name: name.to_string(),
+                args: args.into_iter().collect(),
+            },
+            Span::default(),
+        )
+    }
+}
+
+/// The kinds of expression
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub enum ExprKind {
     /// Number literal: 10, 3.14
     Number(f64),
     /// Template string: "Hello, {name}!"
@@ -100,20 +227,36 @@ pub enum Expr {
     Bool(bool),
     /// Variable reference
     Var(String),
-    /// Binary operation
+    /// Binary operation. `span` points at the operator.
     Binary {
         left: Box<Expr>,
         op: BinOp,
         right: Box<Expr>,
+        span: Span,
     },
     /// Unary negation
     Neg(Box<Expr>),
+    /// Logical not: ala x (lon if x is falsy, else ala)
+    Not(Box<Expr>),
+    /// While loop used as an expression: its value is the value given to
+    /// `pini_tawa e v`, else the `taso` block's value, else ala.
+    While {
+        label: Option<String>,
+        cond: Box<Expr>,
+        body: Block,
+        else_block: Option<Block>,
+    },
+    /// Block expression: open ... pini
+    ///
+    /// Runs in its own scope; evaluates to its last statement when that is
+    /// an expression statement, to the value of a `pana`, or to ala.
+    Block(Block),
     /// Function call: NAME e (args)
     FuncCall { name: String, args: Vec<Expr> },
     /// Anonymous function literal (lambda): ilo (params) [-> type] open ... pini
     ///
-    /// Evaluates to a `Value::Function` whose `captured` field is a snapshot
-    /// of the enclosing scope stack.
+    /// Evaluates to a `Value::Function` whose `captured` field shares the
+    /// enclosing scope stack.
     Lambda {
         params: Vec<String>,
         param_types: Vec<Option<Type>>,
@@ -122,19 +265,143 @@ pub enum Expr {
     },
 }
 
-/// Statement AST node
+/// Statement AST node: what it is, and where it starts.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Stmt {
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    /// `ilo name (params) open ... pini` with unannotated parameters.
+    pub fn func(name: &str, params: &[&str], body: Block) -> Self {
+        Self::new(
+            StmtKind::FuncDef {
+                name: name.to_string(),
+                params: params.iter().map(|p| p.to_string()).collect(),
+                param_types: vec![None; params.len()],
+                return_type: None,
+                body,
+            },
+            Span::default(),
+        )
+    }
+}
+
+/// Builds a [`Block`] one statement at a time:
+/// `BlockBuilder::new().assign("x", Expr::number(1.0)).ret(Expr::var("x")).build()`.
+#[derive(Debug, Default)]
+pub struct BlockBuilder {
+    stmts: Block,
+}
+
+impl BlockBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stmt(mut self, kind: StmtKind) -> Self {
+        self.stmts.push(Stmt::new(kind, Span::default()));
+        self
+    }
+
+    /// `target jo value`
+    pub fn assign(self, target: &str, value: Expr) -> Self {
+        self.stmt(StmtKind::Assign {
+            target: target.to_string(),
+            value,
+        })
+    }
+
+    /// An expression statement, such as a call.
+    pub fn expr(self, expr: Expr) -> Self {
+        self.stmt(StmtKind::Expr(expr))
+    }
+
+    /// `pana value`
+    pub fn ret(self, value: Expr) -> Self {
+        self.stmt(StmtKind::Return(value))
+    }
+
+    pub fn build(self) -> Block {
+        self.stmts
+    }
+}
+
+/// The kinds of statement
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub enum StmtKind {
     /// Assignment: x li jo e Expr
     Assign { target: String, value: Expr },
+    /// Declaration: sin x jo Expr (defines x in the current scope)
+    Declare { name: String, value: Expr },
+    /// Global declaration: ma x, y (lets this function body write globals x, y)
+    Global(Vec<String>),
     /// If statement: Cond la open ... pini taso open ... pini
     If {
         cond: Expr,
         then_block: Block,
         else_block: Option<Block>,
     },
-    /// While loop: wile Cond la open ... pini
-    While { cond: Expr, body: Block },
+    /// Guard: wile Cond taso pana e Expr (returns Expr when Cond is falsy)
+    Guard { cond: Expr, value: Expr },
+    /// While loop: [tawa nimi:] wile Cond la open ... pini [taso open ... pini]
+    ///
+    /// The `taso` block runs when the loop ends because Cond became false,
+    /// not when it was left with `pini_tawa`.
+    While {
+        label: Option<String>,
+        cond: Expr,
+        body: Block,
+        else_block: Option<Block>,
+    },
+    /// Counting loop: [tawa nimi:] tawa i tan Start tawa End la open ... pini
+    ///
+    /// Runs the body with `i` = Start, Start + 1, ... while `i` < End. The
+    /// bounds are evaluated once, and each iteration gets its own `i`.
+    For {
+        label: Option<String>,
+        var: String,
+        start: Expr,
+        end: Expr,
+        body: Block,
+    },
+    /// For-each loop: [tawa nimi:] tawa [k,] x lon Expr la open ... pini
+    ///
+    /// Runs the body once per element of a kulupu, or once per entry of a
+    /// nasin in key order. `x` is the element (or the key, for a nasin with
+    /// one name); with two names `k` is the index (or key) and `x` the
+    /// element (or value). Expr is evaluated once, and each iteration gets
+    /// its own bindings.
+    ForEach {
+        label: Option<String>,
+        key: Option<String>,
+        var: String,
+        iterable: Expr,
+        body: Block,
+    },
+    /// Type alias: nimi_nasin Nimi = type (usable in later annotations)
+    TypeAlias { name: String, ty: Type },
+    /// Capability declaration: nasin_ilo Nimi open ilo f (a, b) ... pini
+    Capability(Capability),
+    /// Import: kepeken lipu/nimi (the module `lipu/nimi.lipo`, relative to
+    /// the importing file)
+    Import(String),
+    /// Break: pini_tawa [nimi] [e Expr] (leaves the innermost loop, or the
+    /// enclosing loop labeled `tawa nimi:`)
+    Break {
+        label: Option<String>,
+        value: Option<Expr>,
+    },
+    /// Continue: awen_sin [nimi] (skips to the next iteration of the
+    /// innermost loop, or of the enclosing loop labeled `tawa nimi:`)
+    Continue { label: Option<String> },
     /// Function definition: ilo NAME (params) open ... pini
     ///
     /// Each parameter may have an optional type annotation (written as
@@ -151,6 +418,8 @@ pub enum Stmt {
     Return(Expr),
     /// Expression statement (for side effects like function calls)
     Expr(Expr),
+    /// Strict mode directive: o awen nasin (only as the first statement)
+    Strict,
 }
 
 /// A block is a sequence of statements
@@ -0,0 +1,467 @@
+//! Startup configuration for the `lipona` command.
+//!
+//! Settings are layered, later sources winning: built-in defaults, the
+//! nearest `.lipona.toml` found upward from the script, `LIPONA_*`
+//! environment variables, and finally command-line flags.
+
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use crate::engine::Limits;
+use crate::interpreter::Options;
+
+/// A setting that has both an environment variable and a flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    MaxDepth,
+    MaxIter,
+    MaxOutput,
+    MaxList,
+    MaxMap,
+    MaxString,
+    Timeout,
+    Seed,
+    StatsFile,
+    Dialect,
+    Color,
+    Lang,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 12] = [
+        Setting::MaxDepth,
+        Setting::MaxIter,
+        Setting::MaxOutput,
+        Setting::MaxList,
+        Setting::MaxMap,
+        Setting::MaxString,
+        Setting::Timeout,
+        Setting::Seed,
+        Setting::StatsFile,
+        Setting::Dialect,
+        Setting::Color,
+        Setting::Lang,
+    ];
+
+    pub fn env_var(self) -> &'static str {
+        match self {
+            Setting::MaxDepth => "LIPONA_MAX_DEPTH",
+            Setting::MaxIter => "LIPONA_MAX_ITER",
+            Setting::MaxOutput => "LIPONA_MAX_OUTPUT",
+            Setting::MaxList => "LIPONA_MAX_LIST",
+            Setting::MaxMap => "LIPONA_MAX_MAP",
+            Setting::MaxString => "LIPONA_MAX_STRING",
+            Setting::Timeout => "LIPONA_TIMEOUT",
+            Setting::Seed => "LIPONA_SEED",
+            Setting::StatsFile => "LIPONA_STATS_FILE",
+            Setting::Dialect => "LIPONA_DIALECT",
+            Setting::Color => "LIPONA_COLOR",
+            Setting::Lang => "LIPONA_LANG",
+        }
+    }
+
+    /// Key in the `[run]` table of `.lipona.toml`.
+    fn config_key(self) -> &'static str {
+        match self {
+            Setting::MaxDepth => "max_depth",
+            Setting::MaxIter => "max_iter",
+            Setting::MaxOutput => "max_output",
+            Setting::MaxList => "max_list",
+            Setting::MaxMap => "max_map",
+            Setting::MaxString => "max_string",
+            Setting::Timeout => "timeout",
+            Setting::Seed => "seed",
+            Setting::StatsFile => "stats_file",
+            Setting::Dialect => "dialect",
+            Setting::Color => "color",
+            Setting::Lang => "lang",
+        }
+    }
+
+    pub fn from_flag(flag: &str) -> Option<Self> {
+        match flag {
+            "--max-depth" => Some(Setting::MaxDepth),
+            "--max-iter" => Some(Setting::MaxIter),
+            "--max-output" => Some(Setting::MaxOutput),
+            "--max-list" => Some(Setting::MaxList),
+            "--max-map" => Some(Setting::MaxMap),
+            "--max-string" => Some(Setting::MaxString),
+            "--timeout" => Some(Setting::Timeout),
+            "--seed" => Some(Setting::Seed),
+            "--stats-file" => Some(Setting::StatsFile),
+            "--dialect" => Some(Setting::Dialect),
+            "--color" => Some(Setting::Color),
+            "--lang" => Some(Setting::Lang),
+            _ => None,
+        }
+    }
+
+    /// What a valid value looks like, for error messages.
+    fn expected(self) -> &'static str {
+        match self {
+            Setting::MaxDepth
+            | Setting::MaxIter
+            | Setting::MaxOutput
+            | Setting::MaxList
+            | Setting::MaxMap
+            | Setting::MaxString
+            | Setting::Timeout => "a positive integer",
+            Setting::Seed => "a whole number from 0 to 2^64 - 1",
+            Setting::StatsFile | Setting::Dialect => "a file path",
+            Setting::Color => "auto, always or never",
+            Setting::Lang => "en or ja",
+        }
+    }
+}
+
+/// When to color error output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Color {
+    /// Color only when stderr is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Language for messages printed by the `lipona` command itself.
+/// Runtime errors keep their `pakala:` wording in every language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Ja,
+}
+
+impl Lang {
+    /// Pick the message for this language.
+    pub fn pick<'a>(self, en: &'a str, ja: &'a str) -> &'a str {
+        match self {
+            Lang::En => en,
+            Lang::Ja => ja,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub options: Options,
+    pub limits: Limits,
+    /// `--timeout`: seconds after which a running script is cancelled.
+    pub timeout: Option<u64>,
+    /// `--stats-file`: where to append a record of each run.
+    pub stats_file: Option<PathBuf>,
+    /// `--dialect`: file of other words for keywords and builtins.
+    pub dialect: Option<PathBuf>,
+    pub color: Color,
+    pub lang: Lang,
+}
+
+impl Settings {
+    /// Build settings from the config file (if any) and then the
+    /// environment. Invalid values are skipped and returned as warnings
+    /// so the caller can report them once the language is known.
+    pub fn load(file: Option<&ConfigFile>) -> (Self, Vec<String>) {
+        let mut settings = Self::default();
+        let mut warnings = Vec::new();
+        if let Some(file) = file {
+            file.apply(&mut settings, &mut warnings);
+        }
+        settings.apply_env(&mut warnings);
+        (settings, warnings)
+    }
+
+    /// Settings from the environment alone, used for messages printed
+    /// before the config file has been found.
+    pub fn from_env() -> Self {
+        let mut settings = Self::default();
+        settings.apply_env(&mut Vec::new());
+        settings
+    }
+
+    fn apply_env(&mut self, warnings: &mut Vec<String>) {
+        for setting in Setting::ALL {
+            if let Ok(value) = std::env::var(setting.env_var()) {
+                if let Err(e) = self.set(setting, &value) {
+                    warnings.push(format!("{}: {e}", setting.env_var()));
+                }
+            }
+        }
+    }
+
+    /// Apply one setting from its string form.
+    pub fn set(&mut self, setting: Setting, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value '{value}', expected {}", setting.expected());
+        let value = value.trim();
+        match setting {
+            Setting::MaxDepth => {
+                self.limits.max_call_depth = parse_positive(value).ok_or_else(invalid)?;
+            }
+            Setting::MaxIter => {
+                self.limits.max_loop_iterations = parse_positive(value).ok_or_else(invalid)? as u64;
+            }
+            Setting::MaxOutput => {
+                self.limits.max_output = Some(parse_positive(value).ok_or_else(invalid)?);
+            }
+            Setting::MaxList => {
+                self.limits.max_list_len = Some(parse_positive(value).ok_or_else(invalid)?);
+            }
+            Setting::MaxMap => {
+                self.limits.max_map_entries = Some(parse_positive(value).ok_or_else(invalid)?);
+            }
+            Setting::MaxString => {
+                self.limits.max_string_len = Some(parse_positive(value).ok_or_else(invalid)?);
+            }
+            Setting::Timeout => {
+                self.timeout = Some(parse_positive(value).ok_or_else(invalid)? as u64);
+            }
+            Setting::Seed => {
+                self.options.seed = Some(value.parse().map_err(|_| invalid())?);
+            }
+            Setting::StatsFile => {
+                if value.is_empty() {
+                    return Err(invalid());
+                }
+                self.stats_file = Some(PathBuf::from(value));
+            }
+            Setting::Dialect => {
+                if value.is_empty() {
+                    return Err(invalid());
+                }
+                self.dialect = Some(PathBuf::from(value));
+            }
+            Setting::Color => {
+                self.color = match value.to_ascii_lowercase().as_str() {
+                    "auto" => Color::Auto,
+                    "always" | "1" | "on" => Color::Always,
+                    "never" | "0" | "off" => Color::Never,
+                    _ => return Err(invalid()),
+                };
+            }
+            Setting::Lang => {
+                // Accept locale-style values such as "ja_JP.UTF-8".
+                let lang = value.to_ascii_lowercase();
+                self.lang = if lang.starts_with("ja") {
+                    Lang::Ja
+                } else if lang.starts_with("en") {
+                    Lang::En
+                } else {
+                    return Err(invalid());
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether error output on stderr should be colored.
+    pub fn use_color(&self) -> bool {
+        match self.color {
+            Color::Auto => std::io::stderr().is_terminal(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
+/// Name of the per-project config file.
+pub const CONFIG_FILE_NAME: &str = ".lipona.toml";
+
+/// Tables reserved for tools that do not exist yet (formatter, permission
+/// sandbox, module search paths). They are accepted so one file can be
+/// shared as those land, but nothing reads them today.
+const RESERVED_TABLES: &[&str] = &["fmt", "check", "permissions", "paths"];
+
+/// A parsed `.lipona.toml`.
+///
+/// ```toml
+/// [run]
+/// max_depth = 200
+/// strict_math = true
+/// lang = "ja"
+///
+/// [lint]
+/// allow = ["sum", "Tiki"]
+/// gradual = true
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    run: toml::Table,
+    /// `[lint] allow`: extra words (or whole identifiers) `--lint` accepts.
+    pub lint_allow: Vec<String>,
+    /// `[lint] gradual`: also report untyped-to-typed boundaries.
+    pub lint_gradual: bool,
+}
+
+impl ConfigFile {
+    /// Find the nearest `.lipona.toml` in `start` or one of its ancestors.
+    pub fn discover(start: &Path) -> Result<Option<Self>, String> {
+        for dir in start.ancestors() {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if path.is_file() {
+                return Self::read(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read '{}': {e}", path.display()))?;
+        Self::parse(&text).map(|mut file| {
+            file.path = path.to_path_buf();
+            file
+        })
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| format!("invalid {CONFIG_FILE_NAME}: {}", e.message()))?;
+        let run = match table.remove("run") {
+            None => toml::Table::new(),
+            Some(toml::Value::Table(run)) => run,
+            Some(_) => return Err(format!("[run] in {CONFIG_FILE_NAME} must be a table")),
+        };
+        let lint_allow = match table.get("lint").and_then(|lint| lint.get("allow")) {
+            None => Vec::new(),
+            Some(toml::Value::Array(items)) => items
+                .iter()
+                .map(|item| item.as_str().map(str::to_string))
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("[lint] allow in {CONFIG_FILE_NAME} must be strings"))?,
+            Some(_) => return Err(format!("[lint] allow in {CONFIG_FILE_NAME} must be a list")),
+        };
+        let lint_gradual = match table.get("lint").and_then(|lint| lint.get("gradual")) {
+            None => false,
+            Some(toml::Value::Boolean(b)) => *b,
+            Some(_) => {
+                return Err(format!(
+                    "[lint] gradual in {CONFIG_FILE_NAME} must be true or false"
+                ))
+            }
+        };
+        for key in table.keys() {
+            if key != "lint" && !RESERVED_TABLES.contains(&key.as_str()) {
+                return Err(format!("unknown table [{key}] in {CONFIG_FILE_NAME}"));
+            }
+        }
+        Ok(Self {
+            path: PathBuf::new(),
+            run,
+            lint_allow,
+            lint_gradual,
+        })
+    }
+
+    fn apply(&self, settings: &mut Settings, warnings: &mut Vec<String>) {
+        for (key, value) in &self.run {
+            let flag = match key.as_str() {
+                "strict_math" => Some(&mut settings.options.strict_math),
+                "strict" => Some(&mut settings.options.strict),
+                "legacy_scopes" => Some(&mut settings.options.legacy_scopes),
+                "warn_float_eq" => Some(&mut settings.options.warn_float_eq),
+                _ => None,
+            };
+            let result = if let Some(flag) = flag {
+                match value {
+                    toml::Value::Boolean(b) => {
+                        *flag = *b;
+                        Ok(())
+                    }
+                    _ => Err("expected true or false".to_string()),
+                }
+            } else if let Some(setting) = Setting::ALL.into_iter().find(|s| s.config_key() == key) {
+                let text = match value {
+                    toml::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                let result = settings.set(setting, &text);
+                // A shared classroom config names files relative to itself.
+                let file = match setting {
+                    Setting::StatsFile => settings.stats_file.as_mut(),
+                    Setting::Dialect => settings.dialect.as_mut(),
+                    _ => None,
+                };
+                if let (Some(file), Some(dir)) = (file, self.path.parent()) {
+                    *file = dir.join(&*file);
+                }
+                result
+            } else {
+                Err("unknown key".to_string())
+            };
+            if let Err(e) = result {
+                warnings.push(format!("{}: [run] {key}: {e}", self.path.display()));
+            }
+        }
+    }
+}
+
+fn parse_positive(value: &str) -> Option<usize> {
+    value.parse().ok().filter(|n| *n > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_parses_values() {
+        let mut settings = Settings::default();
+        settings.set(Setting::MaxDepth, " 50 ").unwrap();
+        settings.set(Setting::Color, "NEVER").unwrap();
+        settings.set(Setting::Lang, "ja_JP.UTF-8").unwrap();
+        settings.set(Setting::Timeout, "5").unwrap();
+        settings.set(Setting::Seed, "0").unwrap();
+        settings.set(Setting::MaxList, "1000").unwrap();
+        assert_eq!(settings.limits.max_call_depth, 50);
+        assert_eq!(settings.timeout, Some(5));
+        assert_eq!(settings.options.seed, Some(0));
+        assert_eq!(settings.limits.max_list_len, Some(1000));
+        assert_eq!(settings.color, Color::Never);
+        assert_eq!(settings.lang, Lang::Ja);
+
+        assert!(settings.set(Setting::MaxIter, "0").is_err());
+        assert!(settings.set(Setting::Lang, "fr").is_err());
+        assert_eq!(settings.lang, Lang::Ja);
+    }
+
+    #[test]
+    fn test_config_file_layers_under_settings() {
+        let file = ConfigFile::parse(
+            r#"
+            [run]
+            max_depth = 200
+            strict_math = true
+            colour = "never"
+
+            [lint]
+            allow = ["sum"]
+            gradual = true
+
+            [fmt]
+            width = 100
+        "#,
+        )
+        .unwrap();
+        assert_eq!(file.lint_allow, vec!["sum"]);
+        assert!(file.lint_gradual);
+
+        let mut settings = Settings::default();
+        let mut warnings = Vec::new();
+        file.apply(&mut settings, &mut warnings);
+        assert_eq!(settings.limits.max_call_depth, 200);
+        assert!(settings.options.strict_math);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+
+        assert!(ConfigFile::parse("[formatter]\nwidth = 1").is_err());
+
+        let mut file =
+            ConfigFile::parse("[run]\nstats_file = \"sona.jsonl\"\ndialect = \"nimi.toml\"")
+                .unwrap();
+        file.path = PathBuf::from("/tomo/.lipona.toml");
+        file.apply(&mut settings, &mut warnings);
+        assert_eq!(settings.stats_file, Some(PathBuf::from("/tomo/sona.jsonl")));
+        assert_eq!(settings.dialect, Some(PathBuf::from("/tomo/nimi.toml")));
+    }
+}
@@ -0,0 +1,109 @@
+//! Grammar conformance corpus, run with `lipona test-grammar <dir>`.
+//!
+//! Every `.lipo` file under the directory is parsed. A file whose name ends
+//! in `_pakala.lipo` must fail to parse; every other file must parse, and its
+//! AST (as JSON) must match the `.ast.json` snapshot next to it. Missing
+//! snapshots are written; with `--bless` all snapshots are rewritten, so a
+//! grammar change shows up as a reviewable diff of the snapshot files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::parser::parse;
+
+/// Suffix (before `.lipo`) of files that must not parse.
+const FAIL_SUFFIX: &str = "_pakala";
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub passed: usize,
+    /// `path: reason` for each file that did not behave as expected.
+    pub failures: Vec<String>,
+    /// Snapshots created (or rewritten with `bless`).
+    pub written: Vec<PathBuf>,
+}
+
+pub fn run_corpus(dir: &Path, bless: bool) -> Result<Outcome, String> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+
+    let mut outcome = Outcome::default();
+    for path in files {
+        let source = fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read '{}': {e}", path.display()))?;
+        let must_fail = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| stem.ends_with(FAIL_SUFFIX));
+        let result = match (parse(&source), must_fail) {
+            (Ok(_), true) => Err("parsed, but the file is marked to fail".to_string()),
+            (Err(_), true) => Ok(()),
+            (Err(e), false) => Err(e.to_string()),
+            (Ok(program), false) => {
+                let json = serde_json::to_string_pretty(&program)
+                    .map_err(|e| format!("cannot serialize AST: {e}"))?
+                    + "\n";
+                let snapshot = path.with_extension("ast.json");
+                match fs::read_to_string(&snapshot) {
+                    Ok(expected) if !bless => {
+                        if expected == json {
+                            Ok(())
+                        } else {
+                            Err(format!(
+                                "AST differs from {} (rerun with --bless to accept)",
+                                snapshot.display()
+                            ))
+                        }
+                    }
+                    _ => {
+                        fs::write(&snapshot, json)
+                            .map_err(|e| format!("cannot write '{}': {e}", snapshot.display()))?;
+                        outcome.written.push(snapshot);
+                        Ok(())
+                    }
+                }
+            }
+        };
+        match result {
+            Ok(()) => outcome.passed += 1,
+            Err(reason) => outcome
+                .failures
+                .push(format!("{}: {reason}", path.display())),
+        }
+    }
+    Ok(outcome)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("cannot read '{}': {e}", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("cannot read '{}': {e}", dir.display()))?
+            .path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "lipo") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grammar_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/grammar");
+        let outcome = run_corpus(&dir, false).unwrap();
+        assert!(outcome.failures.is_empty(), "{:#?}", outcome.failures);
+        assert!(
+            outcome.written.is_empty(),
+            "new snapshots: {:?}",
+            outcome.written
+        );
+        assert!(outcome.passed > 0);
+    }
+}
@@ -0,0 +1,185 @@
+//! Teaching dialects: other words for keywords and builtins.
+//!
+//! A dialect file maps each word a class writes to the word Lipona knows:
+//!
+//! ```toml
+//! [words]
+//! la_pali = "open"    # `la_pali ... pini` instead of `open ... pini`
+//! nanpa_ale = "nanpa_sin"
+//! ```
+//!
+//! Source is translated word by word before it is parsed, so the grammar
+//! never changes. String contents, comments and the lines of a
+//! `sitelen x open` text block are left alone; code inside `{...}`
+//! interpolations is translated. Words are whole identifiers, so
+//! `pini_tawa` is one word and not `pini` followed by `_tawa`. Columns in
+//! error messages count the translated words.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default)]
+pub struct Dialect {
+    /// Dialect word -> Lipona word.
+    words: HashMap<String, String>,
+}
+
+impl Dialect {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read '{}': {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| format!("invalid dialect: {}", e.message()))?;
+        let words = match table.remove("words") {
+            None => toml::Table::new(),
+            Some(toml::Value::Table(words)) => words,
+            Some(_) => return Err("[words] must be a table".to_string()),
+        };
+        if let Some(key) = table.keys().next() {
+            return Err(format!("unknown table [{key}]"));
+        }
+        let mut dialect = Self::default();
+        for (word, target) in words {
+            let Some(target) = target.as_str() else {
+                return Err(format!("[words] {word}: expected a word in quotes"));
+            };
+            for w in [word.as_str(), target] {
+                if !is_word(w) {
+                    return Err(format!("[words] {word}: '{w}' is not a single word"));
+                }
+            }
+            dialect.words.insert(word.clone(), target.to_string());
+        }
+        Ok(dialect)
+    }
+
+    /// `source` with every dialect word replaced by its Lipona word.
+    pub fn translate(&self, source: &str) -> String {
+        let mut out = String::with_capacity(source.len());
+        // Innermost last: code, a string, or code in a string's `{...}`.
+        let mut contexts = vec![Context::Code];
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            let context = *contexts.last().unwrap_or(&Context::Code);
+            if context == Context::String {
+                out.push(c);
+                match c {
+                    '\\' => out.extend(chars.next()),
+                    '"' => {
+                        contexts.pop();
+                    }
+                    '{' => contexts.push(Context::Interpolation),
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '/' if chars.peek() == Some(&'/') => {
+                    out.push(c);
+                    while let Some(c) = chars.next_if(|&c| c != '\n') {
+                        out.push(c);
+                    }
+                }
+                '"' => {
+                    out.push(c);
+                    contexts.push(Context::String);
+                }
+                '}' if context == Context::Interpolation => {
+                    out.push(c);
+                    contexts.pop();
+                }
+                '\n' if context == Context::Code && opens_text_block(&out) => {
+                    out.push(c);
+                    // Copy the block's lines up to `pini_sitelen` as they are.
+                    let mut line = String::new();
+                    for c in chars.by_ref() {
+                        line.push(c);
+                        if c == '\n' {
+                            out.push_str(&line);
+                            let end = line.trim() == "pini_sitelen";
+                            line.clear();
+                            if end {
+                                break;
+                            }
+                        }
+                    }
+                    out.push_str(&line);
+                }
+                c if c.is_ascii_alphanumeric() || c == '_' => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                        word.push(c);
+                    }
+                    // A number such as `2` or `1e3` is never a word.
+                    match self.words.get(&word).filter(|_| !c.is_ascii_digit()) {
+                        Some(target) => out.push_str(target),
+                        None => out.push_str(&word),
+                    }
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Code,
+    String,
+    Interpolation,
+}
+
+/// Whether the last line of `out` is the `sitelen x open` that starts a
+/// text block.
+fn opens_text_block(out: &str) -> bool {
+    let line = out.rsplit('\n').next().unwrap_or("");
+    let words: Vec<&str> = line.split_whitespace().collect();
+    matches!(words.as_slice(), ["sitelen", name, "open"] if is_word(name))
+}
+
+fn is_word(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_words_outside_strings_and_comments() {
+        let dialect = Dialect::parse(
+            r#"
+            [words]
+            la_pali = "open"
+            kama = "toki"
+            ijo = "x"
+        "#,
+        )
+        .unwrap();
+        let source = "x lon la la_pali // kama la_pali\n    kama(\"kama {ijo} la_pali\", pini_la_pali)\npini";
+        assert_eq!(
+            dialect.translate(source),
+            "x lon la open // kama la_pali\n    toki(\"kama {x} la_pali\", pini_la_pali)\npini"
+        );
+        assert_eq!(dialect.translate("kama(\"\\{ijo}\")"), "toki(\"\\{ijo}\")");
+        let block = "sitelen ijo la_pali\r\n  kama ijo\n  pini_sitelen\nkama(ijo)";
+        assert_eq!(
+            dialect.translate(block),
+            "sitelen x open\r\n  kama ijo\n  pini_sitelen\ntoki(x)"
+        );
+
+        assert!(Dialect::parse("[words]\n\"a b\" = \"open\"").is_err());
+        assert!(Dialect::parse("[words]\nkama = 1").is_err());
+        assert!(Dialect::parse("[nimi]\nkama = \"toki\"").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! What a backend that runs Lipona programs provides.
+//!
+//! The CLI and the REPL run, evaluate, limit and extend programs through
+//! [`Engine`] rather than through one backend's own methods, so a second
+//! backend (a bytecode VM) can be swapped in and benchmarked against the
+//! tree-walking [`Interpreter`](crate::interpreter::Interpreter), which is
+//! the only implementation today.
+
+use std::rc::Rc;
+
+use crate::ast::{Expr, Stmt};
+use crate::interpreter::{RuntimeError, Value};
+
+/// Default maximum iterations for a single while loop
+const MAX_LOOP_ITERATIONS: u64 = 10_000_000;
+
+/// Default maximum call stack depth
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// Resource limits. Exceeding the call depth or loop limit raises an error
+/// `ken_pali` cannot catch; the output limit raises a catchable
+/// `OutputLimit` and the size limits a catchable `LimitExceeded`, since a
+/// program can recover by printing less or building something smaller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// `--max-depth` / `LIPONA_MAX_DEPTH`: call depth that raises `StackOverflow`.
+    pub max_call_depth: usize,
+    /// `--max-iter` / `LIPONA_MAX_ITER`: iterations of one `wile` or
+    /// counting `tawa` loop that raise `InfiniteLoop`.
+    pub max_loop_iterations: u64,
+    /// `--max-output` / `LIPONA_MAX_OUTPUT`: total bytes `toki` and
+    /// `o_lukin` may write to stdout before raising `OutputLimit`. Unlimited
+    /// if `None`; meant for hosts such as playgrounds that must not be
+    /// flooded. Output redirected to a file does not count.
+    pub max_output: Option<usize>,
+    /// `--max-list` / `LIPONA_MAX_LIST`: entries one kulupu may hold.
+    pub max_list_len: Option<usize>,
+    /// `--max-map` / `LIPONA_MAX_MAP`: entries one nasin may hold.
+    pub max_map_entries: Option<usize>,
+    /// `--max-string` / `LIPONA_MAX_STRING`: bytes one sitelen may hold.
+    /// The size limits are checked on each value an expression produces,
+    /// so a value made of many values within the limits is not refused.
+    pub max_string_len: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_call_depth: MAX_CALL_DEPTH,
+            max_loop_iterations: MAX_LOOP_ITERATIONS,
+            max_output: None,
+            max_list_len: None,
+            max_map_entries: None,
+            max_string_len: None,
+        }
+    }
+}
+
+/// A function the host makes callable from scripts (see
+/// `Engine::register_fn`). It checks its own arguments.
+pub type HostFn = Rc<dyn Fn(Vec<Value>) -> Result<Value, RuntimeError>>;
+
+pub trait Engine {
+    /// Run a program's top-level statements. The value is that of a
+    /// top-level `pana`, else `ala`.
+    fn run(&mut self, program: &[Stmt]) -> Result<Value, RuntimeError>;
+
+    /// Evaluate one expression in the global scope, keeping any
+    /// definitions it makes.
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, RuntimeError>;
+
+    /// Replace the resource limits; applies from the next call or loop
+    /// iteration on.
+    fn set_limits(&mut self, limits: Limits);
+
+    /// Make `func` callable as `name`. A program's own function of the same
+    /// name still wins; a builtin of the same name does not.
+    fn register_fn(&mut self, name: &str, func: HostFn);
+}
@@ -0,0 +1,100 @@
+//! The error a run of a program ends with, whichever stage raised it.
+
+use thiserror::Error;
+
+use crate::interpreter::RuntimeError;
+use crate::parser::ParseError;
+
+/// Why a program could not be run to the end. Match on the variant for the
+/// stage that failed; the stage's own error is also the `source()`, and a
+/// runtime error located with `at` chains on to the error it wraps.
+#[derive(Error, Debug)]
+pub enum LiponaError {
+    #[error("{0}")]
+    Parse(#[from] ParseError),
+    /// `trace` lists the calls the error passed through, innermost first
+    /// (see `Interpreter::take_trace`), and is shown under the message.
+    #[error("{error}{}", trace.iter().map(|line| format!("\n{line}")).collect::<String>())]
+    Runtime {
+        #[source]
+        error: RuntimeError,
+        trace: Vec<String>,
+    },
+    /// Either of the above, from the source called `name`: the script's
+    /// path, `<-e>` for `-e` code, or `<repl:N>` for the REPL's Nth entry.
+    /// Shown as `name: message`, so the line and column can be traced back
+    /// to where the code came from.
+    #[error("{name}: {error}")]
+    InSource {
+        name: String,
+        #[source]
+        error: Box<LiponaError>,
+    },
+}
+
+impl LiponaError {
+    /// A stable name for the kind of error: `parse`, or the runtime
+    /// error's `RuntimeError::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LiponaError::Parse(_) => "parse",
+            LiponaError::Runtime { error, .. } => error.code(),
+            LiponaError::InSource { error, .. } => error.code(),
+        }
+    }
+
+    /// Name the source the error came from.
+    pub fn in_source(self, name: &str) -> Self {
+        LiponaError::InSource {
+            name: name.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::engine::Engine;
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_sources_chain_to_the_innermost_error() {
+        let err = LiponaError::from(parse("x jo (").unwrap_err());
+        assert!(matches!(err, LiponaError::Parse(_)));
+        assert!(err.source().is_some());
+
+        let mut interpreter = Interpreter::new();
+        let error = interpreter
+            .run(&parse("ilo f () open\n  pana 1 / 0\npini\nf()").unwrap())
+            .unwrap_err();
+        let err = LiponaError::Runtime {
+            error,
+            trace: interpreter.take_trace(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "pakala: division by zero at line 2, column 8\n  in 'f' called at line 4, column 1"
+        );
+        let located = err.source().unwrap();
+        assert_eq!(
+            located.to_string(),
+            "pakala: division by zero at line 2, column 8"
+        );
+        assert_eq!(
+            located.source().unwrap().to_string(),
+            "pakala: division by zero"
+        );
+
+        let named = LiponaError::from(parse("x jo (").unwrap_err()).in_source("<repl:3>");
+        assert!(named.to_string().starts_with("<repl:3>: Parse error"));
+        assert!(named
+            .source()
+            .unwrap()
+            .to_string()
+            .starts_with("Parse error"));
+    }
+}
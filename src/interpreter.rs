@@ -3,35 +3,54 @@
 //! Executes AST nodes directly without compilation.
 //! Provides scoped variable bindings and runtime value types.
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-use crate::ast::{BinOp, Block, Expr, Program, Stmt, StringPart, Type};
-use crate::stdlib::StdLib;
+use crate::ast::{BinOp, Block, Expr, ExprKind, Program, Span, Stmt, StmtKind, StringPart, Type};
+use crate::engine::{Engine, HostFn, Limits};
+use crate::parser::parse;
+use crate::random::Random;
+use crate::rational::Rational;
+use crate::stdlib::{BuiltinFn, StdLib};
 
 /// Runtime value
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
+    /// kipisi - exact rational number, created with `nanpa_kipisi`
+    Decimal(Rational),
     String(String),
     /// lon (true) - only true is represented as Bool
     Bool,
     List(Vec<Value>),
-    Map(HashMap<String, Value>),
+    /// Keyed by any value without an `ilo` in it (see `is_key`).
+    Map(HashMap<Value, Value>),
     /// ala represents null/false/empty
     Ala,
     /// User-defined function (or lambda).
     ///
-    /// `captured` is a snapshot of the scope stack at the time the function
-    /// was created. This is used when the function is invoked so that free
-    /// variables resolve to the creation-time environment (lexical scoping),
-    /// rather than to whatever environment the call site happens to be in.
+    /// `captured` is the scope stack where the function was created. Calls
+    /// run the body on top of it, so free variables resolve to the enclosing
+    /// scopes of the definition (lexical scoping) rather than to whatever
+    /// environment the call site happens to be in.
     Function {
         params: Vec<String>,
         param_types: Vec<Option<Type>>,
         return_type: Option<Type>,
         body: Block,
-        captured: Vec<HashMap<String, Value>>,
+        captured: Vec<Scope>,
     },
 }
 
@@ -41,6 +60,7 @@ impl Value {
             Value::Bool => true,
             Value::Ala => false,
             Value::Number(n) => !n.is_nan() && *n != 0.0,
+            Value::Decimal(d) => !d.is_zero(),
             Value::String(s) => !s.is_empty(),
             Value::List(l) => !l.is_empty(),
             Value::Map(m) => !m.is_empty(),
@@ -51,6 +71,7 @@ impl Value {
     pub fn type_name(&self) -> &'static str {
         match self {
             Value::Number(_) => "nanpa",
+            Value::Decimal(_) => "kipisi",
             Value::String(_) => "sitelen",
             Value::Bool => "lon",
             Value::List(_) => "kulupu",
@@ -64,12 +85,121 @@ impl Value {
     ///
     /// Rules:
     /// - `ijo` matches anything (any type)
+    /// - a `nasin_ilo` capability matches a nasin that holds each listed
+    ///   function with the right number of parameters, or - when it lists
+    ///   exactly one - such a function on its own
     /// - otherwise, the value's type name must equal the annotation's name
     pub fn matches_type(&self, ty: &Type) -> bool {
-        if *ty == Type::Ijo {
-            return true;
+        let has_arity = |value: Option<&Value>, arity: usize| matches!(value, Some(Value::Function { params, .. }) if params.len() == arity);
+        match (ty, self) {
+            (Type::Ijo, _) => true,
+            (Type::Capability(capability), Value::Map(map)) => capability
+                .functions
+                .iter()
+                .all(|(name, arity)| has_arity(map.get(&Value::String(name.clone())), *arity)),
+            (Type::Capability(capability), Value::Function { .. }) => {
+                matches!(capability.functions.as_slice(), [(_, arity)] if has_arity(Some(self), *arity))
+            }
+            (Type::Capability(_), _) => false,
+            _ => self.type_name() == ty.name(),
+        }
+    }
+}
+
+impl Value {
+    /// Whether the value can be a nasin key: any value without an `ilo` in
+    /// it, so a kulupu of arguments can key a memo table.
+    pub fn is_key(&self) -> bool {
+        match self {
+            Value::List(items) => items.iter().all(Value::is_key),
+            Value::Map(map) => map
+                .iter()
+                .all(|(key, value)| key.is_key() && value.is_key()),
+            Value::Function { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// The order nasin keys are visited in: numbers by value, then sitelen,
+    /// lon, ala, kulupu (element by element) and nasin (entry by entry).
+    pub fn key_order(&self, other: &Value) -> cmp::Ordering {
+        fn rank(value: &Value) -> u8 {
+            match value {
+                Value::Number(_) | Value::Decimal(_) => 0,
+                Value::String(_) => 1,
+                Value::Bool => 2,
+                Value::Ala => 3,
+                Value::List(_) => 4,
+                Value::Map(_) => 5,
+                Value::Function { .. } => 6,
+            }
+        }
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.total_cmp(b),
+            (Value::Decimal(a), Value::Decimal(b)) => a.cmp(b),
+            // Equal in value: the nanpa first.
+            (Value::Number(a), Value::Decimal(b)) => {
+                a.total_cmp(&b.to_f64()).then(cmp::Ordering::Less)
+            }
+            (Value::Decimal(a), Value::Number(b)) => {
+                a.to_f64().total_cmp(b).then(cmp::Ordering::Greater)
+            }
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::List(a), Value::List(b)) => a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| a.key_order(b))
+                .find(|order| order.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            (Value::Map(a), Value::Map(b)) => {
+                let (a, b) = (sorted_entries(a), sorted_entries(b));
+                a.iter()
+                    .zip(&b)
+                    .map(|((ak, av), (bk, bv))| ak.key_order(bk).then_with(|| av.key_order(bv)))
+                    .find(|order| order.is_ne())
+                    .unwrap_or_else(|| a.len().cmp(&b.len()))
+            }
+            (Value::Function { params: a, .. }, Value::Function { params: b, .. }) => a.cmp(b),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
+/// A nasin's entries in `key_order`.
+pub fn sorted_entries(map: &HashMap<Value, Value>) -> Vec<(&Value, &Value)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.key_order(b.0));
+    entries
+}
+
+/// Numbers are always finite (see `RuntimeError::NonFinite`), so every
+/// value equals itself.
+impl Eq for Value {}
+
+/// Agrees with `==`: `0` and `-0` hash alike, and a nasin hashes the same
+/// whatever order its entries are in.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Value::Number(n) => (if *n == 0.0 { 0.0f64 } else { *n }).to_bits().hash(state),
+            Value::Decimal(d) => d.hash(state),
+            Value::String(s) => s.hash(state),
+            Value::List(items) => items.hash(state),
+            Value::Map(map) => {
+                let mut sum = 0u64;
+                for entry in map {
+                    let mut hasher = DefaultHasher::new();
+                    entry.hash(&mut hasher);
+                    sum = sum.wrapping_add(hasher.finish());
+                }
+                map.len().hash(state);
+                sum.hash(state);
+            }
+            Value::Bool | Value::Ala => {}
+            // Never a key; equal functions have equal parameters.
+            Value::Function { params, .. } => params.hash(state),
         }
-        self.type_name() == ty.name()
     }
 }
 
@@ -88,6 +218,7 @@ impl std::fmt::Display for Value {
                     write!(f, "{n}")
                 }
             }
+            Value::Decimal(d) => write!(f, "{d}"),
             Value::String(s) => write!(f, "{s}"),
             Value::Bool => write!(f, "lon"),
             Value::List(items) => {
@@ -111,16 +242,46 @@ pub enum RuntimeError {
     UndefinedVariable(String),
     #[error("pakala: undefined function '{0}'")]
     UndefinedFunction(String),
+    #[error("pakala: assignment to undeclared variable '{0}' (strict mode - declare it first with 'sin {0} jo ...')")]
+    UndeclaredAssignment(String),
     #[error("pakala: division by zero")]
     DivisionByZero,
-    #[error("pakala: type error - expected {expected}, got {got}")]
-    TypeError { expected: &'static str, got: String },
+    /// An operator or builtin (`op`) gave infinity or, if `nan`, NaN.
+    /// `span` is the operator's; a builtin's error is located like others.
+    #[error(
+        "pakala: result of '{op}' is {}{}",
+        if *nan { "not a number (undefined)" } else { "infinite (overflow)" },
+        span.map(|span| format!(" at {span}")).unwrap_or_default()
+    )]
+    NonFinite {
+        op: String,
+        nan: bool,
+        span: Option<Span>,
+    },
+    #[error("pakala: precision loss - result of '{op}' leaves the safe integer range at {span}")]
+    PrecisionLoss { op: BinOp, span: Span },
+    #[error("pakala: kipisi overflow - value too large to represent exactly")]
+    DecimalOverflow,
+    /// `arg` names the builtin argument that had the wrong type, if known.
+    #[error("pakala: type error - expected {expected}, got {got}{}", arg.map(|arg| format!(" ({arg})")).unwrap_or_default())]
+    TypeError {
+        expected: &'static str,
+        got: String,
+        arg: Option<ArgPosition>,
+    },
     #[error("pakala: wrong number of arguments for '{name}' - expected {expected}, got {got}")]
     WrongArity {
         name: String,
         expected: usize,
         got: usize,
     },
+    #[error("pakala: wrong number of arguments for '{name}' - expected {min} to {max}, got {got}")]
+    WrongArityRange {
+        name: String,
+        min: usize,
+        max: usize,
+        got: usize,
+    },
     #[error("pakala_toki: function '{func}' parameter '{param}' expected {expected}, got {got}")]
     ParamTypeMismatch {
         func: String,
@@ -140,58 +301,345 @@ pub enum RuntimeError {
     InfiniteLoop,
     #[error("pakala: maximum call depth exceeded (possible infinite recursion)")]
     StackOverflow,
+    /// The host called `CancelHandle::cancel`.
+    #[error("pakala: the script was cancelled")]
+    Cancelled,
+    #[error("pakala: cannot read file '{path}' - {message}")]
+    FileRead { path: String, message: String },
+    #[error("pakala: cannot write file '{path}' - {message}")]
+    FileWrite { path: String, message: String },
+    /// The host's file policy (see `Interpreter::set_file_policy`) refused.
+    #[error("pakala: {access} of file '{path}' is not allowed")]
+    FileDenied { path: String, access: FileAccess },
+    /// Running programs was not allowed (see `Interpreter::set_allow_exec`).
+    #[error("pakala: running '{0}' is not allowed (start lipona with --allow-exec)")]
+    ExecDenied(String),
+    #[error("pakala: cannot run '{command}' - {message}")]
+    Exec { command: String, message: String },
+    /// Fetching over the network was not allowed (see
+    /// `Interpreter::set_allow_net`).
+    #[error("pakala: fetching '{0}' is not allowed (start lipona with --allow-net)")]
+    NetDenied(String),
+    #[error("pakala: cannot fetch '{url}' - {message}")]
+    Net { url: String, message: String },
+    #[error("pakala: cannot read input - {0}")]
+    InputRead(String),
+    #[error("pakala: output limit of {0} bytes exceeded")]
+    OutputLimit(usize),
+    /// A value outgrew `Limits::max_list_len`, `max_map_entries` or
+    /// `max_string_len`. Unlike the other limits, `ken_pali` can catch it.
+    #[error("pakala: size limit exceeded - {kind} of size {size} is over the limit of {limit}")]
+    LimitExceeded {
+        kind: &'static str,
+        size: usize,
+        limit: usize,
+    },
+    #[error("pakala: 'pini_tawa' used outside of a loop")]
+    BreakOutsideLoop,
+    #[error("pakala: 'awen_sin' used outside of a loop")]
+    ContinueOutsideLoop,
+    #[error("pakala: no enclosing loop is labeled '{0}'")]
+    UnknownLabel(String),
+    /// Not a failure: `pini_tawa`, `awen_sin` or `pana` leaving an
+    /// expression (a `wile` or `open ... pini` used as a value) for the loop
+    /// or function around it. The flow waits in `Interpreter::escaping`
+    /// until `exec_stmt` turns it back into a `ControlFlow`.
+    #[error("pakala: internal error - control flow escaped its statement")]
+    Escape,
+    #[error("pakala: wile_sama failed - values differ\n{0}")]
+    AssertionFailed(String),
+    /// Raised by `pakala_sin`; `ken_pali` hands the value back.
+    #[error("pakala: {0}")]
+    UserError(Box<Value>),
+    #[error("pakala: argument error - {0}")]
+    Argument(String),
+    #[error("pakala: import cycle - {0}")]
+    ImportCycle(String),
+    #[error("pakala: cannot parse module '{path}' - {message}")]
+    ModuleParse { path: String, message: String },
+    /// A bug in the interpreter (or a panic in a host callback such as the
+    /// module loader), caught so the embedding program keeps running.
+    #[error("pakala: internal error - {0}")]
+    Internal(String),
+    /// An error raised while a `kepeken` module was running.
+    #[error("{error} in module '{path}'")]
+    InModule {
+        path: String,
+        #[source]
+        error: Box<RuntimeError>,
+    },
+    /// Any other error, with the location of the innermost expression or
+    /// statement that raised it.
+    #[error("{}", located(error, span))]
+    At {
+        #[source]
+        error: Box<RuntimeError>,
+        span: Span,
+    },
+}
+
+/// A builtin argument, by 1-based position: "argument 2 of kulupu_lon".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgPosition {
+    pub function: &'static str,
+    pub position: usize,
+}
+
+impl std::fmt::Display for ArgPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "argument {} of {}", self.position, self.function)
+    }
+}
+
+/// `error` followed by its location. A multi-line message (such as a
+/// `wile_sama` diff) gets the location at the end of its first line.
+fn located(error: &RuntimeError, span: &Span) -> String {
+    let message = error.to_string();
+    match message.split_once('\n') {
+        Some((first, rest)) => format!("{first} at {span}\n{rest}"),
+        None => format!("{message} at {span}"),
+    }
+}
+
+impl RuntimeError {
+    /// The error inside any location and module wrappers.
+    pub fn innermost(&self) -> &RuntimeError {
+        match self {
+            RuntimeError::At { error, .. } | RuntimeError::InModule { error, .. } => {
+                error.innermost()
+            }
+            error => error,
+        }
+    }
+
+    /// A stable name for the kind of error, such as `division_by_zero`,
+    /// for tallying errors (`--stats-file`) without parsing messages.
+    pub fn code(&self) -> &'static str {
+        match self.innermost() {
+            RuntimeError::UndefinedVariable(_) => "undefined_variable",
+            RuntimeError::UndefinedFunction(_) => "undefined_function",
+            RuntimeError::UndeclaredAssignment(_) => "undeclared_assignment",
+            RuntimeError::DivisionByZero => "division_by_zero",
+            RuntimeError::NonFinite { .. } => "non_finite",
+            RuntimeError::PrecisionLoss { .. } => "precision_loss",
+            RuntimeError::DecimalOverflow => "decimal_overflow",
+            RuntimeError::TypeError { .. } => "type_error",
+            RuntimeError::WrongArity { .. } | RuntimeError::WrongArityRange { .. } => "wrong_arity",
+            RuntimeError::ParamTypeMismatch { .. } => "param_type_mismatch",
+            RuntimeError::ReturnTypeMismatch { .. } => "return_type_mismatch",
+            RuntimeError::IndexOutOfBounds { .. } => "index_out_of_bounds",
+            RuntimeError::InfiniteLoop => "infinite_loop",
+            RuntimeError::StackOverflow => "stack_overflow",
+            RuntimeError::Cancelled => "cancelled",
+            RuntimeError::FileRead { .. } => "file_read",
+            RuntimeError::FileWrite { .. } => "file_write",
+            RuntimeError::FileDenied { .. } => "file_denied",
+            RuntimeError::ExecDenied(_) => "exec_denied",
+            RuntimeError::Exec { .. } => "exec",
+            RuntimeError::NetDenied(_) => "net_denied",
+            RuntimeError::Net { .. } => "net",
+            RuntimeError::InputRead(_) => "input_read",
+            RuntimeError::OutputLimit(_) => "output_limit",
+            RuntimeError::LimitExceeded { .. } => "limit_exceeded",
+            RuntimeError::BreakOutsideLoop => "break_outside_loop",
+            RuntimeError::ContinueOutsideLoop => "continue_outside_loop",
+            RuntimeError::UnknownLabel(_) => "unknown_label",
+            RuntimeError::Escape => "internal",
+            RuntimeError::AssertionFailed(_) => "assertion_failed",
+            RuntimeError::UserError(_) => "user_error",
+            RuntimeError::Argument(_) => "argument",
+            RuntimeError::ImportCycle(_) => "import_cycle",
+            RuntimeError::ModuleParse { .. } => "module_parse",
+            // `innermost` never returns a wrapper.
+            RuntimeError::Internal(_) | RuntimeError::InModule { .. } | RuntimeError::At { .. } => {
+                "internal"
+            }
+        }
+    }
+
+    /// Whether `ken_pali` may recover from this error. Exceeding a resource
+    /// limit or an internal error always ends the program.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self.innermost(),
+            RuntimeError::InfiniteLoop
+                | RuntimeError::StackOverflow
+                | RuntimeError::Cancelled
+                | RuntimeError::Internal(_)
+                | RuntimeError::Escape
+        )
+    }
+
+    /// Attach `span` unless the error already carries a location.
+    fn at(self, span: Span) -> Self {
+        match self {
+            RuntimeError::At { .. }
+            | RuntimeError::InModule { .. }
+            | RuntimeError::NonFinite { span: Some(_), .. }
+            | RuntimeError::PrecisionLoss { .. }
+            | RuntimeError::Escape => self,
+            error => RuntimeError::At {
+                error: Box::new(error),
+                span,
+            },
+        }
+    }
+}
+
+/// A call in progress: the function's name and where it was called from
+/// (`None` for `open_ale`, which the interpreter calls itself).
+#[derive(Clone)]
+struct Frame {
+    name: String,
+    span: Option<Span>,
 }
 
 /// Control flow signals
 enum ControlFlow {
     None,
     Return(Value),
+    /// `pini_tawa [label] [e value]` - leave the innermost loop, or the
+    /// loop with that label
+    Break {
+        label: Option<String>,
+        value: Value,
+    },
+    /// `awen_sin [label]` - go on with the next iteration of the innermost
+    /// loop, or of the loop with that label
+    Continue {
+        label: Option<String>,
+    },
+}
+
+/// Whether a `pini_tawa` (or `awen_sin`) aimed at `target` applies to the
+/// loop labeled `label`: an unlabeled one applies to the innermost loop.
+fn breaks_loop(target: Option<&str>, label: Option<&str>) -> bool {
+    target.is_none() || target == label
+}
+
+/// One level of variable bindings, shared by reference.
+///
+/// A function value holds the same `Scope`s as the code that created it, so
+/// it sees (and can update) its enclosing variables as they change. Equality
+/// is identity and `Debug` does not print the bindings: a scope usually
+/// contains the very function that captured it. That makes a cycle of
+/// `Rc`s, which `release` breaks once the scope goes out of use.
+#[derive(Clone, Default)]
+pub struct Scope(Rc<RefCell<HashMap<String, Value>>>);
+
+impl Scope {
+    fn get(&self, name: &str) -> Option<Value> {
+        self.0.borrow().get(name).cloned()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.0.borrow().contains_key(name)
+    }
+
+    fn insert(&self, name: String, value: Value) {
+        self.0.borrow_mut().insert(name, value);
+    }
+
+    /// An independent copy of the bindings (legacy scoping).
+    fn deep_copy(&self) -> Self {
+        Scope(Rc::new(RefCell::new(self.0.borrow().clone())))
+    }
+
+    /// Let go of a scope that has gone out of use (popped, or a call's own
+    /// scope on return). If every other handle to it is held by a function
+    /// in its own bindings, nothing can reach it again but the cycle would
+    /// keep it alive, so its bindings are dropped. A function that escaped
+    /// (returned, or stored in an outer scope) keeps the scope as it is.
+    fn release(self) {
+        let handles = Rc::strong_count(&self.0);
+        if handles > 1 && self.self_references() == handles - 1 {
+            let bindings = mem::take(&mut *self.0.borrow_mut());
+            drop(bindings);
+        }
+    }
+
+    /// How many functions in the bindings (or in lists and maps there)
+    /// captured this scope.
+    fn self_references(&self) -> usize {
+        fn count(value: &Value, scope: &Scope) -> usize {
+            match value {
+                Value::Function { captured, .. } => captured
+                    .iter()
+                    .filter(|captured| *captured == scope)
+                    .count(),
+                Value::List(items) => items.iter().map(|item| count(item, scope)).sum(),
+                Value::Map(map) => map.values().map(|item| count(item, scope)).sum(),
+                _ => 0,
+            }
+        }
+        self.0
+            .borrow()
+            .values()
+            .map(|value| count(value, self))
+            .sum()
+    }
+}
+
+impl PartialEq for Scope {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl std::fmt::Debug for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Scope({} bindings)", self.0.borrow().len())
+    }
 }
 
 /// Environment for variable bindings
 #[derive(Debug, Clone)]
 pub struct Environment {
-    scopes: Vec<HashMap<String, Value>>,
+    scopes: Vec<Scope>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
-            scopes: vec![HashMap::new()],
+            scopes: vec![Scope::default()],
         }
     }
 
     pub fn push_scope(&mut self) {
-        self.scopes.push(HashMap::new());
+        self.scopes.push(Scope::default());
     }
 
     pub fn pop_scope(&mut self) {
         debug_assert!(self.scopes.len() > 1, "attempted to pop global scope");
         if self.scopes.len() > 1 {
-            self.scopes.pop();
+            if let Some(scope) = self.scopes.pop() {
+                scope.release();
+            }
         }
     }
 
     pub fn define(&mut self, name: String, value: Value) {
-        self.scopes
-            .last_mut()
-            .expect("Environment must have at least one scope")
-            .insert(name, value);
+        self.innermost().insert(name, value);
     }
 
-    pub fn get(&self, name: &str) -> Option<&Value> {
-        for scope in self.scopes.iter().rev() {
-            if let Some(value) = scope.get(name) {
-                return Some(value);
-            }
+    /// The current scope. The stack always holds at least the global scope
+    /// (see `replace_scopes`), but should it ever be empty a fresh global
+    /// scope is started rather than panicking.
+    fn innermost(&mut self) -> &Scope {
+        if self.scopes.is_empty() {
+            self.scopes.push(Scope::default());
         }
-        None
+        &self.scopes[self.scopes.len() - 1]
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
     }
 
     pub fn set(&mut self, name: &str, value: Value) {
         // Search all scopes from innermost to outermost
-        for scope in self.scopes.iter_mut().rev() {
-            if scope.contains_key(name) {
+        for scope in self.scopes.iter().rev() {
+            if scope.contains(name) {
                 scope.insert(name.to_string(), value);
                 return;
             }
@@ -200,32 +648,57 @@ impl Environment {
         self.define(name.to_string(), value);
     }
 
-    /// Take a snapshot of the current scope stack (used when building a
-    /// function value to capture its lexical environment).
-    pub fn snapshot(&self) -> Vec<HashMap<String, Value>> {
+    /// Whether any scope other than the global one defines `name`.
+    pub fn contains_local(&self, name: &str) -> bool {
+        self.scopes.iter().skip(1).any(|scope| scope.contains(name))
+    }
+
+    /// Like `set`, but never writes to the global scope: a name found only
+    /// there is defined in the current scope instead. Used for assignments
+    /// inside function bodies without a `ma` declaration.
+    pub fn set_local(&mut self, name: &str, value: Value) {
+        for scope in self.scopes.iter().skip(1).rev() {
+            if scope.contains(name) {
+                scope.insert(name.to_string(), value);
+                return;
+            }
+        }
+        self.define(name.to_string(), value);
+    }
+
+    /// The current scope stack, shared, for a function value to capture
+    /// its lexical environment.
+    pub fn capture(&self) -> Vec<Scope> {
         self.scopes.clone()
     }
 
+    /// Independent copies of the current scope stack (legacy scoping, where
+    /// a function only sees the values at its creation time).
+    pub fn snapshot(&self) -> Vec<Scope> {
+        self.scopes.iter().map(Scope::deep_copy).collect()
+    }
+
     /// Replace the current scope stack and return the previous one.
     ///
     /// Used to enter a function call with the callee's captured environment,
     /// and to restore the caller's environment on return.
-    pub fn replace_scopes(
-        &mut self,
-        new_scopes: Vec<HashMap<String, Value>>,
-    ) -> Vec<HashMap<String, Value>> {
+    /// An empty stack is replaced by a fresh global scope.
+    pub fn replace_scopes(&mut self, mut new_scopes: Vec<Scope>) -> Vec<Scope> {
+        if new_scopes.is_empty() {
+            new_scopes.push(Scope::default());
+        }
         std::mem::replace(&mut self.scopes, new_scopes)
     }
 
-    /// Return a reference to the current global scope (scope index 0).
-    ///
-    /// Used when entering a function call to refresh the captured snapshot's
-    /// global scope to the live one, so top-level bindings — including the
-    /// function itself (for recursion) — remain visible.
-    pub fn global_scope(&self) -> &HashMap<String, Value> {
-        self.scopes
-            .first()
-            .expect("Environment must have at least one scope")
+    /// Return the current global scope (scope index 0).
+    pub fn global_scope(&mut self) -> &Scope {
+        self.innermost();
+        &self.scopes[0]
+    }
+
+    /// A variable from the global scope only.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.scopes.first().and_then(|scope| scope.get(name))
     }
 }
 
@@ -235,343 +708,4496 @@ impl Default for Environment {
     }
 }
 
-/// Maximum iterations for a single while loop
-const MAX_LOOP_ITERATIONS: u64 = 10_000_000;
+/// Longest stretch `sleep` goes without checking for a cancel.
+const SLEEP_SLICE: Duration = Duration::from_millis(20);
+
+/// Frames printed in a trace before the rest are counted.
+const MAX_TRACE_LINES: usize = 20;
+
+/// Options that change interpreter semantics, set from CLI flags and
+/// `LIPONA_*` environment variables. Resource limits are set separately
+/// (see `Engine::set_limits`).
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// `--strict-math`: raise `PrecisionLoss` when `+`, `-` or `*` on two
+    /// integers produces a result outside the f64 safe-integer range,
+    /// instead of silently rounding.
+    pub strict_math: bool,
+    /// `--strict` or an `o awen nasin` directive: assigning to a variable
+    /// that no scope has defined is an error rather than a new definition.
+    /// Variables are introduced with `sin x jo ...`, parameters and `ilo`.
+    pub strict: bool,
+    /// `--legacy-scopes`: functions capture a copy of their enclosing scopes
+    /// and see a fresh copy of the globals on every call, so assignments to
+    /// outer variables are lost when the call returns. This was the only
+    /// behavior before lexical closures; kept for migrating old programs.
+    pub legacy_scopes: bool,
+    /// `--warn-float-eq`: warn when `sama` compares two numbers that are not
+    /// whole, since rounding makes such comparisons unreliable.
+    pub warn_float_eq: bool,
+    /// `--seed` / `LIPONA_SEED`: seed for `nanpa_nasa`, so a run can be
+    /// repeated exactly. Seeded from the clock if `None`.
+    pub seed: Option<u64>,
+}
+
+/// Reads the source of a `kepeken` module from its path: the importing
+/// file's directory joined with `name.lipo` (just `name.lipo` for `-e` and
+/// the REPL). `None` if there is no such module.
+/// Shared by an interpreter and its forks.
+pub type ModuleLoader = Rc<dyn Fn(&str) -> Option<String>>;
+
+/// How a builtin wants to use a file, as asked of the `FilePolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAccess {
+    /// Reading, or checking that the file exists.
+    Read,
+    /// Creating, overwriting or appending.
+    Write,
+}
+
+impl std::fmt::Display for FileAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FileAccess::Read => "reading",
+            FileAccess::Write => "writing",
+        })
+    }
+}
+
+/// Decides which files scripts may touch (see `set_file_policy`).
+pub type FilePolicy = Rc<dyn Fn(&str, FileAccess) -> bool>;
+
+/// A step of a running program, as shown to the observer (see
+/// `set_observer`).
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// A variable was given a value (`x jo ...` or `sin x jo ...`);
+    /// `global` if the variable is a global.
+    Assign {
+        name: &'a str,
+        value: &'a Value,
+        global: bool,
+        span: Span,
+    },
+    /// A program's `ilo` was called (builtins are not observed).
+    Call {
+        name: &'a str,
+        args: &'a [Value],
+        span: Option<Span>,
+    },
+    /// The innermost observed call finished, with its value or `None` if
+    /// it raised an error.
+    Return {
+        name: &'a str,
+        value: Option<&'a Value>,
+    },
+}
+
+/// Watches a program run step by step (see `set_observer`).
+pub type Observer = Rc<dyn Fn(&Event)>;
 
-/// Maximum call stack depth
-const MAX_CALL_DEPTH: usize = 1000;
+/// What `kute` reads (see `set_input`). Shared with forks, so REPL entries
+/// read on from where the last one stopped.
+pub type Input = Rc<RefCell<dyn Read>>;
 
 /// The interpreter
 pub struct Interpreter {
     env: Environment,
     stdlib: StdLib,
-    call_depth: usize,
+    /// The calls in progress, outermost first.
+    call_stack: Vec<Frame>,
+    /// `call_stack` as it was where the last escaping error was raised
+    /// (see `take_trace`).
+    trace: Vec<Frame>,
+    /// One entry per active user function call: the names its body has
+    /// declared `ma` (writable globals).
+    frames: Vec<HashSet<String>>,
+    /// Resources used so far (`usage.output_bytes` is what `max_output`
+    /// counts).
+    usage: Usage,
+    /// Program output collected by `run_capturing` instead of stdout.
+    captured: Option<String>,
+    /// Active `toki_tawa_lipu` redirections; output goes to the last one.
+    redirects: Vec<Redirect>,
+    /// The files being run, outermost first: the script, then the
+    /// `kepeken` modules still loading. Empty for `-e` and the REPL.
+    files: Vec<PathBuf>,
+    /// Functions defined by each module that finished loading.
+    modules: HashMap<PathBuf, Vec<(String, Value)>>,
+    /// Where `kepeken` gets module source (see `set_module_loader`).
+    module_loader: Option<ModuleLoader>,
+    /// Which files the `lipu_*` builtins may use; all of them if `None`.
+    file_policy: Option<FilePolicy>,
+    /// Whether `ilo_ma` may start programs (see `set_allow_exec`).
+    allow_exec: bool,
+    /// Whether `linja_ma_*` may make requests (see `set_allow_net`).
+    allow_net: bool,
+    /// Told of every assignment and call (see `set_observer`).
+    observer: Option<Observer>,
+    /// Where `kute` and `kute_ale` read; nothing if `None`.
+    input: Option<Input>,
+    /// Command-line arguments after the script name.
+    args: Vec<String>,
+    /// Environment variables set by `poki_ma_lon`. They shadow the
+    /// process environment for this interpreter and the programs `ilo_ma`
+    /// starts, without touching the process itself.
+    env_vars: HashMap<String, String>,
+    /// Warnings raised while running (see `Options::warn_float_eq`), and
+    /// the spans already warned about so a loop warns once.
+    warnings: Vec<String>,
+    warned: HashSet<Span>,
+    /// Old builtin names already warned about (once per run each).
+    renamed_warned: HashSet<String>,
+    /// Every builtin called so far (see `builtins_used`).
+    builtins_used: HashSet<&'static str>,
+    /// Set from another thread to stop the running script.
+    cancel: CancelHandle,
+    /// Source of `nanpa_nasa` (see `Options::seed`).
+    random: Random,
+    options: Options,
+    limits: Limits,
+    /// Functions the host registered (see `Engine::register_fn`).
+    host_fns: HashMap<String, HostFn>,
+    /// Control flow on its way out of an expression (see
+    /// `RuntimeError::Escape`).
+    escaping: Option<ControlFlow>,
 }
 
-impl Interpreter {
-    pub fn new() -> Self {
-        Self {
-            env: Environment::new(),
-            stdlib: StdLib::new(),
-            call_depth: 0,
-        }
-    }
+/// Stops a running script from another thread (see
+/// `Interpreter::cancel_handle`).
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
 
-    pub fn run(&mut self, program: &Program) -> Result<Value, RuntimeError> {
-        for stmt in program {
-            match self.exec_stmt(stmt)? {
-                ControlFlow::Return(v) => return Ok(v),
-                ControlFlow::None => {}
-            }
-        }
-        Ok(Value::Ala)
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
     }
+}
 
-    fn exec_stmt(&mut self, stmt: &Stmt) -> Result<ControlFlow, RuntimeError> {
-        match stmt {
-            Stmt::Assign { target, value } => {
-                let val = self.eval_expr(value)?;
-                self.env.set(target, val);
-                Ok(ControlFlow::None)
-            }
-            Stmt::If {
-                cond,
-                then_block,
-                else_block,
-            } => {
-                let cond_val = self.eval_expr(cond)?;
-                if cond_val.is_truthy() {
-                    self.exec_block(then_block)
-                } else if let Some(else_b) = else_block {
-                    self.exec_block(else_b)
-                } else {
-                    Ok(ControlFlow::None)
-                }
-            }
-            Stmt::While { cond, body } => {
-                let mut iterations: u64 = 0;
-                while self.eval_expr(cond)?.is_truthy() {
-                    iterations += 1;
-                    if iterations > MAX_LOOP_ITERATIONS {
-                        return Err(RuntimeError::InfiniteLoop);
-                    }
-                    if let ControlFlow::Return(v) = self.exec_block(body)? {
-                        return Ok(ControlFlow::Return(v));
-                    }
-                }
-                Ok(ControlFlow::None)
-            }
-            Stmt::FuncDef {
-                name,
-                params,
-                param_types,
-                return_type,
-                body,
-            } => {
-                // Tentatively bind the function name to ala first, then take
-                // an environment snapshot that already includes the new name.
-                // This lets the function's own body resolve recursive calls
-                // through the captured environment.
-                self.env.define(name.clone(), Value::Ala);
-                let captured = self.env.snapshot();
-                let func = Value::Function {
-                    params: params.clone(),
-                    param_types: param_types.clone(),
-                    return_type: return_type.clone(),
-                    body: body.clone(),
-                    captured,
-                };
-                self.env.set(name, func);
-                Ok(ControlFlow::None)
-            }
-            Stmt::Return(expr) => {
-                let val = self.eval_expr(expr)?;
-                Ok(ControlFlow::Return(val))
-            }
-            Stmt::Expr(expr) => {
-                self.eval_expr(expr)?;
-                Ok(ControlFlow::None)
-            }
-        }
-    }
+/// Everything one `run_capturing` produced.
+#[derive(Debug)]
+pub struct RunReport {
+    /// What `run` returned.
+    pub value: Result<Value, RuntimeError>,
+    /// Program output (`toki`, `o_lukin`) not redirected to a file.
+    pub stdout: String,
+    /// Warnings raised during the run (see `take_warnings`).
+    pub warnings: Vec<String>,
+    /// The call trace of an escaping error (see `take_trace`).
+    pub trace: Vec<String>,
+    pub usage: Usage,
+}
 
-    fn exec_block(&mut self, block: &Block) -> Result<ControlFlow, RuntimeError> {
-        self.env.push_scope();
-        let result = self.exec_block_in_current_scope(block);
-        self.env.pop_scope();
-        result
+/// Resources a run used, for comparing against the `Options` limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes of program output, redirected output excluded.
+    pub output_bytes: usize,
+    /// Deepest call stack reached.
+    pub deepest_call: usize,
+    /// Loop iterations, summed over every loop.
+    pub loop_iterations: u64,
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes of output, call depth {}, {} loop iterations",
+            self.output_bytes, self.deepest_call, self.loop_iterations
+        )
     }
+}
 
-    /// Execute a block in the current scope without creating a new one.
-    /// Used when the caller has already set up the scope (e.g., in function calls).
-    fn exec_block_in_current_scope(&mut self, block: &Block) -> Result<ControlFlow, RuntimeError> {
-        for stmt in block {
-            if let ControlFlow::Return(v) = self.exec_stmt(stmt)? {
-                return Ok(ControlFlow::Return(v));
-            }
+/// Copies scopes for `Interpreter::fork`. Scopes shared in the original (a
+/// closure and the code that created it) are shared in the copy too, so a
+/// function keeps seeing the copied globals rather than the parent's.
+#[derive(Default)]
+struct ScopeCopier {
+    copies: HashMap<*const RefCell<HashMap<String, Value>>, Scope>,
+}
+
+impl ScopeCopier {
+    fn scope(&mut self, scope: &Scope) -> Scope {
+        let key = Rc::as_ptr(&scope.0);
+        if let Some(copy) = self.copies.get(&key) {
+            return copy.clone();
         }
-        Ok(ControlFlow::None)
+        // Registered before copying the bindings: a scope usually holds a
+        // function that captured it.
+        let copy = Scope::default();
+        self.copies.insert(key, copy.clone());
+        let bindings: Vec<(String, Value)> = scope
+            .0
+            .borrow()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        for (name, value) in bindings {
+            let value = self.value(&value);
+            copy.insert(name, value);
+        }
+        copy
     }
 
-    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
-        match expr {
-            Expr::Number(n) => Ok(Value::Number(*n)),
-            Expr::TemplateString(parts) => self.eval_template_string(parts),
-            // In Lipona, `lon` (true) is Value::Bool, `ala` (false) is Value::Ala
-            Expr::Bool(b) => Ok(if *b { Value::Bool } else { Value::Ala }),
-            Expr::Var(name) => self
-                .env
-                .get(name)
-                .cloned()
-                .ok_or_else(|| RuntimeError::UndefinedVariable(name.clone())),
-            Expr::Neg(inner) => {
-                let val = self.eval_expr(inner)?;
-                match val {
-                    Value::Number(n) => Ok(Value::Number(-n)),
-                    _ => Err(RuntimeError::TypeError {
-                        expected: "nanpa",
-                        got: val.type_name().to_string(),
-                    }),
-                }
-            }
-            Expr::Binary { left, op, right } => self.eval_binary(left, op, right),
-            Expr::FuncCall { name, args } => self.call_function(name, args),
-            Expr::Lambda {
+    fn value(&mut self, value: &Value) -> Value {
+        match value {
+            Value::List(items) => Value::List(items.iter().map(|item| self.value(item)).collect()),
+            Value::Map(map) => Value::Map(
+                map.iter()
+                    .map(|(key, item)| (key.clone(), self.value(item)))
+                    .collect(),
+            ),
+            Value::Function {
                 params,
                 param_types,
                 return_type,
                 body,
-            } => Ok(Value::Function {
+                captured,
+            } => Value::Function {
                 params: params.clone(),
                 param_types: param_types.clone(),
                 return_type: return_type.clone(),
                 body: body.clone(),
-                captured: self.env.snapshot(),
-            }),
+                captured: captured.iter().map(|scope| self.scope(scope)).collect(),
+            },
+            other => other.clone(),
         }
     }
+}
 
-    fn eval_template_string(&mut self, parts: &[StringPart]) -> Result<Value, RuntimeError> {
-        let mut result = String::new();
-        for part in parts {
-            match part {
-                StringPart::Literal(s) => result.push_str(s),
-                StringPart::Interpolation(expr) => {
-                    let value = self.eval_expr(expr)?;
-                    result.push_str(&format!("{value}"));
-                }
-            }
+/// A file that program output is redirected to.
+struct Redirect {
+    path: String,
+    writer: Box<dyn Write>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Self {
+        Self {
+            env: Environment::new(),
+            stdlib: StdLib::new(),
+            call_stack: Vec::new(),
+            trace: Vec::new(),
+            frames: Vec::new(),
+            usage: Usage::default(),
+            captured: None,
+            redirects: Vec::new(),
+            files: Vec::new(),
+            modules: HashMap::new(),
+            module_loader: None,
+            file_policy: None,
+            allow_exec: false,
+            allow_net: false,
+            observer: None,
+            input: None,
+            args: Vec::new(),
+            env_vars: HashMap::new(),
+            warnings: Vec::new(),
+            warned: HashSet::new(),
+            renamed_warned: HashSet::new(),
+            builtins_used: HashSet::new(),
+            cancel: CancelHandle::default(),
+            random: options
+                .seed
+                .map_or_else(Random::from_time, Random::from_seed),
+            options,
+            limits: Limits::default(),
+            host_fns: HashMap::new(),
+            escaping: None,
         }
-        Ok(Value::String(result))
+    }
+
+    /// Write program output to stdout, counting it against `max_output`.
+    /// Text that would cross the limit is not written. While a redirection
+    /// is active the text goes to its file instead.
+    pub fn write_output(&mut self, text: &str) -> Result<(), RuntimeError> {
+        if let Some(redirect) = self.redirects.last_mut() {
+            return redirect.writer.write_all(text.as_bytes()).map_err(|e| {
+                RuntimeError::FileWrite {
+                    path: redirect.path.clone(),
+                    message: e.to_string(),
+                }
+            });
+        }
+        let total = self.usage.output_bytes + text.len();
+        if let Some(limit) = self.limits.max_output {
+            if total > limit {
+                return Err(RuntimeError::OutputLimit(limit));
+            }
+        }
+        self.usage.output_bytes = total;
+        if let Some(captured) = &mut self.captured {
+            captured.push_str(text);
+            return Ok(());
+        }
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+        Ok(())
+    }
+
+    /// Call `func` with no arguments while program output goes to `writer`
+    /// (opened from `path`, which is only used in error messages). The
+    /// writer is flushed and dropped afterwards, even if `func` fails.
+    pub fn with_redirect(
+        &mut self,
+        path: &str,
+        writer: Box<dyn Write>,
+        func: &Value,
+    ) -> Result<Value, RuntimeError> {
+        self.redirects.push(Redirect {
+            path: path.to_string(),
+            writer,
+        });
+        let result = self.call_value("ilo (toki_tawa_lipu)", func, Vec::new());
+        let flushed = match self.redirects.pop() {
+            Some(mut redirect) => redirect
+                .writer
+                .flush()
+                .map_err(|e| RuntimeError::FileWrite {
+                    path: redirect.path,
+                    message: e.to_string(),
+                }),
+            None => Ok(()),
+        };
+        let value = result?;
+        flushed?;
+        Ok(value)
+    }
+
+    /// A child interpreter starting from a copy of this one's variables,
+    /// functions and loaded modules, e.g. for a server to run each request's
+    /// script against a prepared environment. Nothing the child does is
+    /// seen by the parent or by other forks. It has the same options, limits,
+    /// host functions and module loader, but its own usage counters, warnings and cancel
+    /// handle.
+    pub fn fork(&self) -> Self {
+        let mut copier = ScopeCopier::default();
+        let mut child = Self::with_options(self.options.clone());
+        child.limits = self.limits;
+        child.host_fns = self.host_fns.clone();
+        child
+            .env
+            .replace_scopes(self.env.scopes.iter().map(|s| copier.scope(s)).collect());
+        child.modules = self
+            .modules
+            .iter()
+            .map(|(path, exports)| {
+                let exports = exports
+                    .iter()
+                    .map(|(name, value)| (name.clone(), copier.value(value)))
+                    .collect();
+                (path.clone(), exports)
+            })
+            .collect();
+        child.module_loader = self.module_loader.clone();
+        child.file_policy = self.file_policy.clone();
+        child.allow_exec = self.allow_exec;
+        child.allow_net = self.allow_net;
+        child.observer = self.observer.clone();
+        child.input = self.input.clone();
+        child.files = self.files.clone();
+        child.args = self.args.clone();
+        child.warned = self.warned.clone();
+        child.renamed_warned = self.renamed_warned.clone();
+        // A seeded run stays reproducible across the REPL's forks.
+        child.random = self.random.clone();
+        child
+    }
+
+    /// Set the file being run; `kepeken` resolves modules next to it
+    /// (next to the current directory when no file is set).
+    pub fn set_script(&mut self, path: &Path) {
+        self.files = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+    }
+
+    /// Serve `kepeken` imports with `loader`. The interpreter reads no
+    /// module files itself: the CLI loads them from disk, while a host can
+    /// serve them from memory or bundled assets.
+    pub fn set_module_loader(&mut self, loader: impl Fn(&str) -> Option<String> + 'static) {
+        self.module_loader = Some(Rc::new(loader));
+    }
+
+    /// Let builtins that touch the file system (`lipu_lukin`,
+    /// `toki_tawa_lipu`, ...) use a file only if `policy(path, access)`
+    /// allows it; anything else raises `FileDenied`. Without a policy every
+    /// file may be used, as the CLI does unless given `--no-files`.
+    pub fn set_file_policy(&mut self, policy: impl Fn(&str, FileAccess) -> bool + 'static) {
+        self.file_policy = Some(Rc::new(policy));
+    }
+
+    /// Let `ilo_ma` start other programs. Off unless the host turns it on,
+    /// as the CLI does for `--allow-exec`: a script that may run programs
+    /// can do anything the user can.
+    pub fn set_allow_exec(&mut self, allow: bool) {
+        self.allow_exec = allow;
+    }
+
+    /// Let `linja_ma_*` make HTTP requests. Off unless the host turns it
+    /// on, as the CLI does for `--allow-net`.
+    pub fn set_allow_net(&mut self, allow: bool) {
+        self.allow_net = allow;
+    }
+
+    /// Call `observer` at every assignment and every call of a program's
+    /// own `ilo`, in the order they happen (`--record` writes them to a
+    /// file). Slows the run down only as much as `observer` does.
+    pub fn set_observer(&mut self, observer: impl Fn(&Event) + 'static) {
+        self.observer = Some(Rc::new(observer));
+    }
+
+    fn observe(&self, event: Event) {
+        if let Some(observer) = &self.observer {
+            observer(&event);
+        }
+    }
+
+    /// Give `kute` and `kute_ale` `input` to read. Without one they read
+    /// nothing, as if standard input were empty; the CLI passes stdin.
+    pub fn set_input(&mut self, input: impl Read + 'static) {
+        self.input = Some(Rc::new(RefCell::new(input)));
+    }
+
+    /// The next line of input without its line ending, or `None` at the
+    /// end. Reads a byte at a time so nothing past the line is taken from
+    /// a shared stream such as stdin, which the REPL reads too.
+    pub fn read_input_line(&mut self) -> Result<Option<String>, RuntimeError> {
+        let Some(input) = &self.input else {
+            return Ok(None);
+        };
+        let mut input = input.borrow_mut();
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            match input.read(&mut byte) {
+                Ok(0) if line.is_empty() => return Ok(None),
+                Ok(0) => break,
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) => line.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(RuntimeError::InputRead(e.to_string())),
+            }
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|e| RuntimeError::InputRead(e.to_string()))
+    }
+
+    /// Everything left in the input.
+    pub fn read_input_all(&mut self) -> Result<String, RuntimeError> {
+        match &self.input {
+            Some(input) => self.read_text(&mut *input.borrow_mut(), RuntimeError::InputRead),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Raise `FileDenied` unless the file policy allows `access` to `path`.
+    /// Every builtin that opens a file calls this first.
+    pub fn check_file(&self, path: &str, access: FileAccess) -> Result<(), RuntimeError> {
+        match &self.file_policy {
+            Some(policy) if !policy(path, access) => Err(RuntimeError::FileDenied {
+                path: path.to_string(),
+                access,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Raise `ExecDenied` unless programs may be started.
+    pub fn check_exec(&self, command: &str) -> Result<(), RuntimeError> {
+        if self.allow_exec {
+            Ok(())
+        } else {
+            Err(RuntimeError::ExecDenied(command.to_string()))
+        }
+    }
+
+    /// Raise `NetDenied` unless HTTP requests may be made.
+    pub fn check_net(&self, url: &str) -> Result<(), RuntimeError> {
+        if self.allow_net {
+            Ok(())
+        } else {
+            Err(RuntimeError::NetDenied(url.to_string()))
+        }
+    }
+
+    /// Define (or overwrite) a global variable, e.g. so a host can pass
+    /// configuration into a script before `run`.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.env.global_scope().insert(name.to_string(), value);
+    }
+
+    /// A global variable's value, e.g. a result read back after `run`.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.env.get_global(name)
+    }
+
+    /// Set the arguments the script was given (read by `pana_nasin_kama`).
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// An environment variable as the script sees it: set by `poki_ma_lon`,
+    /// else from the process environment (`None` if unset or not UTF-8).
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        match self.env_vars.get(name) {
+            Some(value) => Some(value.clone()),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    /// Set an environment variable for this interpreter only (see
+    /// `env_vars`). The process environment is left alone.
+    pub fn set_env_var(&mut self, name: &str, value: &str) {
+        self.env_vars.insert(name.to_string(), value.to_string());
+    }
+
+    /// The variables `set_env_var` has set, for programs `ilo_ma` starts.
+    pub fn env_vars(&self) -> &HashMap<String, String> {
+        &self.env_vars
+    }
+
+    /// A handle another thread can use to stop the script this interpreter
+    /// is running: the next call or loop iteration raises `Cancelled`,
+    /// which `ken_pali` cannot catch. A cancel made while nothing is running
+    /// stops the next run.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// The random number generator behind `nanpa_nasa`.
+    pub fn random(&mut self) -> &mut Random {
+        &mut self.random
+    }
+
+    /// The standard library, for builtins that keep state in it (the
+    /// `regex_*` pattern cache).
+    #[cfg(feature = "regex")]
+    pub fn stdlib(&self) -> &StdLib {
+        &self.stdlib
+    }
+
+    /// The builtins the program has called by name so far, sorted.
+    pub fn builtins_used(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.builtins_used.iter().copied().collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Take the warnings raised so far.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// Call the global function `name`, e.g. so a host can run a script
+    /// once and then call its `ilo` as event handlers. The arguments are
+    /// checked against the function's parameters like any other call.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        match self.get_global(name) {
+            Some(func @ Value::Function { .. }) => {
+                self.guarded(|interp| interp.call_value(name, &func, args))
+            }
+            _ => Err(RuntimeError::UndefinedFunction(name.to_string())),
+        }
+    }
+
+    /// Run `f`, turning a panic into `RuntimeError::Internal` so a host
+    /// never crashes on a script. The call stack and scopes are put back as
+    /// they were, leaving the interpreter usable. (A native stack overflow
+    /// would abort rather than unwind; `with_stack` is what prevents it.)
+    fn guarded<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, RuntimeError>,
+    ) -> Result<T, RuntimeError> {
+        let scopes = self.env.capture();
+        let depth = (
+            self.call_stack.len(),
+            self.frames.len(),
+            self.files.len(),
+            self.redirects.len(),
+        );
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.env.replace_scopes(scopes);
+                self.call_stack.truncate(depth.0);
+                self.frames.truncate(depth.1);
+                self.files.truncate(depth.2);
+                self.redirects.truncate(depth.3);
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "panic".to_string());
+                Err(RuntimeError::Internal(message))
+            }
+        }
+    }
+
+    /// Call `ilo open_ale` if the program defined one: with the script
+    /// arguments as a kulupu when it takes a parameter, else with none.
+    /// A nanpa it returns is the exit code for the process.
+    pub fn run_main(&mut self) -> Result<Option<i32>, RuntimeError> {
+        let Some(main @ Value::Function { .. }) = self.get_global("open_ale") else {
+            return Ok(None);
+        };
+        let args = match &main {
+            Value::Function { params, .. } if params.len() == 1 => vec![Value::List(
+                self.args.iter().cloned().map(Value::String).collect(),
+            )],
+            _ => Vec::new(),
+        };
+        match self.call("open_ale", args)? {
+            Value::Number(n) if n.fract() == 0.0 && n.abs() <= f64::from(i32::MAX) => {
+                Ok(Some(n as i32))
+            }
+            Value::Number(n) => Err(RuntimeError::TypeError {
+                expected: "whole nanpa for the exit code",
+                got: n.to_string(),
+                arg: None,
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    /// `kepeken name`: run `name.lipo` in an environment of its own (once
+    /// per interpreter) and define its top-level functions in the current
+    /// scope.
+    fn import(&mut self, name: &str) -> Result<(), RuntimeError> {
+        let dir = self
+            .files
+            .last()
+            .and_then(|file| file.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        // The script path is canonical (`set_script`) and a module path has
+        // no `..`, so joining keeps one key per module for cycles and the
+        // cache.
+        let path = dir.join(format!("{name}.lipo"));
+        let display = path.display().to_string();
+
+        if let Some(start) = self.files.iter().position(|file| *file == path) {
+            let chain: Vec<String> = self.files[start..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|file| file.display().to_string())
+                .collect();
+            return Err(RuntimeError::ImportCycle(chain.join(" -> ")));
+        }
+
+        if !self.modules.contains_key(&path) {
+            let exports = self.load_module(&path, &display)?;
+            self.modules.insert(path.clone(), exports);
+        }
+        for (name, value) in self.modules[&path].clone() {
+            self.env.define(name, value);
+        }
+        Ok(())
+    }
+
+    fn load_module(
+        &mut self,
+        path: &Path,
+        display: &str,
+    ) -> Result<Vec<(String, Value)>, RuntimeError> {
+        let source = match &self.module_loader {
+            Some(loader) => loader(display),
+            None => None,
+        };
+        let source = source.ok_or_else(|| RuntimeError::FileRead {
+            path: display.to_string(),
+            message: if self.module_loader.is_some() {
+                "no such module".to_string()
+            } else {
+                "no module loader is set".to_string()
+            },
+        })?;
+        let program = parse(&source).map_err(|e| RuntimeError::ModuleParse {
+            path: display.to_string(),
+            message: e.to_string(),
+        })?;
+
+        // The module gets fresh globals, and its `o awen nasin` stays its own.
+        let env = mem::take(&mut self.env);
+        let frames = mem::take(&mut self.frames);
+        let strict = self.options.strict;
+        self.files.push(path.to_path_buf());
+        let result = self.run(&program);
+        let exports = program
+            .iter()
+            .filter_map(|stmt| match &stmt.kind {
+                StmtKind::FuncDef { name, .. } => {
+                    self.env.get(name).map(|value| (name.clone(), value))
+                }
+                _ => None,
+            })
+            .collect();
+        self.files.pop();
+        self.options.strict = strict;
+        self.frames = frames;
+        self.env = env;
+
+        result.map_err(|e| RuntimeError::InModule {
+            path: display.to_string(),
+            error: Box::new(e),
+        })?;
+        Ok(exports)
+    }
+
+    /// Run one REPL entry. Like `run`, but when the last statement is an
+    /// expression its value is returned so it can be shown.
+    pub fn run_interactive(&mut self, program: &Program) -> Result<Value, RuntimeError> {
+        self.guarded(|interp| match interp.eval_block_value(program) {
+            Err(RuntimeError::Escape) => {
+                let flow = interp.take_escape();
+                flow_value(flow)
+            }
+            result => result,
+        })
+    }
+
+    /// Like `run`, but program output is collected instead of written to
+    /// stdout, and returned together with the result, the warnings, the
+    /// trace of an escaping error and what the run used. For test harnesses
+    /// and playgrounds. The interpreter itself never writes to stderr:
+    /// what a CLI prints there is `warnings` and the error with its `trace`.
+    pub fn run_capturing(&mut self, program: &Program) -> RunReport {
+        let before = self.usage;
+        self.usage.deepest_call = self.call_stack.len();
+        let outer = self.captured.replace(String::new());
+        let value = self.run(program);
+        let stdout = mem::replace(&mut self.captured, outer).unwrap_or_default();
+        let usage = Usage {
+            output_bytes: self.usage.output_bytes - before.output_bytes,
+            deepest_call: self.usage.deepest_call,
+            loop_iterations: self.usage.loop_iterations - before.loop_iterations,
+        };
+        self.usage.deepest_call = self.usage.deepest_call.max(before.deepest_call);
+        RunReport {
+            trace: if value.is_err() {
+                self.take_trace()
+            } else {
+                Vec::new()
+            },
+            value,
+            stdout,
+            warnings: self.take_warnings(),
+            usage,
+        }
+    }
+
+    fn run_program(&mut self, program: &[Stmt]) -> Result<Value, RuntimeError> {
+        for stmt in program {
+            match self.exec_stmt(stmt)? {
+                ControlFlow::Return(v) => return Ok(v),
+                flow @ (ControlFlow::Break { .. } | ControlFlow::Continue { .. }) => {
+                    return flow_value(flow).map_err(|e| e.at(stmt.span))
+                }
+                ControlFlow::None => {}
+            }
+        }
+        Ok(Value::Ala)
+    }
+
+    fn exec_stmt(&mut self, stmt: &Stmt) -> Result<ControlFlow, RuntimeError> {
+        let result = with_stack(|| self.exec_stmt_kind(&stmt.kind, stmt.span));
+        match result {
+            Err(RuntimeError::Escape) => Ok(self.take_escape()),
+            result => result.map_err(|e| e.at(stmt.span)),
+        }
+    }
+
+    /// Leave the expression being evaluated with `flow` (see
+    /// `RuntimeError::Escape`); `ControlFlow::None` just gives ala.
+    fn escape(&mut self, flow: ControlFlow) -> Result<Value, RuntimeError> {
+        match flow {
+            ControlFlow::None => Ok(Value::Ala),
+            flow => {
+                self.escaping = Some(flow);
+                Err(RuntimeError::Escape)
+            }
+        }
+    }
+
+    fn take_escape(&mut self) -> ControlFlow {
+        self.escaping.take().unwrap_or(ControlFlow::None)
+    }
+
+    fn exec_stmt_kind(&mut self, stmt: &StmtKind, span: Span) -> Result<ControlFlow, RuntimeError> {
+        match stmt {
+            StmtKind::Assign { target, value } => {
+                // Inside a function, globals are only written when declared
+                // `ma`, and then the global scope is written directly, even
+                // if an enclosing function has a local of the same name.
+                let ma = self
+                    .frames
+                    .last()
+                    .is_some_and(|globals| globals.contains(target));
+                let local_only = !self.frames.is_empty() && !ma;
+                if self.options.strict && !ma {
+                    let declared = if local_only {
+                        self.env.contains_local(target)
+                    } else {
+                        self.env.get(target).is_some()
+                    };
+                    if !declared {
+                        return Err(RuntimeError::UndeclaredAssignment(target.clone()));
+                    }
+                }
+                let val = self.eval_expr(value)?;
+                self.observe(Event::Assign {
+                    name: target,
+                    value: &val,
+                    global: self.frames.is_empty() || !local_only,
+                    span,
+                });
+                if ma {
+                    self.env.global_scope().insert(target.clone(), val);
+                } else if local_only {
+                    self.env.set_local(target, val);
+                } else {
+                    self.env.set(target, val);
+                }
+                Ok(ControlFlow::None)
+            }
+            // Aliases and capabilities are resolved by the parser.
+            StmtKind::TypeAlias { .. } | StmtKind::Capability(_) => Ok(ControlFlow::None),
+            StmtKind::Import(name) => {
+                self.import(name)?;
+                Ok(ControlFlow::None)
+            }
+            StmtKind::Global(names) => {
+                if let Some(globals) = self.frames.last_mut() {
+                    globals.extend(names.iter().cloned());
+                }
+                Ok(ControlFlow::None)
+            }
+            StmtKind::Declare { name, value } => {
+                let val = self.eval_expr(value)?;
+                self.observe(Event::Assign {
+                    name,
+                    value: &val,
+                    global: self.frames.is_empty(),
+                    span,
+                });
+                self.env.define(name.clone(), val);
+                Ok(ControlFlow::None)
+            }
+            StmtKind::Strict => {
+                self.options.strict = true;
+                Ok(ControlFlow::None)
+            }
+            StmtKind::If {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                let cond_val = self.eval_expr(cond)?;
+                if cond_val.is_truthy() {
+                    self.exec_block(then_block)
+                } else if let Some(else_b) = else_block {
+                    self.exec_block(else_b)
+                } else {
+                    Ok(ControlFlow::None)
+                }
+            }
+            StmtKind::Guard { cond, value } => {
+                if self.eval_expr(cond)?.is_truthy() {
+                    Ok(ControlFlow::None)
+                } else {
+                    Ok(ControlFlow::Return(self.eval_expr(value)?))
+                }
+            }
+            StmtKind::While {
+                label,
+                cond,
+                body,
+                else_block,
+            } => match self.run_while(label.as_deref(), cond, body)? {
+                ControlFlow::None => match else_block {
+                    Some(else_block) => self.exec_block(else_block),
+                    None => Ok(ControlFlow::None),
+                },
+                ControlFlow::Break { label: None, .. } => Ok(ControlFlow::None),
+                flow => Ok(flow),
+            },
+            StmtKind::For {
+                label,
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let start = self.eval_number(start)?;
+                let end = self.eval_number(end)?;
+                // Count iterations as an integer: past 2^53 `i + 1.0` rounds
+                // back to `i` and the loop would never reach `end`.
+                let mut iterations: u64 = 0;
+                loop {
+                    let i = start + iterations as f64;
+                    if i >= end {
+                        break;
+                    }
+                    iterations += 1;
+                    if iterations > self.limits.max_loop_iterations {
+                        return Err(RuntimeError::InfiniteLoop);
+                    }
+                    let bindings = vec![(var, Value::Number(i))];
+                    if let Some(flow) = self.run_iteration(label.as_deref(), bindings, body)? {
+                        return Ok(flow);
+                    }
+                }
+                Ok(ControlFlow::None)
+            }
+            StmtKind::ForEach {
+                label,
+                key,
+                var,
+                iterable,
+                body,
+            } => {
+                let entries: Vec<(Value, Value)> = match self.eval_expr(iterable)? {
+                    Value::List(items) => items
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, item)| (Value::Number(i as f64), item))
+                        .collect(),
+                    Value::Map(map) => {
+                        let mut entries: Vec<_> = map.into_iter().collect();
+                        entries.sort_by(|a, b| a.0.key_order(&b.0));
+                        entries
+                            .into_iter()
+                            .map(|(k, v)| match key {
+                                Some(_) => (k, v),
+                                None => (Value::Ala, k),
+                            })
+                            .collect()
+                    }
+                    other => {
+                        return Err(RuntimeError::TypeError {
+                            expected: "kulupu or nasin",
+                            got: other.type_name().to_string(),
+                            arg: None,
+                        })
+                    }
+                };
+                for (k, item) in entries {
+                    let bindings: Vec<(&String, Value)> = match key {
+                        Some(key) => vec![(key, k), (var, item)],
+                        None => vec![(var, item)],
+                    };
+                    if let Some(flow) = self.run_iteration(label.as_deref(), bindings, body)? {
+                        return Ok(flow);
+                    }
+                }
+                Ok(ControlFlow::None)
+            }
+            StmtKind::Break { label, value } => {
+                let value = match value {
+                    Some(expr) => self.eval_expr(expr)?,
+                    None => Value::Ala,
+                };
+                Ok(ControlFlow::Break {
+                    label: label.clone(),
+                    value,
+                })
+            }
+            StmtKind::Continue { label } => Ok(ControlFlow::Continue {
+                label: label.clone(),
+            }),
+            StmtKind::FuncDef {
+                name,
+                params,
+                param_types,
+                return_type,
+                body,
+            } => {
+                // Tentatively bind the function name to ala first, then
+                // capture an environment that already includes the new name.
+                // This lets the function's own body resolve recursive calls
+                // through the captured environment.
+                self.env.define(name.clone(), Value::Ala);
+                let captured = self.capture_env();
+                let func = Value::Function {
+                    params: params.clone(),
+                    param_types: param_types.clone(),
+                    return_type: return_type.clone(),
+                    body: body.clone(),
+                    captured,
+                };
+                self.env.set(name, func);
+                Ok(ControlFlow::None)
+            }
+            StmtKind::Return(expr) => {
+                let val = self.eval_expr(expr)?;
+                Ok(ControlFlow::Return(val))
+            }
+            StmtKind::Expr(expr) => {
+                self.eval_expr(expr)?;
+                Ok(ControlFlow::None)
+            }
+        }
+    }
+
+    fn exec_block(&mut self, block: &Block) -> Result<ControlFlow, RuntimeError> {
+        self.env.push_scope();
+        let result = self.exec_block_in_current_scope(block);
+        self.env.pop_scope();
+        result
+    }
+
+    /// Execute a block in the current scope without creating a new one.
+    /// Used when the caller has already set up the scope (e.g., in function calls).
+    fn exec_block_in_current_scope(&mut self, block: &Block) -> Result<ControlFlow, RuntimeError> {
+        for stmt in block {
+            match self.exec_stmt(stmt)? {
+                ControlFlow::None => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(ControlFlow::None)
+    }
+
+    /// Run a while loop. Returns `None` when the condition became false,
+    /// an unlabeled `Break` (carrying the value given to `pini_tawa`) if the
+    /// body left this loop, and any other flow - `Return`, or a break or
+    /// `awen_sin` for an outer label - unchanged.
+    /// Run one iteration of a `tawa` loop body in a fresh scope holding
+    /// `bindings`, so closures made in the body each keep their own loop
+    /// variables. Returns the flow that ends the loop, if any.
+    fn run_iteration(
+        &mut self,
+        label: Option<&str>,
+        bindings: Vec<(&String, Value)>,
+        body: &Block,
+    ) -> Result<Option<ControlFlow>, RuntimeError> {
+        self.usage.loop_iterations += 1;
+        self.check_cancelled()?;
+        self.env.push_scope();
+        for (name, value) in bindings {
+            self.env.define(name.clone(), value);
+        }
+        let flow = self.exec_block_in_current_scope(body);
+        self.env.pop_scope();
+        match flow? {
+            ControlFlow::None => Ok(None),
+            ControlFlow::Break { label: target, .. } if breaks_loop(target.as_deref(), label) => {
+                Ok(Some(ControlFlow::None))
+            }
+            ControlFlow::Continue { label: target } if breaks_loop(target.as_deref(), label) => {
+                Ok(None)
+            }
+            flow => Ok(Some(flow)),
+        }
+    }
+
+    fn run_while(
+        &mut self,
+        label: Option<&str>,
+        cond: &Expr,
+        body: &Block,
+    ) -> Result<ControlFlow, RuntimeError> {
+        let mut iterations: u64 = 0;
+        while self.eval_expr(cond)?.is_truthy() {
+            iterations += 1;
+            self.usage.loop_iterations += 1;
+            self.check_cancelled()?;
+            if iterations > self.limits.max_loop_iterations {
+                return Err(RuntimeError::InfiniteLoop);
+            }
+            match self.exec_block(body)? {
+                ControlFlow::None => {}
+                ControlFlow::Break {
+                    label: target,
+                    value,
+                } if breaks_loop(target.as_deref(), label) => {
+                    return Ok(ControlFlow::Break { label: None, value });
+                }
+                ControlFlow::Continue { label: target }
+                    if breaks_loop(target.as_deref(), label) => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(ControlFlow::None)
+    }
+
+    /// Evaluate an expression that must be a nanpa (loop bounds).
+    fn eval_number(&mut self, expr: &Expr) -> Result<f64, RuntimeError> {
+        match self.eval_expr(expr)? {
+            Value::Number(n) => Ok(n),
+            other => Err(RuntimeError::TypeError {
+                expected: "nanpa",
+                got: other.type_name().to_string(),
+                arg: None,
+            }),
+        }
+    }
+
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        with_stack(|| self.eval_expr_kind(&expr.kind, expr.span))
+            .and_then(|value| self.check_size(value))
+            .map_err(|e| e.at(expr.span))
+    }
+
+    /// Raise `LimitExceeded` if `value` is a kulupu, nasin or sitelen
+    /// larger than the size limits allow.
+    fn check_size(&self, value: Value) -> Result<Value, RuntimeError> {
+        let (kind, size) = match &value {
+            Value::List(items) => ("kulupu", items.len()),
+            Value::Map(map) => ("nasin", map.len()),
+            Value::String(s) => ("sitelen", s.len()),
+            _ => return Ok(value),
+        };
+        self.check_len(kind, size)?;
+        Ok(value)
+    }
+
+    /// Raise `LimitExceeded` if a `kind` value ("kulupu", "nasin" or
+    /// "sitelen") of `size` would be over its limit. Builtins that know the
+    /// size of what they are about to build call this first, so an
+    /// oversized value is refused before it is allocated.
+    pub fn check_len(&self, kind: &'static str, size: usize) -> Result<(), RuntimeError> {
+        let limit = match kind {
+            "kulupu" => self.limits.max_list_len,
+            "nasin" => self.limits.max_map_entries,
+            _ => self.limits.max_string_len,
+        };
+        match limit {
+            Some(limit) if size > limit => Err(RuntimeError::LimitExceeded { kind, size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read `reader` to the end as text, raising `LimitExceeded` without
+    /// reading further once it is longer than `--max-string` allows.
+    /// `failed` turns a read error into the caller's error.
+    pub fn read_text(
+        &self,
+        reader: impl Read,
+        failed: impl Fn(String) -> RuntimeError,
+    ) -> Result<String, RuntimeError> {
+        let cap = self
+            .limits
+            .max_string_len
+            .map_or(u64::MAX, |limit| limit as u64 + 1);
+        let mut bytes = Vec::new();
+        reader
+            .take(cap)
+            .read_to_end(&mut bytes)
+            .map_err(|e| failed(e.to_string()))?;
+        self.check_len("sitelen", bytes.len())?;
+        String::from_utf8(bytes).map_err(|e| failed(e.to_string()))
+    }
+
+    fn eval_expr_kind(&mut self, expr: &ExprKind, span: Span) -> Result<Value, RuntimeError> {
+        match expr {
+            ExprKind::Number(n) => Ok(Value::Number(*n)),
+            ExprKind::TemplateString(parts) => self.eval_template_string(parts),
+            // In Lipona, `lon` (true) is Value::Bool, `ala` (false) is Value::Ala
+            ExprKind::Bool(b) => Ok(if *b { Value::Bool } else { Value::Ala }),
+            ExprKind::Var(name) => self
+                .env
+                .get(name)
+                .ok_or_else(|| RuntimeError::UndefinedVariable(name.clone())),
+            ExprKind::Neg(inner) => {
+                let val = self.eval_expr(inner)?;
+                match val {
+                    Value::Number(n) => Ok(Value::Number(-n)),
+                    Value::Decimal(d) => d
+                        .checked_neg()
+                        .map(Value::Decimal)
+                        .ok_or(RuntimeError::DecimalOverflow),
+                    _ => Err(RuntimeError::TypeError {
+                        expected: "nanpa",
+                        got: val.type_name().to_string(),
+                        arg: None,
+                    }),
+                }
+            }
+            ExprKind::Not(inner) => Ok(bool_value(!self.eval_expr(inner)?.is_truthy())),
+            ExprKind::Binary {
+                left,
+                op: op @ (BinOp::And | BinOp::Or),
+                right,
+                ..
+            } => {
+                // `a en b` stops at a falsy `a`, `a anu b` at a truthy one.
+                let left = self.eval_expr(left)?.is_truthy();
+                if left == (*op == BinOp::Or) {
+                    return Ok(bool_value(left));
+                }
+                Ok(bool_value(self.eval_expr(right)?.is_truthy()))
+            }
+            ExprKind::Binary {
+                left,
+                op,
+                right,
+                span,
+            } => self.eval_binary(left, op, right, *span),
+            ExprKind::While {
+                label,
+                cond,
+                body,
+                else_block,
+            } => self.eval_while_value(label.as_deref(), cond, body, else_block.as_ref()),
+            ExprKind::Block(body) => {
+                self.env.push_scope();
+                let result = self.eval_block_value(body);
+                self.env.pop_scope();
+                result
+            }
+            ExprKind::FuncCall { name, args } => self.call_function(name, args, span),
+            ExprKind::Lambda {
+                params,
+                param_types,
+                return_type,
+                body,
+            } => Ok(Value::Function {
+                params: params.clone(),
+                param_types: param_types.clone(),
+                return_type: return_type.clone(),
+                body: body.clone(),
+                captured: self.capture_env(),
+            }),
+        }
+    }
+
+    /// Value of an `open ... pini` expression: its trailing expression
+    /// statement, or the value given to `pana`, or ala. `pini_tawa` and
+    /// `awen_sin` leave the expression for the loop around it.
+    fn eval_block_value(&mut self, body: &Block) -> Result<Value, RuntimeError> {
+        match self.eval_block_flow(body)? {
+            Ok(value) | Err(ControlFlow::Return(value)) => Ok(value),
+            Err(flow) => self.escape(flow),
+        }
+    }
+
+    /// Run `body` for its value: that of its trailing expression statement
+    /// (ala if there is none), or else the control flow that ended it.
+    fn eval_block_flow(
+        &mut self,
+        body: &Block,
+    ) -> Result<Result<Value, ControlFlow>, RuntimeError> {
+        let Some((last, init)) = body.split_last() else {
+            return Ok(Ok(Value::Ala));
+        };
+        for stmt in init {
+            match self.exec_stmt(stmt)? {
+                ControlFlow::None => {}
+                flow => return Ok(Err(flow)),
+            }
+        }
+        let flow = match &last.kind {
+            StmtKind::Expr(expr) => match self.eval_expr(expr) {
+                Err(RuntimeError::Escape) => self.take_escape(),
+                result => return result.map(Ok),
+            },
+            _ => self.exec_stmt(last)?,
+        };
+        Ok(match flow {
+            ControlFlow::None => Ok(Value::Ala),
+            flow => Err(flow),
+        })
+    }
+
+    /// Value of a `wile` used as an expression: the value given to
+    /// `pini_tawa`, or else the value of the `taso` block, or ala. `pana`
+    /// (in the body or the `taso` block) returns from the function.
+    fn eval_while_value(
+        &mut self,
+        label: Option<&str>,
+        cond: &Expr,
+        body: &Block,
+        else_block: Option<&Block>,
+    ) -> Result<Value, RuntimeError> {
+        match self.run_while(label, cond, body)? {
+            ControlFlow::None => match else_block {
+                Some(else_block) => {
+                    self.env.push_scope();
+                    let result = self.eval_block_flow(else_block);
+                    self.env.pop_scope();
+                    match result? {
+                        Ok(value) => Ok(value),
+                        Err(flow) => self.escape(flow),
+                    }
+                }
+                None => Ok(Value::Ala),
+            },
+            ControlFlow::Break { label: None, value } => Ok(value),
+            // `pana`, or a break to an outer label: leave the expression.
+            flow => self.escape(flow),
+        }
+    }
+
+    fn eval_template_string(&mut self, parts: &[StringPart]) -> Result<Value, RuntimeError> {
+        let mut result = String::new();
+        for part in parts {
+            match part {
+                StringPart::Literal(s) => result.push_str(s),
+                StringPart::Interpolation(expr) => {
+                    let value = self.eval_expr(expr)?;
+                    result.push_str(&format!("{value}"));
+                }
+            }
+        }
+        Ok(Value::String(result))
     }
 
     fn eval_binary(
         &mut self,
-        left: &Expr,
-        op: &BinOp,
-        right: &Expr,
+        left: &Expr,
+        op: &BinOp,
+        right: &Expr,
+        span: Span,
+    ) -> Result<Value, RuntimeError> {
+        let left_val = self.eval_expr(left)?;
+        let right_val = self.eval_expr(right)?;
+
+        if let Some((a, b)) = decimal_operands(&left_val, &right_val) {
+            return eval_decimal_binary(op, a, b);
+        }
+
+        if self.options.strict_math {
+            check_precision(op, &left_val, &right_val, span)?;
+        }
+
+        match (op, &left_val, &right_val) {
+            // Numeric operations. Results must stay finite: NaN and
+            // infinity are never observable as Lipona values.
+            (BinOp::Add, Value::Number(a), Value::Number(b)) => finite_result(a + b, op, span),
+            (BinOp::Sub, Value::Number(a), Value::Number(b)) => finite_result(a - b, op, span),
+            (BinOp::Mul, Value::Number(a), Value::Number(b)) => finite_result(a * b, op, span),
+            (BinOp::Div, Value::Number(_), Value::Number(b)) if *b == 0.0 => {
+                Err(RuntimeError::DivisionByZero)
+            }
+            (BinOp::Div, Value::Number(a), Value::Number(b)) => finite_result(a / b, op, span),
+            (BinOp::Mod, Value::Number(_), Value::Number(b)) if *b == 0.0 => {
+                Err(RuntimeError::DivisionByZero)
+            }
+            (BinOp::Mod, Value::Number(a), Value::Number(b)) => {
+                finite_result(a.rem_euclid(*b), op, span)
+            }
+            (BinOp::Pow, Value::Number(a), Value::Number(b)) if *a == 0.0 && *b < 0.0 => {
+                Err(RuntimeError::DivisionByZero)
+            }
+            (BinOp::Pow, Value::Number(a), Value::Number(b)) => finite_result(a.powf(*b), op, span),
+
+            // String concatenation
+            (BinOp::Add, Value::String(a), Value::String(b)) => {
+                self.check_len("sitelen", a.len() + b.len())?;
+                Ok(Value::String(format!("{a}{b}")))
+            }
+
+            // Comparisons - return Bool for true, Ala for false
+            (BinOp::Gt, Value::Number(a), Value::Number(b)) => {
+                Ok(if a > b { Value::Bool } else { Value::Ala })
+            }
+            (BinOp::Lt, Value::Number(a), Value::Number(b)) => {
+                Ok(if a < b { Value::Bool } else { Value::Ala })
+            }
+            (BinOp::Ge, Value::Number(a), Value::Number(b)) => {
+                Ok(if a >= b { Value::Bool } else { Value::Ala })
+            }
+            (BinOp::Le, Value::Number(a), Value::Number(b)) => {
+                Ok(if a <= b { Value::Bool } else { Value::Ala })
+            }
+            (BinOp::Eq, a, b) => {
+                if let (Value::Number(x), Value::Number(y)) = (a, b) {
+                    if self.options.warn_float_eq
+                        && (x.fract() != 0.0 || y.fract() != 0.0)
+                        && self.warned.insert(span)
+                    {
+                        self.warnings.push(format!(
+                            "'sama' compares non-whole numbers {x} and {y} at {span} - \
+                             consider nanpa_sama_lili(a, b, eps)"
+                        ));
+                    }
+                }
+                Ok(if a == b { Value::Bool } else { Value::Ala })
+            }
+
+            // Type errors
+            _ => Err(RuntimeError::TypeError {
+                expected: "compatible types",
+                got: format!("{} and {}", left_val.type_name(), right_val.type_name()),
+                arg: None,
+            }),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        span: Span,
     ) -> Result<Value, RuntimeError> {
-        let left_val = self.eval_expr(left)?;
-        let right_val = self.eval_expr(right)?;
+        self.enter(name, Some(span))?;
+        let result = self.call_function_inner(name, args, span);
+        self.leave(&result);
+        result
+    }
+
+    /// Raise `Cancelled` if the host asked to stop. Checked at every call
+    /// and loop iteration; the request is used up by raising it.
+    fn check_cancelled(&self) -> Result<(), RuntimeError> {
+        if self.cancel.0.swap(false, Ordering::Relaxed) {
+            Err(RuntimeError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sleep for `duration` (`tenpo_awen`), waking every few milliseconds
+    /// so a cancel (`--timeout`) still stops the script promptly. A
+    /// duration too long for the clock sleeps until cancelled.
+    pub fn sleep(&self, duration: Duration) -> Result<(), RuntimeError> {
+        let deadline = Instant::now().checked_add(duration);
+        loop {
+            self.check_cancelled()?;
+            let left = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => SLEEP_SLICE,
+            };
+            if left.is_zero() {
+                return Ok(());
+            }
+            std::thread::sleep(left.min(SLEEP_SLICE));
+        }
+    }
+
+    /// Push a call frame, or raise `StackOverflow` past the depth limit.
+    fn enter(&mut self, name: &str, span: Option<Span>) -> Result<(), RuntimeError> {
+        if self.call_stack.len() >= self.limits.max_call_depth {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.check_cancelled()?;
+        self.call_stack.push(Frame {
+            name: name.to_string(),
+            span,
+        });
+        self.usage.deepest_call = self.usage.deepest_call.max(self.call_stack.len());
+        Ok(())
+    }
+
+    /// Pop the innermost call frame. The first frame an error leaves keeps
+    /// the whole stack as the error's trace.
+    fn leave<T>(&mut self, result: &Result<T, RuntimeError>) {
+        let failed = result
+            .as_ref()
+            .is_err_and(|e| !matches!(e, RuntimeError::Escape));
+        if failed && self.trace.is_empty() {
+            self.trace = self.call_stack.clone();
+        }
+        self.call_stack.pop();
+    }
+
+    /// The calls an escaping error passed through, innermost first, one
+    /// line each: "in 'f' called at line 3, column 5". Taking the trace
+    /// clears it, so it is taken (or dropped) wherever an error is handled.
+    pub fn take_trace(&mut self) -> Vec<String> {
+        let trace = mem::take(&mut self.trace);
+        let more = trace.len().saturating_sub(MAX_TRACE_LINES);
+        let mut lines: Vec<String> = trace
+            .iter()
+            .rev()
+            .take(MAX_TRACE_LINES)
+            .map(|frame| match frame.span {
+                Some(span) => format!("  in '{}' called at {span}", frame.name),
+                None => format!("  in '{}'", frame.name),
+            })
+            .collect();
+        if more > 0 {
+            lines.push(format!("  ... and {more} more"));
+        }
+        lines
+    }
+
+    fn call_function_inner(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        span: Span,
+    ) -> Result<Value, RuntimeError> {
+        // A qualified name (`sitelen.len`) always means the builtin. A plain
+        // one means the program's own function if it defines one, so adding
+        // a builtin never changes what an existing program calls.
+        // Higher-order builtins get the interpreter so they can call back
+        // into `ilo` arguments.
+        let user_defined =
+            !name.contains('.') && matches!(self.env.get(name), Some(Value::Function { .. }));
+        if let Some(func) = self.host_fns.get(name).filter(|_| !user_defined).cloned() {
+            let evaluated_args = self.eval_args(args)?;
+            return func(evaluated_args);
+        }
+        if let Some(builtin) = self.stdlib.get(name).filter(|_| !user_defined) {
+            self.builtins_used.insert(builtin.name);
+            if let Some(new) = self.stdlib.renamed(name) {
+                if self.renamed_warned.insert(name.to_string()) {
+                    self.warnings.push(format!(
+                        "'{name}' at {span} has been renamed - use '{new}' instead"
+                    ));
+                }
+            }
+            let evaluated_args = self.eval_args(args)?;
+            builtin.signature.check(builtin.name, &evaluated_args)?;
+            return match builtin.func {
+                BuiltinFn::Plain(func) => func(evaluated_args),
+                BuiltinFn::HigherOrder(func) => func(self, evaluated_args),
+            };
+        }
+
+        // Check user-defined functions
+        let func = self
+            .env
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?;
+
+        // Evaluate arguments in current environment
+        let evaluated_args = self.eval_args(args)?;
+        self.invoke(name, func, evaluated_args)
+    }
+
+    /// Call a function value with already-evaluated arguments.
+    ///
+    /// Used by higher-order builtins; `name` is only used in error messages.
+    pub fn call_value(
+        &mut self,
+        name: &str,
+        func: &Value,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        // A callback is called from the builtin's call site.
+        let span = self.call_stack.last().and_then(|frame| frame.span);
+        self.enter(name, span)?;
+        let result = self.invoke(name, func.clone(), args);
+        self.leave(&result);
+        result
+    }
+
+    fn invoke(
+        &mut self,
+        name: &str,
+        func: Value,
+        evaluated_args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        match func {
+            Value::Function {
+                params,
+                param_types,
+                return_type,
+                body,
+                captured,
+            } => {
+                if params.len() != evaluated_args.len() {
+                    return Err(RuntimeError::WrongArity {
+                        name: name.to_string(),
+                        expected: params.len(),
+                        got: evaluated_args.len(),
+                    });
+                }
+
+                // Check parameter type annotations (skip when annotation is None)
+                for ((param, ty), value) in params
+                    .iter()
+                    .zip(param_types.iter())
+                    .zip(evaluated_args.iter())
+                {
+                    if let Some(expected) = ty {
+                        if !value.matches_type(expected) {
+                            return Err(RuntimeError::ParamTypeMismatch {
+                                func: name.to_string(),
+                                param: param.clone(),
+                                expected: expected.to_string(),
+                                got: value.type_name().to_string(),
+                            });
+                        }
+                    }
+                }
+
+                // Swap in the function's captured environment (lexical
+                // scoping). The captured scopes are shared with the code
+                // that created the function, so later definitions and
+                // mutations there are visible, and assignments to enclosing
+                // variables update them.
+                //
+                // Legacy scoping runs every call on fresh copies instead,
+                // with the global scope replaced by a copy of the live
+                // globals so top-level definitions (including the function
+                // itself, for recursion) stay visible, while writes are
+                // discarded after the call.
+                let mut call_scopes = captured;
+                let captured_len = call_scopes.len();
+                if self.options.legacy_scopes && !call_scopes.is_empty() {
+                    call_scopes = call_scopes.iter().map(Scope::deep_copy).collect();
+                    call_scopes[0] = self.env.global_scope().deep_copy();
+                }
+                self.observe(Event::Call {
+                    name,
+                    args: &evaluated_args,
+                    span: self.call_stack.last().and_then(|frame| frame.span),
+                });
+                let saved_scopes = self.env.replace_scopes(call_scopes);
+
+                self.env.push_scope();
+                for (param, value) in params.iter().zip(evaluated_args) {
+                    self.env.define(param.clone(), value);
+                }
+
+                // Execute function body
+                self.frames.push(HashSet::new());
+                let result = self.exec_block_in_current_scope(&body);
+                self.frames.pop();
+
+                // Restore the caller's scope stack, and let go of the
+                // scopes the call made.
+                let call_scopes = self.env.replace_scopes(saved_scopes);
+                for scope in call_scopes.into_iter().skip(captured_len).rev() {
+                    scope.release();
+                }
+
+                // Convert result and check the return type annotation
+                let result = result
+                    .and_then(flow_value)
+                    .and_then(|value| match &return_type {
+                        Some(expected) if !value.matches_type(expected) => {
+                            Err(RuntimeError::ReturnTypeMismatch {
+                                func: name.to_string(),
+                                expected: expected.to_string(),
+                                got: value.type_name().to_string(),
+                            })
+                        }
+                        _ => Ok(value),
+                    });
+                self.observe(Event::Return {
+                    name,
+                    value: result.as_ref().ok(),
+                });
+                result
+            }
+            _ => Err(RuntimeError::TypeError {
+                expected: "ilo",
+                got: func.type_name().to_string(),
+                arg: None,
+            }),
+        }
+    }
+
+    /// The scope stack a new function value captures.
+    fn capture_env(&self) -> Vec<Scope> {
+        if self.options.legacy_scopes {
+            self.env.snapshot()
+        } else {
+            self.env.capture()
+        }
+    }
+
+    fn eval_args(&mut self, args: &[Expr]) -> Result<Vec<Value>, RuntimeError> {
+        args.iter().map(|arg| self.eval_expr(arg)).collect()
+    }
+}
+
+/// Native stack left before `with_stack` moves on to a new segment: more
+/// than the deepest run of frames between two statements or expressions.
+const STACK_RED_ZONE: usize = 256 * 1024;
+
+/// Size of each extra stack segment.
+const STACK_SEGMENT: usize = 4 * 1024 * 1024;
+
+/// Run `f`, on a fresh heap-allocated stack segment if the current one is
+/// nearly used up. Every statement and expression goes through here, so a
+/// script recursing up to `max_call_depth` (however many blocks and loops
+/// each call nests) cannot overflow the thread's native stack, whatever its
+/// size; `StackOverflow` is raised by the depth limit instead.
+fn with_stack<T>(f: impl FnOnce() -> T) -> T {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, f)
+}
+
+/// The value a finished block produces where control flow cannot continue
+/// outward (function bodies and expressions): `pana` gives its value, and a
+/// `pini_tawa` or `awen_sin` with no loop (or no loop with its label) left
+/// is an error.
+fn flow_value(flow: ControlFlow) -> Result<Value, RuntimeError> {
+    match flow {
+        ControlFlow::None => Ok(Value::Ala),
+        ControlFlow::Return(v) => Ok(v),
+        ControlFlow::Break { label: None, .. } => Err(RuntimeError::BreakOutsideLoop),
+        ControlFlow::Continue { label: None } => Err(RuntimeError::ContinueOutsideLoop),
+        ControlFlow::Break {
+            label: Some(label), ..
+        }
+        | ControlFlow::Continue { label: Some(label) } => Err(RuntimeError::UnknownLabel(label)),
+    }
+}
+
+/// Wrap an arithmetic result, rejecting NaN and infinity.
+fn finite_result(n: f64, op: &BinOp, span: Span) -> Result<Value, RuntimeError> {
+    if n.is_finite() {
+        Ok(Value::Number(n))
+    } else {
+        Err(RuntimeError::NonFinite {
+            op: op.to_string(),
+            nan: n.is_nan(),
+            span: Some(span),
+        })
+    }
+}
+
+/// `--strict-math` check: integer `+`, `-` and `*` must stay below 2^53 in
+/// size, where every integer is exactly representable as an f64. A result
+/// of 2^53 itself is refused too: `2^53 + 1` rounds to it, so it cannot be
+/// told apart from a rounded one.
+fn check_precision(
+    op: &BinOp,
+    left: &Value,
+    right: &Value,
+    span: Span,
+) -> Result<(), RuntimeError> {
+    let (Value::Number(a), Value::Number(b)) = (left, right) else {
+        return Ok(());
+    };
+    if a.fract() != 0.0 || b.fract() != 0.0 {
+        return Ok(());
+    }
+    let result = match op {
+        BinOp::Add => a + b,
+        BinOp::Sub => a - b,
+        BinOp::Mul => a * b,
+        BinOp::Pow => a.powf(*b),
+        _ => return Ok(()),
+    };
+    if result.abs() < F64_SAFE_INT_MAX {
+        Ok(())
+    } else {
+        Err(RuntimeError::PrecisionLoss { op: *op, span })
+    }
+}
+
+/// lon for true, ala for false.
+fn bool_value(b: bool) -> Value {
+    if b {
+        Value::Bool
+    } else {
+        Value::Ala
+    }
+}
+
+/// If either operand is a kipisi and the other is a kipisi or nanpa, return
+/// both as rationals so the operation can be carried out exactly.
+fn decimal_operands(left: &Value, right: &Value) -> Option<(Rational, Rational)> {
+    match (left, right) {
+        (Value::Decimal(a), Value::Decimal(b)) => Some((*a, *b)),
+        (Value::Decimal(a), Value::Number(b)) => Some((*a, Rational::from_f64(*b)?)),
+        (Value::Number(a), Value::Decimal(b)) => Some((Rational::from_f64(*a)?, *b)),
+        _ => None,
+    }
+}
+
+fn eval_decimal_binary(op: &BinOp, a: Rational, b: Rational) -> Result<Value, RuntimeError> {
+    let result = match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Div if b.is_zero() => return Err(RuntimeError::DivisionByZero),
+        BinOp::Div => a.checked_div(b),
+        BinOp::Mod if b.is_zero() => return Err(RuntimeError::DivisionByZero),
+        BinOp::Mod => a.checked_rem_euclid(b),
+        BinOp::Pow if a.is_zero() && b.to_f64() < 0.0 => return Err(RuntimeError::DivisionByZero),
+        BinOp::Pow => match b.to_integer().and_then(|exp| i32::try_from(exp).ok()) {
+            Some(exp) => a.checked_pow(exp),
+            None => {
+                return Err(RuntimeError::TypeError {
+                    expected: "whole exponent for a kipisi",
+                    got: Value::Decimal(b).to_string(),
+                    arg: None,
+                })
+            }
+        },
+        BinOp::Gt => return Ok(bool_value(a > b)),
+        BinOp::Lt => return Ok(bool_value(a < b)),
+        BinOp::Ge => return Ok(bool_value(a >= b)),
+        BinOp::Le => return Ok(bool_value(a <= b)),
+        BinOp::Eq => return Ok(bool_value(a == b)),
+        // `en` / `anu` short-circuit in eval_expr and never get here.
+        BinOp::And | BinOp::Or => {
+            return Err(RuntimeError::TypeError {
+                expected: "compatible types",
+                got: "kipisi and kipisi".to_string(),
+                arg: None,
+            })
+        }
+    };
+    result
+        .map(Value::Decimal)
+        .ok_or(RuntimeError::DecimalOverflow)
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine for Interpreter {
+    fn run(&mut self, program: &[Stmt]) -> Result<Value, RuntimeError> {
+        self.guarded(|interp| interp.run_program(program))
+    }
+
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        self.guarded(|interp| Interpreter::eval_expr(interp, expr))
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn register_fn(&mut self, name: &str, func: HostFn) {
+        self.host_fns.insert(name.to_string(), func);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn run_with(source: &str, options: Options) -> Interpreter {
+        let program = parse(source).unwrap();
+        let mut interpreter = Interpreter::with_options(options);
+        interpreter.run(&program).unwrap();
+        interpreter
+    }
+
+    /// A fresh interpreter with `limits` applied.
+    fn limited(limits: Limits) -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(limits);
+        interpreter
+    }
+
+    /// The error without the location `at` added to it.
+    fn unlocated(err: &RuntimeError) -> &RuntimeError {
+        match err {
+            RuntimeError::At { error, .. } => error,
+            error => error,
+        }
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> Value {
+        interpreter.get_global(name).unwrap()
+    }
+
+    #[test]
+    fn test_closure_keeps_its_own_counter() {
+        let interpreter = run_with(
+            r#"
+            ilo pali_nanpa () open
+                nanpa jo 0
+                pana ilo () open
+                    nanpa jo nanpa + 1
+                    pana nanpa
+                pini
+            pini
+            wan jo pali_nanpa()
+            tu jo pali_nanpa()
+            wan()
+            wan()
+            a jo wan()
+            b jo tu()
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "a"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn test_free_variables_resolve_at_definition_not_call_site() {
+        let interpreter = run_with(
+            r#"
+            ilo pali () open
+                sin nimi jo "insa"
+                pana ilo () open
+                    pana nimi
+                pini
+            pini
+            ilo kepeken (f) open
+                sin nimi jo "jan"
+                pana f()
+            pini
+            nimi jo "ma"
+            a jo kepeken(pali())
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "a"), Value::String("insa".into()));
+        assert_eq!(global(&interpreter, "nimi"), Value::String("ma".into()));
+    }
+
+    #[test]
+    fn test_functions_update_outer_variables() {
+        let source = r#"
+            nanpa jo 0
+            ilo kama () open
+                ma nanpa
+                nanpa jo nanpa + 1
+            pini
+            kama()
+            kama()
+        "#;
+        let lexical = run_with(source, Options::default());
+        assert_eq!(global(&lexical, "nanpa"), Value::Number(2.0));
+
+        let legacy = run_with(
+            source,
+            Options {
+                legacy_scopes: true,
+                ..Options::default()
+            },
+        );
+        assert_eq!(global(&legacy, "nanpa"), Value::Number(0.0));
+    }
+
+    #[test]
+    fn test_recursive_function_compares_by_identity() {
+        let interpreter = run_with(
+            r#"
+            ilo f (n) open
+                n lili_sama 1 la open
+                    pana 1
+                pini
+                pana n * f(n - 1)
+            pini
+            a jo f(5)
+            g jo f
+            b jo f sama g
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "a"), Value::Number(120.0));
+        assert_eq!(global(&interpreter, "b"), Value::Bool);
+    }
+
+    #[test]
+    fn test_function_writes_globals_only_with_ma() {
+        let interpreter = run_with(
+            r#"
+            a jo 1
+            b jo 1
+            ilo ante () open
+                a jo 2
+                ma b
+                b jo 2
+                pana a
+            pini
+            c jo ante()
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "a"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "c"), Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_ma_writes_the_global_scope_itself() {
+        let source = r#"
+            sin x jo 1
+            ilo poka () open
+                sin x jo 10
+                ilo insa () open
+                    ma x
+                    x jo 2
+                    ma sewi
+                    sewi jo 5
+                pini
+                insa()
+                pana x
+            pini
+            sin y jo poka()
+            sin z jo sewi + 1
+        "#;
+        for options in [
+            Options::default(),
+            Options {
+                strict: true,
+                ..Options::default()
+            },
+        ] {
+            // The nested function's `x` is the global one, not the
+            // enclosing function's local, and a `ma` name that no scope
+            // defines yet becomes a global.
+            let interpreter = run_with(source, options);
+            assert_eq!(global(&interpreter, "x"), Value::Number(2.0));
+            assert_eq!(global(&interpreter, "y"), Value::Number(10.0));
+            assert_eq!(global(&interpreter, "z"), Value::Number(6.0));
+        }
+    }
+
+    #[test]
+    fn test_while_else_and_break_value() {
+        let interpreter = run_with(
+            r#"
+            i jo 0
+            lukin jo wile i lili 10 la open
+                i jo i + 1
+                i sama 3 la open
+                    pini_tawa e i * 100
+                pini
+            pini taso open
+                0 - 1
+            pini
+            ante jo 0
+            wile i lili 5 la open
+                i jo i + 1
+            pini taso open
+                ante jo i
+            pini
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "lukin"), Value::Number(300.0));
+        assert_eq!(global(&interpreter, "ante"), Value::Number(5.0));
+
+        let program = parse("pini_tawa").unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::BreakOutsideLoop));
+    }
+
+    #[test]
+    fn test_labeled_break_leaves_outer_loop() {
+        let interpreter = run_with(
+            r#"
+            x jo 0
+            y jo 0
+            tawa alasa: wile x lili 10 la open
+                y jo 0
+                wile y lili 10 la open
+                    x * y sama 12 la open
+                        pini_tawa alasa
+                    pini
+                    y jo y + 1
+                pini
+                x jo x + 1
+            pini taso open
+                x jo 0 - 1
+            pini
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "x"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "y"), Value::Number(6.0));
+
+        let program = parse("wile lon la open\n  pini_tawa ala_lon\npini").unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::UnknownLabel(l) if l == "ala_lon"));
+    }
+
+    #[test]
+    fn test_for_loop_counts_with_fresh_binding() {
+        let interpreter = run_with(
+            r#"
+            ale jo 0
+            tawa i tan 0 tawa 5 la open
+                ale jo ale + i
+            pini
+            ilo_ale jo kulupu_sin()
+            tawa i tan 0 tawa 3 la open
+                ilo_ale jo kulupu_aksen(ilo_ale, ilo () open pana i pini)
+            pini
+            f jo kulupu_ken(ilo_ale, 1)
+            wan jo f()
+            kulupu jo 0
+            tawa nasin: tawa x tan 1 tawa 10 la open
+                tawa y tan 1 tawa 10 la open
+                    x * y sama 12 la open
+                        kulupu jo x * 10 + y
+                        pini_tawa nasin
+                    pini
+                pini
+            pini
+        "#,
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "ale"), Value::Number(10.0));
+        assert_eq!(global(&interpreter, "wan"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "kulupu"), Value::Number(26.0));
+        assert!(interpreter.env.get("i").is_none());
+    }
+
+    #[test]
+    fn test_capability_annotation() {
+        let source = r#"
+            nasin_ilo Ante open
+                ilo sama_nanpa (a, b)
+            pini
+            ilo lawa (a, b, ante: Ante) open
+                pana ante(a, b)
+            pini
+            ilo lawa_nasin (a, b, ante: Ante) open
+                f jo nasin_ken(ante, "sama_nanpa")
+                pana f(a, b)
+            pini
+            ilo wan (a, b) open
+                pana a - b
+            pini
+            x jo lawa(3, 1, wan)
+            y jo lawa_nasin(3, 1, nasin_lon(nasin_sin(), "sama_nanpa", wan))
+        "#;
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "x"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "y"), Value::Number(2.0));
+
+        let program = parse(&format!("{source}\nlawa(1, 2, nasin_sin())")).unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(
+            matches!(unlocated(&err), RuntimeError::ParamTypeMismatch { expected, .. } if expected == "Ante")
+        );
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let program = parse("toki(\"mute\")\ntoki(\"mute\")\nx jo 1\ntoki(\"a\")\nx jo 2").unwrap();
+        let mut interpreter = limited(Limits {
+            max_output: Some(10),
+            ..Limits::default()
+        });
+        let err = interpreter.run(&program).unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::OutputLimit(10)));
+        assert_eq!(global(&interpreter, "x"), Value::Number(1.0));
+
+        // `ken_pali` catches it. Refused output is not counted, so a
+        // shorter line still fits.
+        let program = parse(
+            "r jo ken_pali(ilo () open toki(\"mute mute mute\") pini, ilo (e) open pana e pini)\n\
+             s jo ken_pali(ilo () open toki(\"a\") pini, ilo (e) open pana e pini)",
+        )
+        .unwrap();
+        let mut interpreter = limited(Limits {
+            max_output: Some(10),
+            ..Limits::default()
+        });
+        interpreter.run(&program).unwrap();
+        assert!(
+            matches!(global(&interpreter, "r"), Value::String(e) if e.contains("output limit"))
+        );
+        assert_eq!(global(&interpreter, "s"), Value::Ala);
+    }
+
+    #[test]
+    fn test_size_limits_are_catchable() {
+        let source = "s jo \"ab\"\ns jo s + s\nr jo ken_pali(ilo () open\n    pana s + s\npini)\nk jo kulupu_sin(1, 2, 3)";
+        let program = parse(source).unwrap();
+        let mut interpreter = limited(Limits {
+            max_string_len: Some(4),
+            max_list_len: Some(2),
+            ..Limits::default()
+        });
+        let err = interpreter.run(&program).unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::LimitExceeded {
+                kind: "kulupu",
+                size: 3,
+                limit: 2
+            }
+        ));
+        assert_eq!(global(&interpreter, "s"), Value::String("abab".into()));
+        match global(&interpreter, "r") {
+            Value::Map(r) => assert_eq!(r.get(&Value::String("pona".into())), Some(&Value::Ala)),
+            other => panic!("expected a nasin, got {other}"),
+        }
+    }
+
+    #[test]
+    fn test_size_limits_refuse_before_allocating() {
+        let limits = Limits {
+            max_string_len: Some(10),
+            max_list_len: Some(1000),
+            ..Limits::default()
+        };
+        let mut cases = vec![
+            ("x jo kulupu_leko_sin(1000000, 100000)", "kulupu", 1_000_000),
+            ("x jo kulupu_leko_sin(100, 100)", "kulupu", 10_000),
+            ("x jo kulupu_leko_sin(0, 5000)", "kulupu", 5000),
+            (
+                "x jo sitelen_ante(\"aaaa\", \"a\", \"bbbbbbbbb\")",
+                "sitelen",
+                36,
+            ),
+            ("x jo \"abcdef\" + \"ghijkl\"", "sitelen", 12),
+            ("x jo kute_ale()", "sitelen", 11),
+        ];
+        if cfg!(feature = "regex") {
+            cases.push(("x jo regex_ante(\"aaaa\", \"a\", \"bbbbb\")", "sitelen", 12));
+        }
+        for (source, kind, size) in cases {
+            let mut interpreter = limited(limits);
+            interpreter.set_input(io::Cursor::new("x".repeat(100)));
+            let err = interpreter.run(&parse(source).unwrap()).unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::LimitExceeded { kind: k, size: s, .. } if *k == kind && *s == size
+                ),
+                "{source}: {err}"
+            );
+        }
+
+        let path = std::env::temp_dir().join(format!("lipona_suli_{}.txt", std::process::id()));
+        std::fs::write(&path, "x".repeat(100)).unwrap();
+        let path = path.display().to_string();
+        let program = parse(&format!("x jo lipu_lukin(\"{path}\")")).unwrap();
+        // The limit must leave room for the path itself.
+        let limit = path.len() + 1;
+        let err = limited(Limits {
+            max_string_len: Some(limit),
+            ..Limits::default()
+        })
+        .run(&program)
+        .unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            matches!(
+                err.innermost(),
+                RuntimeError::LimitExceeded { kind: "sitelen", size, .. } if *size == limit + 1
+            ),
+            "{err}"
+        );
+
+        let program = parse("x jo csv_open(\"a,b,c\")").unwrap();
+        let err = limited(Limits {
+            max_list_len: Some(2),
+            ..Limits::default()
+        })
+        .run(&program)
+        .unwrap_err();
+        assert!(matches!(
+            err.innermost(),
+            RuntimeError::LimitExceeded {
+                kind: "kulupu",
+                size: 3,
+                limit: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn test_interactive_entries_share_state() {
+        let mut interpreter = Interpreter::new();
+        for (entry, expected) in [
+            ("x jo 2", Value::Ala),
+            ("ilo f (a) open\n  pana a * x\npini", Value::Ala),
+            ("f(3)", Value::Number(6.0)),
+        ] {
+            let program = parse(entry).unwrap();
+            assert_eq!(interpreter.run_interactive(&program).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_errors_point_at_innermost_node() {
+        let program = parse("x jo 1\n\ny jo x + z").unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pakala: undefined variable 'z' at line 3, column 10"
+        );
+
+        // An error inside a function is reported where it happens, not at the call.
+        let program = parse("ilo f (a) open\n  pana a / ala\npini\nx jo f(1)").unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::At {
+                span: Span { line: 2, col: 8 },
+                ..
+            }
+        ));
+        assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
+    }
+
+    #[test]
+    fn test_toki_tawa_lipu_redirects_output() {
+        let path = std::env::temp_dir().join(format!("lipona_toki_{}.txt", std::process::id()));
+        let source = format!(
+            "x jo toki_tawa_lipu(\"{}\", ilo () open\n  toki(\"wan\", 1)\n  o_lukin(2)\n  pana 3\npini)",
+            path.display()
+        );
+        let mut interpreter = limited(Limits {
+            max_output: Some(1),
+            ..Limits::default()
+        });
+        interpreter.run(&parse(&source).unwrap()).unwrap();
+        assert_eq!(global(&interpreter, "x"), Value::Number(3.0));
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(written, "wan 1\nnanpa 2\n");
+    }
+
+    #[test]
+    fn test_lipu_file_builtins_and_policy() {
+        let path = std::env::temp_dir().join(format!("lipona_lipu_{}.txt", std::process::id()));
+        let path = path.display().to_string();
+        let source = format!(
+            "a jo lipu_lon(\"{path}\")\nlipu_sitelen(\"{path}\", \"wan\\n\")\nlipu_namako(\"{path}\", \"tu\\n\")\nb jo lipu_lukin(\"{path}\")\nc jo lipu_lon(\"{path}\")"
+        );
+        let program = parse(&source).unwrap();
+
+        let mut sandboxed = Interpreter::new();
+        let allowed = path.clone();
+        sandboxed
+            .set_file_policy(move |file, access| file == allowed && access == FileAccess::Read);
+        let err = sandboxed.run(&program).unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::FileDenied {
+                access: FileAccess::Write,
+                ..
+            }
+        ));
+
+        let interpreter = run_with(&source, Options::default());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(global(&interpreter, "a"), Value::Ala);
+        assert_eq!(
+            global(&interpreter, "b"),
+            Value::String("wan\ntu\n".to_string())
+        );
+        assert_eq!(global(&interpreter, "c"), Value::Bool);
+    }
+
+    #[test]
+    fn test_lipu_linja_kama_reads_line_by_line() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("lipona_linja_{}.txt", std::process::id()));
+        let output = dir.join(format!("lipona_linja_{}.out", std::process::id()));
+        let (input, output) = (input.display().to_string(), output.display().to_string());
+        std::fs::write(&input, "wan\r\ntu\n\nsaw").unwrap();
+        // Callbacks cannot assign outer variables, so they report what they
+        // saw by appending to a file.
+        let source = format!(
+            "lipu_sitelen(\"{output}\", \"\")\n\
+             a jo lipu_linja_kama(\"{input}\", ilo (linja) open\n    lipu_namako(\"{output}\", \"[\" + linja + \"]\")\npini)\n\
+             b jo lipu_linja_kama(\"{input}\", ilo (linja, i) open\n    lipu_namako(\"{output}\", nanpa_sitelen(i))\npini)\n\
+             c jo lipu_lukin(\"{output}\")"
+        );
+        let program = parse(&source).unwrap();
+        let interpreter = run_with(&source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Number(4.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(4.0));
+        // "\r\n" loses both characters, and the last line needs no "\n".
+        assert_eq!(
+            global(&interpreter, "c"),
+            Value::String("[wan][tu][][saw]0123".into())
+        );
+
+        let mut sandboxed = Interpreter::new();
+        let allowed = output.clone();
+        sandboxed.set_file_policy(move |file, _| file == allowed);
+        let err = sandboxed.run(&program).unwrap_err();
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+        assert!(
+            matches!(
+                unlocated(&err),
+                RuntimeError::FileDenied {
+                    access: FileAccess::Read,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_lipu_lukin_awen_calls_back_on_change() {
+        let path = std::env::temp_dir().join(format!("lipona_awen_{}.txt", std::process::id()));
+        std::fs::write(&path, "wan").unwrap();
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(150));
+                std::fs::write(&path, "tu tu").unwrap();
+            })
+        };
+        let path = path.display().to_string();
+        let source = format!(
+            "ilo kama (p) open\n    pana lipu_lukin(p)\npini\na jo lipu_lukin_awen(\"{path}\", kama)"
+        );
+        let interpreter = run_with(&source, Options::default());
+        writer.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(global(&interpreter, "a"), Value::String("tu tu".into()));
+    }
+
+    #[test]
+    fn test_kepeken_imports_functions_and_detects_cycles() {
+        let dir = std::env::temp_dir().join(format!("lipona_kepeken_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lipu")).unwrap();
+        std::fs::write(
+            dir.join("lipu/nanpa.lipo"),
+            "mute jo 2\nilo tu (x) open\n  pana x * mute\npini",
+        )
+        .unwrap();
+        std::fs::write(dir.join("a.lipo"), "kepeken b").unwrap();
+        std::fs::write(dir.join("b.lipo"), "kepeken a").unwrap();
+
+        let program = parse("kepeken lipu/nanpa\nkepeken lipu/nanpa\nx jo tu(5)").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_module_loader(|path| std::fs::read_to_string(path).ok());
+        interpreter.set_script(&dir.join("main.lipo"));
+        interpreter.run(&program).unwrap();
+        assert_eq!(global(&interpreter, "x"), Value::Number(10.0));
+        // Only functions are imported; the module's globals stay its own.
+        assert_eq!(interpreter.env.get("mute"), None);
+        assert_eq!(interpreter.modules.len(), 1);
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_module_loader(|path| std::fs::read_to_string(path).ok());
+        interpreter.set_script(&dir.join("a.lipo"));
+        let err = interpreter.run(&parse("kepeken b").unwrap()).unwrap_err();
+        let _ = std::fs::remove_dir_all(&dir);
+        let RuntimeError::InModule { error, .. } = unlocated(&err) else {
+            panic!("expected error in module b, got {err:?}");
+        };
+        assert!(matches!(unlocated(error), RuntimeError::ImportCycle(_)));
+    }
+
+    #[test]
+    fn test_module_loader_serves_kepeken_from_memory() {
+        let modules = HashMap::from([
+            ("lipu/nanpa.lipo", "ilo tu (x) open\n  pana x * 2\npini"),
+            ("a.lipo", "kepeken b"),
+            ("b.lipo", "kepeken a"),
+        ]);
+        let loader = move |path: &str| modules.get(path).map(|source| source.to_string());
+        let mut interpreter = Interpreter::new();
+        interpreter.set_module_loader(loader.clone());
+        interpreter
+            .run(&parse("kepeken lipu/nanpa\nx jo tu(5)").unwrap())
+            .unwrap();
+        assert_eq!(global(&interpreter, "x"), Value::Number(10.0));
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_module_loader(loader);
+        let err = interpreter.run(&parse("kepeken a").unwrap()).unwrap_err();
+        assert!(matches!(
+            err.innermost(),
+            RuntimeError::ImportCycle(chain) if chain == "a.lipo -> b.lipo -> a.lipo"
+        ));
+        let err = interpreter
+            .run(&parse("kepeken ala_li_lon").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pakala: cannot read file 'ala_li_lon.lipo' - no such module at line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn test_ijo_kama_lists_script_args() {
+        let program = parse("a jo ijo_kama()").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_args(vec!["wan".into(), "--tu".into()]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(
+            global(&interpreter, "a"),
+            Value::List(vec![
+                Value::String("wan".into()),
+                Value::String("--tu".into())
+            ])
+        );
+    }
+
+    #[test]
+    fn test_pana_nasin_kama_parses_script_args() {
+        let program = parse(
+            "a jo pana_nasin_kama(kulupu_sin(\"--v\", \"--nimi=\", \"--mute=1\", \"lipu\", \"ante=x\"))",
+        )
+        .unwrap();
+        let run_args = |args: &[&str]| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_args(args.iter().map(|a| a.to_string()).collect());
+            interpreter.run(&program).map(|_| global(&interpreter, "a"))
+        };
+
+        let Value::Map(map) = run_args(&["--nimi", "jan", "in.txt", "--mute=3"]).unwrap() else {
+            panic!("expected nasin");
+        };
+        assert_eq!(map[&Value::String("v".into())], Value::Ala);
+        assert_eq!(
+            map[&Value::String("nimi".into())],
+            Value::String("jan".into())
+        );
+        assert_eq!(
+            map[&Value::String("mute".into())],
+            Value::String("3".into())
+        );
+        assert_eq!(
+            map[&Value::String("lipu".into())],
+            Value::String("in.txt".into())
+        );
+        assert_eq!(
+            map[&Value::String("ante".into())],
+            Value::String("x".into())
+        );
+
+        let Value::Map(map) = run_args(&["--v", "--", "--lipu"]).unwrap() else {
+            panic!("expected nasin");
+        };
+        assert_eq!(map[&Value::String("v".into())], Value::Bool);
+        assert_eq!(
+            map[&Value::String("lipu".into())],
+            Value::String("--lipu".into())
+        );
+
+        for bad in [&[][..], &["a", "b", "c"], &["--ala", "a"], &["a", "--nimi"]] {
+            let err = run_args(bad).unwrap_err();
+            assert!(
+                matches!(unlocated(&err), RuntimeError::Argument(_)),
+                "{bad:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_ale_runs_last_and_gives_exit_code() {
+        let program =
+            parse("ilo open_ale (args) open\n  pana kulupu_len(args) + x\npini\nx jo 1").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_args(vec!["a".into(), "b".into()]);
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.run_main().unwrap(), Some(3));
+
+        let program = parse("ilo open_ale () open\n  pana \"pona\"\npini").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&program).unwrap();
+        assert_eq!(interpreter.run_main().unwrap(), None);
+
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&parse("x jo 1").unwrap()).unwrap();
+        assert_eq!(interpreter.run_main().unwrap(), None);
+    }
+
+    #[test]
+    fn test_wile_sama_lists_differing_paths() {
+        let source = "a jo kulupu_sin(1, nasin_lon(nasin_sin(), \"nimi\", \"jan\"), 3)\nb jo kulupu_sin(\"1\", nasin_lon(nasin_sin(), \"nimi\", \"soweli\"))\nwile_sama(a, a)\nwile_sama(a, b)";
+        let err = Interpreter::new().run(&parse(source).unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pakala: wile_sama failed - values differ at line 4, column 1\n  [0]: 1 != \"1\"\n  [1][\"nimi\"]: \"jan\" != \"soweli\"\n  [2]: only on the left: 3"
+        );
+    }
+
+    #[test]
+    fn test_awen_sin_continues_and_pini_awen_breaks() {
+        let source = "i jo 0\nale jo 0\nwile i lili 10 la open\n  i jo i + 1\n  i sama 3 la open\n    awen_sin\n  pini\n  i suli 6 la open\n    pini_awen\n  pini\n  ale jo ale + i\npini\nmute jo 0\ntawa ike: tawa a tan 0 tawa 3 la open\n  tawa b tan 0 tawa 3 la open\n    b sama 1 la open\n      awen_sin ike\n    pini\n    mute jo mute + 1\n  pini\npini";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "ale"), Value::Number(18.0));
+        assert_eq!(global(&interpreter, "mute"), Value::Number(3.0));
+
+        let err = Interpreter::new()
+            .run(&parse("ilo f () open\n  awen_sin\npini\nf()").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::ContinueOutsideLoop));
+    }
 
-        match (op, &left_val, &right_val) {
-            // Numeric operations
-            (BinOp::Add, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-            (BinOp::Sub, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-            (BinOp::Mul, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-            (BinOp::Div, Value::Number(_), Value::Number(b)) if *b == 0.0 => {
-                Err(RuntimeError::DivisionByZero)
-            }
-            (BinOp::Div, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a / b)),
+    #[test]
+    fn test_warn_float_eq_and_nanpa_sama_lili() {
+        let source = "x jo 0.1 + 0.2\na jo lon\ntawa i tan 0 tawa 3 la open\n  a jo x sama 0.3\npini\nb jo 2 sama 2\nc jo nanpa_sama_lili(x, 0.3, 0.000001)";
+        let options = Options {
+            warn_float_eq: true,
+            ..Options::default()
+        };
+        let mut interpreter = run_with(source, options);
+        assert_eq!(global(&interpreter, "a"), Value::Ala);
+        assert_eq!(global(&interpreter, "c"), Value::Bool);
+        assert_eq!(
+            interpreter.take_warnings(),
+            vec!["'sama' compares non-whole numbers 0.30000000000000004 and 0.3 at line 4, column 10 - consider nanpa_sama_lili(a, b, eps)".to_string()]
+        );
+        assert!(run_with(source, Options::default())
+            .take_warnings()
+            .is_empty());
 
-            // String concatenation
-            (BinOp::Add, Value::String(a), Value::String(b)) => {
-                Ok(Value::String(format!("{a}{b}")))
+        let err = Interpreter::new()
+            .run(&parse("nanpa_sama_lili(1, 1, 0 - 1)").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
+    }
+
+    #[test]
+    fn test_tawa_lon_iterates_lists_and_maps() {
+        let source = "ale jo 0\ntawa n lon kulupu_sin(1, 2, 3) la open\n  ale jo ale + n\npini\nm jo nasin_lon(nasin_lon(nasin_sin(), \"b\", 2), \"a\", 1)\nnimi jo \"\"\ntawa k lon m la open\n  nimi jo nimi + k\npini\nsin jo \"\"\ntawa k, v lon m la open\n  sin jo sin + \"{k}{v}\"\npini\nf jo kulupu_sin()\ntawa i, x lon kulupu_sin(5, 6) la open\n  f jo kulupu_aksen(f, ilo () open\n    pana i\n  pini)\npini\ng jo kulupu_ken(f, 0)\nr jo g()";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "ale"), Value::Number(6.0));
+        assert_eq!(global(&interpreter, "nimi"), Value::String("ab".into()));
+        assert_eq!(global(&interpreter, "sin"), Value::String("a1b2".into()));
+        assert_eq!(global(&interpreter, "r"), Value::Number(0.0));
+        assert!(interpreter.env.get("x").is_none());
+
+        let err = Interpreter::new()
+            .run(&parse("tawa x lon 3 la open\npini").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
+    }
+
+    #[test]
+    fn test_integer_conversion_builtins() {
+        let source = "a jo nanpa_insa_ale(0 - 2.7)\nb jo nanpa_sike_nasin(2.5)\nc jo nanpa_sike_nasin(2.5, \"half_even\")\nd jo nanpa_sike_nasin(3.5, \"half_even\")\ne jo nanpa_sike_nasin(0 - 2.5)\nf jo nanpa_sike_nasin(2.1, \"ceil\")\ng jo nanpa_sike_nasin(2.9, \"floor\")";
+        let interpreter = run_with(source, Options::default());
+        let numbers: Vec<Value> = ["a", "b", "c", "d", "e", "f", "g"]
+            .iter()
+            .map(|name| global(&interpreter, name))
+            .collect();
+        let expected = [-2.0, 3.0, 2.0, 4.0, -2.0, 3.0, 2.0].map(Value::Number);
+        assert_eq!(numbers, expected);
+
+        for (source, function, position) in [
+            ("nanpa_insa_ale(9007199254740993 * 2)", "nanpa_insa_ale", 1),
+            (
+                "nanpa_sike_nasin(9007199254740993 * 2)",
+                "nanpa_sike_nasin",
+                1,
+            ),
+            ("nanpa_sike_nasin(1.5, \"up\")", "nanpa_sike_nasin", 2),
+            ("nanpa_sike_nasin(1.5, 2)", "nanpa_sike_nasin", 2),
+        ] {
+            let err = Interpreter::new().run(&parse(source).unwrap()).unwrap_err();
+            assert!(
+                matches!(
+                    unlocated(&err),
+                    RuntimeError::TypeError { arg: Some(arg), .. }
+                        if arg.function == function && arg.position == position
+                ),
+                "{source}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builtin_type_errors_name_the_argument() {
+        let err = Interpreter::new()
+            .run(&parse("x jo kulupu_lon(kulupu_sin(1), \"a\", 2)").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pakala: type error - expected nanpa, got sitelen (argument 2 of kulupu_lon) at line 1, column 6"
+        );
+        let err = Interpreter::new()
+            .run(&parse("sitelen_ken_ale(\"toki\", 0, 1.5)").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError {
+                arg: Some(ArgPosition {
+                    function: "sitelen_ken_ale",
+                    position: 3
+                }),
+                ..
             }
+        ));
+    }
 
-            // Comparisons - return Bool for true, Ala for false
-            (BinOp::Gt, Value::Number(a), Value::Number(b)) => {
-                Ok(if a > b { Value::Bool } else { Value::Ala })
+    #[test]
+    fn test_builtin_signatures_checked_before_call() {
+        let err = Interpreter::new()
+            .run(&parse("nanpa_sin(\"1\", 10, 2)").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::WrongArityRange {
+                min: 1,
+                max: 2,
+                got: 3,
+                ..
             }
-            (BinOp::Lt, Value::Number(a), Value::Number(b)) => {
-                Ok(if a < b { Value::Bool } else { Value::Ala })
+        ));
+        let err = Interpreter::new()
+            .run(&parse("kulupu_ante(kulupu_sin(1), 3)").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError {
+                expected: "ilo",
+                arg: Some(ArgPosition {
+                    function: "kulupu_ante",
+                    position: 2
+                }),
+                ..
             }
-            (BinOp::Ge, Value::Number(a), Value::Number(b)) => {
-                Ok(if a >= b { Value::Bool } else { Value::Ala })
+        ));
+        let err = Interpreter::new()
+            .run(&parse("toki(1, 2)\nx jo sitelen_len(5)").unwrap())
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected sitelen, got nanpa (argument 1 of sitelen_len)"));
+    }
+
+    #[test]
+    fn test_pakala_sin_and_ken_pali() {
+        let err = Interpreter::new()
+            .run(&parse("x jo 1\npakala_sin(\"ike\")").unwrap())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "pakala: ike at line 2, column 1");
+
+        let source = "ilo ike () open pakala_sin(kulupu_sin(1, 2)) pini\nilo pona () open pana 3 pini\na jo ken_pali(pona)\nb jo ken_pali(ike)\nc jo ken_pali(ike, ilo (e) open pana kulupu_len(e) pini)\nd jo ken_pali(ilo () open pana nimi_ala pini, ilo (e) open pana e pini)";
+        let interpreter = run_with(source, Options::default());
+        let map = |entries: Vec<(&str, Value)>| {
+            Value::Map(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (Value::String(k.to_string()), v))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            global(&interpreter, "a"),
+            map(vec![("pona", Value::Bool), ("ijo", Value::Number(3.0))])
+        );
+        assert_eq!(
+            global(&interpreter, "b"),
+            map(vec![
+                ("pona", Value::Ala),
+                (
+                    "pakala",
+                    Value::List(vec![Value::Number(1.0), Value::Number(2.0)])
+                )
+            ])
+        );
+        assert_eq!(global(&interpreter, "c"), Value::Number(2.0));
+        assert_eq!(
+            global(&interpreter, "d"),
+            Value::String("pakala: undefined variable 'nimi_ala' at line 6, column 32".to_string())
+        );
+
+        // Resource limits are not caught.
+        let err = limited(Limits {
+            max_call_depth: 20,
+            ..Limits::default()
+        })
+        .run(&parse("ilo sike () open pana sike() pini\nken_pali(sike)").unwrap())
+        .unwrap_err();
+        assert!(matches!(err.innermost(), RuntimeError::StackOverflow));
+    }
+
+    #[test]
+    fn test_stdlib_modules_and_user_functions() {
+        let source = "ilo sitelen_len (s) open pana 99 pini\na jo sitelen_len(\"toki\")\nb jo sitelen.len(\"toki\")\nc jo kulupu.len(kulupu.sin(1, 2))\nd jo sona.ilo_ale(\"nasin\")";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Number(99.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(4.0));
+        assert_eq!(global(&interpreter, "c"), Value::Number(2.0));
+        assert_eq!(
+            global(&interpreter, "d"),
+            Value::List(
+                [
+                    "nasin.ijo",
+                    "nasin.jo",
+                    "nasin.ken",
+                    "nasin.lon",
+                    "nasin.nimi",
+                    "nasin.sin",
+                    "nasin.tu",
+                    "nasin.wan",
+                    "nasin.weka",
+                ]
+                .map(|name| Value::String(name.to_string()))
+                .to_vec()
+            )
+        );
+        let err = Interpreter::new()
+            .run(&parse("sona_ilo_ale(\"ijo\")").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::Argument(_)));
+        let err = Interpreter::new()
+            .run(&parse("sitelen.ala_li_lon(1)").unwrap())
+            .unwrap_err();
+        assert!(
+            matches!(unlocated(&err), RuntimeError::UndefinedFunction(name) if name == "sitelen.ala_li_lon")
+        );
+    }
+
+    #[test]
+    fn test_renamed_builtin_warns_once() {
+        let source =
+            "a jo kulupu_kama_pini(kulupu_sin(1), 2)\nb jo kulupu_kama_pini(a, 3)\nc jo kulupu.kama_pini(b, 4)";
+        let mut interpreter = run_with(source, Options::default());
+        assert_eq!(
+            global(&interpreter, "c"),
+            Value::List((1..=4).map(|n| Value::Number(n as f64)).collect())
+        );
+        assert_eq!(
+            interpreter.take_warnings(),
+            vec![
+                "'kulupu_kama_pini' at line 1, column 6 has been renamed - use 'kulupu_aksen' instead",
+                "'kulupu.kama_pini' at line 3, column 6 has been renamed - use 'kulupu.aksen' instead",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaping_error_keeps_call_trace() {
+        let source = "ilo jaki (x) open\n  pana x / 0\npini\nilo insa (x) open\n  pana jaki(x)\npini\nken_pali(ilo () open pana insa(1) pini)\ninsa(2)";
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&parse(source).unwrap()).unwrap_err();
+        assert_eq!(
+            interpreter.take_trace(),
+            vec![
+                "  in 'jaki' called at line 5, column 8",
+                "  in 'insa' called at line 8, column 1",
+            ]
+        );
+        assert!(interpreter.take_trace().is_empty());
+
+        let mut interpreter = limited(Limits {
+            max_call_depth: 30,
+            ..Limits::default()
+        });
+        interpreter
+            .run(&parse("ilo sike () open pana sike() pini\nsike()").unwrap())
+            .unwrap_err();
+        let trace = interpreter.take_trace();
+        assert_eq!(trace.len(), MAX_TRACE_LINES + 1);
+        assert_eq!(trace[MAX_TRACE_LINES], "  ... and 10 more");
+    }
+
+    #[test]
+    fn test_sitelen_ken_slices_by_char() {
+        let source = "a jo sitelen_ken(\"tōki pona\", 1, 4)\nb jo sitelen_ken(\"toki\", 2, 100)\nc jo sitelen_ken(\"toki\", 4, 5)\nd jo sitelen_ken(\"toki\", 5, 6)\ne jo sitelen_ken(\"toki\", 3, 1)";
+        let interpreter = run_with(source, Options::default());
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(global(&interpreter, "a"), string("ōki"));
+        assert_eq!(global(&interpreter, "b"), string("ki"));
+        assert_eq!(global(&interpreter, "c"), string(""));
+        assert_eq!(global(&interpreter, "d"), Value::Ala);
+        assert_eq!(global(&interpreter, "e"), string(""));
+    }
+
+    #[test]
+    fn test_case_and_trim_builtins() {
+        let source = "a jo sitelen_suli(\"toki Straße\")\nb jo sitelen_lili(\"ΣΟΦΊΑ\")\nc jo sitelen_weka(\"  \\tpona\\n\")";
+        let interpreter = run_with(source, Options::default());
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(global(&interpreter, "a"), string("TOKI STRASSE"));
+        assert_eq!(global(&interpreter, "b"), string("σοφία"));
+        assert_eq!(global(&interpreter, "c"), string("pona"));
+    }
+
+    #[test]
+    fn test_set_global_before_run() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global("mute", Value::Number(3.0));
+        interpreter
+            .run(&parse("ilo tu () open pana mute * 2 pini\nx jo tu()").unwrap())
+            .unwrap();
+        assert_eq!(interpreter.get_global("x"), Some(Value::Number(6.0)));
+        assert_eq!(interpreter.get_global("ala_li_lon"), None);
+    }
+
+    #[test]
+    fn test_call_script_function_from_host() {
+        let mut interpreter = run_with(
+            "ale jo 0\nilo kama (n: nanpa) open\n  ma ale\n  ale jo ale + n\n  pana ale\npini",
+            Options::default(),
+        );
+        assert_eq!(
+            interpreter.call("kama", vec![Value::Number(2.0)]).unwrap(),
+            Value::Number(2.0)
+        );
+        assert_eq!(
+            interpreter.call("kama", vec![Value::Number(3.0)]).unwrap(),
+            Value::Number(5.0)
+        );
+        let err = interpreter.call("kama", vec![]).unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::WrongArity {
+                expected: 1,
+                got: 0,
+                ..
             }
-            (BinOp::Le, Value::Number(a), Value::Number(b)) => {
-                Ok(if a <= b { Value::Bool } else { Value::Ala })
+        ));
+        let err = interpreter
+            .call("kama", vec![Value::String("wan".to_string())])
+            .unwrap_err();
+        assert!(matches!(err, RuntimeError::ParamTypeMismatch { .. }));
+        let err = interpreter.call("ale", vec![]).unwrap_err();
+        assert!(matches!(err, RuntimeError::UndefinedFunction(name) if name == "ale"));
+    }
+
+    #[test]
+    fn test_substring_search_builtins() {
+        let source = "s jo \"ōma tomo\"\na jo sitelen_lon(s, \"tomo\")\nb jo sitelen_lon(s, \"soweli\")\ni jo sitelen_alasa(s, \"tomo\")\nc jo sitelen_ken(s, i, i + 4)\nd jo sitelen_alasa(s, \"x\")\ne jo sitelen_open(s, \"ōma\")\nf jo sitelen_pini(s, \"ma\")";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Bool);
+        assert_eq!(global(&interpreter, "b"), Value::Ala);
+        assert_eq!(global(&interpreter, "i"), Value::Number(4.0));
+        assert_eq!(global(&interpreter, "c"), Value::String("tomo".to_string()));
+        assert_eq!(global(&interpreter, "d"), Value::Ala);
+        assert_eq!(global(&interpreter, "e"), Value::Bool);
+        assert_eq!(global(&interpreter, "f"), Value::Ala);
+    }
+
+    #[test]
+    fn test_sitelen_ante_replaces() {
+        let source = "a jo sitelen_ante(\"NIMI li NIMI\", \"NIMI\", \"jan\")\nb jo sitelen_ante_wan(\"a-b-c\", \"-\", \"+\")";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(
+            global(&interpreter, "a"),
+            Value::String("jan li jan".to_string())
+        );
+        assert_eq!(
+            global(&interpreter, "b"),
+            Value::String("a+b-c".to_string())
+        );
+        let err = Interpreter::new()
+            .run(&parse("sitelen_ante(\"toki\", \"\", \"x\")").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError {
+                arg: Some(ArgPosition { position: 2, .. }),
+                ..
             }
-            (BinOp::Eq, a, b) => Ok(if a == b { Value::Bool } else { Value::Ala }),
+        ));
+    }
 
-            // Type errors
-            _ => Err(RuntimeError::TypeError {
-                expected: "compatible types",
-                got: format!("{} and {}", left_val.type_name(), right_val.type_name()),
-            }),
+    #[test]
+    fn test_character_and_codepoint_builtins() {
+        let source = "a jo sitelen_wan_ken(\"pōka\", 1)\nb jo sitelen_wan_ken(\"pōka\", 4)\nc jo sitelen_nanpa(\"ō\")\nd jo sitelen_tan_nanpa(sitelen_nanpa(\"a\") + 1)";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::String("ō".to_string()));
+        assert_eq!(global(&interpreter, "b"), Value::Ala);
+        assert_eq!(global(&interpreter, "c"), Value::Number(333.0));
+        assert_eq!(global(&interpreter, "d"), Value::String("b".to_string()));
+        for bad in ["sitelen_nanpa(\"ab\")", "sitelen_tan_nanpa(55296)"] {
+            let err = Interpreter::new().run(&parse(bad).unwrap()).unwrap_err();
+            assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
         }
     }
 
-    fn call_function(&mut self, name: &str, args: &[Expr]) -> Result<Value, RuntimeError> {
-        // Check call depth limit
-        self.call_depth += 1;
-        if self.call_depth > MAX_CALL_DEPTH {
-            self.call_depth -= 1;
-            return Err(RuntimeError::StackOverflow);
+    #[test]
+    fn test_host_panic_becomes_internal_error() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_module_loader(|_| panic!("loader failed"));
+        let program = parse("x jo 1\nkepeken ilo_pakala\n").unwrap();
+        let err = interpreter.run(&program).unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::Internal(message) if message == "loader failed"
+        ));
+        assert!(!err.is_catchable());
+
+        // The interpreter is still usable afterwards.
+        let program = parse("y jo x + 1").unwrap();
+        interpreter.run(&program).unwrap();
+        assert_eq!(global(&interpreter, "y"), Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_math_builtins() {
+        let source = "a jo nanpa_anpa(2.5)\nb jo nanpa_sewi(2.1)\nc jo nanpa_sike(2.5)\nd jo nanpa_insa(0 - 3)\nf jo nanpa_noka(16)\ng jo nanpa_mute(2, 10)\nh jo nanpa_mod(0 - 1, 5)";
+        let interpreter = run_with(source, Options::default());
+        for (name, expected) in [
+            ("a", 2.0),
+            ("b", 3.0),
+            ("c", 3.0),
+            ("d", 3.0),
+            ("f", 4.0),
+            ("g", 1024.0),
+            ("h", 4.0),
+        ] {
+            assert_eq!(
+                global(&interpreter, name),
+                Value::Number(expected),
+                "{name}"
+            );
         }
+        let err = Interpreter::new()
+            .run(&parse("nanpa_noka(0 - 1)").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
+        for bad in ["nanpa_mute(0, 0 - 1)", "nanpa_mute(10, 400)"] {
+            let err = Interpreter::new().run(&parse(bad).unwrap()).unwrap_err();
+            assert!(
+                matches!(unlocated(&err), RuntimeError::NonFinite { span: None, .. }),
+                "{bad}"
+            );
+        }
+        let err = Interpreter::new()
+            .run(&parse("nanpa_mod(1, 0)").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::DivisionByZero));
+    }
 
-        let result = self.call_function_inner(name, args);
-        self.call_depth -= 1;
-        result
+    #[test]
+    fn test_run_capturing_reports_output_and_usage() {
+        let source = "ilo f(n) open\n  pana n\npini\nx jo 0\nwile x lili 3 la open\n  toki(f(x))\n  x jo x + 1\npini\npakala_sin(\"pini\")";
+        let mut interpreter = Interpreter::new();
+        let report = interpreter.run_capturing(&parse(source).unwrap());
+        assert_eq!(report.stdout, "0\n1\n2\n");
+        assert!(matches!(
+            report.value.as_ref().map_err(unlocated),
+            Err(RuntimeError::UserError(_))
+        ));
+        assert_eq!(
+            report.usage,
+            Usage {
+                output_bytes: 6,
+                deepest_call: 2,
+                loop_iterations: 3,
+            }
+        );
     }
 
-    fn call_function_inner(&mut self, name: &str, args: &[Expr]) -> Result<Value, RuntimeError> {
-        // Check stdlib first
-        if self.stdlib.has_function(name) {
-            let evaluated_args = self.eval_args(args)?;
-            return self.stdlib.call(name, evaluated_args);
+    #[test]
+    fn test_cancel_handle_stops_running_script() {
+        let mut interpreter = limited(Limits {
+            max_loop_iterations: u64::MAX,
+            ..Limits::default()
+        });
+        let handle = interpreter.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.cancel();
+        });
+        let program =
+            parse("ilo f() open\n  pakala_sin(\"x\")\npini\nwile lon la open\n  ken_pali(f)\npini")
+                .unwrap();
+        let err = interpreter.run(&program).unwrap_err();
+        canceller.join().unwrap();
+        assert!(matches!(err.innermost(), RuntimeError::Cancelled));
+
+        // The request is used up: the interpreter runs normally again.
+        interpreter.run(&parse("x jo 1").unwrap()).unwrap();
+        assert_eq!(global(&interpreter, "x"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn test_mod_and_pow_operators() {
+        let source = "a jo 2 ** 3 ** 2\nb jo (0 - 7) % 3\nc jo 2 ** 0.5 * 2 ** 0.5\nd jo nanpa_kipisi(\"0.5\") ** 2 % 1";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Number(512.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(2.0));
+        assert!(matches!(global(&interpreter, "c"), Value::Number(n) if (n - 2.0).abs() < 1e-9));
+        assert_eq!(
+            global(&interpreter, "d"),
+            Value::Decimal(Rational::new(1, 4).unwrap())
+        );
+        for bad in [
+            "x jo 5 % 0",
+            "x jo 0 ** (0 - 1)",
+            "x jo nanpa_kipisi(\"0\") % 0",
+        ] {
+            let err = Interpreter::new().run(&parse(bad).unwrap()).unwrap_err();
+            assert!(
+                matches!(unlocated(&err), RuntimeError::DivisionByZero),
+                "{bad}"
+            );
         }
+    }
 
-        // Check user-defined functions
-        let func = self
-            .env
-            .get(name)
-            .cloned()
-            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?;
+    #[test]
+    fn test_fork_isolates_state() {
+        let mut parent = Interpreter::new();
+        let setup = "ale jo 0\nilo kama() open\n  ma ale\n  ale jo ale + 1\n  pana ale\npini";
+        parent.run(&parse(setup).unwrap()).unwrap();
 
-        match func {
-            Value::Function {
-                params,
-                param_types,
-                return_type,
-                body,
-                captured,
-            } => {
-                if params.len() != args.len() {
-                    return Err(RuntimeError::WrongArity {
-                        name: name.to_string(),
-                        expected: params.len(),
-                        got: args.len(),
-                    });
-                }
+        let mut child = parent.fork();
+        child
+            .run(&parse("kama()\nkama()\nx jo 1").unwrap())
+            .unwrap();
+        assert_eq!(global(&child, "ale"), Value::Number(2.0));
 
-                // Evaluate arguments in current environment
-                let evaluated_args = self.eval_args(args)?;
+        // The child's calls changed its own copy of the globals only.
+        assert_eq!(global(&parent, "ale"), Value::Number(0.0));
+        assert_eq!(parent.get_global("x"), None);
+        assert_eq!(parent.call("kama", Vec::new()).unwrap(), Value::Number(1.0));
+    }
 
-                // Check parameter type annotations (skip when annotation is None)
-                for ((param, ty), value) in params
-                    .iter()
-                    .zip(param_types.iter())
-                    .zip(evaluated_args.iter())
-                {
-                    if let Some(expected) = ty {
-                        if !value.matches_type(expected) {
-                            return Err(RuntimeError::ParamTypeMismatch {
-                                func: name.to_string(),
-                                param: param.clone(),
-                                expected: expected.to_string(),
-                                got: value.type_name().to_string(),
-                            });
-                        }
-                    }
-                }
+    #[test]
+    fn test_seeded_random_repeats() {
+        let source = "a jo nanpa_nasa()\nb jo nanpa_nasa_insa(1, 6)\nc jo nanpa_nasa_insa(3, 3)";
+        let options = || Options {
+            seed: Some(42),
+            ..Options::default()
+        };
+        let first = run_with(source, options());
+        let second = run_with(source, options());
+        for name in ["a", "b", "c"] {
+            assert_eq!(global(&first, name), global(&second, name), "{name}");
+        }
+        assert!(matches!(global(&first, "a"), Value::Number(x) if (0.0..1.0).contains(&x)));
+        assert!(matches!(
+            global(&first, "b"),
+            Value::Number(x) if (1.0..=6.0).contains(&x) && x.fract() == 0.0
+        ));
+        assert_eq!(global(&first, "c"), Value::Number(3.0));
 
-                // Swap in the function's captured environment (lexical
-                // scoping). The captured snapshot's global scope (index 0)
-                // is refreshed from the caller's current globals so that
-                // top-level definitions and mutations made after the
-                // function was created — including the function itself for
-                // recursion — are still visible inside the call.
-                let mut call_scopes = captured;
-                if !call_scopes.is_empty() {
-                    call_scopes[0] = self.env.global_scope().clone();
-                }
-                let saved_scopes = self.env.replace_scopes(call_scopes);
+        let err = Interpreter::new()
+            .run(&parse("nanpa_nasa_insa(6, 1)").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
+    }
 
-                self.env.push_scope();
-                for (param, value) in params.iter().zip(evaluated_args) {
-                    self.env.define(param.clone(), value);
-                }
+    #[test]
+    fn test_lipu_kulupu_directory_builtins() {
+        let dir = std::env::temp_dir().join(format!("lipona_kulupu_{}", std::process::id()));
+        let dir = dir.display().to_string();
+        let source = format!(
+            "lipu_kulupu_sin(\"{dir}/insa\")\nlipu_sitelen(\"{dir}/b.txt\", \"\")\na jo lipu_kulupu(\"{dir}\")\nlipu_kulupu_weka(\"{dir}/insa\")\nb jo lipu_kulupu(\"{dir}\")"
+        );
+        let interpreter = run_with(&source, Options::default());
+        let _ = std::fs::remove_dir_all(&dir);
+        let names = |items: &[&str]| {
+            Value::List(items.iter().map(|s| Value::String(s.to_string())).collect())
+        };
+        assert_eq!(global(&interpreter, "a"), names(&["b.txt", "insa"]));
+        assert_eq!(global(&interpreter, "b"), names(&["b.txt"]));
+    }
 
-                // Execute function body
-                let result = self.exec_block_in_current_scope(&body);
+    #[test]
+    fn test_kute_reads_input() {
+        let program = parse("a jo kute()\nb jo kute()\nc jo kute_ale()\nd jo kute()").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_input(io::Cursor::new("wan\ntu\r\nmute\nluka"));
+        interpreter.run(&program).unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+        assert_eq!(global(&interpreter, "a"), text("wan"));
+        assert_eq!(global(&interpreter, "b"), text("tu"));
+        assert_eq!(global(&interpreter, "c"), text("mute\nluka"));
+        assert_eq!(global(&interpreter, "d"), Value::Ala);
 
-                // Restore the caller's scope stack.
-                self.env.replace_scopes(saved_scopes);
-
-                // Convert result
-                let value = result.map(|cf| match cf {
-                    ControlFlow::Return(v) => v,
-                    ControlFlow::None => Value::Ala,
-                })?;
-
-                // Check return type annotation
-                if let Some(expected) = &return_type {
-                    if !value.matches_type(expected) {
-                        return Err(RuntimeError::ReturnTypeMismatch {
-                            func: name.to_string(),
-                            expected: expected.to_string(),
-                            got: value.type_name().to_string(),
-                        });
-                    }
-                }
+        // Without input both read nothing.
+        let interpreter = run_with("a jo kute()\nb jo kute_ale()", Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Ala);
+        assert_eq!(global(&interpreter, "b"), text(""));
+    }
+
+    #[test]
+    fn test_error_codes_and_builtins_used() {
+        let mut interpreter = Interpreter::new();
+        let program = parse("toki(sitelen_suli(\"a\"))\nx jo kulupu_sin()\ntoki(1 / 0)").unwrap();
+        let err = interpreter.run(&program).unwrap_err();
+        assert_eq!(err.code(), "division_by_zero");
+        assert_eq!(
+            interpreter.builtins_used(),
+            vec!["kulupu_sin", "sitelen_suli", "toki"]
+        );
+    }
+
+    #[test]
+    fn test_poki_ma_environment_variables() {
+        let name = format!("LIPONA_TEST_{}", std::process::id());
+        let source = format!(
+            "a jo poki_ma_ken(\"{name}\")\npoki_ma_lon(\"{name}\", \"pona\")\nb jo poki_ma_ken(\"{name}\")"
+        );
+        let interpreter = run_with(&source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Ala);
+        assert_eq!(global(&interpreter, "b"), Value::String("pona".into()));
+        // Only this interpreter sees the variable.
+        assert_eq!(interpreter.env_var(&name).as_deref(), Some("pona"));
+        assert!(std::env::var(&name).is_err());
+        let program = parse(&format!("c jo poki_ma_ken(\"{name}\")")).unwrap();
+        let mut other = Interpreter::new();
+        other.run(&program).unwrap();
+        assert_eq!(global(&other, "c"), Value::Ala);
 
-                Ok(value)
+        // Variables not set by the script come from the process.
+        let program = parse("d jo poki_ma_ken(\"PATH\")").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.run(&program).unwrap();
+        assert_eq!(
+            global(&interpreter, "d"),
+            std::env::var("PATH").map_or(Value::Ala, Value::String)
+        );
+
+        let program = parse("poki_ma_lon(\"a=b\", \"x\")").unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError {
+                expected: "environment variable name",
+                ..
             }
-            _ => Err(RuntimeError::TypeError {
-                expected: "ilo",
-                got: func.type_name().to_string(),
+        ));
+    }
+
+    #[test]
+    fn test_ilo_ma_runs_only_when_allowed() {
+        let source = "r jo ilo_ma(\"sh\", kulupu_sin(\"-c\", \"echo pona; echo ike >&2; exit 3\"))\na jo nasin_ken(r, \"stdout\")\nb jo nasin_ken(r, \"stderr\")\nc jo nasin_ken(r, \"exit\")";
+        let program = parse(source).unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::ExecDenied(cmd) if cmd == "sh"));
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_allow_exec(true);
+        interpreter.run(&program).unwrap();
+        assert_eq!(global(&interpreter, "a"), Value::String("pona\n".into()));
+        assert_eq!(global(&interpreter, "b"), Value::String("ike\n".into()));
+        assert_eq!(global(&interpreter, "c"), Value::Number(3.0));
+
+        // Programs see the variables poki_ma_lon set.
+        let program = parse("poki_ma_lon(\"LIPONA_ILO_MA\", \"pona\")\nr jo ilo_ma(\"sh\", kulupu_sin(\"-c\", \"echo $LIPONA_ILO_MA\"))\ne jo nasin_ken(r, \"stdout\")").unwrap();
+        interpreter.run(&program).unwrap();
+        assert_eq!(global(&interpreter, "e"), Value::String("pona\n".into()));
+
+        let program = parse("ilo_ma(\"lipona-no-such-program\")").unwrap();
+        let err = interpreter.run(&program).unwrap_err();
+        assert_eq!(err.code(), "exec");
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_linja_ma_pana_only_when_allowed() {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/sona", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = Vec::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(n) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = n.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 404 Not Found\r\nX-Nimi: a\r\nX-Nimi: b\r\nContent-Length: 3\r\nConnection: close\r\n\r\nala")
+                .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+
+        let source = format!(
+            "r jo linja_ma_pana({url:?}, \"toki\", nasin_lon(nasin_sin(), \"X-Jan\", \"sona\"))\n\
+             status jo nasin_ken(r, \"status\")\n\
+             body jo nasin_ken(r, \"body\")\n\
+             nimi jo nasin_ken(nasin_ken(r, \"headers\"), \"x-nimi\")"
+        );
+        let program = parse(&source).unwrap();
+        let err = Interpreter::new().run(&program).unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::NetDenied(u) if *u == url));
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_allow_net(true);
+        interpreter.run(&program).unwrap();
+        assert_eq!(global(&interpreter, "status"), Value::Number(404.0));
+        assert_eq!(global(&interpreter, "body"), Value::String("ala".into()));
+        assert_eq!(global(&interpreter, "nimi"), Value::String("a, b".into()));
+        let (head, body) = server.join().unwrap();
+        assert_eq!(head[0], "POST /sona HTTP/1.1\r\n");
+        assert!(head.iter().any(|line| line == "X-Jan: sona\r\n"));
+        assert_eq!(body, "toki");
+    }
+
+    #[test]
+    fn test_engine_host_functions_and_eval_expr() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn(
+            "tu",
+            Rc::new(|args: Vec<Value>| match args.as_slice() {
+                [Value::Number(n)] => Ok(Value::Number(n * 2.0)),
+                _ => Err(RuntimeError::Argument("tu takes a nanpa".into())),
             }),
+        );
+        // A host function replaces a builtin, but not the program's own.
+        interpreter.register_fn("sitelen_suli", Rc::new(|_| Ok(Value::Ala)));
+        interpreter
+            .run(
+                &parse("a jo tu(4)\nb jo sitelen_suli(\"x\")\nilo tu (x) open pana x pini")
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(global(&interpreter, "a"), Value::Number(8.0));
+        assert_eq!(global(&interpreter, "b"), Value::Ala);
+
+        let mut fork = interpreter.fork();
+        let program = parse("tu(5) + a").unwrap();
+        let StmtKind::Expr(expr) = &program[0].kind else {
+            panic!("not an expression");
+        };
+        assert_eq!(fork.eval_expr(expr).unwrap(), Value::Number(13.0));
+        fork.register_fn("ante", Rc::new(|_| Ok(Value::Bool)));
+        assert!(interpreter.run(&parse("ante()").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_tenpo_builtins() {
+        let interpreter = run_with(
+            "a jo tenpo_sitelen(\"%Y-%m-%d %H:%M:%S %%\", 951827696)\nb jo tenpo_sitelen(\"%Y %H\", 0 - 1)\nc jo tenpo_kipisi(951782400)\nd jo tenpo_ni()",
+            Options::default(),
+        );
+        assert_eq!(
+            global(&interpreter, "a"),
+            Value::String("2000-02-29 12:34:56 %".into())
+        );
+        assert_eq!(global(&interpreter, "b"), Value::String("1969 23".into()));
+        let Value::Map(c) = global(&interpreter, "c") else {
+            panic!("not a nasin");
+        };
+        assert_eq!(c[&Value::String("month".into())], Value::Number(2.0));
+        assert_eq!(c[&Value::String("day".into())], Value::Number(29.0));
+        assert_eq!(c[&Value::String("weekday".into())], Value::Number(2.0));
+        assert!(matches!(global(&interpreter, "d"), Value::Number(n) if n > 1.7e9));
+
+        let err = Interpreter::new()
+            .run(&parse("tenpo_sitelen(\"%q\")").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::TypeError { .. }));
+    }
+
+    #[test]
+    fn test_tenpo_awen_wakes_on_cancel() {
+        run_with("tenpo_awen(0.01)", Options::default());
+
+        // The second is too long for the clock: it sleeps until cancelled.
+        for source in ["tenpo_awen(60)", "tenpo_awen(10000000000000000000)"] {
+            let mut interpreter = Interpreter::new();
+            let handle = interpreter.cancel_handle();
+            let canceller = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                handle.cancel();
+            });
+            let started = Instant::now();
+            let err = interpreter.run(&parse(source).unwrap()).unwrap_err();
+            canceller.join().unwrap();
+            assert!(
+                matches!(err.innermost(), RuntimeError::Cancelled),
+                "{source}"
+            );
+            assert!(started.elapsed() < Duration::from_secs(5));
         }
     }
 
-    fn eval_args(&mut self, args: &[Expr]) -> Result<Vec<Value>, RuntimeError> {
-        args.iter().map(|arg| self.eval_expr(arg)).collect()
+    #[test]
+    fn test_csv_open_and_pini_round_trip() {
+        let text = "nimi,nanpa\r\n\"jan, Sonja\",\"1 \"\"wan\"\"\"\n\n\"a\nb\",2\n";
+        let source = format!("rows jo csv_open({text:?})\nback jo csv_pini(rows)\nmixed jo csv_pini(kulupu_sin(kulupu_sin(1, ala, \"x\"), kulupu_sin(\"\")))");
+        let interpreter = run_with(&source, Options::default());
+        let row = |fields: &[&str]| {
+            Value::List(
+                fields
+                    .iter()
+                    .map(|f| Value::String(f.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            global(&interpreter, "rows"),
+            Value::List(vec![
+                row(&["nimi", "nanpa"]),
+                row(&["jan, Sonja", "1 \"wan\""]),
+                row(&[]),
+                row(&["a\nb", "2"]),
+            ])
+        );
+        assert_eq!(
+            global(&interpreter, "back"),
+            Value::String("nimi,nanpa\n\"jan, Sonja\",\"1 \"\"wan\"\"\"\n\n\"a\nb\",2\n".into())
+        );
+        assert_eq!(
+            global(&interpreter, "mixed"),
+            Value::String("1,,x\n\"\"\n".into())
+        );
+
+        let err = Interpreter::new()
+            .run(&parse("csv_open(\"a,\\\"b\")").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::Argument(_)));
     }
-}
 
-impl Default for Interpreter {
-    fn default() -> Self {
-        Self::new()
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_builtins() {
+        let source = r#"a jo regex_lon("jan 12", "[0-9]+")
+b jo regex_alasa("jan Sonja li 30", "([a-z]+) ([A-Z][a-z]+)|(x)")
+c jo regex_alasa("pona", "[0-9]")
+d jo regex_ante("2024-05-06", "(\\d+)-(\\d+)-(\\d+)", "$3/$2/$1")
+e jo ala regex_lon("pona", "^ike")"#;
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Bool);
+        assert_eq!(
+            global(&interpreter, "b"),
+            Value::List(vec![
+                Value::String("jan Sonja".into()),
+                Value::String("jan".into()),
+                Value::String("Sonja".into()),
+                Value::Ala,
+            ])
+        );
+        assert_eq!(global(&interpreter, "c"), Value::Ala);
+        assert_eq!(
+            global(&interpreter, "d"),
+            Value::String("06/05/2024".into())
+        );
+        assert_eq!(global(&interpreter, "e"), Value::Bool);
+
+        let err = Interpreter::new()
+            .run(&parse("regex_lon(\"a\", \"(\")").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::Argument(_)));
+    }
+
+    #[test]
+    fn test_kulupu_kipisi_lukin_poka_and_kulupu_e() {
+        let source = "a jo kulupu_sin(1, 2, 3, 4, 5)\n\
+                      k jo kulupu_kipisi(a, 2)\n\
+                      w jo kulupu_lukin_poka(a, 4)\n\
+                      none jo kulupu_lukin_poka(a, 6)\n\
+                      g jo kulupu_kulupu_e(a, ilo (x) open pana x % 2 pini)\n\
+                      odd jo nasin_ken(g, 1)\n\
+                      even jo nasin_ken(g, 0)";
+        let interpreter = run_with(source, Options::default());
+        let list = |items: &[f64]| Value::List(items.iter().map(|&n| Value::Number(n)).collect());
+        assert_eq!(
+            global(&interpreter, "k"),
+            Value::List(vec![list(&[1.0, 2.0]), list(&[3.0, 4.0]), list(&[5.0])])
+        );
+        assert_eq!(
+            global(&interpreter, "w"),
+            Value::List(vec![
+                list(&[1.0, 2.0, 3.0, 4.0]),
+                list(&[2.0, 3.0, 4.0, 5.0])
+            ])
+        );
+        assert_eq!(global(&interpreter, "none"), Value::List(vec![]));
+        assert_eq!(global(&interpreter, "odd"), list(&[1.0, 3.0, 5.0]));
+        assert_eq!(global(&interpreter, "even"), list(&[2.0, 4.0]));
+
+        let err = Interpreter::new()
+            .run(&parse("kulupu_kipisi(kulupu_sin(1), 0)").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            unlocated(&err).to_string(),
+            "pakala: type error - expected positive integer, got 0 (argument 2 of kulupu_kipisi)"
+        );
+    }
+
+    #[test]
+    fn test_sitelen_kipisi_nasin_respects_quotes() {
+        let source =
+            "a jo sitelen_kipisi_nasin(\"  toki  \\\"jan pona\\\" 'a''b' \", \" \", lon)\n\
+                      b jo sitelen_kipisi_nasin(\"a, \\\"b,c\\\",,d,\", \", \", lon)\n\
+                      c jo sitelen_kipisi_nasin(\"x\\\"y z\", \" \")";
+        let interpreter = run_with(source, Options::default());
+        let list = |items: &[&str]| {
+            Value::List(
+                items
+                    .iter()
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            global(&interpreter, "a"),
+            list(&["toki", "jan pona", "a'b"])
+        );
+        assert_eq!(global(&interpreter, "b"), list(&["a", "b,c", "", "d", ""]));
+        assert_eq!(global(&interpreter, "c"), list(&["x\"y", "z"]));
+
+        let err = Interpreter::new()
+            .run(&parse("sitelen_kipisi_nasin(\"\\\"a b\", \" \", lon)").unwrap())
+            .unwrap_err();
+        assert!(matches!(unlocated(&err), RuntimeError::Argument(_)));
+    }
+
+    #[test]
+    fn test_kulupu_nanpa_sorts_stably_with_or_without_ilo() {
+        let source = "a jo kulupu_nanpa(kulupu_sin(\"b\", 3, \"a\", 1))\n\
+                      p jo kulupu_sin(kulupu_sin(2, \"x\"), kulupu_sin(1, \"y\"), kulupu_sin(2, \"z\"))\n\
+                      ilo mute_lili (l, r) open pana kulupu_ken(r, 0) - kulupu_ken(l, 0) pini\n\
+                      d jo kulupu_nanpa(p, mute_lili)\n\
+                      odd jo kulupu_nanpa(kulupu_sin(1, 2, 3), ilo (l, r) open pana -1 pini)";
+        let interpreter = run_with(source, Options::default());
+        let n = Value::Number;
+        let s = |text: &str| Value::String(text.into());
+        assert_eq!(
+            global(&interpreter, "a"),
+            Value::List(vec![n(1.0), n(3.0), s("a"), s("b")])
+        );
+        let pair = |k: f64, v: &str| Value::List(vec![n(k), s(v)]);
+        assert_eq!(
+            global(&interpreter, "d"),
+            Value::List(vec![pair(2.0, "x"), pair(2.0, "z"), pair(1.0, "y")])
+        );
+        let Value::List(odd) = global(&interpreter, "odd") else {
+            panic!("kulupu_nanpa returned no kulupu");
+        };
+        assert_eq!(odd.len(), 3);
+
+        let err = Interpreter::new()
+            .run(&parse("kulupu_nanpa(kulupu_sin(1, 2), ilo (l, r) open pana \"a\" pini)").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError { got, .. } if got == "sitelen"
+        ));
+    }
+
+    #[test]
+    fn test_kulupu_alasa_wawa_and_wan_taso() {
+        let source = "s jo kulupu_sin(1, 3, 3, 3, 8, \"a\", \"c\")\n\
+                      a jo kulupu_alasa_wawa(s, 3)\n\
+                      b jo kulupu_alasa_wawa(s, \"c\")\n\
+                      c jo kulupu_alasa_wawa(s, 4)\n\
+                      d jo kulupu_alasa_wawa(kulupu_sin(), 1)\n\
+                      ilo f () open pini\n\
+                      w jo kulupu_wan_taso(kulupu_sin(2, \"2\", 2, kulupu_sin(1), f, kulupu_sin(1), f))";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(6.0));
+        assert_eq!(global(&interpreter, "c"), Value::Ala);
+        assert_eq!(global(&interpreter, "d"), Value::Ala);
+        let Value::List(w) = global(&interpreter, "w") else {
+            panic!("kulupu_wan_taso returned no kulupu");
+        };
+        assert_eq!(
+            w[..3],
+            [
+                Value::Number(2.0),
+                Value::String("2".into()),
+                Value::List(vec![Value::Number(1.0)])
+            ]
+        );
+        assert_eq!(w.len(), 4);
+
+        let err = Interpreter::new()
+            .run(&parse("kulupu_alasa_wawa(kulupu_sin(lon, lon), 1)").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError { got, .. } if got == "lon"
+        ));
+    }
+
+    #[test]
+    fn test_ijo_nasin_sama_orders_numbers_before_strings() {
+        let source = "a jo ijo_nasin_sama(1, 2)\n\
+                      b jo ijo_nasin_sama(nanpa_kipisi(4, 2), 2)\n\
+                      c jo ijo_nasin_sama(\"b\", \"a\")\n\
+                      d jo ijo_nasin_sama(\"0\", 100)\n\
+                      e jo ijo_nasin_sama(-1, \"\")";
+        let interpreter = run_with(source, Options::default());
+        for (name, expected) in [("a", -1.0), ("b", 0.0), ("c", 1.0), ("d", 1.0), ("e", -1.0)] {
+            assert_eq!(
+                global(&interpreter, name),
+                Value::Number(expected),
+                "{name}"
+            );
+        }
+
+        let err = Interpreter::new()
+            .run(&parse("ijo_nasin_sama(1, lon)").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            unlocated(&err).to_string(),
+            "pakala: type error - expected nanpa or sitelen, got lon (argument 2 of ijo_nasin_sama)"
+        );
+    }
+
+    #[test]
+    fn test_nasin_weka_jo_wan() {
+        let source = "a jo nasin_lon(nasin_lon(nasin_sin(), \"x\", 1), \"y\", ala)\n\
+                      b jo nasin_lon(nasin_lon(nasin_sin(), \"y\", 2), \"z\", 3)\n\
+                      has_y jo nasin_jo(a, \"y\")\n\
+                      has_z jo nasin_jo(a, \"z\")\n\
+                      w jo nasin_wan(a, b)\n\
+                      gone jo nasin_weka(w, \"y\")\n\
+                      same jo nasin_weka(a, \"q\")";
+        let interpreter = run_with(source, Options::default());
+        let map = |entries: &[(&str, f64)]| {
+            Value::Map(
+                entries
+                    .iter()
+                    .map(|&(k, v)| (Value::String(k.to_string()), Value::Number(v)))
+                    .collect(),
+            )
+        };
+        assert_eq!(global(&interpreter, "has_y"), Value::Bool);
+        assert_eq!(global(&interpreter, "has_z"), Value::Ala);
+        assert_eq!(
+            global(&interpreter, "w"),
+            map(&[("x", 1.0), ("y", 2.0), ("z", 3.0)])
+        );
+        assert_eq!(global(&interpreter, "gone"), map(&[("x", 1.0), ("z", 3.0)]));
+        assert_eq!(global(&interpreter, "same"), global(&interpreter, "a"));
+    }
+
+    #[test]
+    fn test_nasin_nimi_ijo_tu_follow_key_order() {
+        let source = "m jo nasin_lon(nasin_lon(nasin_lon(nasin_sin(), \"b\", 1), \"a\", 2), 10, 3)\n\
+                      k jo nasin_nimi(m)\nv jo nasin_ijo(m)\nt jo nasin_tu(m)\ne jo nasin_tu(nasin_sin())";
+        let interpreter = run_with(source, Options::default());
+        let n = Value::Number;
+        let s = |text: &str| Value::String(text.into());
+        assert_eq!(
+            global(&interpreter, "k"),
+            Value::List(vec![n(10.0), s("a"), s("b")])
+        );
+        assert_eq!(
+            global(&interpreter, "v"),
+            Value::List(vec![n(3.0), n(2.0), n(1.0)])
+        );
+        assert_eq!(
+            global(&interpreter, "t"),
+            Value::List(vec![
+                Value::List(vec![n(10.0), n(3.0)]),
+                Value::List(vec![s("a"), n(2.0)]),
+                Value::List(vec![s("b"), n(1.0)]),
+            ])
+        );
+        assert_eq!(global(&interpreter, "e"), Value::List(vec![]));
+    }
+
+    #[test]
+    fn test_nasin_keys_can_be_kulupu_and_nasin() {
+        let source = "m jo nasin_lon(nasin_sin(), kulupu_sin(1, 2), \"a\")\n\
+                      m jo nasin_lon(m, kulupu_sin(1, 2.0), \"b\")\n\
+                      m jo nasin_lon(m, nasin_lon(nasin_sin(), \"x\", 1), \"c\")\n\
+                      m jo nasin_lon(m, 3, \"d\")\n\
+                      pair jo nasin_ken(m, kulupu_sin(1, 2))\n\
+                      inner jo nasin_ken(m, nasin_lon(nasin_sin(), \"x\", 1))\n\
+                      keys jo \"\"\n\
+                      tawa k, v lon m la open\n    keys jo keys + v\npini";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "pair"), Value::String("b".into()));
+        assert_eq!(global(&interpreter, "inner"), Value::String("c".into()));
+        assert_eq!(global(&interpreter, "keys"), Value::String("dbc".into()));
+
+        let err = Interpreter::new()
+            .run(&parse("ilo f () open pini\nnasin_lon(nasin_sin(), kulupu_sin(f), 1)").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::TypeError { got, .. } if got == "kulupu"
+        ));
+    }
+
+    #[test]
+    fn test_strict_math_refuses_results_that_may_have_rounded() {
+        let strict = Options {
+            strict_math: true,
+            ..Options::default()
+        };
+        let interpreter = run_with(
+            "a jo 9007199254740991 + 0\nb jo 0 - 9007199254740990 - 1\nc jo 0.5 * 3",
+            strict.clone(),
+        );
+        assert_eq!(
+            global(&interpreter, "a"),
+            Value::Number(9_007_199_254_740_991.0)
+        );
+        assert_eq!(
+            global(&interpreter, "b"),
+            Value::Number(-9_007_199_254_740_991.0)
+        );
+        assert_eq!(global(&interpreter, "c"), Value::Number(1.5));
+
+        for (source, op, col) in [
+            ("x jo 9007199254740992 + 1", BinOp::Add, 23),
+            ("x jo 0 - 9007199254740991 - 1", BinOp::Sub, 27),
+            ("x jo 1 + 9007199254740992 * 2", BinOp::Mul, 27),
+            ("x jo 2 ** 53", BinOp::Pow, 8),
+        ] {
+            let err = Interpreter::with_options(strict.clone())
+                .run(&parse(source).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::PrecisionLoss { op: o, span } if *o == op && *span == Span { line: 1, col }
+                ),
+                "{source}: {err}"
+            );
+            assert_eq!(err.code(), "precision_loss");
+        }
+
+        // Without --strict-math the result is rounded.
+        let interpreter = run_with("x jo 9007199254740992 + 1", Options::default());
+        assert_eq!(
+            global(&interpreter, "x"),
+            Value::Number(9_007_199_254_740_992.0)
+        );
+    }
+
+    #[test]
+    fn test_non_finite_results_are_errors() {
+        let strict = Options {
+            strict_math: true,
+            ..Options::default()
+        };
+        for (source, options, col) in [
+            // Overflow to infinity.
+            ("x jo 10 ** 400", Options::default(), 9),
+            // A negative base with a fractional exponent is NaN.
+            ("x jo (0 - 1) ** 0.5", Options::default(), 14),
+            // --strict-math skips non-integer operands, so the result
+            // still reaches the finiteness check.
+            ("x jo 10.5 ** 400", strict.clone(), 11),
+            ("x jo (0 - 1) ** 0.5", strict.clone(), 14),
+        ] {
+            let err = Interpreter::with_options(options)
+                .run(&parse(source).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::NonFinite { op, nan, span: Some(span) }
+                        if op == "**" && *nan == source.ends_with("** 0.5") && *span == Span { line: 1, col }
+                ),
+                "{source}: {err}"
+            );
+            assert_eq!(err.code(), "non_finite");
+        }
+
+        // With integer operands --strict-math reports the precision loss
+        // first.
+        let err = Interpreter::with_options(strict)
+            .run(&parse("x jo 10 ** 400").unwrap())
+            .unwrap_err();
+        assert_eq!(err.code(), "precision_loss");
+
+        // The error is catchable like any other runtime error.
+        let interpreter = run_with(
+            "r jo ken_pali(ilo () open\n    pana 10 ** 400\npini)",
+            Options::default(),
+        );
+        match global(&interpreter, "r") {
+            Value::Map(r) => assert_eq!(r.get(&Value::String("pona".into())), Some(&Value::Ala)),
+            other => panic!("expected a nasin, got {other}"),
+        }
+
+        // The message tells NaN from overflow, and `nanpa_mute` raises
+        // the same error, located at the call.
+        for (source, message) in [
+            (
+                "x jo (0 - 8) ** (1 / 3)",
+                "result of '**' is not a number (undefined) at line 1, column 14",
+            ),
+            (
+                "x jo 10 ** 400",
+                "result of '**' is infinite (overflow) at line 1, column 9",
+            ),
+            (
+                "x jo nanpa_mute(0 - 1, 0.5)",
+                "result of 'nanpa_mute' is not a number (undefined) at line 1, column 6",
+            ),
+            (
+                "x jo nanpa_mute(10, 400)",
+                "result of 'nanpa_mute' is infinite (overflow) at line 1, column 6",
+            ),
+        ] {
+            let err = Interpreter::new().run(&parse(source).unwrap()).unwrap_err();
+            assert_eq!(err.code(), "non_finite", "{source}");
+            assert_eq!(err.to_string(), format!("pakala: {message}"));
+        }
+    }
+
+    #[test]
+    fn test_counting_loop_respects_iteration_limit() {
+        let limits = Limits {
+            max_loop_iterations: 5,
+            ..Limits::default()
+        };
+        let program = parse("n jo 0\ntawa i tan 0 tawa 5 la open\n    n jo n + 1\npini").unwrap();
+        let mut interpreter = limited(limits);
+        interpreter.run(&program).unwrap();
+        assert_eq!(global(&interpreter, "n"), Value::Number(5.0));
+
+        let program = parse("tawa i tan 0 tawa 6 la open\npini").unwrap();
+        let err = limited(limits).run(&program).unwrap_err();
+        assert!(
+            matches!(unlocated(&err), RuntimeError::InfiniteLoop),
+            "{err}"
+        );
+
+        // Past 2^53 `i + 1` rounds back to `i`; the loop must still end.
+        let interpreter = run_with(
+            "n jo 0\ntawa i tan 10000000000000000 tawa 10000000000000002 la open\n    n jo n + 1\npini",
+            Options::default(),
+        );
+        assert_eq!(global(&interpreter, "n"), Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_kulupu_leko_builtins() {
+        fn grid(rows: &[&[Value]]) -> Value {
+            Value::List(rows.iter().map(|row| Value::List(row.to_vec())).collect())
+        }
+        let n = Value::Number;
+        let source = r#"
+            a jo kulupu_leko_sin(2, 3)
+            b jo kulupu_leko_sin(1, 2, "x")
+            c jo kulupu_leko_sin(0, 4)
+            d jo kulupu_leko_sin(2, 0)
+            e jo kulupu_leko_sike(kulupu_sin(kulupu_sin(1, 2, 3), kulupu_sin(4, 5, 6)))
+            f jo kulupu_leko_sike(c)
+            g jo kulupu_leko_ante(e, ilo (v) open pana v * 10 pini)
+            h jo kulupu_leko_ante(a, ilo (v, i, j) open pana i * 3 + j pini)
+            k jo kulupu_leko_ante(d, ilo (v) open pana v pini)
+        "#;
+        let interpreter = run_with(source, Options::default());
+        let zero = || n(0.0);
+        assert_eq!(
+            global(&interpreter, "a"),
+            grid(&[&[zero(), zero(), zero()], &[zero(), zero(), zero()]])
+        );
+        let x = || Value::String("x".into());
+        assert_eq!(global(&interpreter, "b"), grid(&[&[x(), x()]]));
+        assert_eq!(global(&interpreter, "c"), Value::List(vec![]));
+        assert_eq!(global(&interpreter, "d"), grid(&[&[], &[]]));
+        assert_eq!(
+            global(&interpreter, "e"),
+            grid(&[&[n(1.0), n(4.0)], &[n(2.0), n(5.0)], &[n(3.0), n(6.0)]])
+        );
+        assert_eq!(global(&interpreter, "f"), Value::List(vec![]));
+        assert_eq!(
+            global(&interpreter, "g"),
+            grid(&[
+                &[n(10.0), n(40.0)],
+                &[n(20.0), n(50.0)],
+                &[n(30.0), n(60.0)]
+            ])
+        );
+        assert_eq!(
+            global(&interpreter, "h"),
+            grid(&[&[n(0.0), n(1.0), n(2.0)], &[n(3.0), n(4.0), n(5.0)]])
+        );
+        assert_eq!(global(&interpreter, "k"), grid(&[&[], &[]]));
+
+        for (function, call) in [
+            ("kulupu_leko_sike", "kulupu_leko_sike(r)"),
+            (
+                "kulupu_leko_ante",
+                "kulupu_leko_ante(r, ilo (v) open pana v pini)",
+            ),
+        ] {
+            let source = format!("r jo kulupu_sin(kulupu_sin(1, 2), kulupu_sin(3))\nx jo {call}");
+            let err = Interpreter::new()
+                .run(&parse(&source).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::TypeError { expected: "rows of equal length", arg: Some(arg), .. }
+                        if arg.function == function && arg.position == 1
+                ),
+                "{function}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_nanpa_sitelen_builtins() {
+        let source = r#"
+            a jo nanpa_sitelen(255)
+            b jo nanpa_sitelen(255, 16)
+            c jo nanpa_sitelen(0 - 10, 2)
+            d jo nanpa_sitelen(0, 2)
+            e jo nanpa_sitelen(1.5)
+            f jo nanpa_sitelen(35, 36)
+            g jo nanpa_sitelen_lili(3.14159, 2)
+            h jo nanpa_sitelen_lili(0 - 0.001, 2)
+            i jo nanpa_sitelen_lili(2, 0)
+            j jo nanpa_sitelen_lili(nanpa_kipisi(1, 3), 3)
+        "#;
+        let interpreter = run_with(source, Options::default());
+        for (name, expected) in [
+            ("a", "255"),
+            ("b", "ff"),
+            ("c", "-1010"),
+            ("d", "0"),
+            ("e", "1.5"),
+            ("f", "z"),
+            ("g", "3.14"),
+            ("h", "0.00"),
+            ("i", "2"),
+            ("j", "0.333"),
+        ] {
+            assert_eq!(
+                global(&interpreter, name),
+                Value::String(expected.into()),
+                "{name}"
+            );
+        }
+
+        for (call, function, position, expected) in [
+            ("nanpa_sitelen(0.5, 2)", "nanpa_sitelen", 1, "integer"),
+            (
+                "nanpa_sitelen(5, 1)",
+                "nanpa_sitelen",
+                2,
+                "radix between 2 and 36",
+            ),
+            (
+                "nanpa_sitelen(5, 37)",
+                "nanpa_sitelen",
+                2,
+                "radix between 2 and 36",
+            ),
+            (
+                "nanpa_sitelen_lili(1, 101)",
+                "nanpa_sitelen_lili",
+                2,
+                "at most 100 decimals",
+            ),
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(&format!("x jo {call}")).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::TypeError { expected: e, arg: Some(arg), .. }
+                        if *e == expected && arg.function == function && arg.position == position
+                ),
+                "{call}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_nasin_pona_sorting() {
+        let strings = |items: &[&str]| {
+            Value::List(items.iter().map(|s| Value::String(s.to_string())).collect())
+        };
+        let source = r#"
+            a jo sitelen_nasin_pona("a", "b")
+            b jo sitelen_nasin_pona("b", "a")
+            c jo sitelen_nasin_pona("a", "a")
+            d jo sitelen_nasin_pona("ä", "b")
+            e jo kulupu_nasin_pona(kulupu_sin(3, 1, 2))
+            f jo kulupu_nasin_pona(kulupu_sin("b", "ä", "a"))
+            g jo kulupu_nasin_pona(kulupu_sin())
+        "#;
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "a"), Value::Number(-1.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "c"), Value::Number(0.0));
+        // Without a locale, by code point: "ä" comes after "b".
+        assert_eq!(global(&interpreter, "d"), Value::Number(1.0));
+        assert_eq!(
+            global(&interpreter, "e"),
+            Value::List(vec![
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(3.0)
+            ])
+        );
+        assert_eq!(global(&interpreter, "f"), strings(&["a", "b", "ä"]));
+        assert_eq!(global(&interpreter, "g"), Value::List(vec![]));
+
+        for (call, position) in [
+            ("kulupu_nasin_pona(kulupu_sin(2, 1), 5)", 2),
+            ("kulupu_nasin_pona(kulupu_sin(2, \"a\"))", 1),
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(&format!("x jo {call}")).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::TypeError { arg: Some(arg), .. } if arg.position == position
+                ),
+                "{call}: {err}"
+            );
+        }
+    }
+
+    #[cfg(feature = "collation")]
+    #[test]
+    fn test_nasin_pona_sorting_with_locale() {
+        let source = r#"
+            a jo sitelen_nasin_pona("ä", "b", "de")
+            b jo kulupu_nasin_pona(kulupu_sin("b", "ä", "a"), "de")
+            c jo kulupu_nasin_pona(kulupu_sin("z", "ö"), "sv")
+            d jo kulupu_nasin_pona(kulupu_sin(2, 1), "de")
+        "#;
+        let interpreter = run_with(source, Options::default());
+        let strings = |items: &[&str]| {
+            Value::List(items.iter().map(|s| Value::String(s.to_string())).collect())
+        };
+        assert_eq!(global(&interpreter, "a"), Value::Number(-1.0));
+        assert_eq!(global(&interpreter, "b"), strings(&["a", "ä", "b"]));
+        assert_eq!(global(&interpreter, "c"), strings(&["z", "ö"]));
+        assert_eq!(
+            global(&interpreter, "d"),
+            Value::List(vec![Value::Number(1.0), Value::Number(2.0)])
+        );
+
+        // A bad locale is an error even when the list holds numbers.
+        for (call, position) in [
+            ("kulupu_nasin_pona(kulupu_sin(2, 1), \"!!\")", 2),
+            ("kulupu_nasin_pona(kulupu_sin(\"b\", \"a\"), \"!!\")", 2),
+            ("sitelen_nasin_pona(\"a\", \"b\", \"!!\")", 3),
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(&format!("x jo {call}")).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::TypeError { arg: Some(arg), .. } if arg.position == position
+                ),
+                "{call}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_nanpa_sin_parses_radix_and_separators() {
+        let source = r#"
+            a jo nanpa_sin("1010", 2)
+            b jo nanpa_sin("ff", 16)
+            c jo nanpa_sin("-FF", 16)
+            d jo nanpa_sin("1_000_000")
+            e jo nanpa_sin("  42\n")
+            f jo nanpa_sin(" 1_0 ", 2)
+            g jo nanpa_sin("2.5")
+            h jo nanpa_sin_ken("12", 2)
+            i jo nanpa_sin_ken("1__0")
+            j jo nanpa_sin_ken("_1")
+            k jo nanpa_sin_ken("inf")
+            l jo nanpa_sin_ken(lon)
+            m jo nanpa_sin_ken("7f", 16)
+            n jo nanpa_sin_ken(nanpa_kipisi("0.25"))
+            o jo nanpa_sin_ken("1._5")
+            p jo nanpa_sin_ken("1_.5")
+            q jo nanpa_sin("1_0.2_5")
+        "#;
+        let interpreter = run_with(source, Options::default());
+        for (name, expected) in [
+            ("a", Value::Number(10.0)),
+            ("b", Value::Number(255.0)),
+            ("c", Value::Number(-255.0)),
+            ("d", Value::Number(1_000_000.0)),
+            ("e", Value::Number(42.0)),
+            ("f", Value::Number(2.0)),
+            ("g", Value::Number(2.5)),
+            ("h", Value::Ala),
+            ("i", Value::Ala),
+            ("j", Value::Ala),
+            ("k", Value::Ala),
+            ("l", Value::Ala),
+            ("m", Value::Number(127.0)),
+            ("n", Value::Number(0.25)),
+            ("o", Value::Ala),
+            ("p", Value::Ala),
+            ("q", Value::Number(10.25)),
+        ] {
+            assert_eq!(global(&interpreter, name), expected, "{name}");
+        }
+
+        for (call, position, expected) in [
+            ("nanpa_sin(\"12\", 2)", 1, "valid number string"),
+            ("nanpa_sin(\"1_\")", 1, "valid number string"),
+            ("nanpa_sin(\"1._5\")", 1, "valid number string"),
+            ("nanpa_sin(\"10\", 1)", 2, "radix between 2 and 36"),
+            ("nanpa_sin(\"10\", 2.5)", 2, "radix between 2 and 36"),
+            ("nanpa_sin_ken(\"10\", 37)", 2, "radix between 2 and 36"),
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(&format!("x jo {call}")).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.innermost(),
+                    RuntimeError::TypeError { expected: e, arg: Some(arg), .. }
+                        if *e == expected && arg.position == position
+                ),
+                "{call}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_kulupu_nanpa_sona_statistics() {
+        let source = r#"
+            l jo kulupu_sin(9, 2, 4, 4, 5, 4, 7, 5)
+            meso jo kulupu_nanpa_sona_meso(l)
+            insa_tu jo kulupu_nanpa_sona_insa(l)
+            insa_wan jo kulupu_nanpa_sona_insa(kulupu_sin(3, 1, 2))
+            ante jo kulupu_nanpa_sona_ante(l)
+            ante_lili jo kulupu_nanpa_sona_ante(l, lon)
+            weka jo kulupu_nanpa_sona_weka(l)
+            weka_wan jo kulupu_nanpa_sona_weka(kulupu_sin(5))
+            lon_open jo kulupu_nanpa_sona_lon(l, 0)
+            lon_pini jo kulupu_nanpa_sona_lon(l, 100)
+            lon_insa jo kulupu_nanpa_sona_lon(l, 50)
+            lon_kipisi jo kulupu_nanpa_sona_lon(kulupu_sin(10, 20), 25)
+        "#;
+        let interpreter = run_with(source, Options::default());
+        for (name, expected) in [
+            ("meso", 5.0),
+            ("insa_tu", 4.5),
+            ("insa_wan", 2.0),
+            ("ante", 4.0),
+            ("ante_lili", 32.0 / 7.0),
+            ("weka", 2.0),
+            ("weka_wan", 0.0),
+            ("lon_open", 2.0),
+            ("lon_pini", 9.0),
+            ("lon_insa", 4.5),
+            ("lon_kipisi", 12.5),
+        ] {
+            assert_eq!(
+                global(&interpreter, name),
+                Value::Number(expected),
+                "{name}"
+            );
+        }
+
+        for (call, expected) in [
+            ("kulupu_nanpa_sona_meso(kulupu_sin())", "non-empty kulupu"),
+            ("kulupu_nanpa_sona_insa(kulupu_sin())", "non-empty kulupu"),
+            ("kulupu_nanpa_sona_ante(kulupu_sin())", "non-empty kulupu"),
+            ("kulupu_nanpa_sona_weka(kulupu_sin())", "non-empty kulupu"),
+            (
+                "kulupu_nanpa_sona_lon(kulupu_sin(), 50)",
+                "non-empty kulupu",
+            ),
+            (
+                "kulupu_nanpa_sona_ante(kulupu_sin(5), lon)",
+                "at least 2 values for sample variance",
+            ),
+            (
+                "kulupu_nanpa_sona_lon(kulupu_sin(1, 2), 101)",
+                "percentile between 0 and 100",
+            ),
+            (
+                "kulupu_nanpa_sona_meso(kulupu_sin(1, \"tu\"))",
+                "kulupu of nanpa",
+            ),
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(&format!("x jo {call}")).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(err.innermost(), RuntimeError::TypeError { expected: e, .. } if *e == expected),
+                "{call}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sitelen_grapheme_builtins() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let e_acute = "e\u{301}";
+        let source = format!(
+            "s jo \"{family}a{e_acute}\"\n\
+             a jo sitelen_len_ale(s)\n\
+             b jo sitelen_ken_ale(s, 0, 1)\n\
+             c jo sitelen_ken_ale(s, 1, 3)\n\
+             d jo sitelen_ken_ale(s, 2, 10)\n\
+             e jo sitelen_ken_ale(s, 3, 1)\n\
+             f jo sitelen_kipisi_ale(s)\n\
+             g jo sitelen_len_ale(\"\")\n\
+             h jo sitelen_kipisi_ale(\"\")\n\
+             i jo sitelen_ken_ale(s, 4, 5)\n\
+             j jo sitelen_ken_ale(s, 3, 5)"
+        );
+        let interpreter = run_with(&source, Options::default());
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(global(&interpreter, "a"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "b"), string(family));
+        assert_eq!(global(&interpreter, "c"), string(&format!("a{e_acute}")));
+        assert_eq!(global(&interpreter, "d"), string(e_acute));
+        assert_eq!(global(&interpreter, "e"), string(""));
+        assert_eq!(
+            global(&interpreter, "f"),
+            Value::List(vec![string(family), string("a"), string(e_acute)])
+        );
+        assert_eq!(global(&interpreter, "g"), Value::Number(0.0));
+        assert_eq!(global(&interpreter, "h"), Value::List(vec![]));
+        // A start past the end gives ala, as in sitelen_ken; the end itself
+        // gives "".
+        assert_eq!(global(&interpreter, "i"), Value::Ala);
+        assert_eq!(global(&interpreter, "j"), string(""));
+    }
+
+    #[test]
+    fn test_sitelen_normalization_builtins() {
+        let composed = "\u{e9}";
+        let decomposed = "e\u{301}";
+        let source = format!(
+            "a jo sitelen_sama_nasin(\"{decomposed}\")\n\
+             b jo sitelen_sama_nasin(\"{composed}\", \"NFD\")\n\
+             c jo sitelen_sama_nasin(\"\u{fb01}\", \"NFC\")\n\
+             d jo sitelen_sama_nasin(\"\u{fb01}{decomposed}\", \"NFKC\")\n\
+             e jo sitelen_sama_nasin(\"\u{2460}\", \"NFKD\")\n\
+             f jo sitelen_sama_lukin(\"{composed}\", \"{decomposed}\")\n\
+             g jo sitelen_sama_lukin(\"\u{fb01}\", \"fi\")\n\
+             h jo \"{composed}\" sama \"{decomposed}\""
+        );
+        let interpreter = run_with(&source, Options::default());
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(global(&interpreter, "a"), string(composed));
+        assert_eq!(global(&interpreter, "b"), string(decomposed));
+        // NFC leaves the compatibility ligature alone; NFKC splits it.
+        assert_eq!(global(&interpreter, "c"), string("\u{fb01}"));
+        assert_eq!(global(&interpreter, "d"), string(&format!("fi{composed}")));
+        assert_eq!(global(&interpreter, "e"), string("1"));
+        assert_eq!(global(&interpreter, "f"), Value::Bool);
+        // Only canonical equivalence counts, not compatibility.
+        assert_eq!(global(&interpreter, "g"), Value::Ala);
+        assert_eq!(global(&interpreter, "h"), Value::Ala);
+
+        let err = Interpreter::new()
+            .run(&parse("x jo sitelen_sama_nasin(\"a\", \"NFX\")").unwrap())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.innermost(),
+                RuntimeError::TypeError { arg: Some(arg), .. } if arg.position == 2
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_kulupu_ante_wile_wan() {
+        let source = r#"
+            l jo kulupu_sin(1, 2, 3, 4)
+            a jo kulupu_ante(l, ilo (v) open pana v * v pini)
+            b jo kulupu_ante(l, ilo (v, i) open pana v + i pini)
+            c jo kulupu_wile(l, ilo (v) open pana v % 2 sama 0 pini)
+            d jo kulupu_wile(l, ilo (v, i) open pana i lili 1 pini)
+            e jo kulupu_wan(l, 0, ilo (acc, v) open pana acc + v pini)
+            f jo kulupu_wan(l, "", ilo (acc, v) open pana acc + nanpa_sitelen(v) pini)
+            g jo kulupu_ante(kulupu_sin(), ilo (v) open pana v / 0 pini)
+            h jo kulupu_wile(kulupu_sin(), ilo (v) open pana v / 0 pini)
+            k jo kulupu_wan(kulupu_sin(), 7, ilo (acc, v) open pana v / 0 pini)
+        "#;
+        let interpreter = run_with(source, Options::default());
+        let numbers = |ns: &[f64]| Value::List(ns.iter().map(|n| Value::Number(*n)).collect());
+        assert_eq!(global(&interpreter, "a"), numbers(&[1.0, 4.0, 9.0, 16.0]));
+        assert_eq!(global(&interpreter, "b"), numbers(&[1.0, 3.0, 5.0, 7.0]));
+        assert_eq!(global(&interpreter, "c"), numbers(&[2.0, 4.0]));
+        assert_eq!(global(&interpreter, "d"), numbers(&[1.0]));
+        assert_eq!(global(&interpreter, "e"), Value::Number(10.0));
+        assert_eq!(global(&interpreter, "f"), Value::String("1234".into()));
+        assert_eq!(global(&interpreter, "g"), Value::List(vec![]));
+        assert_eq!(global(&interpreter, "h"), Value::List(vec![]));
+        assert_eq!(global(&interpreter, "k"), Value::Number(7.0));
+
+        // An error in the callback reaches the caller.
+        for call in [
+            "kulupu_ante(kulupu_sin(1, 0, 2), ilo (v) open pana 1 / v pini)",
+            "kulupu_wile(kulupu_sin(1, 0, 2), ilo (v) open pana 1 / v pini)",
+            "kulupu_wan(kulupu_sin(1, 0, 2), 0, ilo (acc, v) open pana 1 / v pini)",
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(&format!("x jo {call}")).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(err.innermost(), RuntimeError::DivisionByZero),
+                "{call}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_control_flow_leaves_expressions() {
+        let source = r#"
+            i jo 0
+            wile lon la open
+                i jo i + 1
+                x jo open
+                    i sama 3 la open
+                        pini_tawa
+                    pini
+                    i
+                pini
+            pini
+
+            ale jo 0
+            tawa j tan 0 tawa 5 la open
+                ale jo ale + 1 + open
+                    j sama 2 la open
+                        awen_sin
+                    pini
+                    j * 10
+                pini
+            pini
+
+            n jo 0
+            tawa ike: wile lon la open
+                n jo n + 1
+                x jo wile lon la open
+                    pini_tawa ike
+                pini
+                n jo 100
+            pini
+
+            ilo f () open
+                x jo wile lon la open
+                    pana 5
+                pini
+                pana 1
+            pini
+            ilo g () open
+                x jo wile ala la open
+                pini taso open
+                    pana 7
+                pini
+                pana 1
+            pini
+            ilo h () open
+                x jo open
+                    pana 2
+                pini
+                pana x + 1
+            pini
+            a jo f()
+            b jo g()
+            c jo h()
+        "#;
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "i"), Value::Number(3.0));
+        // j = 2 is skipped before its `+` happens.
+        assert_eq!(global(&interpreter, "ale"), Value::Number(84.0));
+        assert_eq!(global(&interpreter, "n"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "a"), Value::Number(5.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(7.0));
+        // In a plain `open ... pini`, `pana` only ends the block.
+        assert_eq!(global(&interpreter, "c"), Value::Number(3.0));
+
+        for source in [
+            "x jo open\n    pini_tawa\npini",
+            "x jo 1 + open\n    pini_tawa\npini",
+            "ilo f () open\n    pana open\n        pini_tawa\n    pini\npini\nwile lon la open\n    f()\npini",
+        ] {
+            let err = Interpreter::new()
+                .run(&parse(source).unwrap())
+                .unwrap_err();
+            assert!(
+                matches!(err.innermost(), RuntimeError::BreakOutsideLoop),
+                "{source}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_scopes_of_finished_calls_are_freed() {
+        // Each `g` captures the scope it is bound in: a call's own scope, a
+        // loop body's, or (for the one `pana_ilo` returns) a scope that
+        // must stay alive.
+        let program = parse(
+            r#"
+            ilo pali () open
+                sin mute jo kulupu_sin(1, 2, 3)
+                sin g jo ilo () open pana mute pini
+                tawa i tan 0 tawa 2 la open
+                    sin g jo kulupu_sin(ilo () open pana i pini)
+                pini
+                pana 1
+            pini
+            ilo pana_ilo () open
+                sin g jo ilo () open pana 2 pini
+                pana g
+            pini
+            pali()
+            h jo pana_ilo()
+        "#,
+        )
+        .unwrap();
+        let scopes = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::new();
+        let seen = Rc::clone(&scopes);
+        interpreter.set_observer(move |event| {
+            if let Event::Assign {
+                name: "g", value, ..
+            } = event
+            {
+                let function = match value {
+                    Value::List(items) => &items[0],
+                    value => value,
+                };
+                if let Value::Function { captured, .. } = function {
+                    let scope = captured.last().unwrap();
+                    seen.borrow_mut().push(Rc::downgrade(&scope.0));
+                }
+            }
+        });
+        interpreter.run(&program).unwrap();
+        let counts: Vec<usize> = scopes
+            .borrow()
+            .iter()
+            .map(|scope| scope.strong_count())
+            .collect();
+        // `h` and the `g` inside its scope.
+        assert_eq!(counts, vec![0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_deep_recursion_through_blocks_stays_on_the_stack() {
+        // Each call nests blocks, a loop and block expressions, so a frame
+        // of the script is many native frames deep.
+        let source = "ilo sike (n) open\n\
+                      \x20 n lili 0 la open pana 0 pini\n\
+                      \x20 lon la open\n\
+                      \x20   wile lon la open\n\
+                      \x20     tawa i tan 0 tawa 1 la open\n\
+                      \x20       pana (open pana 1 + sike(n - 1) pini)\n\
+                      \x20     pini\n\
+                      \x20   pini\n\
+                      \x20 pini\n\
+                      pini\n\
+                      a jo sike(990)\n\
+                      b jo ken_pali(ilo () open pana sike(5000) pini)";
+        let mut interpreter = Interpreter::new();
+        let err = interpreter.run(&parse(source).unwrap()).unwrap_err();
+        assert!(matches!(err.innermost(), RuntimeError::StackOverflow));
+        assert_eq!(global(&interpreter, "a"), Value::Number(991.0));
+
+        let err = Interpreter::new()
+            .run(&parse("ilo sike (n) open pana sike(n + 1) pini\nsike(0)").unwrap())
+            .unwrap_err();
+        assert!(matches!(err.innermost(), RuntimeError::StackOverflow));
+    }
+
+    #[test]
+    fn test_en_anu_ala_short_circuit() {
+        // `nimi_ala` is undefined: reaching it would be an error.
+        let source = "a jo 5\nb jo a suli 1 en a lili 10\nc jo a suli 9 en nimi_ala\nd jo a sama 5 anu nimi_ala\ne jo ala a sama 5\nf jo ala 0 en lon";
+        let interpreter = run_with(source, Options::default());
+        assert_eq!(global(&interpreter, "b"), Value::Bool);
+        assert_eq!(global(&interpreter, "c"), Value::Ala);
+        assert_eq!(global(&interpreter, "d"), Value::Bool);
+        assert_eq!(global(&interpreter, "e"), Value::Ala);
+        assert_eq!(global(&interpreter, "f"), Value::Bool);
+
+        let err = Interpreter::new()
+            .run(&parse("x jo lon en nimi_ala").unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            unlocated(&err),
+            RuntimeError::UndefinedVariable(_)
+        ));
     }
 }
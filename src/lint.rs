@@ -0,0 +1,475 @@
+//! Style lint for Lipona programs.
+//!
+//! Checks that user-defined identifiers (variables, functions, parameters)
+//! are made of toki pona words joined by `_`. A capitalized part is treated
+//! as a proper name (`Lipona`, `Kanata`) and only has to follow toki pona
+//! phonotactics. Used by `lipona --lint` and the `sitelen_pona_nimi` builtin.
+//!
+//! Also warns when a `sin` declaration shadows a variable from an enclosing
+//! scope, and, in a program without `kepeken`, when a name is used but never
+//! defined. Both come from the [`resolve`](crate::resolve) pass.
+//!
+//! With `gradual`, also reports the seams left while adding type
+//! annotations: an unannotated (or `ijo`) parameter passed straight on to a
+//! parameter of a named function that has a concrete annotation.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Block, Expr, ExprKind, Program, Stmt, StmtKind, StringPart, Type};
+use crate::resolve::resolve;
+
+/// nimi pu plus the common nimi ku suli.
+const WORDS: &[&str] = &[
+    "a", "akesi", "ala", "alasa", "ale", "ali", "anpa", "ante", "anu", "awen", "e", "en", "epiku",
+    "esun", "ijo", "ike", "ilo", "insa", "jaki", "jan", "jasima", "jelo", "jo", "kala", "kalama",
+    "kama", "kasi", "ken", "kepeken", "kili", "kin", "kipisi", "kiwen", "ko", "kon", "ku", "kule",
+    "kulupu", "kute", "la", "lanpan", "lape", "laso", "lawa", "leko", "len", "lete", "li", "lili",
+    "linja", "lipu", "loje", "lon", "luka", "lukin", "lupa", "ma", "mama", "mani", "meli", "meso",
+    "mi", "mije", "misikeke", "moku", "moli", "monsi", "monsuta", "mu", "mun", "musi", "mute", "n",
+    "namako", "nanpa", "nasa", "nasin", "nena", "ni", "nimi", "noka", "o", "oko", "olin", "ona",
+    "open", "pakala", "pali", "palisa", "pan", "pana", "pi", "pilin", "pimeja", "pini", "pipi",
+    "poka", "poki", "pona", "pu", "sama", "seli", "selo", "seme", "sewi", "sijelo", "sike", "sin",
+    "sina", "sinpin", "sitelen", "soko", "sona", "soweli", "suli", "suno", "supa", "suwi", "tan",
+    "taso", "tawa", "telo", "tenpo", "toki", "tomo", "tonsi", "tu", "unpa", "uta", "utala", "walo",
+    "wan", "waso", "wawa", "weka", "wile",
+];
+
+/// A style warning for one identifier.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub name: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nimi '{}': {}", self.name, self.message)
+    }
+}
+
+/// Lint every identifier the program defines, reporting each name once.
+/// `allow` lists extra words or whole identifiers to accept (the
+/// `[lint] allow` list of `.lipona.toml`); `gradual` turns on the
+/// untyped-to-typed boundary warnings.
+pub fn lint_program(program: &Program, allow: &[String], gradual: bool) -> Vec<Warning> {
+    let resolution = resolve(program);
+    let mut warnings = Vec::new();
+    let mut seen = HashSet::new();
+    for symbol in &resolution.definitions {
+        if !seen.insert(symbol.name.as_str()) {
+            continue;
+        }
+        for message in identifier_problems(&symbol.name, allow) {
+            warnings.push(Warning {
+                name: symbol.name.clone(),
+                message,
+            });
+        }
+    }
+    for symbol in &resolution.shadows {
+        warnings.push(Warning {
+            name: symbol.name.clone(),
+            message: "'sin' declaration shadows a variable from an enclosing scope".to_string(),
+        });
+    }
+    // An imported module may define any name, so only a program without
+    // `kepeken` can tell a missing name from an imported one.
+    if resolution.imports.is_empty() {
+        let mut seen = HashSet::new();
+        for symbol in &resolution.unresolved {
+            if seen.insert(symbol.name.as_str()) {
+                warnings.push(Warning {
+                    name: symbol.name.clone(),
+                    message: format!("is used at {} but never defined", symbol.span),
+                });
+            }
+        }
+    }
+    if gradual {
+        let mut linter = Linter {
+            params: Vec::new(),
+            signatures: HashMap::new(),
+            warnings,
+        };
+        // Top-level functions can be called before their definition.
+        for stmt in program {
+            linter.signature(stmt);
+        }
+        linter.stmts(program);
+        warnings = linter.warnings;
+    }
+    warnings
+}
+
+/// Return the problems with `name`, or an empty list if it is good toki pona.
+pub fn identifier_problems(name: &str, allow: &[String]) -> Vec<String> {
+    if allow.iter().any(|a| a == name) {
+        return Vec::new();
+    }
+    let parts: Vec<&str> = name.split('_').filter(|p| !p.is_empty()).collect();
+    if parts.is_empty() {
+        return vec!["has no words".to_string()];
+    }
+    parts
+        .into_iter()
+        .filter(|part| !allow.iter().any(|a| a == part))
+        .filter_map(part_problem)
+        .collect()
+}
+
+fn part_problem(part: &str) -> Option<String> {
+    let mut chars = part.chars();
+    let first = chars.next()?;
+    if first.is_ascii_uppercase() {
+        // Proper name: only the first letter may be capitalized.
+        let lower = part.to_ascii_lowercase();
+        if chars.all(|c| c.is_ascii_lowercase()) && follows_phonotactics(&lower) {
+            return None;
+        }
+        return Some(format!(
+            "'{part}' is not a valid proper name (toki pona phonotactics)"
+        ));
+    }
+    if WORDS.contains(&part) {
+        None
+    } else {
+        Some(format!("'{part}' is not a toki pona word"))
+    }
+}
+
+/// Syllables are (C)V(n): consonants p t k s m n l j w, vowels a e i o u.
+/// Only the first syllable may omit its consonant; ji, ti, wo, wu and a
+/// syllable-final n before n or m are not allowed.
+fn follows_phonotactics(word: &str) -> bool {
+    const CONSONANTS: &[u8] = b"ptksmnljw";
+    const VOWELS: &[u8] = b"aeiou";
+    let bytes = word.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let consonant = CONSONANTS.contains(&bytes[i]).then_some(bytes[i]);
+        if consonant.is_some() {
+            i += 1;
+        } else if i != 0 {
+            return false;
+        }
+        let Some(&vowel) = bytes.get(i).filter(|b| VOWELS.contains(b)) else {
+            return false;
+        };
+        if matches!(
+            (consonant, vowel),
+            (Some(b'j'), b'i') | (Some(b't'), b'i') | (Some(b'w'), b'o') | (Some(b'w'), b'u')
+        ) {
+            return false;
+        }
+        i += 1;
+        // Syllable-final n, unless it starts the next syllable.
+        if bytes.get(i) == Some(&b'n') && !bytes.get(i + 1).is_some_and(|b| VOWELS.contains(b)) {
+            if matches!(bytes.get(i + 1), Some(b'n' | b'm')) {
+                return false;
+            }
+            i += 1;
+        }
+    }
+    !bytes.is_empty()
+}
+
+/// Walks the program for the `gradual` boundary warnings.
+struct Linter {
+    /// Parameters of each enclosing function, innermost last, mapped to
+    /// whether they are untyped (no annotation or `ijo`).
+    params: Vec<HashMap<String, bool>>,
+    /// Parameter names and annotations of the named functions seen so far.
+    signatures: HashMap<String, Vec<(String, Option<Type>)>>,
+    warnings: Vec<Warning>,
+}
+
+impl Linter {
+    /// A function body and its parameters.
+    fn function(&mut self, params: &[String], param_types: &[Option<Type>], body: &Block) {
+        let mut untyped = HashMap::new();
+        for (i, param) in params.iter().enumerate() {
+            let ty = param_types.get(i).cloned().flatten();
+            untyped.insert(param.clone(), matches!(ty, None | Some(Type::Ijo)));
+        }
+        self.params.push(untyped);
+        self.stmts(body);
+        self.params.pop();
+    }
+
+    fn signature(&mut self, stmt: &Stmt) {
+        if let StmtKind::FuncDef {
+            name,
+            params,
+            param_types,
+            ..
+        } = &stmt.kind
+        {
+            let signature = params
+                .iter()
+                .cloned()
+                .zip(param_types.iter().cloned())
+                .collect();
+            self.signatures.insert(name.clone(), signature);
+        }
+    }
+
+    /// Warn for untyped parameters passed to annotated ones.
+    fn call(&mut self, name: &str, args: &[Expr]) {
+        let Some(signature) = self.signatures.get(name) else {
+            return;
+        };
+        let mut seams = Vec::new();
+        for (arg, (param, ty)) in args.iter().zip(signature) {
+            let (ExprKind::Var(var), Some(ty)) = (&arg.kind, ty) else {
+                continue;
+            };
+            let untyped = self.params.iter().rev().find_map(|params| params.get(var));
+            if *ty != Type::Ijo && untyped == Some(&true) {
+                seams.push(Warning {
+                    name: var.clone(),
+                    message: format!(
+                        "untyped value is passed to parameter '{param}: {ty}' of '{name}'"
+                    ),
+                });
+            }
+        }
+        self.warnings.extend(seams);
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.stmts(block);
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Assign { value, .. } | StmtKind::Declare { value, .. } => self.expr(value),
+            StmtKind::If {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                self.expr(cond);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            StmtKind::While {
+                cond,
+                body,
+                else_block,
+                ..
+            } => self.while_loop(cond, body, else_block.as_ref()),
+            StmtKind::Guard { cond, value } => {
+                self.expr(cond);
+                self.expr(value);
+            }
+            StmtKind::FuncDef {
+                params,
+                param_types,
+                body,
+                ..
+            } => {
+                self.signature(stmt);
+                self.function(params, param_types, body);
+            }
+            StmtKind::Return(expr) | StmtKind::Expr(expr) => self.expr(expr),
+            StmtKind::For {
+                var,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                self.expr(start);
+                self.expr(end);
+                // Like a function parameter, `i` lives in the body's scope.
+                self.function(std::slice::from_ref(var), &[Some(Type::Nanpa)], body);
+            }
+            StmtKind::ForEach {
+                key,
+                var,
+                iterable,
+                body,
+                ..
+            } => {
+                self.expr(iterable);
+                let names: Vec<String> = key.iter().chain([var]).cloned().collect();
+                self.function(&names, &vec![None; names.len()], body);
+            }
+            StmtKind::Break { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            StmtKind::TypeAlias { .. }
+            | StmtKind::Capability(_)
+            | StmtKind::Global(_)
+            | StmtKind::Import(_)
+            | StmtKind::Continue { .. }
+            | StmtKind::Strict => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(_) | ExprKind::Bool(_) | ExprKind::Var(_) => {}
+            ExprKind::TemplateString(parts) => {
+                for part in parts {
+                    if let StringPart::Interpolation(expr) = part {
+                        self.expr(expr);
+                    }
+                }
+            }
+            ExprKind::Binary { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Neg(inner) | ExprKind::Not(inner) => self.expr(inner),
+            ExprKind::FuncCall { name, args } => {
+                for arg in args {
+                    self.expr(arg);
+                }
+                self.call(name, args);
+            }
+            ExprKind::Lambda {
+                params,
+                param_types,
+                body,
+                ..
+            } => self.function(params, param_types, body),
+            ExprKind::While {
+                cond,
+                body,
+                else_block,
+                ..
+            } => self.while_loop(cond, body, else_block.as_ref()),
+            ExprKind::Block(body) => self.block(body),
+        }
+    }
+
+    fn while_loop(&mut self, cond: &Expr, body: &Block, else_block: Option<&Block>) {
+        self.expr(cond);
+        self.block(body);
+        if let Some(else_block) = else_block {
+            self.block(else_block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_identifier_words() {
+        assert!(identifier_problems("nanpa_suli", &[]).is_empty());
+        assert!(identifier_problems("jan_Lipona", &[]).is_empty());
+        assert_eq!(identifier_problems("count", &[]).len(), 1);
+        assert!(identifier_problems("count_nanpa", &["count".to_string()]).is_empty());
+        assert_eq!(identifier_problems("x1", &[]).len(), 1);
+    }
+
+    #[test]
+    fn test_proper_name_phonotactics() {
+        for good in ["Kanata", "Inli", "Mewika", "Tonsi", "Alan"] {
+            assert!(follows_phonotactics(&good.to_lowercase()), "{good}");
+        }
+        for bad in ["tiki", "jipi", "wuta", "sanmi", "kk", "akka", "x"] {
+            assert!(!follows_phonotactics(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_lint_program_reports_each_name_once() {
+        let program = parse(
+            r#"
+            ilo sum (a, b) open
+                pana a + b
+            pini
+            nanpa jo sum(1, 2)
+            nanpa jo sum(nanpa, 2)
+        "#,
+        )
+        .unwrap();
+        let names: Vec<String> = lint_program(&program, &[], false)
+            .into_iter()
+            .map(|w| w.name)
+            .collect();
+        assert_eq!(names, vec!["sum", "b"]);
+    }
+
+    #[test]
+    fn test_shadowing_sin_declaration() {
+        let program = parse(
+            r#"
+            nanpa jo 1
+            lon la open
+                sin nanpa jo 2
+                sin sike jo 3
+                sin sike jo 4
+            pini
+            ilo pali (nanpa) open
+                sin ijo jo nanpa
+            pini
+        "#,
+        )
+        .unwrap();
+        let shadowed: Vec<String> = lint_program(&program, &[], false)
+            .into_iter()
+            .filter(|w| w.message.contains("shadows"))
+            .map(|w| w.name)
+            .collect();
+        assert_eq!(shadowed, vec!["nanpa"]);
+    }
+
+    #[test]
+    fn test_unresolved_names() {
+        let unresolved = |src: &str| -> Vec<String> {
+            lint_program(&parse(src).unwrap(), &[], false)
+                .into_iter()
+                .filter(|w| w.message.contains("never defined"))
+                .map(|w| w.name)
+                .collect()
+        };
+        let src = "ilo pali () open\n pana mute + mute\npini\ntoki(pali(), ante(1))\nmute jo 1\n";
+        assert_eq!(unresolved(src), vec!["ante"]);
+        assert!(unresolved(&format!("kepeken lipu/ante\n{src}")).is_empty());
+    }
+
+    #[test]
+    fn test_gradual_boundaries() {
+        let program = parse(
+            r#"
+            ilo tu_e (x: nanpa, y) open
+                pana x * 2
+            pini
+            ilo pali (a, b: nanpa, c: ijo) open
+                tu_e(a, a)
+                tu_e(b, a)
+                tu_e(c, 1)
+                ilo (a: nanpa) open
+                    pana tu_e(a, 1)
+                pini
+            pini
+        "#,
+        )
+        .unwrap();
+        let seams = |gradual| -> Vec<String> {
+            lint_program(&program, &[], gradual)
+                .into_iter()
+                .filter(|w| w.message.contains("untyped"))
+                .map(|w| w.name)
+                .collect()
+        };
+        assert_eq!(seams(true), vec!["a", "c"]);
+        assert!(seams(false).is_empty());
+    }
+}
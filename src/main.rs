@@ -1,57 +1,1102 @@
 mod ast;
+mod config;
+#[cfg(feature = "json")]
+mod conformance;
+mod dialect;
+mod engine;
+mod error;
 mod interpreter;
+mod lint;
 mod parser;
+mod printer;
+mod random;
+mod rational;
+mod resolve;
 mod stdlib;
+mod trace;
 
+use std::cell::RefCell;
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
 use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
-use interpreter::Interpreter;
+use ast::{BinOp, BlockBuilder, Expr, ExprKind, Program, Stmt, StmtKind, Type};
+use config::{ConfigFile, Lang, Setting, Settings};
+use dialect::Dialect;
+use engine::{Engine, Limits};
+use error::LiponaError;
+use interpreter::{Interpreter, Options, RuntimeError, Value};
 use parser::parse;
+use printer::ToSource;
+use trace::Recorder;
+
+fn usage(lang: Lang) -> ! {
+    eprintln!(
+        "{} lipona [options] <file.lipo> [args...]",
+        lang.pick("Usage:", "使い方:")
+    );
+    eprintln!("       lipona [options] -e '<code>'");
+    eprintln!(
+        "       lipona [options] repl   {}",
+        lang.pick(
+            "interactive mode (also with no arguments on a terminal)",
+            "対話モード（端末で引数なしのときも）"
+        )
+    );
+    eprintln!(
+        "       lipona types <file.lipo>   {}",
+        lang.pick(
+            "print each top-level function's signature as annotated (no inference)",
+            "トップレベルの関数のシグネチャを注釈どおりに表示する（型推論はしない）"
+        )
+    );
+    eprintln!(
+        "       lipona replay <trace> [--at <step>]   {}",
+        lang.pick(
+            "show the calls and variables at a step of a --record trace",
+            "--record で記録したトレースのある時点の呼び出しと変数を表示する"
+        )
+    );
+    eprintln!(
+        "       lipona new   {}",
+        lang.pick(
+            "print a starter script with an open_ale function",
+            "open_ale 関数のあるスクリプトのひな形を表示する"
+        )
+    );
+    eprintln!(
+        "       lipona test-grammar <dir> [--bless]   {}",
+        lang.pick(
+            "check the grammar corpus against its AST snapshots",
+            "文法コーパスを AST スナップショットと照合する"
+        )
+    );
+    eprintln!();
+    eprintln!("{}", lang.pick("Options:", "オプション:"));
+    eprintln!(
+        "  --strict-math       {}",
+        lang.pick(
+            "error when integer arithmetic loses precision",
+            "整数演算で精度が失われたらエラーにする"
+        )
+    );
+    eprintln!(
+        "  --warn-float-eq     {}",
+        lang.pick(
+            "warn when 'sama' compares numbers that are not whole",
+            "'sama' が整数でない数を比較したら警告する"
+        )
+    );
+    eprintln!(
+        "  --strict            {}",
+        lang.pick(
+            "require 'sin x jo ...' before assigning to a new variable",
+            "新しい変数への代入の前に 'sin x jo ...' での宣言を必須にする"
+        )
+    );
+    eprintln!(
+        "  --legacy-scopes     {}",
+        lang.pick(
+            "functions see copies of outer variables (pre-closure behavior)",
+            "関数は外側の変数のコピーを見る（クロージャ導入前の動作）"
+        )
+    );
+    eprintln!(
+        "  --lint              {}",
+        lang.pick(
+            "check identifiers are toki pona words instead of running",
+            "実行せず、識別子がトキポナの単語か検査する"
+        )
+    );
+    eprintln!(
+        "  --gradual           {}",
+        lang.pick(
+            "with --lint, also report untyped values passed to annotated parameters",
+            "--lint と一緒に使い、型注釈のある引数に型のない値を渡す箇所も報告する"
+        )
+    );
+    eprintln!(
+        "  --stats             {}",
+        lang.pick(
+            "print the output size, call depth and loop iterations of the top-level code",
+            "トップレベルのコードの出力量・呼び出しの深さ・ループ回数を表示する"
+        )
+    );
+    eprintln!(
+        "  --no-files          {}",
+        lang.pick(
+            "refuse every file access from lipu_* builtins",
+            "lipu_* などの組み込み関数からのファイルアクセスをすべて拒否する"
+        )
+    );
+    eprintln!(
+        "  --allow-exec        {}",
+        lang.pick(
+            "let ilo_ma run other programs",
+            "ilo_ma で他のプログラムを実行できるようにする"
+        )
+    );
+    eprintln!(
+        "  --allow-net         {}",
+        lang.pick(
+            "let linja_ma_* make HTTP requests",
+            "linja_ma_* で HTTP リクエストを送れるようにする"
+        )
+    );
+    eprintln!(
+        "  --record <path>     {}",
+        lang.pick(
+            "write every assignment and call to a trace for lipona replay",
+            "すべての代入と呼び出しを lipona replay 用のトレースに書き出す"
+        )
+    );
+    eprintln!(
+        "  --set <name>=<text> {}",
+        lang.pick(
+            "define the global variable name as the sitelen text (repeatable)",
+            "グローバル変数 name を sitelen の text として定義する（複数可）"
+        )
+    );
+    eprintln!(
+        "  --max-depth <n>     {}",
+        lang.pick(
+            "maximum call depth (LIPONA_MAX_DEPTH)",
+            "関数呼び出しの最大深さ (LIPONA_MAX_DEPTH)"
+        )
+    );
+    eprintln!(
+        "  --max-iter <n>      {}",
+        lang.pick(
+            "maximum iterations of one loop (LIPONA_MAX_ITER)",
+            "1 つのループの最大反復回数 (LIPONA_MAX_ITER)"
+        )
+    );
+    eprintln!(
+        "  --max-output <n>    {}",
+        lang.pick(
+            "maximum bytes toki may print (LIPONA_MAX_OUTPUT)",
+            "toki が出力できる最大バイト数 (LIPONA_MAX_OUTPUT)"
+        )
+    );
+    eprintln!(
+        "  --max-list <n>      {}",
+        lang.pick(
+            "maximum entries of one kulupu (LIPONA_MAX_LIST)",
+            "1 つの kulupu の最大要素数 (LIPONA_MAX_LIST)"
+        )
+    );
+    eprintln!(
+        "  --max-map <n>       {}",
+        lang.pick(
+            "maximum entries of one nasin (LIPONA_MAX_MAP)",
+            "1 つの nasin の最大要素数 (LIPONA_MAX_MAP)"
+        )
+    );
+    eprintln!(
+        "  --max-string <n>    {}",
+        lang.pick(
+            "maximum bytes of one sitelen (LIPONA_MAX_STRING)",
+            "1 つの sitelen の最大バイト数 (LIPONA_MAX_STRING)"
+        )
+    );
+    eprintln!(
+        "  --timeout <secs>    {}",
+        lang.pick(
+            "cancel the script after this many seconds (LIPONA_TIMEOUT)",
+            "指定した秒数を超えたらスクリプトを中断する (LIPONA_TIMEOUT)"
+        )
+    );
+    eprintln!(
+        "  --seed <n>          {}",
+        lang.pick(
+            "seed nanpa_nasa so runs repeat exactly (LIPONA_SEED)",
+            "nanpa_nasa の種を固定して毎回同じ結果にする (LIPONA_SEED)"
+        )
+    );
+    eprintln!(
+        "  --stats-file <path> {}",
+        lang.pick(
+            "append a JSON line per run: time taken, error code, builtins used (LIPONA_STATS_FILE)",
+            "実行ごとに所要時間・エラーの種類・使った組み込み関数を JSON で 1 行追記する (LIPONA_STATS_FILE)"
+        )
+    );
+    eprintln!(
+        "  --dialect <path>    {}",
+        lang.pick(
+            "read keywords and builtins under other names from this file (LIPONA_DIALECT)",
+            "キーワードや組み込み関数の別名をこのファイルから読む (LIPONA_DIALECT)"
+        )
+    );
+    eprintln!(
+        "  --color <when>      {}",
+        lang.pick(
+            "auto, always or never (LIPONA_COLOR)",
+            "auto, always, never (LIPONA_COLOR)"
+        )
+    );
+    eprintln!(
+        "  --lang <lang>       {}",
+        lang.pick(
+            "message language: en or ja (LIPONA_LANG)",
+            "メッセージの言語: en, ja (LIPONA_LANG)"
+        )
+    );
+    process::exit(1);
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // Messages before the config file is found use the environment's language.
+    let lang = Settings::from_env().lang;
 
-    if args.len() < 2 {
-        eprintln!("Usage: lipona <file.lipo>");
-        eprintln!("       lipona -e '<code>'");
-        process::exit(1);
+    if args.first().is_some_and(|arg| arg == "test-grammar") {
+        test_grammar(&args[1..], lang);
+    }
+    if args.first().is_some_and(|arg| arg == "replay") {
+        replay(&args[1..], lang);
+    }
+    if args.first().is_some_and(|arg| arg == "new") {
+        print!("{}", starter_script().to_source());
+        return;
+    }
+
+    let mut strict_math = false;
+    let mut strict = false;
+    let mut legacy_scopes = false;
+    let mut warn_float_eq = false;
+    let mut lint_only = false;
+    let mut gradual = false;
+    let mut stats = false;
+    let mut files = true;
+    let mut exec = false;
+    let mut net = false;
+    let mut record = None;
+    let types_only = args.first().is_some_and(|arg| arg == "types");
+    let mut repl_mode = args.is_empty() && io::stdin().is_terminal();
+    let mut flags = Vec::new();
+    let mut globals = Vec::new();
+    let mut script = None;
+    let mut code = None;
+    let mut iter = args.iter().skip(usize::from(types_only));
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--strict-math" => strict_math = true,
+            "--strict" => strict = true,
+            "--legacy-scopes" => legacy_scopes = true,
+            "--warn-float-eq" => warn_float_eq = true,
+            "--lint" => lint_only = true,
+            "--gradual" => gradual = true,
+            "--stats" => stats = true,
+            "--no-files" => files = false,
+            "--allow-exec" => exec = true,
+            "--allow-net" => net = true,
+            "repl" => repl_mode = true,
+            "--set" => match iter.next().and_then(|pair| pair.split_once('=')) {
+                Some((name, value)) => globals.push((name.to_string(), value.to_string())),
+                None => {
+                    eprintln!(
+                        "{}",
+                        lang.pick(
+                            "Error: --set requires name=value",
+                            "エラー: --set には name=value を指定してください"
+                        )
+                    );
+                    process::exit(1);
+                }
+            },
+            "--record" => match iter.next() {
+                Some(path) => record = Some(Path::new(path).to_path_buf()),
+                None => {
+                    eprintln!(
+                        "{}",
+                        lang.pick(
+                            "Error: --record requires a path",
+                            "エラー: --record にはパスを指定してください"
+                        )
+                    );
+                    process::exit(1);
+                }
+            },
+            "-e" => match iter.next() {
+                Some(snippet) => code = Some(snippet.clone()),
+                None => {
+                    eprintln!(
+                        "{}",
+                        lang.pick(
+                            "Error: -e requires code argument",
+                            "エラー: -e にはコードを指定してください"
+                        )
+                    );
+                    process::exit(1);
+                }
+            },
+            flag if flag.starts_with("--") => {
+                let Some(setting) = Setting::from_flag(flag) else {
+                    eprintln!(
+                        "{} '{flag}'",
+                        lang.pick("Error: unknown option", "エラー: 不明なオプション")
+                    );
+                    usage(lang);
+                };
+                let Some(value) = iter.next() else {
+                    eprintln!(
+                        "{} {flag}",
+                        lang.pick("Error: missing value for", "エラー: 値がありません:")
+                    );
+                    usage(lang);
+                };
+                flags.push((flag, setting, value));
+            }
+            filename => match fs::read_to_string(filename) {
+                Ok(content) => {
+                    script = Some(Path::new(filename).to_path_buf());
+                    code = Some(content);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "pakala: {} '{filename}': {e}",
+                        lang.pick("cannot read file", "ファイルを読めません")
+                    );
+                    process::exit(1);
+                }
+            },
+        }
+        if code.is_some() {
+            break;
+        }
     }
+    // Whatever follows the script (or `-e` code) belongs to the script.
+    let script_args: Vec<String> = iter.cloned().collect();
 
-    let code = if args[1] == "-e" {
-        if args.len() < 3 {
-            eprintln!("Error: -e requires code argument");
+    let code = match code {
+        Some(code) => code,
+        None if repl_mode => String::new(),
+        None => usage(lang),
+    };
+    // Errors are prefixed with where the code came from.
+    let source_name = match &script {
+        Some(path) => path.display().to_string(),
+        None => "<-e>".to_string(),
+    };
+
+    // `.lipona.toml` is looked up from the script's directory, or from the
+    // current directory for `-e`.
+    let start = match script.as_deref().and_then(Path::parent) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => env::current_dir().unwrap_or_default(),
+    };
+    let config = match ConfigFile::discover(&start) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("pakala: {e}");
             process::exit(1);
         }
-        args[2].clone()
-    } else {
-        let filename = &args[1];
-        match fs::read_to_string(filename) {
-            Ok(content) => content,
+    };
+
+    let (mut settings, warnings) = Settings::load(config.as_ref());
+    for (flag, setting, value) in flags {
+        if let Err(e) = settings.set(setting, value) {
+            eprintln!("{} {flag}: {e}", settings.lang.pick("Error:", "エラー:"));
+            process::exit(1);
+        }
+    }
+    if strict_math {
+        settings.options.strict_math = true;
+    }
+    if strict {
+        settings.options.strict = true;
+    }
+    if legacy_scopes {
+        settings.options.legacy_scopes = true;
+    }
+    if warn_float_eq {
+        settings.options.warn_float_eq = true;
+    }
+    for warning in warnings {
+        eprintln!("{} {warning}", settings.lang.pick("Warning:", "警告:"));
+    }
+    let color = settings.use_color();
+
+    // A teaching dialect is translated away before anything parses code.
+    let dialect = match settings.dialect.as_deref().map(Dialect::read).transpose() {
+        Ok(dialect) => Rc::new(dialect.unwrap_or_default()),
+        Err(e) => {
+            eprintln!("pakala: {e}");
+            process::exit(1);
+        }
+    };
+    let code = dialect.translate(&code);
+
+    if repl_mode {
+        let interpreter = new_interpreter(
+            settings.options,
+            settings.limits,
+            &globals,
+            files,
+            exec,
+            net,
+            dialect.clone(),
+        );
+        repl(interpreter, color, &dialect);
+        return;
+    }
+
+    if types_only {
+        // Print what parsed even if part of the file is broken.
+        let result = parser::parse_partial(&code);
+        for line in signatures(&result.program) {
+            println!("{line}");
+        }
+        for e in &result.errors {
+            eprintln!("{}", paint(&format!("{source_name}: {e}"), RED, color));
+        }
+        if !result.errors.is_empty() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if lint_only {
+        let (allow, gradual) = match config {
+            Some(config) => (config.lint_allow, gradual || config.lint_gradual),
+            None => (Vec::new(), gradual),
+        };
+        match lint_code(&code, &allow, gradual, color) {
+            Ok(0) => {}
+            Ok(_) => process::exit(1),
             Err(e) => {
-                eprintln!("pakala: cannot read file '{filename}': {e}");
+                let e = e.in_source(&source_name);
+                eprintln!("{}", paint(&e.to_string(), RED, color));
                 process::exit(1);
             }
         }
-    };
+        return;
+    }
 
-    match run(&code) {
-        Ok(_) => {}
+    let mut interpreter = new_interpreter(
+        settings.options.clone(),
+        settings.limits,
+        &globals,
+        files,
+        exec,
+        net,
+        dialect,
+    );
+    let recorder = record.map(|path| {
+        let file = fs::File::create(&path).unwrap_or_else(|e| {
+            eprintln!(
+                "pakala: {} '{}': {e}",
+                lang.pick("cannot write file", "ファイルに書き込めません"),
+                path.display()
+            );
+            process::exit(1);
+        });
+        let recorder = Rc::new(RefCell::new(Recorder::new(io::BufWriter::new(file))));
+        let sink = recorder.clone();
+        interpreter.set_observer(move |event| sink.borrow_mut().record(event));
+        (path, recorder)
+    });
+    let started = Instant::now();
+    let result = run(
+        &code,
+        script.as_deref(),
+        script_args,
+        &mut interpreter,
+        &settings,
+        stats,
+    )
+    .map_err(|e| e.in_source(&source_name));
+    if let Some(path) = &settings.stats_file {
+        let error = result.as_ref().err().map(LiponaError::code);
+        let builtins = interpreter.builtins_used();
+        if let Err(e) = append_stats(path, &source_name, started, error, builtins) {
+            eprintln!(
+                "{} {}: {e}",
+                lang.pick("Warning: cannot write", "警告: 書き込めません:"),
+                path.display()
+            );
+        }
+    }
+    if let Some((path, recorder)) = recorder {
+        if let Err(e) = recorder.borrow_mut().finish() {
+            eprintln!(
+                "{} {}: {e}",
+                lang.pick("Warning: cannot write", "警告: 書き込めません:"),
+                path.display()
+            );
+        }
+    }
+    match result {
+        Ok(Some(exit_code)) => process::exit(exit_code),
+        Ok(None) => {}
         Err(e) => {
-            eprintln!("{e}");
+            eprintln!("{}", paint(&e.to_string(), RED, color));
             process::exit(1);
         }
     }
 }
 
-fn run(code: &str) -> Result<(), String> {
+/// One run, as `--stats-file` records it: a JSON object per line, so a
+/// teacher can tally which errors come up most. Nothing leaves the file.
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct StatsRecord<'a> {
+    /// Seconds since the Unix epoch when the run finished.
+    time: u64,
+    source: &'a str,
+    duration_ms: u128,
+    /// `LiponaError::code` of the error the run ended with.
+    error: Option<&'static str>,
+    builtins: Vec<&'static str>,
+}
+
+#[cfg(feature = "json")]
+fn append_stats(
+    path: &Path,
+    source: &str,
+    started: Instant,
+    error: Option<&'static str>,
+    builtins: Vec<&'static str>,
+) -> io::Result<()> {
+    let record = StatsRecord {
+        time: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        source,
+        duration_ms: started.elapsed().as_millis(),
+        error,
+        builtins,
+    };
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+#[cfg(not(feature = "json"))]
+fn append_stats(
+    _path: &Path,
+    _source: &str,
+    _started: Instant,
+    _error: Option<&'static str>,
+    _builtins: Vec<&'static str>,
+) -> io::Result<()> {
+    Err(io::Error::other(
+        "--stats-file needs lipona built with the \"json\" feature",
+    ))
+}
+
+fn run(
+    code: &str,
+    script: Option<&Path>,
+    args: Vec<String>,
+    interpreter: &mut Interpreter,
+    settings: &Settings,
+    stats: bool,
+) -> Result<Option<i32>, LiponaError> {
+    let color = settings.use_color();
+
     // Parse
-    let program = parse(code).map_err(|e| e.to_string())?;
+    let program = parse(code)?;
 
     // Interpret
-    let mut interpreter = Interpreter::new();
-    interpreter.run(&program).map_err(|e| e.to_string())?;
+    if let Some(seconds) = settings.timeout {
+        let handle = interpreter.cancel_handle();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(seconds));
+            handle.cancel();
+        });
+    }
+    if let Some(script) = script {
+        interpreter.set_script(script);
+    }
+    interpreter.set_args(args);
+    // With `--stats` the top-level code's output is captured so its usage
+    // can be reported after it.
+    let ran = if stats {
+        let report = interpreter.run_capturing(&program);
+        print!("{}", report.stdout);
+        for warning in report.warnings {
+            eprintln!("{}", paint(&format!("nasin: {warning}"), YELLOW, color));
+        }
+        eprintln!(
+            "{}",
+            paint(&format!("sona: {}", report.usage), YELLOW, color)
+        );
+        report.value.map_err(|error| LiponaError::Runtime {
+            error,
+            trace: report.trace,
+        })
+    } else {
+        interpreter
+            .run(&program)
+            .map_err(|error| LiponaError::Runtime {
+                error,
+                trace: interpreter.take_trace(),
+            })
+    };
+    // Then `ilo open_ale`, if defined; a nanpa it returns is the exit code.
+    let result = ran.and_then(|_| {
+        interpreter
+            .run_main()
+            .map_err(|error| LiponaError::Runtime {
+                error,
+                trace: interpreter.take_trace(),
+            })
+    });
+
+    for warning in interpreter.take_warnings() {
+        eprintln!("{}", paint(&format!("nasin: {warning}"), YELLOW, color));
+    }
+    result
+}
 
-    Ok(())
+/// An interpreter with what scripts and the REPL share: the limits,
+/// modules from disk (in the dialect), `--no-files`, `--allow-exec`,
+/// `--allow-net` and `--set` globals.
+fn new_interpreter(
+    options: Options,
+    limits: Limits,
+    globals: &[(String, String)],
+    files: bool,
+    exec: bool,
+    net: bool,
+    dialect: Rc<Dialect>,
+) -> Interpreter {
+    let mut interpreter = Interpreter::with_options(options);
+    interpreter.set_limits(limits);
+    interpreter
+        .set_module_loader(move |path| read_module(path).map(|source| dialect.translate(&source)));
+    interpreter.set_input(io::stdin());
+    if !files {
+        interpreter.set_file_policy(|_, _| false);
+    }
+    interpreter.set_allow_exec(exec);
+    interpreter.set_allow_net(net);
+    define_globals(&mut interpreter, globals);
+    interpreter
+}
+
+/// `--set name=text`: each name becomes a global sitelen.
+fn define_globals(interpreter: &mut Interpreter, globals: &[(String, String)]) {
+    for (name, value) in globals {
+        interpreter.set_global(name, Value::String(value.clone()));
+    }
+}
+
+/// `kepeken` modules come from disk.
+fn read_module(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Read-eval-print loop. Variables and functions carry over from one entry
+/// to the next; an entry ending in an expression prints its value. Lines
+/// are collected until they parse, so blocks can span several lines.
+/// Errors name the entry they come from, counting from 1: `<repl:3>`.
+/// `o_pini([code])` ends the session.
+fn repl(interpreter: Interpreter, color: bool, dialect: &Dialect) {
+    let mut session = Session::new(interpreter);
+    let mut entry = String::new();
+    let mut number = 0;
+    let mut line = String::new();
+    loop {
+        print!("{}", if entry.is_empty() { "> " } else { "... " });
+        let _ = io::stdout().flush();
+        // Stdin is not kept locked: `kute` in an entry reads from it too.
+        line.clear();
+        if !matches!(io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+            println!();
+            return;
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+        if entry.is_empty() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            number += 1;
+        }
+        entry.push_str(line);
+        entry.push('\n');
+        let source_name = format!("<repl:{number}>");
+
+        let source = dialect.translate(&entry);
+        let program = match parse(&source) {
+            Ok(program) => program,
+            // Keep reading while the error is at the end of the input
+            // (an unclosed `open`, a trailing operator, ...).
+            Err(e) if !line.trim().is_empty() && e.at_end_of(&source) => continue,
+            Err(e) => {
+                let e = LiponaError::from(e).in_source(&source_name);
+                eprintln!("{}", paint(&e.to_string(), RED, color));
+                entry.clear();
+                continue;
+            }
+        };
+        entry.clear();
+        let (result, warnings) = session.run(&program);
+        match result {
+            Ok(value) => {
+                if value != Value::Ala {
+                    println!("{value}");
+                }
+            }
+            Err(error) => {
+                let error = error.in_source(&source_name);
+                eprintln!("{}", paint(&error.to_string(), RED, color));
+            }
+        }
+        for warning in warnings {
+            eprintln!("{}", paint(&format!("nasin: {warning}"), YELLOW, color));
+        }
+    }
+}
+
+/// What the REPL keeps from one entry to the next.
+struct Session {
+    interpreter: Interpreter,
+    /// Values of the entries that ended in an expression, as `ni_ale`.
+    history: Vec<Value>,
+}
+
+impl Session {
+    fn new(mut interpreter: Interpreter) -> Self {
+        interpreter.register_fn("o_pini", Rc::new(repl_exit));
+        Session {
+            interpreter,
+            history: Vec::new(),
+        }
+    }
+
+    /// Run one entry, returning its value and the warnings it raised. The
+    /// entry runs on a fork that becomes the session only if it succeeds,
+    /// so a failed entry leaves no half-done assignments. The value of an
+    /// entry ending in an expression becomes the global `ni` and is added
+    /// to `ni_ale`.
+    fn run(&mut self, program: &Program) -> (Result<Value, LiponaError>, Vec<String>) {
+        let mut attempt = self.interpreter.fork();
+        let result = match program.as_slice() {
+            [Stmt {
+                kind: StmtKind::Expr(expr),
+                ..
+            }] => attempt.eval_expr(expr),
+            _ => attempt.run_interactive(program),
+        };
+        let warnings = attempt.take_warnings();
+        let result = match result {
+            Ok(value) => {
+                self.interpreter = attempt;
+                if matches!(program.last(), Some(stmt) if matches!(stmt.kind, StmtKind::Expr(_))) {
+                    self.history.push(value.clone());
+                    self.interpreter.set_global("ni", value.clone());
+                    self.interpreter
+                        .set_global("ni_ale", Value::List(self.history.clone()));
+                }
+                Ok(value)
+            }
+            Err(error) => Err(LiponaError::Runtime {
+                error,
+                trace: attempt.take_trace(),
+            }),
+        };
+        (result, warnings)
+    }
+}
+
+/// `o_pini([code])` in the REPL: exit with `code` (0 if not given).
+fn repl_exit(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let code = match args.as_slice() {
+        [] => 0,
+        [Value::Number(n)] => *n as i32,
+        [other] => {
+            return Err(RuntimeError::TypeError {
+                expected: "nanpa",
+                got: other.type_name().to_string(),
+                arg: None,
+            })
+        }
+        _ => {
+            return Err(RuntimeError::Argument(
+                "o_pini takes at most 1 argument".into(),
+            ))
+        }
+    };
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
+
+/// Print lint warnings and return how many there were.
+fn lint_code(
+    code: &str,
+    allow: &[String],
+    gradual: bool,
+    color: bool,
+) -> Result<usize, LiponaError> {
+    let program = parse(code)?;
+    let warnings = lint::lint_program(&program, allow, gradual);
+    for warning in &warnings {
+        eprintln!("{}", paint(&format!("nasin: {warning}"), YELLOW, color));
+    }
+    Ok(warnings.len())
+}
+
+/// `lipona new`: a script to start from, built as an AST.
+fn starter_script() -> Program {
+    let greeting = Expr::binary(
+        Expr::binary(Expr::string("toki, "), BinOp::Add, Expr::var("nimi")),
+        BinOp::Add,
+        Expr::string("!"),
+    );
+    vec![
+        Stmt::func(
+            "toki_e",
+            &["nimi"],
+            BlockBuilder::new().ret(greeting).build(),
+        ),
+        Stmt::func(
+            "open_ale",
+            &[],
+            BlockBuilder::new()
+                .assign("nimi", Expr::string("ma"))
+                .expr(Expr::call(
+                    "toki",
+                    [Expr::call("toki_e", [Expr::var("nimi")])],
+                ))
+                .ret(Expr::number(0.0))
+                .build(),
+        ),
+    ]
+}
+
+/// `lipona test-grammar <dir> [--bless]`
+#[cfg(feature = "json")]
+fn test_grammar(args: &[String], lang: Lang) -> ! {
+    let bless = args.iter().any(|arg| arg == "--bless");
+    let Some(dir) = args.iter().find(|arg| !arg.starts_with("--")) else {
+        usage(lang)
+    };
+    let outcome = match conformance::run_corpus(Path::new(dir), bless) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("pakala: {e}");
+            process::exit(1);
+        }
+    };
+    for path in &outcome.written {
+        println!("{} {}", lang.pick("wrote", "書き込み:"), path.display());
+    }
+    for failure in &outcome.failures {
+        eprintln!("pakala: {failure}");
+    }
+    println!(
+        "{}: {}, {}: {}",
+        lang.pick("passed", "成功"),
+        outcome.passed,
+        lang.pick("failed", "失敗"),
+        outcome.failures.len()
+    );
+    process::exit(if outcome.failures.is_empty() { 0 } else { 1 });
+}
+
+#[cfg(not(feature = "json"))]
+fn test_grammar(_args: &[String], lang: Lang) -> ! {
+    eprintln!(
+        "pakala: {}",
+        lang.pick(
+            "test-grammar needs lipona built with the \"json\" feature",
+            "test-grammar には \"json\" フィーチャーつきのビルドが必要です"
+        )
+    );
+    process::exit(1);
+}
+
+/// `lipona replay <trace> [--at <step>]`
+fn replay(args: &[String], lang: Lang) -> ! {
+    let mut path = None;
+    let mut at = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--at" => match iter.next().and_then(|step| step.parse().ok()) {
+                Some(step) => at = Some(step),
+                None => {
+                    eprintln!(
+                        "{}",
+                        lang.pick(
+                            "Error: --at requires a step number",
+                            "エラー: --at にはステップ番号を指定してください"
+                        )
+                    );
+                    process::exit(1);
+                }
+            },
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else { usage(lang) };
+    let state = fs::read_to_string(path)
+        .map_err(|e| {
+            format!(
+                "{} '{path}': {e}",
+                lang.pick("cannot read file", "ファイルを読めません")
+            )
+        })
+        .and_then(|trace| trace::replay(&trace, at).map_err(|e| format!("{path}: {e}")));
+    match state {
+        Ok(state) => {
+            print!("{state}");
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("pakala: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// One line per top-level function (`ilo f ...` or `f jo ilo ...`), written
+/// the way it is declared. Nothing is inferred: unannotated parameters are
+/// shown as `ijo` and an unannotated return type is left out.
+fn signatures(program: &[Stmt]) -> Vec<String> {
+    program
+        .iter()
+        .filter_map(|stmt| match &stmt.kind {
+            StmtKind::FuncDef {
+                name,
+                params,
+                param_types,
+                return_type,
+                ..
+            }
+            | StmtKind::Assign {
+                target: name,
+                value:
+                    Expr {
+                        kind:
+                            ExprKind::Lambda {
+                                params,
+                                param_types,
+                                return_type,
+                                ..
+                            },
+                        ..
+                    },
+            } => {
+                let params: Vec<String> = params
+                    .iter()
+                    .zip(param_types)
+                    .map(|(param, ty)| format!("{param}: {}", ty.as_ref().unwrap_or(&Type::Ijo)))
+                    .collect();
+                let ret = return_type
+                    .as_ref()
+                    .map(|ty| format!(" -> {ty}"))
+                    .unwrap_or_default();
+                Some(format!("ilo {name} ({}){ret}", params.join(", ")))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[33m";
+
+/// Wrap `text` in an ANSI color when coloring is enabled.
+fn paint(text: &str, ansi: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{ansi}{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs each entry in one REPL session; parse errors fail the test.
+    fn session(entries: &[&str]) -> (Session, Vec<Result<Value, LiponaError>>) {
+        let mut session = Session::new(Interpreter::new());
+        let results = entries
+            .iter()
+            .map(|entry| session.run(&parse(entry).unwrap()).0)
+            .collect();
+        (session, results)
+    }
+
+    fn global(session: &Session, name: &str) -> Option<Value> {
+        session.interpreter.get_global(name)
+    }
+
+    #[test]
+    fn test_repl_keeps_state_across_entries() {
+        let (session, results) = session(&[
+            "x jo 2",
+            "ilo tu (n) open\n  pana n * 2\npini",
+            "tu(x)",
+            "x jo 10\ny jo nimi_ala",
+            "x + 1",
+        ]);
+        assert!(matches!(results[2], Ok(Value::Number(n)) if n == 4.0));
+        // The failed entry's assignment to `x` was discarded with it, and
+        // the entry after it runs as usual.
+        assert!(matches!(results[3], Err(LiponaError::Runtime { .. })));
+        assert!(matches!(results[4], Ok(Value::Number(n)) if n == 3.0));
+        assert_eq!(global(&session, "x"), Some(Value::Number(2.0)));
+        assert_eq!(global(&session, "y"), None);
+    }
+
+    #[test]
+    fn test_signatures_show_annotations_of_what_parsed() {
+        let result = parser::parse_partial(
+            "nimi_nasin Nimi = sitelen\n\
+             ilo wan (a: nanpa, b) -> nanpa open\n  pana a\npini\n\
+             ilo pakala (a open\n\
+             tu jo ilo (nimi: Nimi) open\n  pana nimi + 1\npini\n\
+             x jo 1\n\
+             ilo tu_wan () open pini",
+        );
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            signatures(&result.program),
+            vec![
+                "ilo wan (a: nanpa, b: ijo) -> nanpa",
+                "ilo tu (nimi: sitelen)",
+                "ilo tu_wan ()",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repl_binds_ni_to_expression_entries() {
+        let (mut session, _) = session(&["1 + 1"]);
+        assert_eq!(global(&session, "ni"), Some(Value::Number(2.0)));
+        // Statements, and entries that fail, leave `ni` and `ni_ale` alone.
+        for entry in ["x jo 5", "ni + nimi_ala", "ilo f () open pana 7 pini"] {
+            let _ = session.run(&parse(entry).unwrap());
+            assert_eq!(global(&session, "ni"), Some(Value::Number(2.0)));
+        }
+        // An entry ending in an expression counts even after statements.
+        let (result, _) = session.run(&parse("y jo ni * 3\ny + x").unwrap());
+        assert!(matches!(result, Ok(Value::Number(n)) if n == 11.0));
+        let _ = session.run(&parse("f()").unwrap());
+        assert_eq!(global(&session, "ni"), Some(Value::Number(7.0)));
+        assert_eq!(
+            global(&session, "ni_ale"),
+            Some(Value::List(vec![
+                Value::Number(2.0),
+                Value::Number(11.0),
+                Value::Number(7.0)
+            ]))
+        );
+    }
 }
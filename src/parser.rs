@@ -3,11 +3,17 @@
 //! Uses pest PEG parser to convert source code into an AST.
 //! The grammar is defined in `lipona.pest`.
 
-use pest::Parser;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use pest::error::{ErrorVariant, InputLocation, LineColLocation};
+use pest::{Parser, Position};
 use pest_derive::Parser;
 use thiserror::Error;
 
-use crate::ast::{BinOp, Block, Expr, Program, Stmt, StringPart, Type};
+use crate::ast::{
+    BinOp, Block, Capability, Expr, ExprKind, Program, Span, Stmt, StmtKind, StringPart, Type,
+};
 
 #[derive(Parser)]
 #[grammar = "lipona.pest"]
@@ -19,19 +25,163 @@ pub enum ParseError {
     Pest(Box<pest::error::Error<Rule>>),
     #[error("Unexpected rule: {0:?}")]
     UnexpectedRule(Rule),
-    #[error("Invalid number: {0}")]
-    InvalidNumber(String),
+    #[error("Invalid number: {text} at {span}")]
+    InvalidNumber { text: String, span: Span },
     #[error("Invalid boolean: {0}")]
     InvalidBoolean(String),
-    #[error("Unknown type name: '{0}'. Valid types are: nanpa, sitelen, lon, kulupu, nasin, ilo, ala, ijo")]
-    UnknownType(String),
+    #[error("Unknown type name: '{name}' at {span}. Valid types are: nanpa, kipisi, sitelen, lon, kulupu, nasin, ilo, ala, ijo, or a nimi_nasin alias declared earlier")]
+    UnknownType { name: String, span: Span },
+    #[error("'{name}' is a built-in type and cannot be redefined at {span}")]
+    BuiltinTypeAlias { name: String, span: Span },
     #[error("Parse error: missing inner element in {0:?}")]
     MissingInner(Rule),
 }
 
+impl ParseError {
+    /// Whether the parser ran out of input, i.e. `input` could still become
+    /// valid by appending to it (used by the REPL to read more lines).
+    pub fn at_end_of(&self, input: &str) -> bool {
+        match self {
+            ParseError::Pest(e) => match e.location {
+                pest::error::InputLocation::Pos(pos) => input[pos..].trim().is_empty(),
+                pest::error::InputLocation::Span((_, end)) => input[end..].trim().is_empty(),
+            },
+            _ => false,
+        }
+    }
+}
+
+impl ParseError {
+    /// Where the error is, for ordering errors; `None` if not known.
+    fn line_col(&self) -> Option<(usize, usize)> {
+        match self {
+            ParseError::Pest(e) => match e.line_col {
+                LineColLocation::Pos(at) | LineColLocation::Span(at, _) => Some(at),
+            },
+            ParseError::InvalidNumber { span, .. }
+            | ParseError::UnknownType { span, .. }
+            | ParseError::BuiltinTypeAlias { span, .. } => Some((span.line, span.col)),
+            ParseError::UnexpectedRule(_)
+            | ParseError::InvalidBoolean(_)
+            | ParseError::MissingInner(_) => None,
+        }
+    }
+}
+
 impl From<pest::error::Error<Rule>> for ParseError {
     fn from(err: pest::error::Error<Rule>) -> Self {
-        ParseError::Pest(Box::new(err))
+        ParseError::Pest(Box::new(user_facing(err)))
+    }
+}
+
+/// `err` with what it expected described in words rather than grammar
+/// rule names (`expected an expression`, not `expected not_expr`).
+fn user_facing(err: pest::error::Error<Rule>) -> pest::error::Error<Rule> {
+    let ErrorVariant::ParsingError {
+        positives,
+        negatives,
+    } = &err.variant
+    else {
+        return err;
+    };
+    let words = |rules: &[Rule]| {
+        let mut rules: Vec<Rule> = rules.iter().map(|rule| describes(*rule)).collect();
+        rules.sort();
+        rules.dedup();
+        rules
+    };
+    let variant = ErrorVariant::ParsingError {
+        positives: words(positives),
+        negatives: words(negatives),
+    };
+    let mut err = err;
+    err.variant = variant;
+    err.renamed_rules(|rule| rule_words(*rule).to_string())
+}
+
+/// The rule whose name stands for `rule` in error messages: every kind of
+/// statement is `stmt`, every level of expression `expr`, and so on.
+fn describes(rule: Rule) -> Rule {
+    match rule {
+        Rule::program
+        | Rule::partial_stmt
+        | Rule::func_def
+        | Rule::if_stmt
+        | Rule::while_stmt
+        | Rule::for_stmt
+        | Rule::for_each_stmt
+        | Rule::guard_stmt
+        | Rule::return_stmt
+        | Rule::break_stmt
+        | Rule::continue_stmt
+        | Rule::decl_stmt
+        | Rule::global_stmt
+        | Rule::type_alias
+        | Rule::capability_def
+        | Rule::import_stmt
+        | Rule::data_stmt
+        | Rule::assign_stmt
+        | Rule::expr_stmt => Rule::stmt,
+        Rule::or_expr
+        | Rule::and_expr
+        | Rule::not_expr
+        | Rule::comparison
+        | Rule::add_expr
+        | Rule::mul_expr
+        | Rule::unary_expr
+        | Rule::pow_expr
+        | Rule::primary
+        | Rule::lambda
+        | Rule::block_expr
+        | Rule::func_call
+        | Rule::number
+        | Rule::string
+        | Rule::boolean => Rule::expr,
+        Rule::or_op | Rule::and_op | Rule::not_op | Rule::add_op | Rule::mul_op | Rule::pow_op => {
+            Rule::comp_op
+        }
+        Rule::callee | Rule::module_path => Rule::ident,
+        Rule::param => Rule::param_list,
+        Rule::loop_label | Rule::break_label => Rule::continue_label,
+        rule => rule,
+    }
+}
+
+/// How a rule left by `describes` reads in an error message.
+fn rule_words(rule: Rule) -> &'static str {
+    match rule {
+        Rule::stmt => "a statement",
+        Rule::expr => "an expression",
+        Rule::comp_op => "an operator",
+        Rule::ident => "a name",
+        Rule::param_list => "a parameter",
+        Rule::arg_list => "an argument",
+        Rule::type_expr => "a type",
+        Rule::return_type => "'->' and a type",
+        Rule::continue_label => "a label",
+        Rule::else_block => "'taso open'",
+        Rule::strict_directive => "'o awen nasin'",
+        Rule::capability_fn => "'ilo' and a name",
+        Rule::kw_nimi_nasin => "'nimi_nasin'",
+        Rule::kw_nasin_ilo => "'nasin_ilo'",
+        Rule::kw_kepeken => "'kepeken'",
+        Rule::kw_tan => "'tan'",
+        Rule::kw_lon => "'lon'",
+        Rule::kw_tawa => "'tawa'",
+        Rule::kw_pini_tawa => "'pini_tawa'",
+        Rule::kw_awen_sin => "'awen_sin'",
+        Rule::kw_sin => "'sin'",
+        Rule::kw_li => "'li'",
+        Rule::kw_e => "'e'",
+        Rule::kw_ma => "'ma'",
+        Rule::data_line => "a line of text",
+        Rule::data_end => "'pini_sitelen'",
+        Rule::interpolation => "'{' and an expression",
+        Rule::string_literal | Rule::string_inner | Rule::escape => "text",
+        Rule::keyword => "a keyword",
+        Rule::EOI => "the end of the input",
+        // Rules `describes` replaces, and whitespace and comments.
+        _ => "something else",
     }
 }
 
@@ -42,27 +192,385 @@ pub fn parse(input: &str) -> Result<Program, ParseError> {
     for pair in pairs {
         if pair.as_rule() == Rule::program {
             for inner in pair.into_inner() {
-                if inner.as_rule() == Rule::stmt {
-                    stmts.push(parse_stmt(inner)?);
+                match inner.as_rule() {
+                    Rule::strict_directive => {
+                        stmts.push(Stmt::new(StmtKind::Strict, span_of(&inner)))
+                    }
+                    Rule::stmt => stmts.push(parse_stmt(inner)?),
+                    _ => {}
                 }
             }
         }
     }
 
+    resolve_types(&mut stmts, &mut HashMap::new())?;
     Ok(stmts)
 }
 
+/// The statements that parsed, with every error met on the way.
+#[derive(Debug)]
+pub struct ParseResult {
+    pub program: Program,
+    pub errors: Vec<ParseError>,
+}
+
+/// Parse as much of `input` as possible. Each top-level statement is parsed
+/// on its own; when one fails, its error is recorded and parsing resumes on
+/// the next line. For tools that must keep working on code being edited.
+pub fn parse_partial(input: &str) -> ParseResult {
+    if let Ok(program) = parse(input) {
+        return ParseResult {
+            program,
+            errors: Vec::new(),
+        };
+    }
+
+    let mut program = Vec::new();
+    let mut errors = Vec::new();
+    // Each statement is parsed from where the last one ended, so the whole
+    // input is read once. `offset` turns positions in that rest of the
+    // input back into positions in the whole of it.
+    let mut pos = 0;
+    let mut offset = (0, 0);
+    while pos < input.len() {
+        let rest = &input[pos..];
+        let consumed = match LiponaParser::parse(Rule::partial_stmt, rest) {
+            Ok(mut pairs) => {
+                let Some(pair) = pairs.next() else { break };
+                let end = pair.as_span().end();
+                let Some(inner) = pair.into_inner().next() else {
+                    break;
+                };
+                match inner.as_rule() {
+                    Rule::strict_directive if program.is_empty() => program.push(Stmt::new(
+                        StmtKind::Strict,
+                        with_span_offset(offset, || span_of(&inner)),
+                    )),
+                    Rule::stmt => match with_span_offset(offset, || parse_stmt(inner)) {
+                        Ok(stmt) => program.push(stmt),
+                        Err(e) => errors.push(e),
+                    },
+                    _ => break,
+                }
+                end
+            }
+            Err(e) => {
+                errors.push(error_in(input, pos, offset, e).into());
+                match skip_statement(rest) {
+                    Some(skipped) => skipped,
+                    None => break,
+                }
+            }
+        };
+        offset = advance(offset, &rest[..consumed]);
+        pos += consumed;
+    }
+
+    if let Err(e) = resolve_types(&mut program, &mut HashMap::new()) {
+        errors.push(e);
+    }
+    // Resuming after a failure can meet the same error again.
+    errors.sort_by_key(|e| e.line_col().unwrap_or((usize::MAX, 0)));
+    errors.dedup_by(|a, b| a.line_col() == b.line_col() && a.to_string() == b.to_string());
+    ParseResult { program, errors }
+}
+
+/// How much of `rest`, which starts with a statement that failed to parse,
+/// to skip before trying the next one: the line it starts on, or if that
+/// line opens blocks (`open`), every line up to the one closing them
+/// (`pini`), so a broken function's body is not taken for top-level code.
+/// A line indented no deeper than the first that does not close a block
+/// (`pini`, `taso`) starts the next statement: the broken one most likely
+/// lost its `pini`. `None` if its blocks are still open at the end of the
+/// input.
+fn skip_statement(rest: &str) -> Option<usize> {
+    let start = rest.len() - rest.trim_start().len();
+    let indent = |line: &str| line.len() - line.trim_start_matches([' ', '\t']).len();
+    let first_indent = indent(&rest[rest[..start].rfind('\n').map_or(0, |n| n + 1)..]);
+    let mut depth = 0;
+    let mut end = start;
+    for (i, line) in rest[start..].split_inclusive('\n').enumerate() {
+        let code = line.trim();
+        if i > 0
+            && indent(line) <= first_indent
+            && !code.is_empty()
+            && !code.starts_with("//")
+            && !code.starts_with("pini")
+            && !code.starts_with("taso")
+        {
+            return Some(end);
+        }
+        end += line.len();
+        depth += block_depth(line);
+        if depth <= 0 {
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// The number of `open` minus the number of `pini` (or `pini_sitelen`)
+/// on `line`, outside strings and comments.
+fn block_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut code = String::new();
+    let mut chars = line.chars();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => in_string = !in_string,
+            '/' if !in_string && chars.as_str().starts_with('/') => break,
+            c if !in_string => code.push(c),
+            _ => {}
+        }
+    }
+    for word in code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')) {
+        match word {
+            "open" => depth += 1,
+            "pini" | "pini_sitelen" => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// Replace `nimi_nasin` aliases in annotations with the types they name.
+/// An alias can be used anywhere after its declaration.
+fn resolve_types(
+    stmts: &mut [Stmt],
+    aliases: &mut HashMap<String, Type>,
+) -> Result<(), ParseError> {
+    for stmt in stmts {
+        match &mut stmt.kind {
+            StmtKind::TypeAlias { name, ty } => {
+                resolve_type(ty, aliases, stmt.span)?;
+                if Type::from_name(name).is_some() {
+                    return Err(ParseError::BuiltinTypeAlias {
+                        name: name.clone(),
+                        span: stmt.span,
+                    });
+                }
+                aliases.insert(name.clone(), ty.clone());
+            }
+            StmtKind::Capability(capability) => {
+                if Type::from_name(&capability.name).is_some() {
+                    return Err(ParseError::BuiltinTypeAlias {
+                        name: capability.name.clone(),
+                        span: stmt.span,
+                    });
+                }
+                aliases.insert(
+                    capability.name.clone(),
+                    Type::Capability(Box::new(capability.clone())),
+                );
+            }
+            StmtKind::FuncDef {
+                param_types,
+                return_type,
+                body,
+                ..
+            } => {
+                resolve_signature(param_types, return_type, aliases, stmt.span)?;
+                resolve_types(body, aliases)?;
+            }
+            StmtKind::Assign { value: expr, .. }
+            | StmtKind::Declare { value: expr, .. }
+            | StmtKind::Return(expr)
+            | StmtKind::Expr(expr) => resolve_expr_types(expr, aliases)?,
+            StmtKind::Break { value, .. } => {
+                if let Some(expr) = value {
+                    resolve_expr_types(expr, aliases)?;
+                }
+            }
+            StmtKind::Guard { cond, value } => {
+                resolve_expr_types(cond, aliases)?;
+                resolve_expr_types(value, aliases)?;
+            }
+            StmtKind::If {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                resolve_expr_types(cond, aliases)?;
+                resolve_types(then_block, aliases)?;
+                if let Some(block) = else_block {
+                    resolve_types(block, aliases)?;
+                }
+            }
+            StmtKind::While {
+                cond,
+                body,
+                else_block,
+                ..
+            } => {
+                resolve_expr_types(cond, aliases)?;
+                resolve_types(body, aliases)?;
+                if let Some(block) = else_block {
+                    resolve_types(block, aliases)?;
+                }
+            }
+            StmtKind::For {
+                start, end, body, ..
+            } => {
+                resolve_expr_types(start, aliases)?;
+                resolve_expr_types(end, aliases)?;
+                resolve_types(body, aliases)?;
+            }
+            StmtKind::ForEach { iterable, body, .. } => {
+                resolve_expr_types(iterable, aliases)?;
+                resolve_types(body, aliases)?;
+            }
+            StmtKind::Global(_)
+            | StmtKind::Import(_)
+            | StmtKind::Continue { .. }
+            | StmtKind::Strict => {}
+        }
+    }
+    Ok(())
+}
+
+fn resolve_expr_types(
+    expr: &mut Expr,
+    aliases: &mut HashMap<String, Type>,
+) -> Result<(), ParseError> {
+    match &mut expr.kind {
+        ExprKind::Number(_) | ExprKind::Bool(_) | ExprKind::Var(_) => {}
+        ExprKind::TemplateString(parts) => {
+            for part in parts {
+                if let StringPart::Interpolation(expr) = part {
+                    resolve_expr_types(expr, aliases)?;
+                }
+            }
+        }
+        ExprKind::Binary { left, right, .. } => {
+            resolve_expr_types(left, aliases)?;
+            resolve_expr_types(right, aliases)?;
+        }
+        ExprKind::Neg(inner) | ExprKind::Not(inner) => resolve_expr_types(inner, aliases)?,
+        ExprKind::FuncCall { args, .. } => {
+            for arg in args {
+                resolve_expr_types(arg, aliases)?;
+            }
+        }
+        ExprKind::Lambda {
+            param_types,
+            return_type,
+            body,
+            ..
+        } => {
+            resolve_signature(param_types, return_type, aliases, expr.span)?;
+            resolve_types(body, aliases)?;
+        }
+        ExprKind::While {
+            cond,
+            body,
+            else_block,
+            ..
+        } => {
+            resolve_expr_types(cond, aliases)?;
+            resolve_types(body, aliases)?;
+            if let Some(block) = else_block {
+                resolve_types(block, aliases)?;
+            }
+        }
+        ExprKind::Block(body) => resolve_types(body, aliases)?,
+    }
+    Ok(())
+}
+
+fn resolve_signature(
+    param_types: &mut [Option<Type>],
+    return_type: &mut Option<Type>,
+    aliases: &HashMap<String, Type>,
+    span: Span,
+) -> Result<(), ParseError> {
+    for ty in param_types
+        .iter_mut()
+        .chain(std::iter::once(return_type))
+        .flatten()
+    {
+        resolve_type(ty, aliases, span)?;
+    }
+    Ok(())
+}
+
+/// `span` is that of the declaration the annotation belongs to.
+fn resolve_type(
+    ty: &mut Type,
+    aliases: &HashMap<String, Type>,
+    span: Span,
+) -> Result<(), ParseError> {
+    if let Type::Named(name) = ty {
+        *ty = aliases
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ParseError::UnknownType {
+                name: name.clone(),
+                span,
+            })?;
+    }
+    Ok(())
+}
+
 fn parse_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
+    let span = span_of(&pair);
     let inner = pair
         .into_inner()
         .next()
         .ok_or(ParseError::MissingInner(Rule::stmt))?;
 
-    match inner.as_rule() {
+    let kind = match inner.as_rule() {
         Rule::func_def => parse_func_def(inner),
         Rule::if_stmt => parse_if_stmt(inner),
+        Rule::guard_stmt => parse_guard_stmt(inner),
         Rule::while_stmt => parse_while_stmt(inner),
         Rule::return_stmt => parse_return_stmt(inner),
+        Rule::for_stmt => parse_for_stmt(inner),
+        Rule::for_each_stmt => parse_for_each_stmt(inner),
+        Rule::break_stmt => parse_break_stmt(inner),
+        Rule::continue_stmt => Ok(StmtKind::Continue {
+            label: inner
+                .into_inner()
+                .find(|p| p.as_rule() == Rule::continue_label)
+                .map(|p| p.as_str()["awen_sin".len()..].trim().to_string()),
+        }),
+        Rule::decl_stmt => parse_decl_stmt(inner),
+        Rule::global_stmt => Ok(StmtKind::Global(
+            inner
+                .into_inner()
+                .filter(|p| p.as_rule() == Rule::ident)
+                .map(|p| p.as_str().to_string())
+                .collect(),
+        )),
+        Rule::type_alias => {
+            // type_alias = { kw_nimi_nasin ~ ident ~ "=" ~ type_expr }
+            let mut name = None;
+            let mut ty = None;
+            for item in inner.into_inner() {
+                match item.as_rule() {
+                    Rule::ident => name = Some(item.as_str().to_string()),
+                    Rule::type_expr => ty = Some(parse_type_expr(item)?),
+                    _ => {}
+                }
+            }
+            match (name, ty) {
+                (Some(name), Some(ty)) => Ok(StmtKind::TypeAlias { name, ty }),
+                _ => Err(ParseError::MissingInner(Rule::type_alias)),
+            }
+        }
+        Rule::capability_def => parse_capability_def(inner),
+        Rule::import_stmt => Ok(StmtKind::Import(
+            inner
+                .into_inner()
+                .find(|p| p.as_rule() == Rule::module_path)
+                .ok_or(ParseError::MissingInner(Rule::import_stmt))?
+                .as_str()
+                .to_string(),
+        )),
+        Rule::data_stmt => parse_data_stmt(inner),
         Rule::assign_stmt => parse_assign_stmt(inner),
         Rule::expr_stmt => {
             let expr = parse_expr(
@@ -71,13 +579,14 @@ fn parse_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
                     .next()
                     .ok_or(ParseError::MissingInner(Rule::expr_stmt))?,
             )?;
-            Ok(Stmt::Expr(expr))
+            Ok(StmtKind::Expr(expr))
         }
         rule => Err(ParseError::UnexpectedRule(rule)),
-    }
+    }?;
+    Ok(Stmt::new(kind, span))
 }
 
-fn parse_func_def(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
+fn parse_func_def(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
     let mut inner = pair.into_inner();
     let name = inner
         .next()
@@ -126,7 +635,7 @@ fn parse_func_def(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError>
         }
     }
 
-    Ok(Stmt::FuncDef {
+    Ok(StmtKind::FuncDef {
         name,
         params,
         param_types,
@@ -142,10 +651,33 @@ fn parse_type_expr(pair: pest::iterators::Pair<Rule>) -> Result<Type, ParseError
         .next()
         .ok_or(ParseError::MissingInner(Rule::type_expr))?;
     let name = ident.as_str();
-    Type::from_name(name).ok_or_else(|| ParseError::UnknownType(name.to_string()))
+    // Other names may be `nimi_nasin` aliases, checked by resolve_types.
+    Ok(Type::from_name(name).unwrap_or_else(|| Type::Named(name.to_string())))
+}
+
+fn parse_capability_def(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    // capability_def = { kw_nasin_ilo ~ ident ~ "open" ~ capability_fn* ~ "pini" }
+    let mut name = None;
+    let mut functions = Vec::new();
+    for item in pair.into_inner() {
+        match item.as_rule() {
+            Rule::ident => name = Some(item.as_str().to_string()),
+            Rule::capability_fn => {
+                // capability_fn = { "ilo" ~ ident ~ "(" ~ (ident ~ ("," ~ ident)*)? ~ ")" }
+                let mut idents = item.into_inner().map(|p| p.as_str().to_string());
+                let fn_name = idents
+                    .next()
+                    .ok_or(ParseError::MissingInner(Rule::capability_fn))?;
+                functions.push((fn_name, idents.count()));
+            }
+            _ => {}
+        }
+    }
+    let name = name.ok_or(ParseError::MissingInner(Rule::capability_def))?;
+    Ok(StmtKind::Capability(Capability { name, functions }))
 }
 
-fn parse_if_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
+fn parse_if_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
     let mut inner = pair.into_inner();
     let cond = parse_expr(
         inner
@@ -176,15 +708,22 @@ fn parse_if_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError>
         }
     }
 
-    Ok(Stmt::If {
+    Ok(StmtKind::If {
         cond,
         then_block,
         else_block,
     })
 }
 
-fn parse_while_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
-    let mut inner = pair.into_inner();
+fn parse_while_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    let mut inner = pair.into_inner().peekable();
+    let label = match inner.peek() {
+        Some(p) if p.as_rule() == Rule::loop_label => inner
+            .next()
+            .and_then(|p| p.into_inner().find(|p| p.as_rule() == Rule::ident))
+            .map(|p| p.as_str().to_string()),
+        _ => None,
+    };
     let cond = parse_expr(
         inner
             .next()
@@ -192,27 +731,144 @@ fn parse_while_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseErro
     )?;
 
     let mut body = Vec::new();
+    let mut else_block = None;
     for item in inner {
         match item.as_rule() {
             Rule::stmt => body.push(parse_stmt(item)?),
+            Rule::else_block => {
+                else_block = Some(
+                    item.into_inner()
+                        .filter(|p| p.as_rule() == Rule::stmt)
+                        .map(parse_stmt)
+                        .collect::<Result<_, _>>()?,
+                );
+            }
             Rule::EOI => {}
             rule => return Err(ParseError::UnexpectedRule(rule)),
         }
     }
 
-    Ok(Stmt::While { cond, body })
+    Ok(StmtKind::While {
+        label,
+        cond,
+        body,
+        else_block,
+    })
+}
+
+fn parse_for_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    // for_stmt = { loop_label? ~ kw_tawa ~ ident ~ kw_tan ~ expr ~ kw_tawa ~ expr ~ "la" ~ "open" ~ stmt* ~ "pini" }
+    let mut label = None;
+    let mut var = None;
+    let mut bounds = Vec::new();
+    let mut body = Vec::new();
+    for item in pair.into_inner() {
+        match item.as_rule() {
+            Rule::loop_label => {
+                label = item
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::ident)
+                    .map(|p| p.as_str().to_string());
+            }
+            Rule::ident => var = Some(item.as_str().to_string()),
+            Rule::expr => bounds.push(parse_expr(item)?),
+            Rule::stmt => body.push(parse_stmt(item)?),
+            Rule::kw_tawa | Rule::kw_tan => {}
+            rule => return Err(ParseError::UnexpectedRule(rule)),
+        }
+    }
+    let var = var.ok_or(ParseError::MissingInner(Rule::for_stmt))?;
+    let mut bounds = bounds.into_iter();
+    let (Some(start), Some(end)) = (bounds.next(), bounds.next()) else {
+        return Err(ParseError::MissingInner(Rule::for_stmt));
+    };
+    Ok(StmtKind::For {
+        label,
+        var,
+        start,
+        end,
+        body,
+    })
+}
+
+fn parse_for_each_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    // for_each_stmt = { loop_label? ~ kw_tawa ~ ident ~ ("," ~ ident)? ~ kw_lon ~ expr ~ "la" ~ "open" ~ stmt* ~ "pini" }
+    let mut label = None;
+    let mut names = Vec::new();
+    let mut iterable = None;
+    let mut body = Vec::new();
+    for item in pair.into_inner() {
+        match item.as_rule() {
+            Rule::loop_label => {
+                label = item
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::ident)
+                    .map(|p| p.as_str().to_string());
+            }
+            Rule::ident => names.push(item.as_str().to_string()),
+            Rule::expr => iterable = Some(parse_expr(item)?),
+            Rule::stmt => body.push(parse_stmt(item)?),
+            Rule::kw_tawa | Rule::kw_lon => {}
+            rule => return Err(ParseError::UnexpectedRule(rule)),
+        }
+    }
+    let iterable = iterable.ok_or(ParseError::MissingInner(Rule::for_each_stmt))?;
+    let var = names
+        .pop()
+        .ok_or(ParseError::MissingInner(Rule::for_each_stmt))?;
+    Ok(StmtKind::ForEach {
+        label,
+        key: names.pop(),
+        var,
+        iterable,
+        body,
+    })
+}
+
+fn parse_break_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    // break_stmt = { (break_label | kw_pini_tawa) ~ (kw_e ~ expr)? }
+    // (`pini_awen` is accepted in place of `pini_tawa`, same length)
+    let mut label = None;
+    let mut value = None;
+    for item in pair.into_inner() {
+        match item.as_rule() {
+            // break_label is atomic: "pini_tawa" followed by the label.
+            Rule::break_label => {
+                label = Some(item.as_str()["pini_tawa".len()..].trim().to_string());
+            }
+            Rule::expr => value = Some(parse_expr(item)?),
+            _ => {}
+        }
+    }
+    Ok(StmtKind::Break { label, value })
 }
 
-fn parse_return_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
+fn parse_guard_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    // guard_stmt = { "wile" ~ expr ~ "taso" ~ "pana" ~ (kw_e ~ expr | expr) }
+    let mut inner = pair.into_inner().filter(|p| p.as_rule() != Rule::kw_e);
+    let cond = parse_expr(
+        inner
+            .next()
+            .ok_or(ParseError::MissingInner(Rule::guard_stmt))?,
+    )?;
+    let value = parse_expr(
+        inner
+            .next()
+            .ok_or(ParseError::MissingInner(Rule::guard_stmt))?,
+    )?;
+    Ok(StmtKind::Guard { cond, value })
+}
+
+fn parse_return_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
     let expr = parse_expr(
         pair.into_inner()
             .next()
             .ok_or(ParseError::MissingInner(Rule::return_stmt))?,
     )?;
-    Ok(Stmt::Return(expr))
+    Ok(StmtKind::Return(expr))
 }
 
-fn parse_assign_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseError> {
+fn parse_assign_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
     let mut inner = pair.into_inner();
     let target = inner
         .next()
@@ -225,27 +881,114 @@ fn parse_assign_stmt(pair: pest::iterators::Pair<Rule>) -> Result<Stmt, ParseErr
             .ok_or(ParseError::MissingInner(Rule::assign_stmt))?,
     )?;
 
-    Ok(Stmt::Assign { target, value })
+    Ok(StmtKind::Assign { target, value })
+}
+
+fn parse_data_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    let span = span_of(&pair);
+    let mut target = None;
+    let mut lines = Vec::new();
+    for item in pair.into_inner() {
+        match item.as_rule() {
+            Rule::ident => target = Some(item.as_str().to_string()),
+            Rule::data_line => lines.push(item.as_str().trim_end_matches(['\n', '\r'])),
+            Rule::data_end => {}
+            rule => return Err(ParseError::UnexpectedRule(rule)),
+        }
+    }
+    let target = target.ok_or(ParseError::MissingInner(Rule::data_stmt))?;
+    // Blank lines do not count towards the shared indentation.
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    let text = lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(StmtKind::Assign {
+        target,
+        value: Expr::new(
+            ExprKind::TemplateString(vec![StringPart::Literal(text)]),
+            span,
+        ),
+    })
+}
+
+fn parse_decl_stmt(pair: pest::iterators::Pair<Rule>) -> Result<StmtKind, ParseError> {
+    // decl_stmt = { kw_sin ~ ident ~ (kw_li ~ "jo" ~ kw_e | "jo") ~ expr }
+    let mut inner = pair
+        .into_inner()
+        .filter(|p| !matches!(p.as_rule(), Rule::kw_sin | Rule::kw_li | Rule::kw_e));
+    let name = inner
+        .next()
+        .ok_or(ParseError::MissingInner(Rule::decl_stmt))?
+        .as_str()
+        .to_string();
+    let value = parse_expr(
+        inner
+            .next()
+            .ok_or(ParseError::MissingInner(Rule::decl_stmt))?,
+    )?;
+
+    Ok(StmtKind::Declare { name, value })
 }
 
 fn parse_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     match pair.as_rule() {
         Rule::expr => parse_expr(
             pair.into_inner()
                 .next()
                 .ok_or(ParseError::MissingInner(Rule::expr))?,
         ),
+        Rule::or_expr => {
+            parse_binary_expr(pair, Rule::or_expr, |s| (s == "anu").then_some(BinOp::Or))
+        }
+        Rule::and_expr => {
+            parse_binary_expr(pair, Rule::and_expr, |s| (s == "en").then_some(BinOp::And))
+        }
+        Rule::not_expr => parse_not_expr(pair),
         Rule::comparison => parse_comparison(pair),
         Rule::add_expr => parse_add_expr(pair),
         Rule::mul_expr => parse_mul_expr(pair),
         Rule::unary_expr => parse_unary_expr(pair),
+        Rule::pow_expr => parse_pow_expr(pair),
         Rule::primary => parse_primary(pair),
         Rule::func_call => parse_func_call(pair),
         Rule::lambda => parse_lambda(pair),
+        Rule::block_expr => Ok(Expr::new(
+            ExprKind::Block(
+                pair.into_inner()
+                    .map(parse_stmt)
+                    .collect::<Result<_, _>>()?,
+            ),
+            span,
+        )),
+        Rule::while_stmt => match parse_while_stmt(pair)? {
+            StmtKind::While {
+                label,
+                cond,
+                body,
+                else_block,
+            } => Ok(Expr::new(
+                ExprKind::While {
+                    label,
+                    cond: Box::new(cond),
+                    body,
+                    else_block,
+                },
+                span,
+            )),
+            _ => Err(ParseError::UnexpectedRule(Rule::while_stmt)),
+        },
         Rule::number => parse_number(pair),
         Rule::string => parse_string(pair),
         Rule::boolean => parse_boolean(pair),
-        Rule::ident => Ok(Expr::Var(pair.as_str().to_string())),
+        Rule::ident => Ok(Expr::new(ExprKind::Var(pair.as_str().to_string()), span)),
         rule => Err(ParseError::UnexpectedRule(rule)),
     }
 }
@@ -285,11 +1028,85 @@ fn parse_comparison(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseErro
         .ok_or(ParseError::MissingInner(Rule::comparison))?;
     let right = parse_expr(right_pair)?;
 
-    Ok(Expr::Binary {
-        left: Box::new(left),
-        op,
-        right: Box::new(right),
-    })
+    let span = left.span;
+    Ok(Expr::new(
+        ExprKind::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+            span: span_of(&comp_op),
+        },
+        span,
+    ))
+}
+
+thread_local! {
+    /// Lines before the text being parsed, and columns before it on its
+    /// first line. Zero except while `parse_partial` parses the rest of an
+    /// input (see `with_span_offset`).
+    static SPAN_OFFSET: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+/// Start position of a pair as a [`Span`].
+fn span_of(pair: &pest::iterators::Pair<Rule>) -> Span {
+    let (line, col) = pair.line_col();
+    let (lines, cols) = SPAN_OFFSET.get();
+    if line == 1 {
+        Span {
+            line: lines + 1,
+            col: col + cols,
+        }
+    } else {
+        Span {
+            line: line + lines,
+            col,
+        }
+    }
+}
+
+/// Run `f` with `span_of` shifted by `offset`, for text cut from a larger
+/// input.
+fn with_span_offset<T>(offset: (usize, usize), f: impl FnOnce() -> T) -> T {
+    let outer = SPAN_OFFSET.replace(offset);
+    let result = f();
+    SPAN_OFFSET.set(outer);
+    result
+}
+
+/// The span offset after `text`, for what follows it.
+fn advance((lines, cols): (usize, usize), text: &str) -> (usize, usize) {
+    match text.rfind('\n') {
+        Some(newline) => (
+            lines + text.matches('\n').count(),
+            text[newline + 1..].chars().count(),
+        ),
+        None => (lines, cols + text.chars().count()),
+    }
+}
+
+/// `error`, raised parsing `input[pos..]` with span offset `offset`, as an
+/// error in the whole of `input`: positions count from its start, and the
+/// line shown is the whole line.
+fn error_in(
+    input: &str,
+    pos: usize,
+    offset: (usize, usize),
+    error: pest::error::Error<Rule>,
+) -> pest::error::Error<Rule> {
+    let at = pos
+        + match error.location {
+            InputLocation::Pos(at) | InputLocation::Span((at, _)) => at,
+        };
+    let line_start = input[..at].rfind('\n').map_or(0, |newline| newline + 1);
+    let lines = offset.0 + input[pos..line_start.max(pos)].matches('\n').count();
+    let position = Position::new(&input[line_start..], at - line_start)
+        .expect("a parse error lies inside its input");
+    let mut error = pest::error::Error::new_from_pos(error.variant, position);
+    if let LineColLocation::Pos((line, col)) = error.line_col {
+        error.line_col = LineColLocation::Pos((line + lines, col));
+    }
+    error.location = InputLocation::Pos(at);
+    error
 }
 
 fn parse_binary_expr(
@@ -305,13 +1122,19 @@ fn parse_binary_expr(
             return Err(ParseError::UnexpectedRule(op_pair.as_rule()));
         };
 
+        let op_span = span_of(&op_pair);
         let right_pair = inner.next().ok_or(ParseError::MissingInner(rule))?;
         let right = parse_expr(right_pair)?;
-        left = Expr::Binary {
-            left: Box::new(left),
-            op,
-            right: Box::new(right),
-        };
+        let span = left.span;
+        left = Expr::new(
+            ExprKind::Binary {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+                span: op_span,
+            },
+            span,
+        );
     }
 
     Ok(left)
@@ -329,11 +1152,38 @@ fn parse_mul_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError>
     parse_binary_expr(pair, Rule::mul_expr, |s| match s {
         "*" => Some(BinOp::Mul),
         "/" => Some(BinOp::Div),
+        "%" => Some(BinOp::Mod),
         _ => None,
     })
 }
 
+fn parse_pow_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    // The right operand is a unary_expr containing the rest of the chain,
+    // so `**` groups to the right.
+    parse_binary_expr(pair, Rule::pow_expr, |s| (s == "**").then_some(BinOp::Pow))
+}
+
+fn parse_not_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    // not_expr = { not_op ~ not_expr | comparison }
+    let span = span_of(&pair);
+    let mut inner = pair.into_inner();
+    let first = inner
+        .next()
+        .ok_or(ParseError::MissingInner(Rule::not_expr))?;
+    if first.as_rule() != Rule::not_op {
+        return parse_expr(first);
+    }
+    let operand = inner
+        .next()
+        .ok_or(ParseError::MissingInner(Rule::not_expr))?;
+    Ok(Expr::new(
+        ExprKind::Not(Box::new(parse_expr(operand)?)),
+        span,
+    ))
+}
+
 fn parse_unary_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     let mut inner = pair.into_inner().peekable();
 
     // Check if there's a negation operator by peeking at the first element
@@ -345,7 +1195,7 @@ fn parse_unary_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseErro
             .next()
             .ok_or(ParseError::MissingInner(Rule::unary_expr))?;
         let expr = parse_expr(primary)?;
-        Ok(Expr::Neg(Box::new(expr)))
+        Ok(Expr::new(ExprKind::Neg(Box::new(expr)), span))
     } else {
         let primary = inner
             .next()
@@ -363,6 +1213,7 @@ fn parse_primary(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError>
 }
 
 fn parse_lambda(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     // lambda = { "ilo" ~ "(" ~ param_list? ~ ")" ~ return_type? ~ "open" ~ stmt* ~ "pini" }
     // Same shape as func_def but without a leading identifier.
     let mut params: Vec<String> = Vec::new();
@@ -402,15 +1253,19 @@ fn parse_lambda(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
         }
     }
 
-    Ok(Expr::Lambda {
-        params,
-        param_types,
-        return_type,
-        body,
-    })
+    Ok(Expr::new(
+        ExprKind::Lambda {
+            params,
+            param_types,
+            return_type,
+            body,
+        },
+        span,
+    ))
 }
 
 fn parse_func_call(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     let mut inner = pair.into_inner();
     let name = inner
         .next()
@@ -430,23 +1285,29 @@ fn parse_func_call(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError
         }
     }
 
-    Ok(Expr::FuncCall { name, args })
+    Ok(Expr::new(ExprKind::FuncCall { name, args }, span))
 }
 
 fn parse_number(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     let s = pair.as_str();
-    let n = s
-        .parse::<f64>()
-        .map_err(|_| ParseError::InvalidNumber(s.to_string()))?;
+    let n = s.parse::<f64>().map_err(|_| ParseError::InvalidNumber {
+        text: s.to_string(),
+        span,
+    })?;
 
     if !n.is_finite() {
-        return Err(ParseError::InvalidNumber(s.to_string()));
+        return Err(ParseError::InvalidNumber {
+            text: s.to_string(),
+            span,
+        });
     }
 
-    Ok(Expr::Number(n))
+    Ok(Expr::new(ExprKind::Number(n), span))
 }
 
 fn parse_string(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     let mut parts = Vec::new();
 
     for inner in pair.into_inner() {
@@ -476,7 +1337,7 @@ fn parse_string(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
         }
     }
 
-    Ok(Expr::TemplateString(parts))
+    Ok(Expr::new(ExprKind::TemplateString(parts), span))
 }
 
 fn unescape_string(s: &str) -> String {
@@ -506,9 +1367,10 @@ fn unescape_string(s: &str) -> String {
 }
 
 fn parse_boolean(pair: pest::iterators::Pair<Rule>) -> Result<Expr, ParseError> {
+    let span = span_of(&pair);
     match pair.as_str() {
-        "lon" => Ok(Expr::Bool(true)),
-        "ala" => Ok(Expr::Bool(false)),
+        "lon" => Ok(Expr::new(ExprKind::Bool(true), span)),
+        "ala" => Ok(Expr::new(ExprKind::Bool(false), span)),
         other => Err(ParseError::InvalidBoolean(other.to_string())),
     }
 }
@@ -549,8 +1411,8 @@ mod tests {
         "#;
         let result = parse(code).unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
-            Stmt::FuncDef {
+        match &result[0].kind {
+            StmtKind::FuncDef {
                 param_types,
                 return_type,
                 ..
@@ -573,8 +1435,8 @@ mod tests {
             pini
         "#;
         let result = parse(code).unwrap();
-        match &result[0] {
-            Stmt::FuncDef {
+        match &result[0].kind {
+            StmtKind::FuncDef {
                 param_types,
                 return_type,
                 ..
@@ -596,8 +1458,454 @@ mod tests {
         "#;
         let err = parse(code).unwrap_err();
         match err {
-            ParseError::UnknownType(name) => assert_eq!(name, "bogus"),
+            ParseError::UnknownType { name, span } => {
+                assert_eq!(name, "bogus");
+                assert_eq!(span, Span { line: 2, col: 13 });
+            }
             other => panic!("expected UnknownType, got {:?}", other),
         }
     }
+
+    #[test]
+    fn test_binary_span_points_at_operator() {
+        let result = parse("x jo 1\ny jo x  * 2").unwrap();
+        match &result[1].kind {
+            StmtKind::Assign { value, .. } => {
+                let ExprKind::Binary { op, span, .. } = &value.kind else {
+                    panic!("expected Binary, got {:?}", value);
+                };
+                assert_eq!(*op, BinOp::Mul);
+                assert_eq!(*span, Span { line: 2, col: 9 });
+                // The expression itself starts at its left operand.
+                assert_eq!(value.span, Span { line: 2, col: 6 });
+            }
+            other => panic!("expected Binary assignment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pow_binds_tighter_and_groups_right() {
+        let result = parse("x jo 2 * 3 ** 2 ** 2 % 5").unwrap();
+        let StmtKind::Assign { value, .. } = &result[0].kind else {
+            panic!("expected assignment, got {:?}", result[0]);
+        };
+        // ((2 * (3 ** (2 ** 2))) % 5)
+        let ExprKind::Binary { left, op, .. } = &value.kind else {
+            panic!("expected Binary, got {:?}", value);
+        };
+        assert_eq!(*op, BinOp::Mod);
+        let ExprKind::Binary { right, op, .. } = &left.kind else {
+            panic!("expected Binary, got {:?}", left);
+        };
+        assert_eq!(*op, BinOp::Mul);
+        let ExprKind::Binary { right, op, .. } = &right.kind else {
+            panic!("expected Binary, got {:?}", right);
+        };
+        assert_eq!(*op, BinOp::Pow);
+        assert!(matches!(
+            right.kind,
+            ExprKind::Binary { op: BinOp::Pow, .. }
+        ));
+    }
+
+    #[test]
+    fn test_every_node_has_a_span() {
+        let program = parse("ilo f (a) open\n  pana a\npini\n\n  toki(f(1), x)").unwrap();
+        assert_eq!(program[0].span, Span { line: 1, col: 1 });
+        let StmtKind::FuncDef { body, .. } = &program[0].kind else {
+            panic!("expected FuncDef");
+        };
+        assert_eq!(body[0].span, Span { line: 2, col: 3 });
+        assert_eq!(program[1].span, Span { line: 5, col: 3 });
+        let StmtKind::Expr(call) = &program[1].kind else {
+            panic!("expected expression statement");
+        };
+        let ExprKind::FuncCall { args, .. } = &call.kind else {
+            panic!("expected call");
+        };
+        assert_eq!(args[0].span, Span { line: 5, col: 8 });
+        assert_eq!(args[1].span, Span { line: 5, col: 14 });
+    }
+
+    #[test]
+    fn test_strict_directive_and_declaration() {
+        let program = parse("o awen nasin\nsin x jo 1\nsinpin jo 2\nsin y li jo e x").unwrap();
+        assert_eq!(program.len(), 4);
+        assert_eq!(program[0].kind, StmtKind::Strict);
+        assert!(matches!(&program[1].kind, StmtKind::Declare { name, .. } if name == "x"));
+        assert!(matches!(&program[2].kind, StmtKind::Assign { target, .. } if target == "sinpin"));
+        assert!(matches!(
+            &program[3].kind,
+            StmtKind::Declare { name, value } if name == "y" && value.kind == ExprKind::Var("x".into())
+        ));
+
+        // The directive is only recognized at the top of a program.
+        let later = parse("x jo 1\no awen nasin").unwrap();
+        assert!(!later.iter().any(|stmt| stmt.kind == StmtKind::Strict));
+    }
+
+    #[test]
+    fn test_guard_is_not_a_while_loop() {
+        let program = parse("wile x suli 0 taso pana e ala\nwile lon taso pana 1").unwrap();
+        assert!(matches!(
+            &program[0].kind,
+            StmtKind::Guard { value, .. } if value.kind == ExprKind::Bool(false)
+        ));
+        assert!(matches!(
+            &program[1].kind,
+            StmtKind::Guard { value, .. } if value.kind == ExprKind::Number(1.0)
+        ));
+    }
+
+    #[test]
+    fn test_block_expression() {
+        let program = parse("x jo open\n  sin t jo 2\n  t * 3\npini").unwrap();
+        let StmtKind::Assign { value, .. } = &program[0].kind else {
+            panic!("expected assignment, got {:?}", program[0]);
+        };
+        let ExprKind::Block(body) = &value.kind else {
+            panic!("expected block, got {:?}", value);
+        };
+        assert_eq!(body.len(), 2);
+        assert!(matches!(
+            &body[1].kind,
+            StmtKind::Expr(Expr {
+                kind: ExprKind::Binary { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_while_else_and_break() {
+        let source = "x jo wile lon la open\n  pini_tawa e 5\npini taso open\n  0\npini\nwile lon la open\n  pini_tawa\npini";
+        let program = parse(source).unwrap();
+        let StmtKind::Assign { value, .. } = &program[0].kind else {
+            panic!("expected assignment, got {:?}", program[0]);
+        };
+        let ExprKind::While {
+            body,
+            else_block: Some(else_block),
+            ..
+        } = &value.kind
+        else {
+            panic!("expected while expression, got {:?}", value);
+        };
+        assert!(matches!(
+            &body[0].kind,
+            StmtKind::Break {
+                label: None,
+                value: Some(Expr {
+                    kind: ExprKind::Number(_),
+                    ..
+                })
+            }
+        ));
+        assert_eq!(else_block.len(), 1);
+
+        let StmtKind::While {
+            body,
+            else_block: None,
+            ..
+        } = &program[1].kind
+        else {
+            panic!("expected while statement, got {:?}", program[1]);
+        };
+        assert!(matches!(
+            body[0].kind,
+            StmtKind::Break {
+                label: None,
+                value: None
+            }
+        ));
+    }
+
+    #[test]
+    fn test_type_alias_resolves_in_annotations() {
+        let program = parse(
+            "nimi_nasin Nimi = sitelen\nnimi_nasin Nimi_Lili = Nimi\nilo toki_e (n: Nimi_Lili) -> Nimi open\n  pana n\npini",
+        )
+        .unwrap();
+        match &program[2].kind {
+            StmtKind::FuncDef {
+                param_types,
+                return_type,
+                ..
+            } => {
+                assert_eq!(param_types, &vec![Some(Type::Sitelen)]);
+                assert_eq!(return_type, &Some(Type::Sitelen));
+            }
+            other => panic!("expected function, got {:?}", other),
+        }
+
+        // An alias must be declared before it is used.
+        let err = parse("ilo f (n: Nimi) open\npini\nnimi_nasin Nimi = sitelen").unwrap_err();
+        assert!(matches!(err, ParseError::UnknownType { name, .. } if name == "Nimi"));
+        assert!(matches!(
+            parse("nimi_nasin nanpa = sitelen"),
+            Err(ParseError::BuiltinTypeAlias { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_partial_keeps_good_statements() {
+        let result = parse_partial("x jo 1\n) 2\nz jo 3\nilo f () open\n  pana 1\npini\n");
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.program.len(), 3);
+        assert!(matches!(&result.program[2].kind, StmtKind::FuncDef { name, .. } if name == "f"));
+        assert_eq!(result.program[2].span, Span { line: 4, col: 1 });
+
+        let result = parse_partial("o awen nasin\nx jo 1");
+        assert!(result.errors.is_empty());
+        assert_eq!(result.program.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_partial_positions_are_those_of_the_whole_input() {
+        let result = parse_partial("a jo 1  b jo 2\n) 3\nc jo 4  d jo (5)\ne jo 6 ) 7");
+        let spans: Vec<Span> = result.program.iter().map(|stmt| stmt.span).collect();
+        assert_eq!(
+            spans,
+            [
+                Span { line: 1, col: 1 },
+                Span { line: 1, col: 9 },
+                Span { line: 3, col: 1 },
+                Span { line: 3, col: 9 },
+                Span { line: 4, col: 1 },
+            ]
+        );
+        let StmtKind::Assign { value, .. } = &result.program[3].kind else {
+            panic!("expected an assignment");
+        };
+        assert_eq!(value.span, Span { line: 3, col: 15 });
+
+        let errors: Vec<_> = result
+            .errors
+            .iter()
+            .map(|e| match e {
+                ParseError::Pest(e) => (e.line_col.clone(), e.line().to_string()),
+                other => panic!("expected a pest error, got {other}"),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (LineColLocation::Pos((2, 1)), ") 3".to_string()),
+                (LineColLocation::Pos((4, 8)), "e jo 6 ) 7".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_partial_skips_broken_blocks_whole() {
+        let result = parse_partial(
+            "a jo 1\n\
+             ilo f () open\n  x jo (\n  toki(\"pini open\") // pini\npini\n\
+             b jo 2\n\
+             ilo g () open\n  wile lon la open\n    y jo )\n  pini\npini\n\
+             c jo 3\n\
+             ilo k (a open\n\
+             d jo 4\n\
+             ilo h () open\n  z jo 1 +\n",
+        );
+        // Nothing from the broken bodies is taken for top-level code, and
+        // a header that lost its `pini` does not swallow what follows.
+        let names: Vec<&str> = result
+            .program
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::Assign { target, .. } => target.as_str(),
+                other => panic!("expected an assignment, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(names, ["a", "b", "c", "d"]);
+
+        // One error per broken statement, in order, in words.
+        let errors: Vec<(LineColLocation, String)> = result
+            .errors
+            .iter()
+            .map(|e| match e {
+                ParseError::Pest(e) => (e.line_col.clone(), e.variant.message().to_string()),
+                other => panic!("expected a pest error, got {other}"),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    LineColLocation::Pos((5, 1)),
+                    "expected an operator".to_string()
+                ),
+                (
+                    LineColLocation::Pos((9, 10)),
+                    "expected an expression".to_string()
+                ),
+                (
+                    LineColLocation::Pos((13, 1)),
+                    "expected a statement".to_string()
+                ),
+                (
+                    LineColLocation::Pos((17, 1)),
+                    "expected an expression".to_string()
+                ),
+            ]
+        );
+
+        let err = parse("x jo )").unwrap_err();
+        assert!(
+            err.to_string().ends_with("= expected an expression"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_partial_reads_long_input_once() {
+        // Re-reading everything before each statement took minutes here.
+        let input = format!(") 1\n{}", "x jo 1\n".repeat(20_000));
+        let result = parse_partial(&input);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.program.len(), 20_000);
+        assert_eq!(
+            result.program[19_999].span,
+            Span {
+                line: 20_001,
+                col: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let program = parse("tawa ale: tawa i tan 0 tawa n + 1 la open\n  toki(i)\npini").unwrap();
+        match &program[0].kind {
+            StmtKind::For {
+                label: Some(label),
+                var,
+                start,
+                end,
+                body,
+            } => {
+                assert_eq!(label, "ale");
+                assert_eq!(var, "i");
+                assert!(matches!(start.kind, ExprKind::Number(_)));
+                assert!(matches!(end.kind, ExprKind::Binary { .. }));
+                assert_eq!(body.len(), 1);
+            }
+            other => panic!("expected for loop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let program = parse("a jo ala x suli 1 en y anu z\nb jo ala\nc jo ala (lon)").unwrap();
+        let StmtKind::Assign { value, .. } = &program[0].kind else {
+            panic!("expected assignment, got {:?}", program[0]);
+        };
+        // (ala (x suli 1)) en y, then anu z
+        let ExprKind::Binary {
+            left,
+            op: BinOp::Or,
+            ..
+        } = &value.kind
+        else {
+            panic!("expected anu at the top, got {value:?}");
+        };
+        let ExprKind::Binary {
+            left,
+            op: BinOp::And,
+            ..
+        } = &left.kind
+        else {
+            panic!("expected en under anu, got {left:?}");
+        };
+        assert!(matches!(&left.kind, ExprKind::Not(inner)
+            if matches!(inner.kind, ExprKind::Binary { op: BinOp::Gt, .. })));
+        assert!(matches!(&program[1].kind, StmtKind::Assign { value, .. }
+            if value.kind == ExprKind::Bool(false)));
+        assert!(matches!(&program[2].kind, StmtKind::Assign { value, .. }
+            if matches!(value.kind, ExprKind::Not(_))));
+    }
+
+    #[test]
+    fn test_for_each_loop() {
+        let program = parse("tawa x lon kulupu_sin(1, 2) la open\n  toki(x)\npini\ntawa ale: tawa k, v lon m la open\npini").unwrap();
+        assert!(matches!(
+            &program[0].kind,
+            StmtKind::ForEach { label: None, key: None, var, iterable, body }
+                if var == "x" && matches!(iterable.kind, ExprKind::FuncCall { .. }) && body.len() == 1
+        ));
+        assert!(matches!(
+            &program[1].kind,
+            StmtKind::ForEach { label: Some(l), key: Some(k), var, .. }
+                if l == "ale" && k == "k" && var == "v"
+        ));
+    }
+
+    #[test]
+    fn test_loop_labels() {
+        let source = "tawa ale: wile lon la open\n  wile lon la open\n    pini_tawa ale e 1\n    pini_tawa\n  pini\npini";
+        let program = parse(source).unwrap();
+        let StmtKind::While {
+            label: Some(label),
+            body,
+            ..
+        } = &program[0].kind
+        else {
+            panic!("expected labeled while, got {:?}", program[0]);
+        };
+        assert_eq!(label, "ale");
+        let StmtKind::While {
+            label: None, body, ..
+        } = &body[0].kind
+        else {
+            panic!("expected inner while");
+        };
+        assert!(
+            matches!(&body[0].kind, StmtKind::Break { label: Some(l), value: Some(_) } if l == "ale")
+        );
+        assert!(matches!(
+            &body[1].kind,
+            StmtKind::Break {
+                label: None,
+                value: None
+            }
+        ));
+
+        let program =
+            parse("wile lon la open\n  awen_sin ale\n  awen_sin\n  pini_awen e 2\npini").unwrap();
+        let StmtKind::While { body, .. } = &program[0].kind else {
+            panic!("expected while, got {:?}", program[0]);
+        };
+        assert!(matches!(&body[0].kind, StmtKind::Continue { label: Some(l) } if l == "ale"));
+        assert!(matches!(&body[1].kind, StmtKind::Continue { label: None }));
+        assert!(matches!(
+            &body[2].kind,
+            StmtKind::Break {
+                label: None,
+                value: Some(_)
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_text_block() {
+        let program = parse(
+            "ilo f () open\n    sitelen data open\n        {\"a\": 1}\r\n\n          \\n \"b\"\n      pini_sitelen nimi\n    pini_sitelen\n    pana data\npini",
+        )
+        .unwrap();
+        let StmtKind::FuncDef { body, .. } = &program[0].kind else {
+            panic!("expected a function");
+        };
+        let StmtKind::Assign { target, value } = &body[0].kind else {
+            panic!("expected an assignment, got {:?}", body[0]);
+        };
+        assert_eq!(target, "data");
+        assert_eq!(
+            value.kind,
+            ExprKind::TemplateString(vec![StringPart::Literal(
+                "  {\"a\": 1}\n\n    \\n \"b\"\npini_sitelen nimi".to_string()
+            )])
+        );
+        assert!(parse("sitelen x open\nmu").is_err());
+    }
 }
@@ -0,0 +1,497 @@
+//! Turn an AST back into Lipona source.
+//!
+//! For tools that generate code (`lipona new`, transpilers targeting
+//! Lipona): build the program with the constructors in [`crate::ast`] and
+//! call `to_source`. The output parses back to a program that does the
+//! same thing, with operands parenthesized only where precedence needs it.
+//! Comments are not part of the AST, so they are not kept.
+
+use crate::ast::{BinOp, Block, Expr, ExprKind, Stmt, StmtKind, StringPart, Type};
+
+const INDENT: &str = "    ";
+
+pub trait ToSource {
+    fn to_source(&self) -> String;
+}
+
+/// A whole program: one statement per line, with a blank line around each
+/// top-level function definition.
+impl ToSource for [Stmt] {
+    fn to_source(&self) -> String {
+        let mut out = String::new();
+        for (i, stmt) in self.iter().enumerate() {
+            let is_func = matches!(stmt.kind, StmtKind::FuncDef { .. });
+            let after_func = i > 0 && matches!(self[i - 1].kind, StmtKind::FuncDef { .. });
+            if i > 0 && (is_func || after_func) {
+                out.push('\n');
+            }
+            write_stmt(&mut out, stmt, 0);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl ToSource for Stmt {
+    fn to_source(&self) -> String {
+        let mut out = String::new();
+        write_stmt(&mut out, self, 0);
+        out
+    }
+}
+
+impl ToSource for Expr {
+    fn to_source(&self) -> String {
+        let mut out = String::new();
+        write_expr(&mut out, self, 0);
+        out
+    }
+}
+
+fn write_block(out: &mut String, block: &Block, depth: usize) {
+    out.push_str("open\n");
+    for stmt in block {
+        write_stmt(out, stmt, depth + 1);
+        out.push('\n');
+    }
+    out.push_str(&INDENT.repeat(depth));
+    out.push_str("pini");
+}
+
+fn write_else(out: &mut String, else_block: Option<&Block>, depth: usize) {
+    if let Some(else_block) = else_block {
+        out.push_str(" taso ");
+        write_block(out, else_block, depth);
+    }
+}
+
+fn write_label(out: &mut String, label: Option<&str>) {
+    if let Some(label) = label {
+        out.push_str(&format!("tawa {label}: "));
+    }
+}
+
+fn write_params(out: &mut String, params: &[String], types: &[Option<Type>]) {
+    let params: Vec<String> = params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| match types.get(i).cloned().flatten() {
+            Some(ty) => format!("{param}: {ty}"),
+            None => param.clone(),
+        })
+        .collect();
+    out.push_str(&format!("({})", params.join(", ")));
+}
+
+fn write_return_type(out: &mut String, return_type: Option<&Type>) {
+    if let Some(ty) = return_type {
+        out.push_str(&format!(" -> {ty}"));
+    }
+}
+
+fn write_stmt(out: &mut String, stmt: &Stmt, depth: usize) {
+    out.push_str(&INDENT.repeat(depth));
+    match &stmt.kind {
+        StmtKind::Assign { target, value } => match text_block(value) {
+            Some(text) => {
+                out.push_str(&format!("sitelen {target} open\n"));
+                for line in text.split('\n') {
+                    if !line.is_empty() {
+                        out.push_str(&INDENT.repeat(depth + 1));
+                        out.push_str(line);
+                    }
+                    out.push('\n');
+                }
+                out.push_str(&INDENT.repeat(depth));
+                out.push_str("pini_sitelen");
+            }
+            None => {
+                out.push_str(&format!("{target} jo "));
+                write_expr(out, value, depth);
+            }
+        },
+        StmtKind::Declare { name, value } => {
+            out.push_str(&format!("sin {name} jo "));
+            write_expr(out, value, depth);
+        }
+        StmtKind::Global(names) => out.push_str(&format!("ma {}", names.join(", "))),
+        StmtKind::If {
+            cond,
+            then_block,
+            else_block,
+        } => {
+            write_expr(out, cond, depth);
+            out.push_str(" la ");
+            write_block(out, then_block, depth);
+            write_else(out, else_block.as_ref(), depth);
+        }
+        StmtKind::Guard { cond, value } => {
+            out.push_str("wile ");
+            write_expr(out, cond, depth);
+            out.push_str(" taso pana ");
+            write_expr(out, value, depth);
+        }
+        StmtKind::While {
+            label,
+            cond,
+            body,
+            else_block,
+        } => write_while(
+            out,
+            label.as_deref(),
+            cond,
+            body,
+            else_block.as_ref(),
+            depth,
+        ),
+        StmtKind::For {
+            label,
+            var,
+            start,
+            end,
+            body,
+        } => {
+            write_label(out, label.as_deref());
+            out.push_str(&format!("tawa {var} tan "));
+            write_expr(out, start, depth);
+            out.push_str(" tawa ");
+            write_expr(out, end, depth);
+            out.push_str(" la ");
+            write_block(out, body, depth);
+        }
+        StmtKind::ForEach {
+            label,
+            key,
+            var,
+            iterable,
+            body,
+        } => {
+            write_label(out, label.as_deref());
+            out.push_str("tawa ");
+            if let Some(key) = key {
+                out.push_str(&format!("{key}, "));
+            }
+            out.push_str(&format!("{var} lon "));
+            write_expr(out, iterable, depth);
+            out.push_str(" la ");
+            write_block(out, body, depth);
+        }
+        StmtKind::TypeAlias { name, ty } => out.push_str(&format!("nimi_nasin {name} = {ty}")),
+        StmtKind::Capability(capability) => {
+            out.push_str(&format!("nasin_ilo {} open\n", capability.name));
+            for (name, arity) in &capability.functions {
+                // Only the parameter count is kept.
+                let params = vec!["ijo"; *arity].join(", ");
+                out.push_str(&format!(
+                    "{}ilo {name} ({params})\n",
+                    INDENT.repeat(depth + 1)
+                ));
+            }
+            out.push_str(&INDENT.repeat(depth));
+            out.push_str("pini");
+        }
+        StmtKind::Import(path) => out.push_str(&format!("kepeken {path}")),
+        StmtKind::Break { label, value } => {
+            out.push_str("pini_tawa");
+            if let Some(label) = label {
+                out.push_str(&format!(" {label}"));
+            }
+            if let Some(value) = value {
+                out.push_str(" e ");
+                write_expr(out, value, depth);
+            }
+        }
+        StmtKind::Continue { label } => {
+            out.push_str("awen_sin");
+            if let Some(label) = label {
+                out.push_str(&format!(" {label}"));
+            }
+        }
+        StmtKind::FuncDef {
+            name,
+            params,
+            param_types,
+            return_type,
+            body,
+        } => {
+            out.push_str(&format!("ilo {name} "));
+            write_params(out, params, param_types);
+            write_return_type(out, return_type.as_ref());
+            out.push(' ');
+            write_block(out, body, depth);
+        }
+        StmtKind::Return(value) => {
+            out.push_str("pana ");
+            write_expr(out, value, depth);
+        }
+        StmtKind::Expr(expr) => write_expr(out, expr, depth),
+        StmtKind::Strict => out.push_str("o awen nasin"),
+    }
+}
+
+fn write_while(
+    out: &mut String,
+    label: Option<&str>,
+    cond: &Expr,
+    body: &Block,
+    else_block: Option<&Block>,
+    depth: usize,
+) {
+    write_label(out, label);
+    out.push_str("wile ");
+    write_expr(out, cond, depth);
+    out.push_str(" la ");
+    write_block(out, body, depth);
+    write_else(out, else_block, depth);
+}
+
+// Binding strength, loosest first, following the grammar's expression rules.
+const OR: u8 = 1;
+const AND: u8 = 2;
+const NOT: u8 = 3;
+const COMPARISON: u8 = 4;
+const ADD: u8 = 5;
+const MUL: u8 = 6;
+const NEG: u8 = 7;
+const POW: u8 = 8;
+const PRIMARY: u8 = 9;
+
+fn op_strength(op: BinOp) -> u8 {
+    match op {
+        BinOp::Or => OR,
+        BinOp::And => AND,
+        BinOp::Gt | BinOp::Lt | BinOp::Ge | BinOp::Le | BinOp::Eq => COMPARISON,
+        BinOp::Add | BinOp::Sub => ADD,
+        BinOp::Mul | BinOp::Div | BinOp::Mod => MUL,
+        BinOp::Pow => POW,
+    }
+}
+
+fn strength(expr: &Expr) -> u8 {
+    match &expr.kind {
+        ExprKind::Binary { op, .. } => op_strength(*op),
+        ExprKind::Not(_) => NOT,
+        // Written as a subtraction, see `write_expr`.
+        ExprKind::Neg(_) => ADD,
+        ExprKind::Number(n) if *n < 0.0 => ADD,
+        _ => PRIMARY,
+    }
+}
+
+/// Write `expr` where the grammar expects something binding at least as
+/// tightly as `min`, adding parentheses if it binds more loosely.
+fn write_operand(out: &mut String, expr: &Expr, min: u8, depth: usize) {
+    if strength(expr) < min {
+        out.push('(');
+        write_expr(out, expr, depth);
+        out.push(')');
+    } else {
+        write_expr(out, expr, depth);
+    }
+}
+
+fn write_expr(out: &mut String, expr: &Expr, depth: usize) {
+    match &expr.kind {
+        ExprKind::Number(n) if *n < 0.0 => {
+            // The parser drops a leading `-`, so a negative number is
+            // written as a subtraction.
+            out.push_str(&format!("0 - {}", -n));
+        }
+        ExprKind::Number(n) => out.push_str(&n.to_string()),
+        ExprKind::TemplateString(parts) => {
+            out.push('"');
+            for part in parts {
+                match part {
+                    StringPart::Literal(text) => out.push_str(&escape(text)),
+                    StringPart::Interpolation(inner) => {
+                        out.push('{');
+                        write_expr(out, inner, depth);
+                        out.push('}');
+                    }
+                }
+            }
+            out.push('"');
+        }
+        ExprKind::Bool(true) => out.push_str("lon"),
+        ExprKind::Bool(false) => out.push_str("ala"),
+        ExprKind::Var(name) => out.push_str(name),
+        ExprKind::Binary {
+            left, op, right, ..
+        } => {
+            let strength = op_strength(*op);
+            let (left_min, right_min) = match strength {
+                // Groups to the right, and its right side may be negated.
+                POW => (PRIMARY, NEG),
+                // At most one comparison, so neither side may be another.
+                COMPARISON => (ADD, ADD),
+                _ => (strength, strength + 1),
+            };
+            write_operand(out, left, left_min, depth);
+            out.push_str(&format!(" {op} "));
+            write_operand(out, right, right_min, depth);
+        }
+        ExprKind::Neg(inner) => {
+            out.push_str("0 - ");
+            write_operand(out, inner, MUL, depth);
+        }
+        ExprKind::Not(inner) => {
+            out.push_str("ala ");
+            write_operand(out, inner, NOT, depth);
+        }
+        ExprKind::While {
+            label,
+            cond,
+            body,
+            else_block,
+        } => write_while(
+            out,
+            label.as_deref(),
+            cond,
+            body,
+            else_block.as_ref(),
+            depth,
+        ),
+        ExprKind::Block(body) => write_block(out, body, depth),
+        ExprKind::FuncCall { name, args } => {
+            out.push_str(name);
+            out.push('(');
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(out, arg, depth);
+            }
+            out.push(')');
+        }
+        ExprKind::Lambda {
+            params,
+            param_types,
+            return_type,
+            body,
+        } => {
+            out.push_str("ilo ");
+            write_params(out, params, param_types);
+            write_return_type(out, return_type.as_ref());
+            out.push(' ');
+            write_block(out, body, depth);
+        }
+    }
+}
+
+/// Escape a literal part of a string. The parser keeps the backslash of
+/// `\{` and `\}`, so a backslash already before a brace is written as is,
+/// and a lone `{` can only be written as `\{`.
+/// The text of a plain string with braces, which a `"..."` literal cannot
+/// hold, if a `sitelen x open` block reads back as exactly that text.
+fn text_block(value: &Expr) -> Option<&str> {
+    let ExprKind::TemplateString(parts) = &value.kind else {
+        return None;
+    };
+    let [StringPart::Literal(text)] = parts.as_slice() else {
+        return None;
+    };
+    let lines = || text.split('\n');
+    let fits = text.contains(['{', '}'])
+        && !text.contains('\r')
+        && lines().all(|line| line.is_empty() || !line.trim().is_empty())
+        && lines().all(|line| line.trim() != "pini_sitelen")
+        // The block removes the indentation all lines share.
+        && lines().any(|line| !line.is_empty() && !line.starts_with([' ', '\t']));
+    fits.then_some(text.as_str())
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' if matches!(chars.peek(), Some('{' | '}')) => {
+                out.push(c);
+                out.extend(chars.next());
+            }
+            '\\' | '"' | '{' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::BlockBuilder;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_printed_source_parses_back() {
+        let source = r#"
+            o awen nasin
+            kepeken lipu/nanpa
+            nimi_nasin Nanpa_lili = nanpa
+            nasin_ilo Kalama open
+                ilo mu (a, b)
+            pini
+            ilo pali (a: nanpa, b) -> nanpa open
+                ma x
+                wile a suli 0 taso pana 0
+                sin y jo (a + b) * 2 ** 3 ** 2 % 5 - (1 - 2)
+                ala (a sama 1 en b) anu lon la open
+                    pana -a
+                pini taso open
+                    pana "a {a} \"b\" \{c\}\n"
+                pini
+                sitelen data open
+                    {"a": [1,
+                      2]}
+
+                pini_sitelen
+            pini
+            tawa ale: tawa i tan 0 tawa 3 la open
+                tawa k, v lon xs la open
+                    awen_sin ale
+                pini
+                w jo tawa insa: wile lon la open
+                    pini_tawa insa e ilo (x) open
+                        pana open
+                            x
+                        pini
+                    pini
+                pini
+            pini
+        "#;
+        let printed = parse(source).unwrap().to_source();
+        let reparsed = parse(&printed).unwrap();
+        assert_eq!(reparsed.to_source(), printed);
+        assert!(printed.contains("(a + b) * 2 ** 3 ** 2 % 5 - (1 - 2)"));
+        assert!(printed.contains("ala (a sama 1 en b) anu lon la open"));
+        assert!(printed.contains(
+            "    sitelen data open\n        {\"a\": [1,\n          2]}\n\n    pini_sitelen"
+        ));
+    }
+
+    #[test]
+    fn test_builders() {
+        let program = [Stmt::func(
+            "pali",
+            &["a"],
+            BlockBuilder::new()
+                .assign(
+                    "b",
+                    Expr::binary(Expr::var("a"), BinOp::Mul, Expr::number(-2.0)),
+                )
+                .expr(Expr::call("toki", [Expr::string("{b}")]))
+                .ret(Expr::var("b"))
+                .build(),
+        )];
+        assert_eq!(
+            program.to_source(),
+            "ilo pali (a) open\n    b jo a * (0 - 2)\n    toki(\"\\{b}\")\n    pana b\npini\n"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Exact rational numbers for the Lipona language.
+//!
+//! Backs `Value::Decimal` (type name `kipisi`). Values are stored as a
+//! normalized `i128` fraction so that `0.1 + 0.2` is exactly `0.3`.
+//! Every operation is checked; overflow is reported as `None` and turned
+//! into a runtime error by the caller.
+
+use std::cmp::Ordering;
+
+/// A normalized fraction: `den > 0` and `gcd(num, den) == 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rational {
+    num: i128,
+    den: i128,
+}
+
+impl Rational {
+    /// Build a fraction, normalizing sign and common factors.
+    /// Returns None when `den` is zero or normalization overflows.
+    pub fn new(num: i128, den: i128) -> Option<Self> {
+        if den == 0 {
+            return None;
+        }
+        let g = gcd(num, den);
+        let (mut num, mut den) = (num / g, den / g);
+        if den < 0 {
+            num = num.checked_neg()?;
+            den = den.checked_neg()?;
+        }
+        Some(Self { num, den })
+    }
+
+    /// Parse a plain decimal string such as `"-12.345"` exactly.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (negative, rest) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (int_part, frac_part) = rest.split_once('.').unwrap_or((rest, ""));
+        if int_part.is_empty() && frac_part.is_empty() {
+            return None;
+        }
+        if !int_part
+            .chars()
+            .chain(frac_part.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let mut num: i128 = 0;
+        for c in int_part.chars().chain(frac_part.chars()) {
+            num = num.checked_mul(10)?.checked_add(c.to_digit(10)? as i128)?;
+        }
+        let den = 10i128.checked_pow(frac_part.len() as u32)?;
+        Self::new(if negative { -num } else { num }, den)
+    }
+
+    /// Convert a finite f64 through its shortest decimal representation,
+    /// so that `0.1` becomes exactly `1/10` rather than the binary value.
+    pub fn from_f64(n: f64) -> Option<Self> {
+        if !n.is_finite() {
+            return None;
+        }
+        Self::parse(&format!("{n}"))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.num == 0
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    pub fn checked_neg(self) -> Option<Self> {
+        Some(Self {
+            num: self.num.checked_neg()?,
+            den: self.den,
+        })
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let num = self
+            .num
+            .checked_mul(other.den)?
+            .checked_add(other.num.checked_mul(self.den)?)?;
+        Self::new(num, self.den.checked_mul(other.den)?)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.checked_add(other.checked_neg()?)
+    }
+
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        Self::new(
+            self.num.checked_mul(other.num)?,
+            self.den.checked_mul(other.den)?,
+        )
+    }
+
+    /// Division; None on division by zero or overflow.
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        Self::new(
+            self.num.checked_mul(other.den)?,
+            self.den.checked_mul(other.num)?,
+        )
+    }
+
+    /// Remainder of division by `other`, never negative (like
+    /// `f64::rem_euclid`); None on division by zero or overflow.
+    pub fn checked_rem_euclid(self, other: Self) -> Option<Self> {
+        let divisor = Self {
+            num: other.num.checked_abs()?,
+            den: other.den,
+        };
+        let quotient = self.checked_div(divisor)?;
+        let whole = Self::new(quotient.num.div_euclid(quotient.den), 1)?;
+        self.checked_sub(divisor.checked_mul(whole)?)
+    }
+
+    /// Raise to a whole power; None on overflow, or for zero to a negative
+    /// power.
+    pub fn checked_pow(self, exp: i32) -> Option<Self> {
+        let k = exp.unsigned_abs();
+        let (num, den) = (self.num.checked_pow(k)?, self.den.checked_pow(k)?);
+        if exp < 0 {
+            Self::new(den, num)
+        } else {
+            Self::new(num, den)
+        }
+    }
+
+    /// The value as an integer, if it is one.
+    pub fn to_integer(self) -> Option<i128> {
+        (self.den == 1).then_some(self.num)
+    }
+
+    /// Format with exactly `decimals` digits after the point, rounding half
+    /// away from zero.
+    pub fn to_fixed(self, decimals: usize) -> Option<String> {
+        let scale = 10i128.checked_pow(decimals as u32)?;
+        let scaled = self.num.unsigned_abs().checked_mul(scale as u128)?;
+        let den = self.den as u128;
+        let mut digits = scaled / den;
+        if (scaled % den) * 2 >= den {
+            digits += 1;
+        }
+        let mut s = format!("{:0>width$}", digits, width = decimals + 1);
+        if decimals > 0 {
+            s.insert(s.len() - decimals, '.');
+        }
+        if self.num < 0 && digits != 0 {
+            s.insert(0, '-');
+        }
+        Some(s)
+    }
+
+    /// Number of decimal digits needed to print this value exactly, or
+    /// None if the denominator has prime factors other than 2 and 5.
+    fn exact_decimals(self) -> Option<usize> {
+        let (mut d, mut twos, mut fives) = (self.den, 0, 0);
+        while d % 2 == 0 {
+            d /= 2;
+            twos += 1;
+        }
+        while d % 5 == 0 {
+            d /= 5;
+            fives += 1;
+        }
+        (d == 1).then_some(twos.max(fives))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Denominators are positive, so cross-multiplying preserves order.
+        // Fall back to float comparison in the (rare) overflow case.
+        match (
+            self.num.checked_mul(other.den),
+            other.num.checked_mul(self.den),
+        ) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => self.to_f64().total_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl std::fmt::Display for Rational {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.exact_decimals().and_then(|d| self.to_fixed(d)) {
+            Some(s) => write!(f, "{s}"),
+            None => write!(f, "{}/{}", self.num, self.den),
+        }
+    }
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    // gcd(0, 0) never happens since den != 0; guard against dividing by zero anyway
+    a.max(1) as i128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenths_add_exactly() {
+        let a = Rational::parse("0.1").unwrap();
+        let b = Rational::parse("0.2").unwrap();
+        let sum = a.checked_add(b).unwrap();
+        assert_eq!(sum, Rational::parse("0.3").unwrap());
+        assert_eq!(sum.to_string(), "0.3");
+    }
+
+    #[test]
+    fn test_display_non_terminating() {
+        let third = Rational::new(1, 3).unwrap();
+        assert_eq!(third.to_string(), "1/3");
+        assert_eq!(third.to_fixed(4).unwrap(), "0.3333");
+        assert_eq!(Rational::new(2, -4).unwrap().to_string(), "-0.5");
+    }
+
+    #[test]
+    fn test_rem_euclid_and_pow() {
+        let r = |s| Rational::parse(s).unwrap();
+        assert_eq!(r("-0.5").checked_rem_euclid(r("0.2")), Some(r("0.1")));
+        assert_eq!(r("1").checked_rem_euclid(r("0")), None);
+        assert_eq!(r("0.5").checked_pow(3), Some(r("0.125")));
+        assert_eq!(r("0.5").checked_pow(-2), Some(r("4")));
+        assert_eq!(r("0").checked_pow(-1), None);
+    }
+
+    #[test]
+    fn test_overflow_is_reported() {
+        let big = Rational::new(i128::MAX, 1).unwrap();
+        assert_eq!(big.checked_add(big), None);
+        assert_eq!(Rational::new(1, 0), None);
+    }
+}
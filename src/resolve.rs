@@ -0,0 +1,378 @@
+//! Name resolution for Lipona programs.
+//!
+//! Walks a parsed program once, before anything runs, and reports what it
+//! defines, which modules it imports, which `sin` declarations shadow an
+//! outer variable, and which names it uses without defining them. The
+//! scopes mirror the interpreter's: every `open ... pini` block is a scope,
+//! a function body is one scope together with its parameters, and an
+//! assignment defines in the current scope only if no enclosing scope has
+//! the name. `ma x` makes `x` a global.
+//!
+//! A use counts as resolved if the name is defined anywhere in its scope or
+//! an enclosing one by the end of the program, so a function may use a
+//! global assigned after it. A call also resolves to a builtin. Names a
+//! `kepeken` module provides are not known here; callers that care check
+//! [`Resolution::imports`].
+//!
+//! Only the linter consumes the result so far. The interpreter still looks
+//! names up by string at runtime: a use resolved here may still be bound
+//! in a different scope at runtime (an assignment in a block defines there
+//! only if no outer scope has the name yet), so the resolution cannot
+//! replace that lookup until it tracks definition order. Resolving
+//! variables to scope slots for the interpreter is left for later.
+
+use std::collections::HashSet;
+
+use crate::ast::{Block, Expr, ExprKind, Program, Span, Stmt, StmtKind, StringPart};
+use crate::stdlib::StdLib;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Function,
+    Parameter,
+    /// A loop label.
+    Label,
+    /// A `nimi_nasin` alias or `nasin_ilo` capability.
+    Type,
+    /// A function listed in a `nasin_ilo` capability.
+    Member,
+    /// A `kepeken` module path.
+    Module,
+}
+
+/// A name and where it appears. Parameters and loop variables carry the
+/// span of the function or loop that binds them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Span,
+}
+
+/// Everything [`resolve`] found, each list in source order.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Every definition, including each assignment that creates a name.
+    pub definitions: Vec<Symbol>,
+    pub imports: Vec<Symbol>,
+    /// `sin` declarations that hide a variable from an enclosing scope.
+    pub shadows: Vec<Symbol>,
+    /// Uses of a name that no enclosing scope defines and that is not a
+    /// builtin (for calls).
+    pub unresolved: Vec<Symbol>,
+}
+
+pub fn resolve(program: &Program) -> Resolution {
+    let mut resolver = Resolver {
+        scopes: vec![Scope::default()],
+        current: 0,
+        uses: Vec::new(),
+        resolution: Resolution::default(),
+    };
+    resolver.stmts(program);
+    resolver.finish()
+}
+
+#[derive(Default)]
+struct Scope {
+    parent: Option<usize>,
+    names: HashSet<String>,
+}
+
+struct Use {
+    symbol: Symbol,
+    scope: usize,
+    call: bool,
+}
+
+struct Resolver {
+    /// Every scope seen so far; index 0 is the global scope.
+    scopes: Vec<Scope>,
+    current: usize,
+    uses: Vec<Use>,
+    resolution: Resolution,
+}
+
+impl Resolver {
+    fn chain(&self, scope: usize) -> impl Iterator<Item = &Scope> {
+        std::iter::successors(Some(&self.scopes[scope]), |s| {
+            s.parent.map(|parent| &self.scopes[parent])
+        })
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.chain(self.current)
+            .any(|scope| scope.names.contains(name))
+    }
+
+    fn record(&mut self, name: &str, kind: SymbolKind, span: Span) {
+        self.resolution.definitions.push(Symbol {
+            name: name.to_string(),
+            kind,
+            span,
+        });
+    }
+
+    fn define(&mut self, name: &str, kind: SymbolKind, span: Span) {
+        self.record(name, kind, span);
+        self.scopes[self.current].names.insert(name.to_string());
+    }
+
+    fn use_name(&mut self, name: &str, span: Span, call: bool) {
+        self.uses.push(Use {
+            symbol: Symbol {
+                name: name.to_string(),
+                kind: SymbolKind::Variable,
+                span,
+            },
+            scope: self.current,
+            call,
+        });
+    }
+
+    fn enter(&mut self) {
+        self.scopes.push(Scope {
+            parent: Some(self.current),
+            names: HashSet::new(),
+        });
+        self.current = self.scopes.len() - 1;
+    }
+
+    fn leave(&mut self) {
+        self.current = self.scopes[self.current].parent.unwrap_or(0);
+    }
+
+    fn finish(mut self) -> Resolution {
+        let stdlib = StdLib::new();
+        for u in std::mem::take(&mut self.uses) {
+            let defined = self
+                .chain(u.scope)
+                .any(|scope| scope.names.contains(&u.symbol.name));
+            let builtin = u.call && stdlib.get(&u.symbol.name).is_some();
+            if !defined && !builtin {
+                self.resolution.unresolved.push(u.symbol);
+            }
+        }
+        self.resolution
+    }
+
+    /// A function body or loop: one new scope holding the bound names.
+    fn function(&mut self, params: &[String], span: Span, body: &Block) {
+        self.enter();
+        for param in params {
+            self.define(param, SymbolKind::Parameter, span);
+        }
+        self.stmts(body);
+        self.leave();
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.enter();
+        self.stmts(block);
+        self.leave();
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        let span = stmt.span;
+        match &stmt.kind {
+            StmtKind::Assign { target, value } => {
+                self.expr(value);
+                if !self.is_defined(target) {
+                    self.define(target, SymbolKind::Variable, span);
+                }
+            }
+            StmtKind::Declare { name, value } => {
+                self.expr(value);
+                let scope = &self.scopes[self.current];
+                let shadows = !scope.names.contains(name)
+                    && scope
+                        .parent
+                        .is_some_and(|parent| self.chain(parent).any(|s| s.names.contains(name)));
+                if shadows {
+                    self.resolution.shadows.push(Symbol {
+                        name: name.clone(),
+                        kind: SymbolKind::Variable,
+                        span,
+                    });
+                }
+                self.define(name, SymbolKind::Variable, span);
+            }
+            StmtKind::Global(names) => {
+                for name in names {
+                    self.scopes[0].names.insert(name.clone());
+                }
+            }
+            StmtKind::If {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                self.expr(cond);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            StmtKind::While {
+                label,
+                cond,
+                body,
+                else_block,
+            } => self.while_loop(label.as_deref(), span, cond, body, else_block.as_ref()),
+            StmtKind::Guard { cond, value } => {
+                self.expr(cond);
+                self.expr(value);
+            }
+            StmtKind::FuncDef {
+                name, params, body, ..
+            } => {
+                self.define(name, SymbolKind::Function, span);
+                self.function(params, span, body);
+            }
+            StmtKind::Return(expr) | StmtKind::Expr(expr) => self.expr(expr),
+            StmtKind::For {
+                label,
+                var,
+                start,
+                end,
+                body,
+            } => {
+                if let Some(label) = label {
+                    self.record(label, SymbolKind::Label, span);
+                }
+                self.expr(start);
+                self.expr(end);
+                // Like a function parameter, `i` lives in the body's scope.
+                self.function(std::slice::from_ref(var), span, body);
+            }
+            StmtKind::ForEach {
+                label,
+                key,
+                var,
+                iterable,
+                body,
+            } => {
+                if let Some(label) = label {
+                    self.record(label, SymbolKind::Label, span);
+                }
+                self.expr(iterable);
+                let names: Vec<String> = key.iter().chain([var]).cloned().collect();
+                self.function(&names, span, body);
+            }
+            StmtKind::Break { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            StmtKind::TypeAlias { name, .. } => self.record(name, SymbolKind::Type, span),
+            StmtKind::Capability(capability) => {
+                self.record(&capability.name, SymbolKind::Type, span);
+                for (name, _) in &capability.functions {
+                    self.record(name, SymbolKind::Member, span);
+                }
+            }
+            StmtKind::Import(module) => self.resolution.imports.push(Symbol {
+                name: module.clone(),
+                kind: SymbolKind::Module,
+                span,
+            }),
+            StmtKind::Continue { .. } | StmtKind::Strict => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(_) | ExprKind::Bool(_) => {}
+            ExprKind::Var(name) => self.use_name(name, expr.span, false),
+            ExprKind::TemplateString(parts) => {
+                for part in parts {
+                    if let StringPart::Interpolation(expr) = part {
+                        self.expr(expr);
+                    }
+                }
+            }
+            ExprKind::Binary { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Neg(inner) | ExprKind::Not(inner) => self.expr(inner),
+            ExprKind::FuncCall { name, args } => {
+                self.use_name(name, expr.span, true);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            ExprKind::Lambda { params, body, .. } => self.function(params, expr.span, body),
+            ExprKind::While {
+                label,
+                cond,
+                body,
+                else_block,
+            } => self.while_loop(label.as_deref(), expr.span, cond, body, else_block.as_ref()),
+            ExprKind::Block(body) => self.block(body),
+        }
+    }
+
+    fn while_loop(
+        &mut self,
+        label: Option<&str>,
+        span: Span,
+        cond: &Expr,
+        body: &Block,
+        else_block: Option<&Block>,
+    ) {
+        if let Some(label) = label {
+            self.record(label, SymbolKind::Label, span);
+        }
+        self.expr(cond);
+        self.block(body);
+        if let Some(else_block) = else_block {
+            self.block(else_block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn names(symbols: &[Symbol]) -> Vec<&str> {
+        symbols.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_resolve_reports_definitions_and_free_names() {
+        let program = parse(
+            r#"
+            kepeken lipu/nanpa
+            ilo pali (a) open
+                pana a + mute + ante(a)
+            pini
+            mute jo 2
+            toki(pali(1), weka)
+            lon la open
+                sin mute jo 3
+            pini
+        "#,
+        )
+        .unwrap();
+        let resolution = resolve(&program);
+        assert_eq!(
+            names(&resolution.definitions),
+            vec!["pali", "a", "mute", "mute"]
+        );
+        assert_eq!(resolution.definitions[1].kind, SymbolKind::Parameter);
+        assert_eq!(names(&resolution.imports), vec!["lipu/nanpa"]);
+        assert_eq!(names(&resolution.shadows), vec!["mute"]);
+        assert_eq!(names(&resolution.unresolved), vec!["ante", "weka"]);
+        assert_eq!(resolution.unresolved[1].span.line, 7);
+    }
+}
@@ -3,57 +3,754 @@
 //! Provides built-in functions for I/O, string manipulation,
 //! list operations, and map operations.
 
-use std::collections::HashMap;
-use std::io::{self, Write};
+use std::cell::OnceCell;
+#[cfg(feature = "regex")]
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::interpreter::{RuntimeError, Value, F64_SAFE_INT_MAX};
+#[cfg(feature = "collation")]
+use icu_collator::{Collator, CollatorOptions};
+#[cfg(feature = "collation")]
+use icu_locid::Locale;
+#[cfg(feature = "regex")]
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::ast::Type;
+use crate::interpreter::{
+    sorted_entries, ArgPosition, FileAccess, Interpreter, RuntimeError, Value, F64_SAFE_INT_MAX,
+};
+use crate::lint;
+use crate::rational::Rational;
 
 /// Standard library function signature
 type StdLibFn = fn(Vec<Value>) -> Result<Value, RuntimeError>;
 
-/// Standard library functions
+/// Signature for builtins that take an `ilo` argument and need the
+/// interpreter to call it (see `Interpreter::call_value`).
+pub type HigherOrderFn = fn(&mut Interpreter, Vec<Value>) -> Result<Value, RuntimeError>;
+
+/// The Rust function behind a builtin.
+#[derive(Clone, Copy)]
+pub enum BuiltinFn {
+    Plain(StdLibFn),
+    HigherOrder(HigherOrderFn),
+}
+
+/// The parameters a builtin accepts. `StdLib` checks the argument count
+/// and each argument's type against it before the call, so the functions
+/// themselves only unpack their arguments and check values (ranges,
+/// finiteness). Unions such as "nanpa or kipisi" are `ijo` here and
+/// checked by the function.
+pub struct Signature {
+    pub params: &'static [Type],
+    /// How many of `params` must be given; the rest are optional.
+    pub required: usize,
+    /// Any number of further arguments of the last parameter's type.
+    pub variadic: bool,
+}
+
+impl Signature {
+    const fn new(params: &'static [Type]) -> Self {
+        Self::optional(params, params.len())
+    }
+
+    const fn optional(params: &'static [Type], required: usize) -> Self {
+        Self {
+            params,
+            required,
+            variadic: false,
+        }
+    }
+
+    const fn variadic(params: &'static [Type], required: usize) -> Self {
+        Self {
+            params,
+            required,
+            variadic: true,
+        }
+    }
+
+    /// Check `args` against the signature of `name`.
+    pub fn check(&self, name: &'static str, args: &[Value]) -> Result<(), RuntimeError> {
+        let max = self.params.len();
+        if args.len() < self.required || (!self.variadic && args.len() > max) {
+            return Err(if self.required == max {
+                RuntimeError::WrongArity {
+                    name: name.to_string(),
+                    expected: max,
+                    got: args.len(),
+                }
+            } else {
+                RuntimeError::WrongArityRange {
+                    name: name.to_string(),
+                    min: self.required,
+                    max,
+                    got: args.len(),
+                }
+            });
+        }
+        for (i, arg) in args.iter().enumerate() {
+            let Some(ty) = self.params.get(i).or(self.params.last()) else {
+                break;
+            };
+            if !arg.matches_type(ty) {
+                return Err(RuntimeError::TypeError {
+                    expected: ty.name(),
+                    got: arg.type_name().to_string(),
+                    arg: arg_at(name, i + 1),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A builtin: its name, what it accepts and the function that runs it.
+pub struct Builtin {
+    pub name: &'static str,
+    pub signature: Signature,
+    pub func: BuiltinFn,
+}
+
+const fn plain(name: &'static str, signature: Signature, func: StdLibFn) -> Builtin {
+    Builtin {
+        name,
+        signature,
+        func: BuiltinFn::Plain(func),
+    }
+}
+
+const fn higher_order(name: &'static str, signature: Signature, func: HigherOrderFn) -> Builtin {
+    Builtin {
+        name,
+        signature,
+        func: BuiltinFn::HigherOrder(func),
+    }
+}
+
+/// A group of builtins (`sitelen`, `kulupu`, `lipu`, ...). Each can be
+/// called by its plain name or qualified with the module: `sitelen_len` is
+/// also `sitelen.len`.
+pub struct Module {
+    pub name: &'static str,
+    pub builtins: &'static [Builtin],
+    /// Old names of renamed builtins, with the name that replaced them.
+    /// The old name keeps working but warns once per run.
+    pub renamed: &'static [(&'static str, &'static str)],
+}
+
+impl Module {
+    /// The module-qualified form of one of its builtin names.
+    pub fn qualified(&self, name: &str) -> String {
+        let short = name
+            .strip_prefix(self.name)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .unwrap_or(name);
+        format!("{}.{short}", self.name)
+    }
+}
+
+use Type::{Ijo, Ilo, Kulupu, Nanpa, Nasin, Sitelen};
+
+/// Every builtin, by module. Higher-order ones get the interpreter so they
+/// can call back into `ilo` arguments (and write output through its limits).
+pub static MODULES: &[Module] = &[
+    Module {
+        name: "lipu",
+        builtins: &[
+            // I/O (output goes through the interpreter's --max-output count)
+            higher_order("toki", Signature::variadic(&[Ijo], 0), stdlib_toki),
+            higher_order("o_lukin", Signature::new(&[Ijo]), stdlib_o_lukin),
+            higher_order(
+                "lipu_linja_kama",
+                Signature::new(&[Sitelen, Ilo]),
+                stdlib_lipu_linja_kama,
+            ),
+            higher_order(
+                "toki_tawa_lipu",
+                Signature::new(&[Sitelen, Ilo]),
+                stdlib_toki_tawa_lipu,
+            ),
+            // Files (each asks the interpreter's file policy first)
+            higher_order("lipu_lukin", Signature::new(&[Sitelen]), stdlib_lipu_lukin),
+            higher_order(
+                "lipu_sitelen",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_lipu_sitelen,
+            ),
+            higher_order(
+                "lipu_namako",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_lipu_namako,
+            ),
+            higher_order("lipu_lon", Signature::new(&[Sitelen]), stdlib_lipu_lon),
+            higher_order(
+                "lipu_kulupu",
+                Signature::new(&[Sitelen]),
+                stdlib_lipu_kulupu,
+            ),
+            higher_order(
+                "lipu_kulupu_sin",
+                Signature::new(&[Sitelen]),
+                stdlib_lipu_kulupu_sin,
+            ),
+            higher_order(
+                "lipu_kulupu_weka",
+                Signature::new(&[Sitelen]),
+                stdlib_lipu_kulupu_weka,
+            ),
+            higher_order(
+                "lipu_lukin_awen",
+                Signature::new(&[Sitelen, Ilo]),
+                stdlib_lipu_lukin_awen,
+            ),
+            // Standard input
+            higher_order("kute", Signature::new(&[]), stdlib_kute),
+            higher_order("kute_ale", Signature::new(&[]), stdlib_kute_ale),
+            // Script arguments
+            higher_order("ijo_kama", Signature::new(&[]), stdlib_ijo_kama),
+            higher_order(
+                "pana_nasin_kama",
+                Signature::new(&[Kulupu]),
+                stdlib_pana_nasin_kama,
+            ),
+            // Environment variables
+            higher_order(
+                "poki_ma_ken",
+                Signature::new(&[Sitelen]),
+                stdlib_poki_ma_ken,
+            ),
+            higher_order(
+                "poki_ma_lon",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_poki_ma_lon,
+            ),
+            // Other programs (only with --allow-exec)
+            higher_order(
+                "ilo_ma",
+                Signature::optional(&[Sitelen, Kulupu], 1),
+                stdlib_ilo_ma,
+            ),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "nanpa",
+        builtins: &[
+            plain(
+                "nanpa_sin",
+                Signature::optional(&[Ijo, Nanpa], 1),
+                stdlib_nanpa_sin,
+            ),
+            plain(
+                "nanpa_sin_ken",
+                Signature::optional(&[Ijo, Nanpa], 1),
+                stdlib_nanpa_sin_ken,
+            ),
+            plain("nanpa_len", Signature::new(&[Nanpa]), stdlib_nanpa_len),
+            plain(
+                "nanpa_sitelen",
+                Signature::optional(&[Nanpa, Nanpa], 1),
+                stdlib_nanpa_sitelen,
+            ),
+            plain(
+                "nanpa_sitelen_lili",
+                Signature::new(&[Ijo, Nanpa]),
+                stdlib_nanpa_sitelen_lili,
+            ),
+            plain(
+                "nanpa_kipisi",
+                Signature::optional(&[Ijo, Nanpa], 1),
+                stdlib_nanpa_kipisi,
+            ),
+            plain(
+                "nanpa_sama_lili",
+                Signature::new(&[Ijo, Ijo, Ijo]),
+                stdlib_nanpa_sama_lili,
+            ),
+            plain(
+                "nanpa_insa_ale",
+                Signature::new(&[Nanpa]),
+                stdlib_nanpa_insa_ale,
+            ),
+            plain(
+                "nanpa_sike_nasin",
+                Signature::optional(&[Nanpa, Sitelen], 1),
+                stdlib_nanpa_sike_nasin,
+            ),
+            plain("nanpa_anpa", Signature::new(&[Nanpa]), stdlib_nanpa_anpa),
+            plain("nanpa_sewi", Signature::new(&[Nanpa]), stdlib_nanpa_sewi),
+            plain("nanpa_sike", Signature::new(&[Nanpa]), stdlib_nanpa_sike),
+            plain("nanpa_insa", Signature::new(&[Nanpa]), stdlib_nanpa_insa),
+            plain("nanpa_noka", Signature::new(&[Nanpa]), stdlib_nanpa_noka),
+            plain(
+                "nanpa_mute",
+                Signature::new(&[Nanpa, Nanpa]),
+                stdlib_nanpa_mute,
+            ),
+            plain(
+                "nanpa_mod",
+                Signature::new(&[Nanpa, Nanpa]),
+                stdlib_nanpa_mod,
+            ),
+            higher_order("nanpa_nasa", Signature::new(&[]), stdlib_nanpa_nasa),
+            higher_order(
+                "nanpa_nasa_insa",
+                Signature::new(&[Nanpa, Nanpa]),
+                stdlib_nanpa_nasa_insa,
+            ),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "sitelen",
+        builtins: &[
+            plain(
+                "sitelen_len",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_len,
+            ),
+            plain(
+                "sitelen_ken",
+                Signature::new(&[Sitelen, Nanpa, Nanpa]),
+                stdlib_sitelen_ken,
+            ),
+            plain(
+                "sitelen_wan_ken",
+                Signature::new(&[Sitelen, Nanpa]),
+                stdlib_sitelen_wan_ken,
+            ),
+            plain(
+                "sitelen_nanpa",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_nanpa,
+            ),
+            plain(
+                "sitelen_tan_nanpa",
+                Signature::new(&[Nanpa]),
+                stdlib_sitelen_tan_nanpa,
+            ),
+            plain(
+                "sitelen_sama",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_sitelen_sama,
+            ),
+            plain(
+                "sitelen_lon",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_sitelen_lon,
+            ),
+            plain(
+                "sitelen_alasa",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_sitelen_alasa,
+            ),
+            plain(
+                "sitelen_open",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_sitelen_open,
+            ),
+            plain(
+                "sitelen_pini",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_sitelen_pini,
+            ),
+            higher_order(
+                "sitelen_ante",
+                Signature::new(&[Sitelen, Sitelen, Sitelen]),
+                stdlib_sitelen_ante,
+            ),
+            plain(
+                "sitelen_ante_wan",
+                Signature::new(&[Sitelen, Sitelen, Sitelen]),
+                stdlib_sitelen_ante_wan,
+            ),
+            plain(
+                "sitelen_suli",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_suli,
+            ),
+            plain(
+                "sitelen_lili",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_lili,
+            ),
+            plain(
+                "sitelen_weka",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_weka,
+            ),
+            plain(
+                "sitelen_len_ale",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_len_ale,
+            ),
+            plain(
+                "sitelen_ken_ale",
+                Signature::new(&[Sitelen, Nanpa, Nanpa]),
+                stdlib_sitelen_ken_ale,
+            ),
+            plain(
+                "sitelen_kipisi_ale",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_kipisi_ale,
+            ),
+            plain(
+                "sitelen_kipisi_nasin",
+                Signature::optional(&[Sitelen, Sitelen, Ijo], 2),
+                stdlib_sitelen_kipisi_nasin,
+            ),
+            plain(
+                "sitelen_sama_nasin",
+                Signature::optional(&[Sitelen, Sitelen], 1),
+                stdlib_sitelen_sama_nasin,
+            ),
+            plain(
+                "sitelen_sama_lukin",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_sitelen_sama_lukin,
+            ),
+            plain(
+                "sitelen_nasin_pona",
+                Signature::optional(&[Sitelen, Sitelen, Sitelen], 2),
+                stdlib_sitelen_nasin_pona,
+            ),
+            plain(
+                "sitelen_pona_nimi",
+                Signature::new(&[Sitelen]),
+                stdlib_sitelen_pona_nimi,
+            ),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "kulupu",
+        builtins: &[
+            // List
+            higher_order(
+                "kulupu_sin",
+                Signature::variadic(&[Ijo], 0),
+                stdlib_kulupu_sin,
+            ),
+            plain("kulupu_len", Signature::new(&[Kulupu]), stdlib_kulupu_len),
+            plain(
+                "kulupu_ken",
+                Signature::new(&[Kulupu, Nanpa]),
+                stdlib_kulupu_ken,
+            ),
+            plain(
+                "kulupu_lon",
+                Signature::new(&[Kulupu, Nanpa, Ijo]),
+                stdlib_kulupu_lon,
+            ),
+            plain(
+                "kulupu_aksen",
+                Signature::new(&[Kulupu, Ijo]),
+                stdlib_kulupu_aksen,
+            ),
+            plain(
+                "kulupu_nasin_pona",
+                Signature::optional(&[Kulupu, Sitelen], 1),
+                stdlib_kulupu_nasin_pona,
+            ),
+            plain(
+                "ijo_nasin_sama",
+                Signature::new(&[Ijo, Ijo]),
+                stdlib_ijo_nasin_sama,
+            ),
+            higher_order(
+                "kulupu_nanpa",
+                Signature::optional(&[Kulupu, Ilo], 1),
+                stdlib_kulupu_nanpa,
+            ),
+            plain(
+                "kulupu_alasa_wawa",
+                Signature::new(&[Kulupu, Ijo]),
+                stdlib_kulupu_alasa_wawa,
+            ),
+            plain(
+                "kulupu_wan_taso",
+                Signature::new(&[Kulupu]),
+                stdlib_kulupu_wan_taso,
+            ),
+            higher_order(
+                "kulupu_ante",
+                Signature::new(&[Kulupu, Ilo]),
+                stdlib_kulupu_ante,
+            ),
+            higher_order(
+                "kulupu_wile",
+                Signature::new(&[Kulupu, Ilo]),
+                stdlib_kulupu_wile,
+            ),
+            higher_order(
+                "kulupu_wan",
+                Signature::new(&[Kulupu, Ijo, Ilo]),
+                stdlib_kulupu_wan,
+            ),
+            plain(
+                "kulupu_kipisi",
+                Signature::new(&[Kulupu, Nanpa]),
+                stdlib_kulupu_kipisi,
+            ),
+            plain(
+                "kulupu_lukin_poka",
+                Signature::new(&[Kulupu, Nanpa]),
+                stdlib_kulupu_lukin_poka,
+            ),
+            higher_order(
+                "kulupu_kulupu_e",
+                Signature::new(&[Kulupu, Ilo]),
+                stdlib_kulupu_kulupu_e,
+            ),
+            // Grid (list of lists)
+            higher_order(
+                "kulupu_leko_sin",
+                Signature::optional(&[Nanpa, Nanpa, Ijo], 2),
+                stdlib_kulupu_leko_sin,
+            ),
+            plain(
+                "kulupu_leko_sike",
+                Signature::new(&[Kulupu]),
+                stdlib_kulupu_leko_sike,
+            ),
+            higher_order(
+                "kulupu_leko_ante",
+                Signature::new(&[Kulupu, Ilo]),
+                stdlib_kulupu_leko_ante,
+            ),
+            // Statistics
+            plain(
+                "kulupu_nanpa_sona_meso",
+                Signature::new(&[Kulupu]),
+                stdlib_sona_meso,
+            ),
+            plain(
+                "kulupu_nanpa_sona_insa",
+                Signature::new(&[Kulupu]),
+                stdlib_sona_insa,
+            ),
+            plain(
+                "kulupu_nanpa_sona_ante",
+                Signature::optional(&[Kulupu, Ijo], 1),
+                stdlib_sona_ante,
+            ),
+            plain(
+                "kulupu_nanpa_sona_weka",
+                Signature::optional(&[Kulupu, Ijo], 1),
+                stdlib_sona_weka,
+            ),
+            plain(
+                "kulupu_nanpa_sona_lon",
+                Signature::new(&[Kulupu, Nanpa]),
+                stdlib_sona_lon,
+            ),
+        ],
+        // No builtin has been renamed yet; this old name only exercises the
+        // mechanism in tests.
+        renamed: &[
+            #[cfg(test)]
+            ("kulupu_kama_pini", "kulupu_aksen"),
+        ],
+    },
+    Module {
+        name: "pakala",
+        builtins: &[
+            // Testing
+            plain("wile_sama", Signature::new(&[Ijo, Ijo]), stdlib_wile_sama),
+            // Errors
+            plain("pakala_sin", Signature::new(&[Ijo]), stdlib_pakala_sin),
+            higher_order(
+                "ken_pali",
+                Signature::optional(&[Ilo, Ilo], 1),
+                stdlib_ken_pali,
+            ),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "nasin",
+        builtins: &[
+            plain("nasin_sin", Signature::new(&[]), stdlib_nasin_sin),
+            plain("nasin_ken", Signature::new(&[Nasin, Ijo]), stdlib_nasin_ken),
+            plain(
+                "nasin_lon",
+                Signature::new(&[Nasin, Ijo, Ijo]),
+                stdlib_nasin_lon,
+            ),
+            plain(
+                "nasin_weka",
+                Signature::new(&[Nasin, Ijo]),
+                stdlib_nasin_weka,
+            ),
+            plain("nasin_jo", Signature::new(&[Nasin, Ijo]), stdlib_nasin_jo),
+            plain(
+                "nasin_wan",
+                Signature::new(&[Nasin, Nasin]),
+                stdlib_nasin_wan,
+            ),
+            plain("nasin_nimi", Signature::new(&[Nasin]), stdlib_nasin_nimi),
+            plain("nasin_ijo", Signature::new(&[Nasin]), stdlib_nasin_ijo),
+            plain("nasin_tu", Signature::new(&[Nasin]), stdlib_nasin_tu),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "regex",
+        builtins: &[
+            higher_order(
+                "regex_lon",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_regex_lon,
+            ),
+            higher_order(
+                "regex_alasa",
+                Signature::new(&[Sitelen, Sitelen]),
+                stdlib_regex_alasa,
+            ),
+            higher_order(
+                "regex_ante",
+                Signature::new(&[Sitelen, Sitelen, Sitelen]),
+                stdlib_regex_ante,
+            ),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "csv",
+        builtins: &[
+            higher_order("csv_open", Signature::new(&[Sitelen]), stdlib_csv_open),
+            plain("csv_pini", Signature::new(&[Kulupu]), stdlib_csv_pini),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "linja",
+        builtins: &[
+            // HTTP (only with --allow-net)
+            higher_order(
+                "linja_ma_kama",
+                Signature::optional(&[Sitelen, Nasin], 1),
+                stdlib_linja_ma_kama,
+            ),
+            higher_order(
+                "linja_ma_pana",
+                Signature::optional(&[Sitelen, Sitelen, Nasin], 2),
+                stdlib_linja_ma_pana,
+            ),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "tenpo",
+        builtins: &[
+            plain("tenpo_ni", Signature::new(&[]), stdlib_tenpo_ni),
+            plain(
+                "tenpo_sitelen",
+                Signature::optional(&[Sitelen, Nanpa], 1),
+                stdlib_tenpo_sitelen,
+            ),
+            plain(
+                "tenpo_kipisi",
+                Signature::optional(&[Nanpa], 0),
+                stdlib_tenpo_kipisi,
+            ),
+            higher_order("tenpo_awen", Signature::new(&[Nanpa]), stdlib_tenpo_awen),
+        ],
+        renamed: &[],
+    },
+    Module {
+        name: "sona",
+        builtins: &[plain(
+            "sona_ilo_ale",
+            Signature::optional(&[Sitelen], 0),
+            stdlib_sona_ilo_ale,
+        )],
+        renamed: &[],
+    },
+];
+
+/// A name in the `StdLib` index: the builtin, and for an old name of a
+/// renamed builtin, the name to use instead.
+type Entry = (&'static Builtin, Option<String>);
+
+/// Standard library functions, by plain and qualified name. The index is
+/// built on the first lookup, so a run that calls no builtin never builds it.
 pub struct StdLib {
-    functions: HashMap<&'static str, StdLibFn>,
+    index: OnceCell<HashMap<String, Entry>>,
+    /// Patterns the `regex_*` builtins have compiled, so one used in a loop
+    /// is compiled once.
+    #[cfg(feature = "regex")]
+    regexes: RefCell<HashMap<String, Regex>>,
 }
 
+/// Compiled patterns kept before the cache starts over.
+#[cfg(feature = "regex")]
+const REGEX_CACHE_SIZE: usize = 64;
+
 impl StdLib {
     pub fn new() -> Self {
-        let functions: HashMap<&'static str, StdLibFn> = [
-            // I/O
-            ("toki", stdlib_toki as StdLibFn),
-            // Number
-            ("nanpa_sin", stdlib_nanpa_sin as StdLibFn),
-            ("nanpa_len", stdlib_nanpa_len as StdLibFn),
-            // String
-            ("sitelen_len", stdlib_sitelen_len as StdLibFn),
-            ("sitelen_sama", stdlib_sitelen_sama as StdLibFn),
-            // List
-            ("kulupu_sin", stdlib_kulupu_sin as StdLibFn),
-            ("kulupu_len", stdlib_kulupu_len as StdLibFn),
-            ("kulupu_ken", stdlib_kulupu_ken as StdLibFn),
-            ("kulupu_lon", stdlib_kulupu_lon as StdLibFn),
-            ("kulupu_aksen", stdlib_kulupu_aksen as StdLibFn),
-            // Map
-            ("nasin_sin", stdlib_nasin_sin as StdLibFn),
-            ("nasin_ken", stdlib_nasin_ken as StdLibFn),
-            ("nasin_lon", stdlib_nasin_lon as StdLibFn),
-        ]
-        .into_iter()
-        .collect();
+        Self {
+            index: OnceCell::new(),
+            #[cfg(feature = "regex")]
+            regexes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// `pattern` compiled, from the cache if it was compiled before.
+    /// `name` is the builtin asking, for the error message.
+    #[cfg(feature = "regex")]
+    fn regex(&self, name: &str, pattern: &str) -> Result<Regex, RuntimeError> {
+        if let Some(regex) = self.regexes.borrow().get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(pattern)
+            .map_err(|e| RuntimeError::Argument(format!("{name}: invalid pattern - {e}")))?;
+        let mut regexes = self.regexes.borrow_mut();
+        if regexes.len() >= REGEX_CACHE_SIZE {
+            regexes.clear();
+        }
+        regexes.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
 
-        Self { functions }
+    fn index(&self) -> &HashMap<String, Entry> {
+        self.index.get_or_init(|| {
+            let mut index = HashMap::new();
+            for module in MODULES {
+                for builtin in module.builtins {
+                    index.insert(builtin.name.to_string(), (builtin, None));
+                    index.insert(module.qualified(builtin.name), (builtin, None));
+                }
+                for (old, new) in module.renamed {
+                    let builtin = index[*new].0;
+                    index.insert(old.to_string(), (builtin, Some(new.to_string())));
+                    index.insert(
+                        module.qualified(old),
+                        (builtin, Some(module.qualified(new))),
+                    );
+                }
+            }
+            index
+        })
     }
 
-    pub fn has_function(&self, name: &str) -> bool {
-        self.functions.contains_key(name)
+    /// Look up a builtin. The result does not borrow `self`, so a
+    /// higher-order builtin can be called with the interpreter.
+    pub fn get(&self, name: &str) -> Option<&'static Builtin> {
+        self.index().get(name).map(|(builtin, _)| *builtin)
     }
 
-    pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
-        if let Some(func) = self.functions.get(name) {
-            func(args)
-        } else {
-            Err(RuntimeError::UndefinedFunction(name.to_string()))
-        }
+    /// The current name of a builtin called by an old name.
+    pub fn renamed(&self, name: &str) -> Option<&str> {
+        self.index().get(name)?.1.as_deref()
     }
 }
 
@@ -66,259 +763,2570 @@ impl Default for StdLib {
 // === I/O ===
 
 /// toki e (x) - print
-fn stdlib_toki(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
-    for (i, arg) in args.iter().enumerate() {
-        if i > 0 {
-            let _ = write!(handle, " ");
+fn stdlib_toki(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let line: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    interp.write_output(&format!("{}\n", line.join(" ")))?;
+    Ok(Value::Ala)
+}
+
+/// lipu_linja_kama e (path, f) - call f(line) for each line of the file,
+/// or f(line, index) if it takes two parameters. Lines are read one at a
+/// time, so the file is never held in memory; the line ending is removed.
+/// Returns the number of lines read.
+fn stdlib_lipu_linja_kama(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Read)?;
+    let read_error = |e: std::io::Error| RuntimeError::FileRead {
+        path: path.to_string(),
+        message: e.to_string(),
+    };
+    let with_index = takes_params(&args[1], 2);
+
+    let mut reader = BufReader::new(File::open(path).map_err(read_error)?);
+    let mut line = String::new();
+    let mut count = 0usize;
+    while reader.read_line(&mut line).map_err(read_error)? > 0 {
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        let call_args = if with_index {
+            vec![
+                Value::String(std::mem::take(&mut line)),
+                Value::Number(count as f64),
+            ]
+        } else {
+            vec![Value::String(std::mem::take(&mut line))]
+        };
+        interp.call_value("ilo (lipu_linja_kama)", &args[1], call_args)?;
+        count += 1;
+    }
+    Ok(Value::Number(count as f64))
+}
+
+/// toki_tawa_lipu e (path, f) - call f() with everything `toki` and
+/// `o_lukin` print going to the file at path (created, or overwritten)
+/// instead of stdout. Returns what f returns.
+fn stdlib_toki_tawa_lipu(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Write)?;
+    let file = File::create(path).map_err(|e| RuntimeError::FileWrite {
+        path: path.to_string(),
+        message: e.to_string(),
+    })?;
+    interp.with_redirect(path, Box::new(BufWriter::new(file)), &args[1])
+}
+
+/// lipu_lukin e (path) - the whole file as a sitelen
+fn stdlib_lipu_lukin(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Read)?;
+    let failed = |message| RuntimeError::FileRead {
+        path: path.to_string(),
+        message,
+    };
+    let file = File::open(path).map_err(|e| failed(e.to_string()))?;
+    interp.read_text(file, failed).map(Value::String)
+}
+
+/// lipu_sitelen e (path, contents) - write contents to the file (created,
+/// or overwritten)
+fn stdlib_lipu_sitelen(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Write)?;
+    fs::write(path, expect_string(&args[1])?)
+        .map(|()| Value::Ala)
+        .map_err(|e| RuntimeError::FileWrite {
+            path: path.to_string(),
+            message: e.to_string(),
+        })
+}
+
+/// lipu_namako e (path, contents) - add contents to the end of the file
+/// (created if missing)
+fn stdlib_lipu_namako(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Write)?;
+    let write_error = |e: std::io::Error| RuntimeError::FileWrite {
+        path: path.to_string(),
+        message: e.to_string(),
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(write_error)?;
+    file.write_all(expect_string(&args[1])?.as_bytes())
+        .map_err(write_error)?;
+    Ok(Value::Ala)
+}
+
+/// lipu_lon e (path) - lon if a file (or directory) exists at path
+fn stdlib_lipu_lon(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Read)?;
+    Ok(if Path::new(path).exists() {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// lipu_kulupu e (path) - the names of the entries in the directory at
+/// path, sorted
+fn stdlib_lipu_kulupu(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Read)?;
+    let read_error = |e: std::io::Error| RuntimeError::FileRead {
+        path: path.to_string(),
+        message: e.to_string(),
+    };
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path).map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(Value::List(names.into_iter().map(Value::String).collect()))
+}
+
+/// lipu_kulupu_sin e (path) - create the directory at path, and any missing
+/// parents
+fn stdlib_lipu_kulupu_sin(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Write)?;
+    fs::create_dir_all(path)
+        .map(|()| Value::Ala)
+        .map_err(|e| RuntimeError::FileWrite {
+            path: path.to_string(),
+            message: e.to_string(),
+        })
+}
+
+/// lipu_kulupu_weka e (path) - remove the directory at path, which must be
+/// empty
+fn stdlib_lipu_kulupu_weka(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Write)?;
+    fs::remove_dir(path)
+        .map(|()| Value::Ala)
+        .map_err(|e| RuntimeError::FileWrite {
+            path: path.to_string(),
+            message: e.to_string(),
+        })
+}
+
+/// How often lipu_lukin_awen looks at the file.
+const WATCH_POLL: Duration = Duration::from_millis(100);
+
+/// How long a changed file must stay unchanged before lipu_lukin_awen
+/// calls back, so an editor's save (often several writes) counts once.
+const WATCH_SETTLE: Duration = Duration::from_millis(200);
+
+/// lipu_lukin_awen e (path, f) - watch the file at path and call f() (or
+/// f(path)) each time it is written, created or removed, once the changes
+/// have settled. Watching goes on while f returns ala; any other value
+/// ends it and is returned. `--timeout` also ends it.
+fn stdlib_lipu_lukin_awen(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let path = expect_string(&args[0])?;
+    interp.check_file(path, FileAccess::Read)?;
+    let with_path = takes_params(&args[1], 1);
+    let mut seen = file_stamp(path);
+    loop {
+        interp.sleep(WATCH_POLL)?;
+        let mut stamp = file_stamp(path);
+        if stamp == seen {
+            continue;
+        }
+        loop {
+            interp.sleep(WATCH_SETTLE)?;
+            let latest = file_stamp(path);
+            if latest == stamp {
+                break;
+            }
+            stamp = latest;
+        }
+        seen = stamp;
+        let call_args = if with_path {
+            vec![Value::String(path.to_string())]
+        } else {
+            Vec::new()
+        };
+        let result = interp.call_value("ilo (lipu_lukin_awen)", &args[1], call_args)?;
+        if !matches!(result, Value::Ala) {
+            return Ok(result);
         }
-        let _ = write!(handle, "{arg}");
     }
-    let _ = writeln!(handle);
+}
+
+/// When the file at path was last modified and its size; `None` if there
+/// is no file.
+fn file_stamp(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// o_lukin e (x) - print x over several lines, one indented line per
+/// element of a kulupu or nasin, each labeled with its type
+fn stdlib_o_lukin(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut out = String::new();
+    inspect(&args[0], 0, &mut out);
+    interp.write_output(&out)?;
     Ok(Value::Ala)
 }
 
+/// Append the `o_lukin` rendering of `value` (starting mid-line, after any
+/// label) to `out`.
+fn inspect(value: &Value, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth + 1);
+    match value {
+        Value::List(items) => {
+            out.push_str(&format!("kulupu ({})\n", items.len()));
+            for (i, item) in items.iter().enumerate() {
+                out.push_str(&format!("{indent}{i}: "));
+                inspect(item, depth + 1, out);
+            }
+        }
+        Value::Map(map) => {
+            out.push_str(&format!("nasin ({})\n", map.len()));
+            for (key, item) in sorted_entries(map) {
+                out.push_str(&format!("{indent}{}: ", show(key)));
+                inspect(item, depth + 1, out);
+            }
+        }
+        Value::String(s) => out.push_str(&format!("sitelen {s:?}\n")),
+        Value::Function { params, .. } => out.push_str(&format!("ilo ({})\n", params.join(", "))),
+        Value::Bool | Value::Ala => out.push_str(&format!("{value}\n")),
+        other => out.push_str(&format!("{} {other}\n", other.type_name())),
+    }
+}
+
 // === Number ===
 
-/// nanpa_sin e (x) - string to number
+/// nanpa_sin e (x, [radix]) - string to number
+///
+/// Surrounding whitespace is ignored and `_` may be used as a digit
+/// separator (`"1_000"`). With a radix other than 10 only integers are
+/// accepted.
 fn stdlib_nanpa_sin(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("nanpa_sin", &args, 1)?;
+    let radix = optional_radix(&args, 1).arg("nanpa_sin", 2)?;
     match &args[0] {
         Value::String(s) => {
-            s.parse::<f64>()
+            parse_number(s, radix)
                 .map(Value::Number)
-                .map_err(|_| RuntimeError::TypeError {
+                .ok_or_else(|| RuntimeError::TypeError {
                     expected: "valid number string",
-                    got: "invalid string".to_string(),
+                    got: format!("\"{s}\""),
+                    arg: arg_at("nanpa_sin", 1),
                 })
         }
         Value::Number(n) => Ok(Value::Number(*n)),
+        Value::Decimal(d) => Ok(Value::Number(d.to_f64())),
         other => Err(RuntimeError::TypeError {
             expected: "sitelen",
             got: other.type_name().to_string(),
+            arg: arg_at("nanpa_sin", 1),
         }),
     }
 }
 
-/// nanpa_len e (x) - number of digits
-fn stdlib_nanpa_len(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("nanpa_len", &args, 1)?;
+/// nanpa_sin_ken e (x, [radix]) - string to number, or ala if it cannot be
+/// parsed; accepts what `nanpa_sin` accepts
+fn stdlib_nanpa_sin_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let radix = optional_radix(&args, 1).arg("nanpa_sin_ken", 2)?;
     match &args[0] {
-        Value::Number(n) => {
-            if n.is_nan() || n.is_infinite() {
-                return Err(RuntimeError::TypeError {
-                    expected: "finite number",
-                    got: format!("{n}"),
-                });
-            }
-            let abs = n.abs();
-            let len = if abs < 1.0 {
-                1 // 0.xxx is considered 1 digit for integer part
-            } else {
-                // Use string formatting to accurately count digits
-                format!("{:.0}", abs.trunc()).len()
-            };
-            Ok(Value::Number(len as f64))
-        }
-        other => Err(RuntimeError::TypeError {
-            expected: "nanpa",
-            got: other.type_name().to_string(),
-        }),
+        Value::String(s) => Ok(parse_number(s, radix).map_or(Value::Ala, Value::Number)),
+        Value::Number(n) => Ok(Value::Number(*n)),
+        Value::Decimal(d) => Ok(Value::Number(d.to_f64())),
+        _ => Ok(Value::Ala),
     }
 }
 
-// === String ===
+/// Parse a number string for `nanpa_sin`. Returns None for anything that is
+/// not a finite number in the given radix.
+fn parse_number(s: &str, radix: u32) -> Option<f64> {
+    let trimmed = s.trim();
+    // `_` is only accepted between digits: "1_000" but not "_1", "1_",
+    // "1__0" or "1._5"
+    let chars: Vec<char> = trimmed.chars().collect();
+    let between_digits = |i: usize| {
+        i > 0 && chars[i - 1].is_digit(radix) && chars.get(i + 1).is_some_and(|c| c.is_digit(radix))
+    };
+    if (0..chars.len()).any(|i| chars[i] == '_' && !between_digits(i)) {
+        return None;
+    }
+    let cleaned: String = trimmed.chars().filter(|c| *c != '_').collect();
 
-/// sitelen_len e (s) - string length
-fn stdlib_sitelen_len(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("sitelen_len", &args, 1)?;
-    match &args[0] {
-        Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
-        other => Err(RuntimeError::TypeError {
-            expected: "sitelen",
-            got: other.type_name().to_string(),
+    if radix == 10 {
+        // Rust's float parser also accepts "inf" and "NaN"; only digits are valid here.
+        if !cleaned
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'))
+        {
+            return None;
+        }
+        return cleaned.parse::<f64>().ok().filter(|n| n.is_finite());
+    }
+
+    let (negative, digits) = match cleaned.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, cleaned.strip_prefix('+').unwrap_or(&cleaned)),
+    };
+    if digits.is_empty() || digits.starts_with(['-', '+']) {
+        return None;
+    }
+    let n = u128::from_str_radix(digits, radix).ok()? as f64;
+    Some(if negative { -n } else { n })
+}
+
+/// Read an optional radix argument at `index`, defaulting to 10.
+fn optional_radix(args: &[Value], index: usize) -> Result<u32, RuntimeError> {
+    match args.get(index) {
+        None => Ok(10),
+        Some(Value::Number(n)) if n.fract() == 0.0 && (2.0..=36.0).contains(n) => Ok(*n as u32),
+        Some(other) => Err(RuntimeError::TypeError {
+            expected: "radix between 2 and 36",
+            got: format!("{other}"),
+            arg: None,
         }),
     }
 }
 
-/// sitelen_sama e (a, b) - string equality
-fn stdlib_sitelen_sama(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("sitelen_sama", &args, 2)?;
-    let a = expect_string(&args[0])?;
-    let b = expect_string(&args[1])?;
-    Ok(if a == b { Value::Bool } else { Value::Ala })
+/// nanpa_len e (x) - number of digits
+fn stdlib_nanpa_len(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let abs = expect_finite_number(&args[0]).arg("nanpa_len", 1)?.abs();
+    let len = if abs < 1.0 {
+        1 // 0.xxx is considered 1 digit for integer part
+    } else {
+        // Use string formatting to accurately count digits
+        format!("{:.0}", abs.trunc()).len()
+    };
+    Ok(Value::Number(len as f64))
 }
 
-fn expect_string(value: &Value) -> Result<&str, RuntimeError> {
-    match value {
-        Value::String(s) => Ok(s),
-        other => Err(RuntimeError::TypeError {
-            expected: "sitelen",
-            got: other.type_name().to_string(),
-        }),
+/// nanpa_sitelen e (n, [radix]) - number to string in the given radix
+fn stdlib_nanpa_sitelen(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_sitelen", 1)?;
+    let radix = optional_radix(&args, 1).arg("nanpa_sitelen", 2)?;
+    if radix == 10 {
+        return Ok(Value::String(format!("{}", Value::Number(n))));
+    }
+    let mut magnitude = to_integer(n).arg("nanpa_sitelen", 1)?.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        let digit = (magnitude % radix as u64) as u32;
+        digits.push(char::from_digit(digit, radix).unwrap_or('?'));
+        magnitude /= radix as u64;
+        if magnitude == 0 {
+            break;
+        }
     }
+    if n < 0.0 {
+        digits.push('-');
+    }
+    Ok(Value::String(digits.iter().rev().collect()))
 }
 
-// === List ===
+/// Upper bound for the `decimals` argument of `nanpa_sitelen_lili`
+const MAX_DECIMALS: usize = 100;
 
-/// kulupu_sin e (...items) - create list
-fn stdlib_kulupu_sin(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    Ok(Value::List(args))
+/// nanpa_sitelen_lili e (n, decimals) - number to string with a fixed number of decimals
+fn stdlib_nanpa_sitelen_lili(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let decimals = expect_index(&args[1]).arg("nanpa_sitelen_lili", 2)?;
+    if decimals > MAX_DECIMALS {
+        return Err(RuntimeError::TypeError {
+            expected: "at most 100 decimals",
+            got: decimals.to_string(),
+            arg: arg_at("nanpa_sitelen_lili", 2),
+        });
+    }
+    if let Value::Decimal(d) = &args[0] {
+        return d
+            .to_fixed(decimals)
+            .map(Value::String)
+            .ok_or(RuntimeError::DecimalOverflow);
+    }
+    let n = expect_finite_number(&args[0]).arg("nanpa_sitelen_lili", 1)?;
+    let formatted = format!("{n:.decimals$}");
+    // Avoid "-0.00" when a small negative number rounds to zero
+    if formatted.starts_with('-') && formatted[1..].chars().all(|c| c == '0' || c == '.') {
+        return Ok(Value::String(formatted[1..].to_string()));
+    }
+    Ok(Value::String(formatted))
 }
 
-/// kulupu_len e (arr) - list length
-fn stdlib_kulupu_len(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("kulupu_len", &args, 1)?;
+/// nanpa_kipisi e (x, [den]) - exact rational number
+///
+/// Accepts a decimal string (`"0.1"`), a number (converted through its
+/// printed form, so `0.1` is exactly 1/10), or an integer numerator and
+/// denominator.
+fn stdlib_nanpa_kipisi(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    if args.len() == 2 {
+        let num = expect_integer(&args[0]).arg("nanpa_kipisi", 1)?;
+        let den = expect_integer(&args[1]).arg("nanpa_kipisi", 2)?;
+        if den == 0 {
+            return Err(RuntimeError::DivisionByZero);
+        }
+        return Rational::new(num, den)
+            .map(Value::Decimal)
+            .ok_or(RuntimeError::DecimalOverflow);
+    }
     match &args[0] {
-        Value::List(items) => Ok(Value::Number(items.len() as f64)),
+        Value::Decimal(d) => Ok(Value::Decimal(*d)),
+        Value::Number(n) => {
+            Rational::from_f64(expect_finite_number(&args[0]).arg("nanpa_kipisi", 1)?)
+                .map(Value::Decimal)
+                .ok_or_else(|| RuntimeError::TypeError {
+                    expected: "number representable as kipisi",
+                    got: format!("{n}"),
+                    arg: arg_at("nanpa_kipisi", 1),
+                })
+        }
+        Value::String(s) => {
+            Rational::parse(s)
+                .map(Value::Decimal)
+                .ok_or_else(|| RuntimeError::TypeError {
+                    expected: "decimal number string",
+                    got: format!("\"{s}\""),
+                    arg: arg_at("nanpa_kipisi", 1),
+                })
+        }
         other => Err(RuntimeError::TypeError {
-            expected: "kulupu",
+            expected: "nanpa or sitelen",
             got: other.type_name().to_string(),
+            arg: arg_at("nanpa_kipisi", 1),
         }),
     }
 }
 
-/// kulupu_ken e (arr, i) - get element
-fn stdlib_kulupu_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("kulupu_ken", &args, 2)?;
-    match (&args[0], &args[1]) {
-        (Value::List(items), Value::Number(i)) => {
-            let index = to_index(*i)?;
-            if index >= items.len() {
-                Ok(Value::Ala)
-            } else {
-                Ok(items[index].clone())
-            }
-        }
-        (Value::List(_), other) => Err(RuntimeError::TypeError {
-            expected: "nanpa",
-            got: other.type_name().to_string(),
-        }),
-        (other, _) => Err(RuntimeError::TypeError {
-            expected: "kulupu",
-            got: other.type_name().to_string(),
-        }),
+/// nanpa_sama_lili e (a, b, eps) - lon if a and b differ by at most eps
+///
+/// The comparison to use instead of `sama` for numbers that are not whole,
+/// e.g. `0.1 + 0.2` and `0.3`. Kipisi are compared through their nearest f64.
+fn stdlib_nanpa_sama_lili(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let as_f64 = |value: &Value| match value {
+        Value::Decimal(d) => Ok(d.to_f64()),
+        other => expect_finite_number(other),
+    };
+    let a = as_f64(&args[0]).arg("nanpa_sama_lili", 1)?;
+    let b = as_f64(&args[1]).arg("nanpa_sama_lili", 2)?;
+    let eps = as_f64(&args[2]).arg("nanpa_sama_lili", 3)?;
+    if eps < 0.0 {
+        return Err(RuntimeError::TypeError {
+            expected: "eps of at least 0",
+            got: format!("{eps}"),
+            arg: arg_at("nanpa_sama_lili", 3),
+        });
     }
+    Ok(if (a - b).abs() <= eps {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
 }
 
-/// kulupu_lon e (arr, i, val) - set element
-fn stdlib_kulupu_lon(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("kulupu_lon", &args, 3)?;
-    match (&args[0], &args[1]) {
-        (Value::List(items), Value::Number(i)) => {
-            let index = to_index(*i)?;
-            if index >= items.len() {
-                Err(RuntimeError::IndexOutOfBounds {
-                    index,
-                    len: items.len(),
-                })
-            } else {
-                let mut new_items = items.clone();
-                new_items[index] = args[2].clone();
-                Ok(Value::List(new_items))
-            }
+/// nanpa_insa_ale e (n) - drop the fractional part (towards zero)
+fn stdlib_nanpa_insa_ale(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_insa_ale", 1)?;
+    Ok(Value::Number(
+        to_integer(n.trunc()).arg("nanpa_insa_ale", 1)? as f64,
+    ))
+}
+
+/// nanpa_sike_nasin e (n, [mode]) - round to an integer
+///
+/// `mode` is "half_up" (the default; halves go towards +infinity),
+/// "half_even" (halves go to the even neighbour), "floor" or "ceil".
+fn stdlib_nanpa_sike_nasin(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_sike_nasin", 1)?;
+    let mode = match args.get(1) {
+        Some(mode) => expect_string(mode).arg("nanpa_sike_nasin", 2)?,
+        None => "half_up",
+    };
+    let rounded = match mode {
+        "half_up" => round_half_up(n),
+        "half_even" => n.round_ties_even(),
+        "floor" => n.floor(),
+        "ceil" => n.ceil(),
+        _ => {
+            return Err(RuntimeError::TypeError {
+                expected: "rounding mode half_up, half_even, floor or ceil",
+                got: format!("\"{mode}\""),
+                arg: arg_at("nanpa_sike_nasin", 2),
+            })
         }
-        (Value::List(_), other) => Err(RuntimeError::TypeError {
-            expected: "nanpa",
-            got: other.type_name().to_string(),
-        }),
-        (other, _) => Err(RuntimeError::TypeError {
-            expected: "kulupu",
-            got: other.type_name().to_string(),
-        }),
+    };
+    Ok(Value::Number(
+        to_integer(rounded).arg("nanpa_sike_nasin", 1)? as f64,
+    ))
+}
+
+/// Round to the nearest integer, halves towards +infinity.
+fn round_half_up(n: f64) -> f64 {
+    let floor = n.floor();
+    // Exact for every f64 with a fractional part, unlike `(n + 0.5).floor()`.
+    if n - floor >= 0.5 {
+        floor + 1.0
+    } else {
+        floor
     }
 }
 
-/// kulupu_aksen e (arr, val) - append
-fn stdlib_kulupu_aksen(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("kulupu_aksen", &args, 2)?;
-    match &args[0] {
-        Value::List(items) => {
-            let mut new_items = items.clone();
-            new_items.push(args[1].clone());
-            Ok(Value::List(new_items))
-        }
-        other => Err(RuntimeError::TypeError {
-            expected: "kulupu",
-            got: other.type_name().to_string(),
-        }),
+/// nanpa_anpa e (x) - largest integer not above x
+fn stdlib_nanpa_anpa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_anpa", 1)?;
+    Ok(Value::Number(n.floor()))
+}
+
+/// nanpa_sewi e (x) - smallest integer not below x
+fn stdlib_nanpa_sewi(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_sewi", 1)?;
+    Ok(Value::Number(n.ceil()))
+}
+
+/// nanpa_sike e (x) - nearest integer (halves go up, like nanpa_sike_nasin)
+fn stdlib_nanpa_sike(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_sike", 1)?;
+    Ok(Value::Number(round_half_up(n)))
+}
+
+/// nanpa_insa e (x) - absolute value
+fn stdlib_nanpa_insa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_insa", 1)?;
+    Ok(Value::Number(n.abs()))
+}
+
+/// nanpa_noka e (x) - square root of a non-negative number
+fn stdlib_nanpa_noka(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_finite_number(&args[0]).arg("nanpa_noka", 1)?;
+    if n < 0.0 {
+        return Err(RuntimeError::TypeError {
+            expected: "nanpa that is not negative",
+            got: format!("{}", Value::Number(n)),
+            arg: arg_at("nanpa_noka", 1),
+        });
     }
+    Ok(Value::Number(n.sqrt()))
 }
 
-// === Map ===
+/// nanpa_mute e (a, b) - a to the power b; a result that is not a finite
+/// number (`0` to a negative power, a fractional power of a negative
+/// number, overflow) is an error
+fn stdlib_nanpa_mute(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let a = expect_finite_number(&args[0]).arg("nanpa_mute", 1)?;
+    let b = expect_finite_number(&args[1]).arg("nanpa_mute", 2)?;
+    finite_number("nanpa_mute", a.powf(b))
+}
 
-/// nasin_sin e () - create empty map
-fn stdlib_nasin_sin(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("nasin_sin", &args, 0)?;
-    Ok(Value::Map(HashMap::new()))
+/// nanpa_nasa e () - random number in [0, 1)
+fn stdlib_nanpa_nasa(interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(interp.random().next_f64()))
 }
 
-/// nasin_ken e (m, key) - get value
-fn stdlib_nasin_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("nasin_ken", &args, 2)?;
-    match (&args[0], &args[1]) {
-        (Value::Map(map), Value::String(key)) => Ok(map.get(key).cloned().unwrap_or(Value::Ala)),
-        (Value::Map(_), other) => Err(RuntimeError::TypeError {
-            expected: "sitelen",
-            got: other.type_name().to_string(),
-        }),
-        (other, _) => Err(RuntimeError::TypeError {
-            expected: "nasin",
-            got: other.type_name().to_string(),
-        }),
+/// nanpa_nasa_insa e (min, max) - random integer from min to max, both
+/// included
+fn stdlib_nanpa_nasa_insa(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let min = expect_integer(&args[0]).arg("nanpa_nasa_insa", 1)?;
+    let max = expect_integer(&args[1]).arg("nanpa_nasa_insa", 2)?;
+    if min > max {
+        return Err(RuntimeError::TypeError {
+            expected: "max not below min",
+            got: format!("{min} and {max}"),
+            arg: arg_at("nanpa_nasa_insa", 2),
+        });
     }
+    // Both are within ±2^53, so the span fits in a u64.
+    let span = (max - min + 1) as u64;
+    Ok(Value::Number(
+        (min + i128::from(interp.random().below(span))) as f64,
+    ))
 }
 
-/// nasin_lon e (m, key, val) - set value
-fn stdlib_nasin_lon(args: Vec<Value>) -> Result<Value, RuntimeError> {
-    check_arity("nasin_lon", &args, 3)?;
-    match (&args[0], &args[1]) {
-        (Value::Map(map), Value::String(key)) => {
-            let mut new_map = map.clone();
-            new_map.insert(key.clone(), args[2].clone());
-            Ok(Value::Map(new_map))
-        }
-        (Value::Map(_), other) => Err(RuntimeError::TypeError {
-            expected: "sitelen",
-            got: other.type_name().to_string(),
-        }),
-        (other, _) => Err(RuntimeError::TypeError {
-            expected: "nasin",
-            got: other.type_name().to_string(),
-        }),
+/// nanpa_mod e (a, b) - remainder of a / b, never negative (so
+/// `nanpa_mod(0 - 1, 5)` is 4)
+fn stdlib_nanpa_mod(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let a = expect_finite_number(&args[0]).arg("nanpa_mod", 1)?;
+    let b = expect_finite_number(&args[1]).arg("nanpa_mod", 2)?;
+    if b == 0.0 {
+        return Err(RuntimeError::DivisionByZero);
     }
+    Ok(Value::Number(a.rem_euclid(b)))
 }
 
-// === Helper ===
+fn expect_integer(value: &Value) -> Result<i128, RuntimeError> {
+    to_integer(expect_finite_number(value)?).map(i128::from)
+}
 
-fn check_arity(name: &str, args: &[Value], expected: usize) -> Result<(), RuntimeError> {
-    if args.len() != expected {
-        Err(RuntimeError::WrongArity {
-            name: name.to_string(),
-            expected,
-            got: args.len(),
-        })
+/// Wrap a computed result, rejecting NaN and infinity as `NonFinite` from
+/// `function` (see Lipona.md §8).
+fn finite_number(function: &str, n: f64) -> Result<Value, RuntimeError> {
+    if n.is_finite() {
+        Ok(Value::Number(n))
     } else {
-        Ok(())
+        Err(RuntimeError::NonFinite {
+            op: function.to_string(),
+            nan: n.is_nan(),
+            span: None,
+        })
     }
 }
 
-/// Convert f64 to usize for indexing, validating it's a non-negative integer
-fn to_index(n: f64) -> Result<usize, RuntimeError> {
-    if n < 0.0 || n.is_nan() || n.is_infinite() || n.fract() != 0.0 {
-        return Err(RuntimeError::TypeError {
-            expected: "non-negative integer",
+fn expect_finite_number(value: &Value) -> Result<f64, RuntimeError> {
+    match value {
+        Value::Number(n) if n.is_finite() => Ok(*n),
+        Value::Number(n) => Err(RuntimeError::TypeError {
+            expected: "finite number",
             got: format!("{n}"),
-        });
-    }
-    // Check against both f64 safe integer range and platform's usize::MAX
-    let max_safe = F64_SAFE_INT_MAX.min(usize::MAX as f64);
-    if n > max_safe {
+            arg: None,
+        }),
+        other => Err(RuntimeError::TypeError {
+            expected: "nanpa",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    }
+}
+
+// === String ===
+
+/// sitelen_len e (s) - string length
+fn stdlib_sitelen_len(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    Ok(Value::Number(s.chars().count() as f64))
+}
+
+/// sitelen_ken e (s, start, end) - substring by character index
+///
+/// Like `kulupu_ken`, a start past the end gives ala; an end past the end
+/// is clamped, and `start >= end` gives "".
+fn stdlib_sitelen_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let start = expect_index(&args[1]).arg("sitelen_ken", 2)?;
+    let end = expect_index(&args[2]).arg("sitelen_ken", 3)?;
+    if start > s.chars().count() {
+        return Ok(Value::Ala);
+    }
+    let slice: String = s
+        .chars()
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect();
+    Ok(Value::String(slice))
+}
+
+/// sitelen_wan_ken e (s, i) - the character at index i as a sitelen, or
+/// ala past the end (like kulupu_ken)
+fn stdlib_sitelen_wan_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let index = expect_index(&args[1]).arg("sitelen_wan_ken", 2)?;
+    Ok(s.chars()
+        .nth(index)
+        .map_or(Value::Ala, |c| Value::String(c.to_string())))
+}
+
+/// sitelen_nanpa e (c) - Unicode codepoint of a one-character sitelen
+fn stdlib_sitelen_nanpa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Value::Number(f64::from(u32::from(c)))),
+        _ => Err(RuntimeError::TypeError {
+            expected: "sitelen of one character",
+            got: format!("{s:?}"),
+            arg: arg_at("sitelen_nanpa", 1),
+        }),
+    }
+}
+
+/// sitelen_tan_nanpa e (n) - the one-character sitelen for codepoint n
+fn stdlib_sitelen_tan_nanpa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let n = expect_integer(&args[0]).arg("sitelen_tan_nanpa", 1)?;
+    u32::try_from(n)
+        .ok()
+        .and_then(char::from_u32)
+        .map(|c| Value::String(c.to_string()))
+        .ok_or_else(|| RuntimeError::TypeError {
+            expected: "Unicode codepoint",
+            got: n.to_string(),
+            arg: arg_at("sitelen_tan_nanpa", 1),
+        })
+}
+
+/// sitelen_sama e (a, b) - string equality
+fn stdlib_sitelen_sama(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let a = expect_string(&args[0])?;
+    let b = expect_string(&args[1])?;
+    Ok(if a == b { Value::Bool } else { Value::Ala })
+}
+
+/// sitelen_lon e (s, needle) - does s contain needle?
+fn stdlib_sitelen_lon(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let needle = expect_string(&args[1])?;
+    Ok(if s.contains(needle) {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// sitelen_alasa e (s, needle) - character index of the first needle in s
+/// (the index sitelen_ken takes), or ala
+fn stdlib_sitelen_alasa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    Ok(match s.find(expect_string(&args[1])?) {
+        Some(byte) => Value::Number(s[..byte].chars().count() as f64),
+        None => Value::Ala,
+    })
+}
+
+/// sitelen_open e (s, prefix) - does s start with prefix?
+fn stdlib_sitelen_open(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let prefix = expect_string(&args[1])?;
+    Ok(if s.starts_with(prefix) {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// sitelen_pini e (s, suffix) - does s end with suffix?
+fn stdlib_sitelen_pini(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let suffix = expect_string(&args[1])?;
+    Ok(if s.ends_with(suffix) {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// sitelen_ante e (s, from, to) - s with every `from` replaced by `to`
+fn stdlib_sitelen_ante(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (s, from, to) = replace_args("sitelen_ante", &args)?;
+    let count = s.matches(from).count();
+    let len = (s.len() - count * from.len()).saturating_add(count.saturating_mul(to.len()));
+    interp.check_len("sitelen", len)?;
+    Ok(Value::String(s.replace(from, to)))
+}
+
+/// sitelen_ante_wan e (s, from, to) - s with the first `from` replaced by `to`
+fn stdlib_sitelen_ante_wan(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (s, from, to) = replace_args("sitelen_ante_wan", &args)?;
+    Ok(Value::String(s.replacen(from, to, 1)))
+}
+
+/// sitelen_kipisi_nasin e (s, delims, [quotes]) - the tokens of s, split at
+/// every character of delims. A run of whitespace delimiters counts once
+/// and never makes an empty token; any other delimiter ends a token, empty
+/// or not. If quotes is truthy, text in "..." or '...' is part of a token
+/// whatever it holds (the quotes are dropped, a doubled quote inside is
+/// one quote).
+fn stdlib_sitelen_kipisi_nasin(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let delims = expect_string(&args[1])?;
+    if delims.is_empty() {
+        return Err(RuntimeError::TypeError {
+            expected: "non-empty sitelen",
+            got: "\"\"".to_string(),
+            arg: arg_at("sitelen_kipisi_nasin", 2),
+        });
+    }
+    let quotes = args.get(2).is_some_and(Value::is_truthy);
+
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    // Whether `token` has begun: it has text or quotes.
+    let mut started = false;
+    // Whether a delimiter other than whitespace is waiting for its token.
+    let mut pending = false;
+    // Whether whitespace just ended a token, so a `,` after it ends nothing.
+    let mut spaced = false;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quotes && (c == '"' || c == '\'') {
+            loop {
+                match chars.next() {
+                    Some(q) if q == c && chars.peek() == Some(&c) => {
+                        chars.next();
+                        token.push(c);
+                    }
+                    Some(q) if q == c => break,
+                    Some(q) => token.push(q),
+                    None => {
+                        return Err(RuntimeError::Argument(format!(
+                            "sitelen_kipisi_nasin: {c} is never closed"
+                        )))
+                    }
+                }
+            }
+            started = true;
+        } else if !delims.contains(c) {
+            token.push(c);
+            started = true;
+        } else if c.is_whitespace() {
+            if started {
+                tokens.push(Value::String(std::mem::take(&mut token)));
+                (started, pending, spaced) = (false, false, true);
+            }
+        } else {
+            if started || pending || !spaced {
+                tokens.push(Value::String(std::mem::take(&mut token)));
+            }
+            (started, pending, spaced) = (false, true, false);
+        }
+    }
+    if started || pending {
+        tokens.push(Value::String(token));
+    }
+    Ok(Value::List(tokens))
+}
+
+/// The arguments of the replace builtins. An empty `from` would match
+/// between every character, so it is rejected.
+fn replace_args<'a>(
+    function: &'static str,
+    args: &'a [Value],
+) -> Result<(&'a str, &'a str, &'a str), RuntimeError> {
+    let from = expect_string(&args[1])?;
+    if from.is_empty() {
         return Err(RuntimeError::TypeError {
-            expected: "index within safe integer range",
-            got: format!("{n} exceeds maximum safe index"),
+            expected: "non-empty sitelen",
+            got: "\"\"".to_string(),
+            arg: arg_at(function, 2),
+        });
+    }
+    Ok((expect_string(&args[0])?, from, expect_string(&args[2])?))
+}
+
+/// sitelen_suli e (s) - uppercase (Unicode: "ß" becomes "SS")
+fn stdlib_sitelen_suli(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::String(expect_string(&args[0])?.to_uppercase()))
+}
+
+/// sitelen_lili e (s) - lowercase
+fn stdlib_sitelen_lili(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::String(expect_string(&args[0])?.to_lowercase()))
+}
+
+/// sitelen_weka e (s) - without leading and trailing whitespace
+fn stdlib_sitelen_weka(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::String(expect_string(&args[0])?.trim().to_string()))
+}
+
+/// sitelen_len_ale e (s) - length in user-perceived characters (grapheme clusters)
+///
+/// Unlike `sitelen_len`, an emoji with modifiers or a letter with combining
+/// marks counts as one.
+fn stdlib_sitelen_len_ale(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    Ok(Value::Number(s.graphemes(true).count() as f64))
+}
+
+/// sitelen_ken_ale e (s, start, end) - substring by grapheme cluster index
+///
+/// Like `sitelen_ken`, a start past the end gives ala; an end past the end
+/// is clamped, and `start >= end` gives "".
+fn stdlib_sitelen_ken_ale(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let start = expect_index(&args[1]).arg("sitelen_ken_ale", 2)?;
+    let end = expect_index(&args[2]).arg("sitelen_ken_ale", 3)?;
+    if start > s.graphemes(true).count() {
+        return Ok(Value::Ala);
+    }
+    let slice: String = s
+        .graphemes(true)
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect();
+    Ok(Value::String(slice))
+}
+
+/// sitelen_kipisi_ale e (s) - split into a list of grapheme clusters
+fn stdlib_sitelen_kipisi_ale(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    Ok(Value::List(
+        s.graphemes(true)
+            .map(|g| Value::String(g.to_string()))
+            .collect(),
+    ))
+}
+
+/// sitelen_sama_nasin e (s, [form]) - Unicode normalization
+///
+/// `form` is one of "NFC" (default), "NFD", "NFKC" or "NFKD".
+fn stdlib_sitelen_sama_nasin(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    let form = match args.get(1) {
+        Some(value) => expect_string(value)?,
+        None => "NFC",
+    };
+    normalize(s, form)
+        .map(Value::String)
+        .arg("sitelen_sama_nasin", 2)
+}
+
+/// sitelen_sama_lukin e (a, b) - equality up to canonical equivalence
+///
+/// "é" typed as one code point and as e + U+0301 compare equal here,
+/// while `sitelen_sama` and `sama` compare code points.
+fn stdlib_sitelen_sama_lukin(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let a = expect_string(&args[0])?;
+    let b = expect_string(&args[1])?;
+    Ok(if a.nfc().eq(b.nfc()) {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// sitelen_nasin_pona e (a, b, [locale]) - collation order: -1, 0 or 1
+///
+/// Without a locale strings are ordered by code point, like `kulupu_nasin_pona`.
+fn stdlib_sitelen_nasin_pona(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let a = expect_string(&args[0])?;
+    let b = expect_string(&args[1])?;
+    let ordering = match args.get(2) {
+        Some(locale) => collator(expect_string(locale)?)
+            .arg("sitelen_nasin_pona", 3)?
+            .compare(a, b),
+        None => a.cmp(b),
+    };
+    Ok(Value::Number(ordering as i8 as f64))
+}
+
+/// sitelen_pona_nimi e (s) - is `s` an identifier made of toki pona words?
+///
+/// Parts are separated by `_`; a capitalized part is a proper name and only
+/// needs to follow toki pona phonotactics. Same rule as `lipona --lint`.
+fn stdlib_sitelen_pona_nimi(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let s = expect_string(&args[0])?;
+    Ok(if lint::identifier_problems(s, &[]).is_empty() {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// Build a collator for a BCP 47 locale tag such as "sv" or "de-DE".
+#[cfg(feature = "collation")]
+fn collator(tag: &str) -> Result<Collator, RuntimeError> {
+    let invalid = || RuntimeError::TypeError {
+        expected: "locale such as \"en\" or \"sv-SE\"",
+        got: format!("\"{tag}\""),
+        arg: None,
+    };
+    let locale: Locale = tag.parse().map_err(|_| invalid())?;
+    Collator::try_new(&(&locale).into(), CollatorOptions::new()).map_err(|_| invalid())
+}
+
+/// Without the `collation` feature there is no collator to build, so any
+/// locale argument is an error.
+#[cfg(not(feature = "collation"))]
+enum Collator {}
+
+#[cfg(not(feature = "collation"))]
+impl Collator {
+    fn compare(&self, _: &str, _: &str) -> Ordering {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "collation"))]
+fn collator(_tag: &str) -> Result<Collator, RuntimeError> {
+    Err(RuntimeError::Argument(
+        "locale comparison needs lipona built with the \"collation\" feature".to_string(),
+    ))
+}
+
+fn normalize(s: &str, form: &str) -> Result<String, RuntimeError> {
+    match form.to_ascii_uppercase().as_str() {
+        "NFC" => Ok(s.nfc().collect()),
+        "NFD" => Ok(s.nfd().collect()),
+        "NFKC" => Ok(s.nfkc().collect()),
+        "NFKD" => Ok(s.nfkd().collect()),
+        _ => Err(RuntimeError::TypeError {
+            expected: "normalization form NFC, NFD, NFKC or NFKD",
+            got: format!("\"{form}\""),
+            arg: None,
+        }),
+    }
+}
+
+fn expect_string(value: &Value) -> Result<&str, RuntimeError> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(RuntimeError::TypeError {
+            expected: "sitelen",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    }
+}
+
+// === List ===
+
+/// kulupu_sin e (...items) - create list
+fn stdlib_kulupu_sin(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    interp.check_len("kulupu", args.len())?;
+    Ok(Value::List(args))
+}
+
+/// kulupu_len e (arr) - list length
+fn stdlib_kulupu_len(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    Ok(Value::Number(items.len() as f64))
+}
+
+/// kulupu_ken e (arr, i) - get element
+fn stdlib_kulupu_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let index = expect_index(&args[1]).arg("kulupu_ken", 2)?;
+    Ok(items.get(index).cloned().unwrap_or(Value::Ala))
+}
+
+/// kulupu_lon e (arr, i, val) - set element
+fn stdlib_kulupu_lon(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let index = expect_index(&args[1]).arg("kulupu_lon", 2)?;
+    if index >= items.len() {
+        return Err(RuntimeError::IndexOutOfBounds {
+            index,
+            len: items.len(),
+        });
+    }
+    let mut new_items = items.clone();
+    new_items[index] = args[2].clone();
+    Ok(Value::List(new_items))
+}
+
+/// kulupu_aksen e (arr, val) - append
+fn stdlib_kulupu_aksen(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut new_items = expect_list(&args[0])?.clone();
+    new_items.push(args[1].clone());
+    Ok(Value::List(new_items))
+}
+
+/// kulupu_ante e (arr, f) - new list of f(x) for each element, or
+/// f(x, index) if f takes two parameters
+fn stdlib_kulupu_ante(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let with_index = takes_params(&args[1], 2);
+    let mut result = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let call_args = if with_index {
+            vec![item.clone(), Value::Number(i as f64)]
+        } else {
+            vec![item.clone()]
+        };
+        result.push(interp.call_value("ilo (kulupu_ante)", &args[1], call_args)?);
+    }
+    Ok(Value::List(result))
+}
+
+/// kulupu_wile e (arr, f) - new list of the elements for which f(x) (or
+/// f(x, index)) is truthy
+fn stdlib_kulupu_wile(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let with_index = takes_params(&args[1], 2);
+    let mut result = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let call_args = if with_index {
+            vec![item.clone(), Value::Number(i as f64)]
+        } else {
+            vec![item.clone()]
+        };
+        if interp
+            .call_value("ilo (kulupu_wile)", &args[1], call_args)?
+            .is_truthy()
+        {
+            result.push(item.clone());
+        }
+    }
+    Ok(Value::List(result))
+}
+
+/// kulupu_kipisi e (arr, n) - arr cut into kulupu of n elements; the last
+/// one has the rest, which may be fewer
+fn stdlib_kulupu_kipisi(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let size = expect_size(&args[1]).arg("kulupu_kipisi", 2)?;
+    Ok(Value::List(
+        items
+            .chunks(size)
+            .map(|chunk| Value::List(chunk.to_vec()))
+            .collect(),
+    ))
+}
+
+/// kulupu_lukin_poka e (arr, n) - every run of n neighbouring elements, in
+/// order; none if arr is shorter than n
+fn stdlib_kulupu_lukin_poka(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let size = expect_size(&args[1]).arg("kulupu_lukin_poka", 2)?;
+    Ok(Value::List(
+        items
+            .windows(size)
+            .map(|window| Value::List(window.to_vec()))
+            .collect(),
+    ))
+}
+
+/// kulupu_kulupu_e e (arr, f) - a nasin from each f(x) (or f(x, index)) to
+/// the elements it was returned for, in their order in arr
+fn stdlib_kulupu_kulupu_e(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let with_index = takes_params(&args[1], 2);
+    let mut groups: HashMap<Value, Vec<Value>> = HashMap::new();
+    for (i, item) in items.iter().enumerate() {
+        let call_args = if with_index {
+            vec![item.clone(), Value::Number(i as f64)]
+        } else {
+            vec![item.clone()]
+        };
+        let key = interp.call_value("ilo (kulupu_kulupu_e)", &args[1], call_args)?;
+        if !key.is_key() {
+            return Err(RuntimeError::TypeError {
+                expected: "nasin key (a value without an ilo in it)",
+                got: key.type_name().to_string(),
+                arg: None,
+            });
+        }
+        groups.entry(key).or_default().push(item.clone());
+    }
+    Ok(Value::Map(
+        groups
+            .into_iter()
+            .map(|(key, group)| (key, Value::List(group)))
+            .collect(),
+    ))
+}
+
+/// kulupu_wan e (arr, init, f) - fold from the left: f(f(init, x0), x1) ...
+fn stdlib_kulupu_wan(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let mut acc = args[1].clone();
+    for item in items {
+        acc = interp.call_value("ilo (kulupu_wan)", &args[2], vec![acc, item.clone()])?;
+    }
+    Ok(acc)
+}
+
+/// kulupu_nasin_pona e (arr, [locale]) - sorted copy of a list
+///
+/// The list must hold only numbers (nanpa/kipisi) or only strings. Strings
+/// are ordered by code point unless a locale is given, in which case that
+/// locale's collation rules are used (e.g. "sv" puts "ö" after "z").
+fn stdlib_kulupu_nasin_pona(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut items = expect_list(&args[0])?.clone();
+    // Checked even when the list holds numbers, so a bad locale is never
+    // silently ignored.
+    let collator = match args.get(1) {
+        Some(locale) => Some(collator(expect_string(locale)?).arg("kulupu_nasin_pona", 2)?),
+        None => None,
+    };
+
+    let strings: Option<Vec<String>> = items
+        .iter()
+        .map(|v| match v {
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        })
+        .collect();
+    if let Some(mut strings) = strings {
+        strings.sort_by(|a, b| match &collator {
+            Some(collator) => collator.compare(a, b),
+            None => a.cmp(b),
+        });
+        items = strings.into_iter().map(Value::String).collect();
+    } else if let Some(other) = items
+        .iter()
+        .find(|v| !matches!(v, Value::Number(_) | Value::Decimal(_)))
+    {
+        return Err(RuntimeError::TypeError {
+            expected: "kulupu of only nanpa or only sitelen",
+            got: other.type_name().to_string(),
+            arg: arg_at("kulupu_nasin_pona", 1),
+        });
+    } else {
+        items.sort_by(compare_numbers);
+    }
+    Ok(Value::List(items))
+}
+
+/// ijo_nasin_sama e (a, b) - -1, 0 or 1 as a comes before, with or after b
+///
+/// Every nanpa comes before every sitelen. Numbers compare by value, so
+/// `2` and a kipisi `2` are 0; sitelen compare by code point, like
+/// kulupu_nasin_pona without a locale.
+fn stdlib_ijo_nasin_sama(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    for (i, value) in args.iter().enumerate() {
+        expect_ordered(value).arg("ijo_nasin_sama", i + 1)?;
+    }
+    Ok(Value::Number(
+        compare_ordered(&args[0], &args[1]) as i8 as f64
+    ))
+}
+
+/// kulupu_nanpa e (arr, [f]) - new list of arr's elements in order, equal
+/// ones keeping their order. Without f the elements must be nanpa or
+/// sitelen and go in ijo_nasin_sama order; with f, a comes before b when
+/// f(a, b) is below 0 (and after it when above 0).
+fn stdlib_kulupu_nanpa(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?.clone();
+    let Some(compare) = args.get(1) else {
+        for item in &items {
+            expect_ordered(item).map_err(|_| RuntimeError::TypeError {
+                expected: "kulupu of nanpa and sitelen",
+                got: item.type_name().to_string(),
+                arg: arg_at("kulupu_nanpa", 1),
+            })?;
+        }
+        let mut items = items;
+        items.sort_by(compare_ordered);
+        return Ok(Value::List(items));
+    };
+    let sorted = merge_sort(items, &mut |a, b| match interp.call_value(
+        "ilo (kulupu_nanpa)",
+        compare,
+        vec![a.clone(), b.clone()],
+    )? {
+        Value::Number(n) => Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+        other => Err(RuntimeError::TypeError {
+            expected: "nanpa from the ilo given to kulupu_nanpa",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    })?;
+    Ok(Value::List(sorted))
+}
+
+/// A stable sort that stops at the first error from `compare`. Unlike
+/// `sort_by`, any answers from a script's ilo are fine, even inconsistent ones.
+fn merge_sort(
+    mut items: Vec<Value>,
+    compare: &mut impl FnMut(&Value, &Value) -> Result<Ordering, RuntimeError>,
+) -> Result<Vec<Value>, RuntimeError> {
+    if items.len() < 2 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, compare)?;
+    let right = merge_sort(right, compare)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // Take from the right only when strictly smaller, so ties keep order.
+        let next = if compare(r, l)?.is_lt() {
+            right.next()
+        } else {
+            left.next()
+        };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+/// kulupu_alasa_wawa e (sorted, v) - the first index of v in sorted, or
+/// ala if it is not there. sorted must be in ijo_nasin_sama order (as
+/// kulupu_nasin_pona leaves it); only the elements looked at are checked.
+fn stdlib_kulupu_alasa_wawa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let items = expect_list(&args[0])?;
+    let target = expect_ordered(&args[1]).arg("kulupu_alasa_wawa", 2)?;
+    let (mut low, mut high) = (0, items.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let item = expect_ordered(&items[mid]).map_err(|_| RuntimeError::TypeError {
+            expected: "kulupu of nanpa and sitelen",
+            got: items[mid].type_name().to_string(),
+            arg: arg_at("kulupu_alasa_wawa", 1),
+        })?;
+        if compare_ordered(item, target).is_lt() {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(match items.get(low) {
+        Some(item) if compare_ordered(item, target).is_eq() => Value::Number(low as f64),
+        _ => Value::Ala,
+    })
+}
+
+/// kulupu_wan_taso e (arr) - arr without the elements equal to an earlier
+/// one, compared as nasin keys are
+fn stdlib_kulupu_wan_taso(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut seen = HashSet::new();
+    let mut items: Vec<Value> = Vec::new();
+    for item in expect_list(&args[0])? {
+        // An ilo cannot be hashed; there are few enough to compare one by one.
+        let new = if item.is_key() {
+            seen.insert(item)
+        } else {
+            !items.contains(item)
+        };
+        if new {
+            items.push(item.clone());
+        }
+    }
+    Ok(Value::List(items))
+}
+
+fn expect_ordered(value: &Value) -> Result<&Value, RuntimeError> {
+    match value {
+        Value::Number(_) | Value::Decimal(_) | Value::String(_) => Ok(value),
+        other => Err(RuntimeError::TypeError {
+            expected: "nanpa or sitelen",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    }
+}
+
+/// The ijo_nasin_sama order of two values `expect_ordered` accepted.
+fn compare_ordered(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::String(_), _) => Ordering::Greater,
+        (_, Value::String(_)) => Ordering::Less,
+        (a, b) => compare_numbers(a, b),
+    }
+}
+
+fn compare_numbers(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Decimal(a), Value::Decimal(b)) => a.cmp(b),
+        _ => as_f64(a).total_cmp(&as_f64(b)),
+    }
+}
+
+fn as_f64(value: &Value) -> f64 {
+    match value {
+        Value::Number(n) => *n,
+        Value::Decimal(r) => r.to_f64(),
+        _ => f64::NAN,
+    }
+}
+
+// === Grid ===
+
+/// kulupu_leko_sin e (rows, cols, [fill]) - rows x cols list of lists, filled with `fill` (default 0)
+///
+/// Under `--max-list` the cell count rows x cols must fit the limit too,
+/// not only each list on its own.
+fn stdlib_kulupu_leko_sin(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let rows = expect_index(&args[0]).arg("kulupu_leko_sin", 1)?;
+    let cols = expect_index(&args[1]).arg("kulupu_leko_sin", 2)?;
+    interp.check_len("kulupu", rows)?;
+    interp.check_len("kulupu", cols)?;
+    interp.check_len("kulupu", rows.saturating_mul(cols))?;
+    let fill = args.get(2).cloned().unwrap_or(Value::Number(0.0));
+    let row = Value::List(vec![fill; cols]);
+    Ok(Value::List(vec![row; rows]))
+}
+
+/// kulupu_leko_sike e (grid) - transpose a rectangular list of lists
+fn stdlib_kulupu_leko_sike(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let rows = expect_grid(&args[0]).arg("kulupu_leko_sike", 1)?;
+    let cols = rows.first().map_or(0, |r| r.len());
+    let transposed = (0..cols)
+        .map(|j| Value::List(rows.iter().map(|row| row[j].clone()).collect()))
+        .collect();
+    Ok(Value::List(transposed))
+}
+
+/// kulupu_leko_ante e (grid, f) - apply `f` to every cell
+///
+/// `f` is called as `f(value)`, or `f(value, row, col)` if it takes three
+/// parameters.
+fn stdlib_kulupu_leko_ante(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let rows = expect_grid(&args[0]).arg("kulupu_leko_ante", 1)?;
+    let with_position = takes_params(&args[1], 3);
+
+    let mut result = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        let mut new_row = Vec::with_capacity(row.len());
+        for (j, cell) in row.iter().enumerate() {
+            let call_args = if with_position {
+                vec![
+                    cell.clone(),
+                    Value::Number(i as f64),
+                    Value::Number(j as f64),
+                ]
+            } else {
+                vec![cell.clone()]
+            };
+            new_row.push(interp.call_value("ilo (kulupu_leko_ante)", &args[1], call_args)?);
+        }
+        result.push(Value::List(new_row));
+    }
+    Ok(Value::List(result))
+}
+
+/// Borrow the rows of a rectangular grid (every row a list of equal length).
+fn expect_grid(value: &Value) -> Result<Vec<&Vec<Value>>, RuntimeError> {
+    let Value::List(rows) = value else {
+        return Err(RuntimeError::TypeError {
+            expected: "kulupu of kulupu",
+            got: value.type_name().to_string(),
+            arg: None,
+        });
+    };
+    let rows = rows
+        .iter()
+        .map(|row| match row {
+            Value::List(cells) => Ok(cells),
+            other => Err(RuntimeError::TypeError {
+                expected: "kulupu of kulupu",
+                got: format!("kulupu containing {}", other.type_name()),
+                arg: None,
+            }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(first) = rows.first() {
+        if let Some(bad) = rows.iter().find(|r| r.len() != first.len()) {
+            return Err(RuntimeError::TypeError {
+                expected: "rows of equal length",
+                got: format!("rows of length {} and {}", first.len(), bad.len()),
+                arg: None,
+            });
+        }
+    }
+    Ok(rows)
+}
+
+fn expect_index(value: &Value) -> Result<usize, RuntimeError> {
+    match value {
+        Value::Number(n) => to_index(*n),
+        other => Err(RuntimeError::TypeError {
+            expected: "nanpa",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    }
+}
+
+/// A count of elements, at least 1.
+fn expect_size(value: &Value) -> Result<usize, RuntimeError> {
+    match expect_index(value)? {
+        0 => Err(RuntimeError::TypeError {
+            expected: "positive integer",
+            got: "0".to_string(),
+            arg: None,
+        }),
+        size => Ok(size),
+    }
+}
+
+// === Statistics ===
+
+/// kulupu_nanpa_sona_meso e (arr) - arithmetic mean
+fn stdlib_sona_meso(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let nums = expect_number_list(&args[0]).arg("kulupu_nanpa_sona_meso", 1)?;
+    finite_number("kulupu_nanpa_sona_meso", mean(&nums))
+}
+
+/// kulupu_nanpa_sona_insa e (arr) - median
+fn stdlib_sona_insa(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let nums = sorted(expect_number_list(&args[0]).arg("kulupu_nanpa_sona_insa", 1)?);
+    finite_number("kulupu_nanpa_sona_insa", percentile(&nums, 50.0))
+}
+
+/// kulupu_nanpa_sona_ante e (arr, [sample]) - variance
+///
+/// Population variance by default; pass a truthy second argument for the
+/// sample variance (divides by n - 1).
+fn stdlib_sona_ante(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let nums = expect_number_list(&args[0]).arg("kulupu_nanpa_sona_ante", 1)?;
+    let sample = args.get(1).is_some_and(Value::is_truthy);
+    finite_number("kulupu_nanpa_sona_ante", variance(&nums, sample)?)
+}
+
+/// kulupu_nanpa_sona_weka e (arr, [sample]) - standard deviation
+fn stdlib_sona_weka(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let nums = expect_number_list(&args[0]).arg("kulupu_nanpa_sona_weka", 1)?;
+    let sample = args.get(1).is_some_and(Value::is_truthy);
+    finite_number("kulupu_nanpa_sona_weka", variance(&nums, sample)?.sqrt())
+}
+
+/// kulupu_nanpa_sona_lon e (arr, p) - p-th percentile (0..=100), linearly interpolated
+fn stdlib_sona_lon(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let nums = sorted(expect_number_list(&args[0]).arg("kulupu_nanpa_sona_lon", 1)?);
+    let p = match &args[1] {
+        Value::Number(p) if (0.0..=100.0).contains(p) => *p,
+        other => {
+            return Err(RuntimeError::TypeError {
+                expected: "percentile between 0 and 100",
+                got: format!("{other}"),
+                arg: arg_at("kulupu_nanpa_sona_lon", 2),
+            })
+        }
+    };
+    finite_number("kulupu_nanpa_sona_lon", percentile(&nums, p))
+}
+
+/// Extract a non-empty list of numbers (kipisi are converted to nanpa).
+fn expect_number_list(value: &Value) -> Result<Vec<f64>, RuntimeError> {
+    let Value::List(items) = value else {
+        return Err(RuntimeError::TypeError {
+            expected: "kulupu",
+            got: value.type_name().to_string(),
+            arg: None,
+        });
+    };
+    if items.is_empty() {
+        return Err(RuntimeError::TypeError {
+            expected: "non-empty kulupu",
+            got: "empty kulupu".to_string(),
+            arg: None,
+        });
+    }
+    items
+        .iter()
+        .map(|item| match item {
+            Value::Number(n) => Ok(*n),
+            Value::Decimal(d) => Ok(d.to_f64()),
+            other => Err(RuntimeError::TypeError {
+                expected: "kulupu of nanpa",
+                got: format!("kulupu containing {}", other.type_name()),
+                arg: None,
+            }),
+        })
+        .collect()
+}
+
+fn sorted(mut nums: Vec<f64>) -> Vec<f64> {
+    nums.sort_by(f64::total_cmp);
+    nums
+}
+
+fn mean(nums: &[f64]) -> f64 {
+    nums.iter().sum::<f64>() / nums.len() as f64
+}
+
+fn variance(nums: &[f64], sample: bool) -> Result<f64, RuntimeError> {
+    let divisor = if sample { nums.len() - 1 } else { nums.len() };
+    if divisor == 0 {
+        return Err(RuntimeError::TypeError {
+            expected: "at least 2 values for sample variance",
+            got: "1 value".to_string(),
+            arg: None,
+        });
+    }
+    let m = mean(nums);
+    Ok(nums.iter().map(|n| (n - m) * (n - m)).sum::<f64>() / divisor as f64)
+}
+
+/// Percentile of an already sorted, non-empty slice.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+// === Map ===
+
+/// nasin_sin e () - create empty map
+fn stdlib_nasin_sin(_args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Map(HashMap::new()))
+}
+
+/// nasin_ken e (m, key) - get value
+fn stdlib_nasin_ken(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = expect_map(&args[0])?;
+    let key = expect_key(&args[1]).arg("nasin_ken", 2)?;
+    Ok(map.get(key).cloned().unwrap_or(Value::Ala))
+}
+
+/// nasin_lon e (m, key, val) - set value
+fn stdlib_nasin_lon(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut new_map = expect_map(&args[0])?.clone();
+    let key = expect_key(&args[1]).arg("nasin_lon", 2)?;
+    new_map.insert(key.clone(), args[2].clone());
+    Ok(Value::Map(new_map))
+}
+
+/// nasin_weka e (m, key) - new map without key (m itself if key is not in it)
+fn stdlib_nasin_weka(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut new_map = expect_map(&args[0])?.clone();
+    let key = expect_key(&args[1]).arg("nasin_weka", 2)?;
+    new_map.remove(key);
+    Ok(Value::Map(new_map))
+}
+
+/// nasin_jo e (m, key) - lon if m has key, even when its value is ala
+fn stdlib_nasin_jo(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = expect_map(&args[0])?;
+    let key = expect_key(&args[1]).arg("nasin_jo", 2)?;
+    Ok(if map.contains_key(key) {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// nasin_wan e (a, b) - new map with the entries of both; b wins where both
+/// have a key
+fn stdlib_nasin_wan(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut new_map = expect_map(&args[0])?.clone();
+    new_map.extend(
+        expect_map(&args[1])?
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone())),
+    );
+    Ok(Value::Map(new_map))
+}
+
+/// nasin_nimi e (m) - the keys of m, in the order `tawa ... lon m` visits
+/// them (see `Value::key_order`)
+fn stdlib_nasin_nimi(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let entries = sorted_entries(expect_map(&args[0])?);
+    Ok(Value::List(
+        entries.into_iter().map(|(key, _)| key.clone()).collect(),
+    ))
+}
+
+/// nasin_ijo e (m) - the values of m, in the order of their keys
+fn stdlib_nasin_ijo(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let entries = sorted_entries(expect_map(&args[0])?);
+    Ok(Value::List(
+        entries
+            .into_iter()
+            .map(|(_, value)| value.clone())
+            .collect(),
+    ))
+}
+
+/// nasin_tu e (m) - a kulupu_sin(key, value) for each entry, in the order
+/// of the keys
+fn stdlib_nasin_tu(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let entries = sorted_entries(expect_map(&args[0])?);
+    Ok(Value::List(
+        entries
+            .into_iter()
+            .map(|(key, value)| Value::List(vec![key.clone(), value.clone()]))
+            .collect(),
+    ))
+}
+
+// === Regex ===
+
+/// regex_lon e (s, pattern) - lon if pattern matches somewhere in s
+#[cfg(feature = "regex")]
+fn stdlib_regex_lon(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let regex = interp
+        .stdlib()
+        .regex("regex_lon", expect_string(&args[1])?)?;
+    Ok(if regex.is_match(expect_string(&args[0])?) {
+        Value::Bool
+    } else {
+        Value::Ala
+    })
+}
+
+/// regex_alasa e (s, pattern) - the first match in s as a kulupu: the
+/// whole match, then each group (ala for a group that took no part), or
+/// ala if nothing matches
+#[cfg(feature = "regex")]
+fn stdlib_regex_alasa(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let regex = interp
+        .stdlib()
+        .regex("regex_alasa", expect_string(&args[1])?)?;
+    Ok(match regex.captures(expect_string(&args[0])?) {
+        Some(captures) => Value::List(
+            captures
+                .iter()
+                .map(|group| group.map_or(Value::Ala, |m| Value::String(m.as_str().to_string())))
+                .collect(),
+        ),
+        None => Value::Ala,
+    })
+}
+
+/// regex_ante e (s, pattern, repl) - s with every match replaced by repl,
+/// in which `$1` or `${name}` is a group and `$$` a dollar sign
+#[cfg(feature = "regex")]
+fn stdlib_regex_ante(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let regex = interp
+        .stdlib()
+        .regex("regex_ante", expect_string(&args[1])?)?;
+    let s = expect_string(&args[0])?;
+    let replacement = expect_string(&args[2])?;
+    // Replace match by match (as `Regex::replace_all` does) so that a
+    // result over --max-string is refused before it is built.
+    let mut replaced = String::new();
+    let mut last = 0;
+    for captures in regex.captures_iter(s) {
+        let matched = captures.get(0).expect("group 0 is the whole match");
+        replaced.push_str(&s[last..matched.start()]);
+        captures.expand(replacement, &mut replaced);
+        last = matched.end();
+        interp.check_len("sitelen", replaced.len() + s.len() - last)?;
+    }
+    replaced.push_str(&s[last..]);
+    Ok(Value::String(replaced))
+}
+
+/// Without the `regex` feature every `regex_*` builtin is an error.
+#[cfg(not(feature = "regex"))]
+fn no_regex() -> Result<Value, RuntimeError> {
+    Err(RuntimeError::Argument(
+        "regex_* needs lipona built with the \"regex\" feature".to_string(),
+    ))
+}
+
+#[cfg(not(feature = "regex"))]
+fn stdlib_regex_lon(_interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    no_regex()
+}
+
+#[cfg(not(feature = "regex"))]
+fn stdlib_regex_alasa(_interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    no_regex()
+}
+
+#[cfg(not(feature = "regex"))]
+fn stdlib_regex_ante(_interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    no_regex()
+}
+
+// === CSV ===
+
+/// csv_open e (text) - the rows of CSV text, each a kulupu of sitelen.
+/// Quoted fields may hold commas, newlines and doubled quotes (`""`); a
+/// blank line is an empty row. Numbers stay sitelen (see nanpa_sin).
+fn stdlib_csv_open(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = expect_string(&args[0])?;
+    let mut rows = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let mut row = Vec::new();
+        loop {
+            let mut field = String::new();
+            if chars.next_if_eq(&'"').is_some() {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(RuntimeError::Argument(format!(
+                                "csv_open: the quoted field on line {start} is not closed"
+                            )))
+                        }
+                    }
+                }
+            }
+            // Anything after a closing quote is kept as it is.
+            while let Some(c) = chars.next_if(|c| !matches!(c, ',' | '\n' | '\r')) {
+                field.push(c);
+            }
+            row.push(Value::String(field));
+            interp.check_len("kulupu", row.len())?;
+            match chars.next() {
+                Some(',') => {}
+                Some('\r') => {
+                    chars.next_if_eq(&'\n');
+                    break;
+                }
+                _ => break,
+            }
+        }
+        line += 1;
+        if matches!(row.as_slice(), [Value::String(field)] if field.is_empty()) {
+            row.clear();
+        }
+        rows.push(Value::List(row));
+        interp.check_len("kulupu", rows.len())?;
+    }
+    Ok(Value::List(rows))
+}
+
+/// csv_pini e (rows) - CSV text for a kulupu of rows, each a kulupu of
+/// sitelen or nanpa (ala is an empty field), one line per row. Fields
+/// holding commas, quotes or line breaks are quoted.
+fn stdlib_csv_pini(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut text = String::new();
+    for row in expect_list(&args[0])? {
+        let Value::List(fields) = row else {
+            return Err(RuntimeError::TypeError {
+                expected: "kulupu of rows (kulupu)",
+                got: row.type_name().to_string(),
+                arg: arg_at("csv_pini", 1),
+            });
+        };
+        let mut cells = Vec::with_capacity(fields.len());
+        for field in fields {
+            let cell = match field {
+                Value::String(s) => s.clone(),
+                Value::Number(_) | Value::Decimal(_) => field.to_string(),
+                Value::Ala => String::new(),
+                other => {
+                    return Err(RuntimeError::TypeError {
+                        expected: "sitelen or nanpa in a row",
+                        got: other.type_name().to_string(),
+                        arg: arg_at("csv_pini", 1),
+                    })
+                }
+            };
+            // A lone empty field is quoted so it does not read back as a blank line.
+            if cell.contains([',', '"', '\n', '\r']) || (fields.len() == 1 && cell.is_empty()) {
+                cells.push(format!("\"{}\"", cell.replace('"', "\"\"")));
+            } else {
+                cells.push(cell);
+            }
+        }
+        text.push_str(&cells.join(","));
+        text.push('\n');
+    }
+    Ok(Value::String(text))
+}
+
+// === Testing ===
+
+/// Differences listed by `wile_sama` before the rest are counted.
+const MAX_DIFF_LINES: usize = 20;
+
+/// wile_sama e (a, b) - raise `AssertionFailed` unless a sama b. The error
+/// lists each path inside nested kulupu / nasin where the values differ.
+fn stdlib_wile_sama(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    if args[0] == args[1] {
+        return Ok(Value::Ala);
+    }
+    let mut lines = Vec::new();
+    value_diff(&args[0], &args[1], &mut String::new(), &mut lines);
+    let more = lines.len().saturating_sub(MAX_DIFF_LINES);
+    lines.truncate(MAX_DIFF_LINES);
+    if more > 0 {
+        lines.push(format!("... and {more} more"));
+    }
+    let lines: Vec<String> = lines.iter().map(|line| format!("  {line}")).collect();
+    Err(RuntimeError::AssertionFailed(lines.join("\n")))
+}
+
+/// Append one line per place where `a` and `b` differ, written as the
+/// path from the top (`[1]["nimi"]`) and the two values there.
+fn value_diff(a: &Value, b: &Value, path: &mut String, out: &mut Vec<String>) {
+    let at = |path: &str| {
+        if path.is_empty() {
+            String::new()
+        } else {
+            format!("{path}: ")
+        }
+    };
+    match (a, b) {
+        (Value::List(left), Value::List(right)) => {
+            for i in 0..left.len().max(right.len()) {
+                let len = path.len();
+                path.push_str(&format!("[{i}]"));
+                match (left.get(i), right.get(i)) {
+                    (Some(l), Some(r)) => value_diff(l, r, path, out),
+                    (Some(l), None) => {
+                        out.push(format!("{}only on the left: {}", at(path), show(l)))
+                    }
+                    (None, Some(r)) => {
+                        out.push(format!("{}only on the right: {}", at(path), show(r)))
+                    }
+                    (None, None) => {}
+                }
+                path.truncate(len);
+            }
+        }
+        (Value::Map(left), Value::Map(right)) => {
+            let mut keys: Vec<&Value> = left.keys().chain(right.keys()).collect();
+            keys.sort_by(|a, b| a.key_order(b));
+            keys.dedup();
+            for key in keys {
+                let len = path.len();
+                path.push_str(&format!("[{}]", show(key)));
+                match (left.get(key), right.get(key)) {
+                    (Some(l), Some(r)) => value_diff(l, r, path, out),
+                    (Some(l), None) => {
+                        out.push(format!("{}only on the left: {}", at(path), show(l)))
+                    }
+                    (None, Some(r)) => {
+                        out.push(format!("{}only on the right: {}", at(path), show(r)))
+                    }
+                    (None, None) => {}
+                }
+                path.truncate(len);
+            }
+        }
+        _ if a != b => out.push(format!("{}{} != {}", at(path), show(a), show(b))),
+        _ => {}
+    }
+}
+
+/// A value in a diff line: strings quoted, so "1" and 1 look different.
+pub fn show(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{s:?}"),
+        other => other.to_string(),
+    }
+}
+
+// === Errors ===
+
+/// pakala_sin e (msg) - raise an error carrying msg (any value), which
+/// ken_pali can catch
+fn stdlib_pakala_sin(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Err(RuntimeError::UserError(Box::new(args[0].clone())))
+}
+
+/// ken_pali e (f) - run f() and return nasin {pona: lon, ijo: result}, or
+/// {pona: ala, pakala: error} if it raised an error. With a handler,
+/// ken_pali(f, g) returns f(), or g(error) if it raised one.
+///
+/// The error is the value given to pakala_sin, or the message of any other
+/// runtime error. Exceeding a resource limit is never caught.
+fn stdlib_ken_pali(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let error = match interp.call_value("ilo (ken_pali)", &args[0], vec![]) {
+        Ok(value) if args.len() == 2 => return Ok(value),
+        Ok(value) => return Ok(outcome(true, "ijo", value)),
+        Err(err) if err.is_catchable() => {
+            interp.take_trace();
+            match err.innermost() {
+                RuntimeError::UserError(value) => (**value).clone(),
+                _ => Value::String(err.to_string()),
+            }
+        }
+        Err(err) => return Err(err),
+    };
+    match args.get(1) {
+        Some(handler) => interp.call_value("ilo (ken_pali)", handler, vec![error]),
+        None => Ok(outcome(false, "pakala", error)),
+    }
+}
+
+/// The nasin ken_pali returns without a handler.
+fn outcome(ok: bool, key: &str, value: Value) -> Value {
+    let pona = if ok { Value::Bool } else { Value::Ala };
+    Value::Map(HashMap::from([
+        (Value::String("pona".to_string()), pona),
+        (Value::String(key.to_string()), value),
+    ]))
+}
+
+// === Introspection ===
+
+/// sona_ilo_ale e ([module]) - sorted qualified names of every builtin, or
+/// of those in one module
+fn stdlib_sona_ilo_ale(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let wanted = args.first().map(expect_string).transpose()?;
+    if let Some(wanted) = wanted {
+        if !MODULES.iter().any(|module| module.name == wanted) {
+            return Err(RuntimeError::Argument(format!(
+                "no stdlib module '{wanted}'"
+            )));
+        }
+    }
+    let mut names: Vec<String> = MODULES
+        .iter()
+        .filter(|module| wanted.is_none_or(|wanted| module.name == wanted))
+        .flat_map(|module| {
+            module
+                .builtins
+                .iter()
+                .map(|builtin| module.qualified(builtin.name))
+        })
+        .collect();
+    names.sort();
+    Ok(Value::List(names.into_iter().map(Value::String).collect()))
+}
+
+// === Time ===
+
+/// tenpo_ni e () - seconds since 1970-01-01 00:00 UTC, with a fraction
+fn stdlib_tenpo_ni(_args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(unix_now()))
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// The time argument at `position` (1-based), or now.
+fn time_arg(args: &[Value], position: usize, function: &'static str) -> Result<f64, RuntimeError> {
+    match args.get(position - 1) {
+        Some(value) => expect_finite_number(value).arg(function, position),
+        None => Ok(unix_now()),
+    }
+}
+
+/// A Unix time broken into UTC calendar fields.
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    /// 0 for Sunday.
+    weekday: u32,
+}
+
+impl DateTime {
+    fn from_unix(time: f64) -> Self {
+        let seconds = time.floor() as i64;
+        let days = seconds.div_euclid(86_400);
+        let of_day = seconds.rem_euclid(86_400) as u32;
+        // Days to civil date (proleptic Gregorian), after Howard Hinnant's
+        // `civil_from_days`.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        Self {
+            year,
+            month,
+            day,
+            hour: of_day / 3600,
+            minute: of_day / 60 % 60,
+            second: of_day % 60,
+            // 1970-01-01 was a Thursday.
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+}
+
+/// tenpo_sitelen e (format, [time]) - the time (default now) in UTC, with
+/// %Y %m %d %H %M %S replaced by its fields and %% by %
+fn stdlib_tenpo_sitelen(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let format = expect_string(&args[0]).arg("tenpo_sitelen", 1)?;
+    let t = DateTime::from_unix(time_arg(&args, 2, "tenpo_sitelen")?);
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", t.year)),
+            Some('m') => out.push_str(&format!("{:02}", t.month)),
+            Some('d') => out.push_str(&format!("{:02}", t.day)),
+            Some('H') => out.push_str(&format!("{:02}", t.hour)),
+            Some('M') => out.push_str(&format!("{:02}", t.minute)),
+            Some('S') => out.push_str(&format!("{:02}", t.second)),
+            Some('%') => out.push('%'),
+            other => {
+                return Err(RuntimeError::TypeError {
+                    expected: "format with %Y %m %d %H %M %S or %%",
+                    got: format!("'%{}'", other.map(String::from).unwrap_or_default()),
+                    arg: arg_at("tenpo_sitelen", 1),
+                })
+            }
+        }
+    }
+    Ok(Value::String(out))
+}
+
+/// tenpo_kipisi e ([time]) - the time (default now) in UTC as a nasin with
+/// year, month, day, hour, minute, second and weekday (0 = Sunday)
+fn stdlib_tenpo_kipisi(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let t = DateTime::from_unix(time_arg(&args, 1, "tenpo_kipisi")?);
+    let fields = [
+        ("year", t.year as f64),
+        ("month", f64::from(t.month)),
+        ("day", f64::from(t.day)),
+        ("hour", f64::from(t.hour)),
+        ("minute", f64::from(t.minute)),
+        ("second", f64::from(t.second)),
+        ("weekday", f64::from(t.weekday)),
+    ];
+    Ok(Value::Map(
+        fields
+            .into_iter()
+            .map(|(key, n)| (Value::String(key.to_string()), Value::Number(n)))
+            .collect(),
+    ))
+}
+
+/// tenpo_awen e (seconds) - wait this many seconds (fractions allowed)
+fn stdlib_tenpo_awen(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let seconds = expect_finite_number(&args[0]).arg("tenpo_awen", 1)?;
+    let duration = Duration::try_from_secs_f64(seconds).map_err(|_| RuntimeError::TypeError {
+        expected: "nanpa that is not negative",
+        got: format!("{seconds}"),
+        arg: arg_at("tenpo_awen", 1),
+    })?;
+    interp.sleep(duration)?;
+    Ok(Value::Ala)
+}
+
+// === Standard input ===
+
+/// kute e () - the next line of input without its line ending, or ala at
+/// the end of the input
+fn stdlib_kute(interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(interp
+        .read_input_line()?
+        .map(Value::String)
+        .unwrap_or(Value::Ala))
+}
+
+/// kute_ale e () - the rest of the input as one sitelen
+fn stdlib_kute_ale(interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    interp.read_input_all().map(Value::String)
+}
+
+// === Script arguments ===
+
+/// ijo_kama e () - the script's arguments, as a kulupu of sitelen
+fn stdlib_ijo_kama(interp: &mut Interpreter, _args: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::List(
+        interp.args().iter().cloned().map(Value::String).collect(),
+    ))
+}
+
+/// pana_nasin_kama e (spec) - parse the script's arguments into a nasin.
+///
+/// `spec` is a kulupu of sitelen, one per argument:
+/// - `"--nimi"`: flag; `nimi` is lon when given, else ala
+/// - `"--nimi="` / `"--nimi=x"`: option taking a value (`--nimi v` or
+///   `--nimi=v`); defaults to ala / "x"
+/// - `"nimi"`: required positional argument
+/// - `"nimi=x"`: optional positional argument defaulting to "x"
+///
+/// Values are sitelen; arguments after a bare `--` are all positional.
+/// Unknown options, missing values and extra arguments raise `Argument`.
+fn stdlib_pana_nasin_kama(
+    interp: &mut Interpreter,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    let spec = expect_list(&args[0])
+        .arg("pana_nasin_kama", 1)?
+        .iter()
+        .map(expect_string)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // name -> takes a value
+    let mut options: HashMap<&str, bool> = HashMap::new();
+    let mut positionals: Vec<(&str, Option<&str>)> = Vec::new();
+    let mut result = HashMap::new();
+    for item in spec {
+        let (name, default) = match item.split_once('=') {
+            Some((name, default)) => (name, Some(default)),
+            None => (item, None),
+        };
+        match name.strip_prefix("--") {
+            Some(option) => {
+                options.insert(option, default.is_some());
+                let value = match default {
+                    Some("") | None => Value::Ala,
+                    Some(default) => Value::String(default.to_string()),
+                };
+                result.insert(Value::String(option.to_string()), value);
+            }
+            None => positionals.push((name, default)),
+        }
+    }
+
+    let argv = interp.args().to_vec();
+    let mut argv = argv.iter();
+    let mut positional = positionals.iter();
+    let mut only_positional = false;
+    while let Some(arg) = argv.next() {
+        // After a bare `--`, everything is positional.
+        if arg == "--" && !only_positional {
+            only_positional = true;
+            continue;
+        }
+        let Some(option) = arg.strip_prefix("--").filter(|_| !only_positional) else {
+            let Some((name, _)) = positional.next() else {
+                return Err(RuntimeError::Argument(format!(
+                    "unexpected argument '{arg}'"
+                )));
+            };
+            result.insert(Value::String(name.to_string()), Value::String(arg.clone()));
+            continue;
+        };
+        let (option, inline) = match option.split_once('=') {
+            Some((option, value)) => (option, Some(value)),
+            None => (option, None),
+        };
+        let value = match (options.get(option).copied(), inline) {
+            (None, _) => {
+                return Err(RuntimeError::Argument(format!(
+                    "unknown option '--{option}'"
+                )))
+            }
+            (Some(false), None) => Value::Bool,
+            (Some(false), Some(_)) => {
+                return Err(RuntimeError::Argument(format!(
+                    "'--{option}' does not take a value"
+                )))
+            }
+            (Some(true), Some(value)) => Value::String(value.to_string()),
+            (Some(true), None) => match argv.next() {
+                Some(value) => Value::String(value.clone()),
+                None => {
+                    return Err(RuntimeError::Argument(format!(
+                        "'--{option}' needs a value"
+                    )))
+                }
+            },
+        };
+        result.insert(Value::String(option.to_string()), value);
+    }
+    for (name, default) in positional {
+        match default {
+            Some(default) => result.insert(
+                Value::String(name.to_string()),
+                Value::String(default.to_string()),
+            ),
+            None => return Err(RuntimeError::Argument(format!("missing argument '{name}'"))),
+        };
+    }
+    Ok(Value::Map(result))
+}
+
+// === Environment variables ===
+
+/// poki_ma_ken e (name) - the environment variable's value, or ala if it is
+/// not set (or not valid UTF-8)
+fn stdlib_poki_ma_ken(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let name = expect_string(&args[0])?;
+    Ok(interp.env_var(name).map_or(Value::Ala, Value::String))
+}
+
+/// poki_ma_lon e (name, value) - set the environment variable for this
+/// script and the programs ilo_ma starts. The process environment is not
+/// changed, so other interpreters in the same process do not see it.
+fn stdlib_poki_ma_lon(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let name = expect_string(&args[0])?;
+    let value = expect_string(&args[1])?;
+    // No program could be given a variable like this.
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Err(RuntimeError::TypeError {
+            expected: "environment variable name",
+            got: format!("{name:?}"),
+            arg: arg_at("poki_ma_lon", 1),
+        });
+    }
+    if value.contains('\0') {
+        return Err(RuntimeError::TypeError {
+            expected: "sitelen without a NUL character",
+            got: format!("{value:?}"),
+            arg: arg_at("poki_ma_lon", 2),
+        });
+    }
+    interp.set_env_var(name, value);
+    Ok(Value::Ala)
+}
+
+// === Other programs ===
+
+/// ilo_ma e (cmd, [args]) - run the program cmd with the sitelen in args
+/// and wait for it. Returns a map: `stdout` and `stderr` are what it
+/// printed, `exit` its exit code (ala if a signal ended it). Its standard
+/// input is empty. Raises ExecDenied unless the host allows it.
+fn stdlib_ilo_ma(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let command = expect_string(&args[0])?;
+    let mut arguments = Vec::new();
+    if let Some(list) = args.get(1) {
+        for item in expect_list(list)? {
+            match item {
+                Value::String(arg) => arguments.push(arg.as_str()),
+                other => {
+                    return Err(RuntimeError::TypeError {
+                        expected: "kulupu of sitelen",
+                        got: other.type_name().to_string(),
+                        arg: arg_at("ilo_ma", 2),
+                    })
+                }
+            }
+        }
+    }
+    interp.check_exec(command)?;
+    let output = std::process::Command::new(command)
+        .args(&arguments)
+        .envs(interp.env_vars())
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| RuntimeError::Exec {
+            command: command.to_string(),
+            message: e.to_string(),
+        })?;
+    let exit = output
+        .status
+        .code()
+        .map_or(Value::Ala, |code| Value::Number(f64::from(code)));
+    Ok(Value::Map(HashMap::from([
+        (
+            Value::String("stdout".to_string()),
+            Value::String(String::from_utf8_lossy(&output.stdout).into_owned()),
+        ),
+        (
+            Value::String("stderr".to_string()),
+            Value::String(String::from_utf8_lossy(&output.stderr).into_owned()),
+        ),
+        (Value::String("exit".to_string()), exit),
+    ])))
+}
+
+// === HTTP ===
+
+/// How long linja_ma_* wait to connect, and then for each read.
+#[cfg(feature = "net")]
+const NET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// linja_ma_kama e (url, [headers]) - GET url, sending the sitelen in the
+/// nasin headers. Returns a map: `status` (a nanpa), `headers` (a nasin
+/// with lower-case names; repeated headers are joined with ", ") and
+/// `body` (sitelen). A 404 or 500 is a response, not an error; only a
+/// request that gets no response raises Net. Raises NetDenied unless the
+/// host allows it.
+fn stdlib_linja_ma_kama(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let url = expect_string(&args[0])?;
+    let headers = match args.get(1) {
+        Some(headers) => expect_headers(headers).arg("linja_ma_kama", 2)?,
+        None => Vec::new(),
+    };
+    interp.check_net(url)?;
+    http_request("GET", url, &headers, None)
+}
+
+/// linja_ma_pana e (url, body, [headers]) - POST the sitelen body to url;
+/// returns what linja_ma_kama does.
+fn stdlib_linja_ma_pana(interp: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let url = expect_string(&args[0])?;
+    let body = expect_string(&args[1])?;
+    let headers = match args.get(2) {
+        Some(headers) => expect_headers(headers).arg("linja_ma_pana", 3)?,
+        None => Vec::new(),
+    };
+    interp.check_net(url)?;
+    http_request("POST", url, &headers, Some(body))
+}
+
+fn expect_headers(value: &Value) -> Result<Vec<(String, String)>, RuntimeError> {
+    let mut headers = Vec::new();
+    for (name, value) in sorted_entries(expect_map(value)?) {
+        match (name, value) {
+            (Value::String(name), Value::String(value)) => {
+                headers.push((name.clone(), value.clone()));
+            }
+            _ => {
+                return Err(RuntimeError::TypeError {
+                    expected: "nasin of sitelen to sitelen",
+                    got: format!("{}: {}", name.type_name(), value.type_name()),
+                    arg: None,
+                })
+            }
+        }
+    }
+    Ok(headers)
+}
+
+#[cfg(feature = "net")]
+fn http_request(
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    body: Option<&str>,
+) -> Result<Value, RuntimeError> {
+    let failed = |message: String| RuntimeError::Net {
+        url: url.to_string(),
+        message,
+    };
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(NET_TIMEOUT)
+        .timeout_read(NET_TIMEOUT)
+        .build();
+    let mut request = agent.request(method, url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let response = match body {
+        Some(body) => request.send_string(body),
+        None => request.call(),
+    };
+    let response = match response {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(ureq::Error::Transport(e)) => return Err(failed(e.to_string())),
+    };
+    let status = Value::Number(f64::from(response.status()));
+    let mut names = response.headers_names();
+    names.dedup();
+    let headers: HashMap<Value, Value> = names
+        .iter()
+        .map(|name| {
+            (
+                Value::String(name.to_ascii_lowercase()),
+                Value::String(response.all(name).join(", ")),
+            )
+        })
+        .collect();
+    let body = response.into_string().map_err(|e| failed(e.to_string()))?;
+    Ok(Value::Map(HashMap::from([
+        (Value::String("status".to_string()), status),
+        (Value::String("headers".to_string()), Value::Map(headers)),
+        (Value::String("body".to_string()), Value::String(body)),
+    ])))
+}
+
+#[cfg(not(feature = "net"))]
+fn http_request(
+    _method: &str,
+    url: &str,
+    _headers: &[(String, String)],
+    _body: Option<&str>,
+) -> Result<Value, RuntimeError> {
+    Err(RuntimeError::Net {
+        url: url.to_string(),
+        message: "lipona was built without the \"net\" feature".to_string(),
+    })
+}
+
+// === Helper ===
+
+fn expect_list(value: &Value) -> Result<&Vec<Value>, RuntimeError> {
+    match value {
+        Value::List(items) => Ok(items),
+        other => Err(RuntimeError::TypeError {
+            expected: "kulupu",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    }
+}
+
+/// Any value without an ilo in it can be a nasin key.
+fn expect_key(value: &Value) -> Result<&Value, RuntimeError> {
+    if value.is_key() {
+        Ok(value)
+    } else {
+        Err(RuntimeError::TypeError {
+            expected: "nasin key (a value without an ilo in it)",
+            got: value.type_name().to_string(),
+            arg: None,
+        })
+    }
+}
+
+fn expect_map(value: &Value) -> Result<&HashMap<Value, Value>, RuntimeError> {
+    match value {
+        Value::Map(map) => Ok(map),
+        other => Err(RuntimeError::TypeError {
+            expected: "nasin",
+            got: other.type_name().to_string(),
+            arg: None,
+        }),
+    }
+}
+
+/// Whether `func` is a user function taking `count` parameters (builtins
+/// that pass an optional extra argument check this first).
+fn takes_params(func: &Value, count: usize) -> bool {
+    matches!(func, Value::Function { params, .. } if params.len() == count)
+}
+
+/// Attach the builtin argument a `TypeError` came from (see `ArgPosition`).
+trait ArgContext<T> {
+    fn arg(self, function: &'static str, position: usize) -> Result<T, RuntimeError>;
+}
+
+impl<T> ArgContext<T> for Result<T, RuntimeError> {
+    fn arg(self, function: &'static str, position: usize) -> Result<T, RuntimeError> {
+        self.map_err(|error| match error {
+            RuntimeError::TypeError {
+                expected,
+                got,
+                arg: None,
+            } => RuntimeError::TypeError {
+                expected,
+                got,
+                arg: arg_at(function, position),
+            },
+            error => error,
+        })
+    }
+}
+
+fn arg_at(function: &'static str, position: usize) -> Option<ArgPosition> {
+    Some(ArgPosition { function, position })
+}
+
+/// Convert f64 to an integer, validating it is whole and within the f64
+/// safe-integer range (where every integer is exact).
+fn to_integer(n: f64) -> Result<i64, RuntimeError> {
+    if !n.is_finite() || n.fract() != 0.0 {
+        return Err(RuntimeError::TypeError {
+            expected: "integer",
+            got: format!("{}", Value::Number(n)),
+            arg: None,
+        });
+    }
+    if n.abs() > F64_SAFE_INT_MAX {
+        return Err(RuntimeError::TypeError {
+            expected: "integer within safe range",
+            got: format!("{}", Value::Number(n)),
+            arg: None,
+        });
+    }
+    Ok(n as i64)
+}
+
+/// Convert f64 to usize for indexing, validating it's a non-negative integer
+fn to_index(n: f64) -> Result<usize, RuntimeError> {
+    if n < 0.0 {
+        return Err(RuntimeError::TypeError {
+            expected: "non-negative integer",
+            got: format!("{}", Value::Number(n)),
+            arg: None,
         });
     }
-    Ok(n as usize)
+    usize::try_from(to_integer(n)?).map_err(|_| RuntimeError::TypeError {
+        expected: "index within safe integer range",
+        got: format!("{n} exceeds maximum safe index"),
+        arg: None,
+    })
 }
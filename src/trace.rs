@@ -0,0 +1,354 @@
+//! `--record` and `lipona replay`: a run written down step by step, so the
+//! state of a student's program can be looked at after it ended, on
+//! another machine if need be.
+//!
+//! A trace is text with one step per line and tab-separated fields:
+//!
+//! ```text
+//! global  1:1   x    5        a global was set (line:column)
+//! call    6:9   sum  5  2     an ilo was called; the arguments follow
+//! set     3:5   total  7      a variable of the innermost call was set
+//! return  sum   7             the innermost call returned
+//! raise   sum                 the innermost call ended with an error
+//! ```
+//!
+//! Values are written as `wile_sama` shows them, strings quoted. A trace is
+//! for reading, not for resuming the run.
+
+use std::io::{self, Write};
+
+use crate::ast::Span;
+use crate::interpreter::Event;
+use crate::stdlib::show;
+
+/// Writes each observed event as a step (see `Interpreter::set_observer`).
+pub struct Recorder<W: Write> {
+    out: W,
+    /// The first write that failed; later steps are dropped.
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, error: None }
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        if self.error.is_some() {
+            return;
+        }
+        let fields = match *event {
+            Event::Assign {
+                name,
+                value,
+                global,
+                span,
+            } => {
+                let kind = if global { "global" } else { "set" };
+                vec![
+                    kind.to_string(),
+                    span_field(Some(span)),
+                    name.to_string(),
+                    show(value),
+                ]
+            }
+            Event::Call { name, args, span } => {
+                let mut fields = vec!["call".to_string(), span_field(span), name.to_string()];
+                fields.extend(args.iter().map(show));
+                fields
+            }
+            Event::Return {
+                name,
+                value: Some(value),
+            } => vec!["return".to_string(), name.to_string(), show(value)],
+            Event::Return { name, value: None } => vec!["raise".to_string(), name.to_string()],
+        };
+        let line: Vec<String> = fields.iter().map(|field| escape(field)).collect();
+        if let Err(e) = writeln!(self.out, "{}", line.join("\t")) {
+            self.error = Some(e);
+        }
+    }
+
+    /// Flush the trace, reporting the first write that failed.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// The program's state after step `at` (counting from 1) of `trace`.
+/// Without `at`, the state where the run ended; if it ended in an error,
+/// where the error was raised, before it unwound the calls.
+pub fn replay(trace: &str, at: Option<usize>) -> Result<String, String> {
+    let steps = trace
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            Step::parse(line).ok_or_else(|| format!("line {}: not a trace step", i + 1))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if steps.is_empty() {
+        return Err("the trace has no steps".to_string());
+    }
+    let at = match at {
+        Some(at) if at == 0 || at > steps.len() => {
+            return Err(format!("--at must be between 1 and {}", steps.len()))
+        }
+        Some(at) => at,
+        None => {
+            let unwinding = steps
+                .iter()
+                .rev()
+                .take_while(|step| matches!(step, Step::Return { value: None, .. }))
+                .count();
+            (steps.len() - unwinding).max(1)
+        }
+    };
+
+    let mut state = State::default();
+    for step in &steps[..at] {
+        state.apply(step);
+    }
+    let mut out = format!("step {at} of {}: {}\n", steps.len(), steps[at - 1]);
+    if !state.calls.is_empty() {
+        out.push_str("calls, innermost first:\n");
+        for call in state.calls.iter().rev() {
+            out.push_str(&format!("  {}\n", call.describe()));
+            for (name, value) in &call.locals {
+                out.push_str(&format!("    {name} = {value}\n"));
+            }
+        }
+    }
+    out.push_str("globals:\n");
+    for (name, value) in &state.globals {
+        out.push_str(&format!("  {name} = {value}\n"));
+    }
+    Ok(out)
+}
+
+/// One line of a trace.
+#[derive(Debug)]
+enum Step {
+    Set {
+        global: bool,
+        span: Option<Span>,
+        name: String,
+        value: String,
+    },
+    Call(Call),
+    /// `value` is `None` for `raise`.
+    Return {
+        name: String,
+        value: Option<String>,
+    },
+}
+
+impl Step {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match fields.as_slice() {
+            [kind, span, name, value] if kind == "set" || kind == "global" => Some(Step::Set {
+                global: kind == "global",
+                span: parse_span(span)?,
+                name: name.clone(),
+                value: value.clone(),
+            }),
+            [kind, span, name, args @ ..] if kind == "call" => Some(Step::Call(Call {
+                name: name.clone(),
+                args: args.to_vec(),
+                span: parse_span(span)?,
+                locals: Vec::new(),
+            })),
+            [kind, name, value] if kind == "return" => Some(Step::Return {
+                name: name.clone(),
+                value: Some(value.clone()),
+            }),
+            [kind, name] if kind == "raise" => Some(Step::Return {
+                name: name.clone(),
+                value: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Set {
+                span, name, value, ..
+            } => {
+                write!(f, "{name} = {value}")?;
+                if let Some(span) = span {
+                    write!(f, " at {span}")?;
+                }
+                Ok(())
+            }
+            Step::Call(call) => f.write_str(&call.describe()),
+            Step::Return {
+                name,
+                value: Some(value),
+            } => write!(f, "{name} returned {value}"),
+            Step::Return { name, value: None } => write!(f, "{name} raised an error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Call {
+    name: String,
+    args: Vec<String>,
+    span: Option<Span>,
+    /// Variables the call has set, in the order first set.
+    locals: Vec<(String, String)>,
+}
+
+impl Call {
+    fn describe(&self) -> String {
+        let call = format!("{}({})", self.name, self.args.join(", "));
+        match self.span {
+            Some(span) => format!("{call} called at {span}"),
+            None => call,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    globals: Vec<(String, String)>,
+    /// Calls in progress, outermost first.
+    calls: Vec<Call>,
+}
+
+impl State {
+    fn apply(&mut self, step: &Step) {
+        match step {
+            Step::Set {
+                global,
+                name,
+                value,
+                ..
+            } => {
+                let vars = match self.calls.last_mut() {
+                    Some(call) if !global => &mut call.locals,
+                    _ => &mut self.globals,
+                };
+                match vars.iter_mut().find(|(var, _)| var == name) {
+                    Some((_, old)) => old.clone_from(value),
+                    None => vars.push((name.clone(), value.clone())),
+                }
+            }
+            Step::Call(call) => self.calls.push(call.clone()),
+            Step::Return { .. } => {
+                self.calls.pop();
+            }
+        }
+    }
+}
+
+fn span_field(span: Option<Span>) -> String {
+    match span {
+        Some(span) => format!("{}:{}", span.line, span.col),
+        None => "-".to_string(),
+    }
+}
+
+/// `Some(None)` for `-`, `None` if the field is not a location.
+fn parse_span(field: &str) -> Option<Option<Span>> {
+    if field == "-" {
+        return Some(None);
+    }
+    let (line, col) = field.split_once(':')?;
+    Some(Some(Span {
+        line: line.parse().ok()?,
+        col: col.parse().ok()?,
+    }))
+}
+
+/// Keep a field on one line and free of tabs.
+fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn record(source: &str) -> String {
+        let recorder = Rc::new(RefCell::new(Recorder::new(Vec::new())));
+        let mut interpreter = Interpreter::new();
+        let sink = recorder.clone();
+        interpreter.set_observer(move |event| sink.borrow_mut().record(event));
+        let _ = interpreter.run(&parse(source).unwrap());
+        drop(interpreter);
+        let recorder = Rc::try_unwrap(recorder).ok().unwrap().into_inner();
+        String::from_utf8(recorder.out).unwrap()
+    }
+
+    #[test]
+    fn test_replay_shows_calls_and_variables_at_a_step() {
+        let trace = record(
+            "x jo 5\nilo sum (a, b) open\n    total jo a + b\n    pana total\npini\ny jo sum(x, 2)\nk jo kulupu_sin(\"a\\tb\")",
+        );
+        assert_eq!(trace.lines().count(), 6);
+        assert_eq!(
+            replay(&trace, Some(3)).unwrap(),
+            "step 3 of 6: total = 7 at line 3, column 5\n\
+             calls, innermost first:\n  sum(5, 2) called at line 6, column 6\n    total = 7\n\
+             globals:\n  x = 5\n"
+        );
+        assert_eq!(
+            replay(&trace, None).unwrap(),
+            "step 6 of 6: k = [a\tb] at line 7, column 1\nglobals:\n  x = 5\n  y = 7\n  k = [a\tb]\n"
+        );
+        assert!(replay(&trace, Some(7)).is_err());
+        assert!(replay("hello", None).is_err());
+    }
+
+    #[test]
+    fn test_replay_defaults_to_where_an_error_was_raised() {
+        let trace = record("ilo f (n) open\n    m jo n / 0\n    pana m\npini\nilo g () open\n    pana f(2)\npini\ng()");
+        assert_eq!(
+            replay(&trace, None).unwrap(),
+            "step 2 of 4: f(2) called at line 6, column 10\n\
+             calls, innermost first:\n  f(2) called at line 6, column 10\n  g() called at line 8, column 1\n\
+             globals:\n"
+        );
+    }
+}